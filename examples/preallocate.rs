@@ -0,0 +1,38 @@
+// Compares the time it takes to fill a tree with and without preallocating the db file.
+//
+//      cargo run --release --example preallocate [entries]
+
+use bptree::{BTree, Result};
+use std::{env, time::Instant};
+use tempfile::TempDir;
+
+
+fn fill(btree: &mut BTree<u64, u64>, count: u64) -> Result<()> {
+    // Spread the keys over the tree, so the leaves are split all over the file.
+    for i in 0..count {
+        let key = i.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        btree.set(key, i)?;
+    }
+    btree.flush()
+}
+
+
+fn main() -> Result<()> {
+    let count: u64 = env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(1_000_000);
+
+    let temp_dir = TempDir::new()?;
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), None)?;
+    let start = Instant::now();
+    fill(&mut btree, count)?;
+    let pages = std::fs::metadata(temp_dir.path().join("db"))?.len() / 4096;
+    println!("{} entries, {} pages", count, pages);
+    println!("without preallocation: {:?}", start.elapsed());
+
+    let temp_dir = TempDir::new()?;
+    let mut btree: BTree<u64, u64> = BTree::with_preallocated(temp_dir.path(), pages, None)?;
+    let start = Instant::now();
+    fill(&mut btree, count)?;
+    println!("with preallocation:    {:?}", start.elapsed());
+
+    Ok(())
+}
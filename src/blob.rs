@@ -0,0 +1,97 @@
+use crate::error::{Error, Result};
+use crate::{db_path, lock_path, meta_file_path, meta_tmp_file_path, write_meta, BTree, BTreeBuilder, PageCodec};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    path::Path,
+};
+
+
+// A whole tree in a single stream, to ship a prebuilt tree as one file:
+//
+//      length of the metadata (u64, little endian)
+//      the metadata, as stored in the `meta` file
+//      the pages of the db file
+//
+// The tree is compacted on the way out, so there are no deleted pages in between and the number of
+// pages follows from the metadata.
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Writes the tree to `w`, see `load_from` to read it back. The tree is rebuilt like `compact` does,
+    // in a directory next to it that is removed afterwards, and left as it is.
+    pub fn save_to<W: Write>(&mut self, mut w: W) -> Result<()> {
+        // `compact_step` builds its tree in the same directory.
+        self.discard_compaction()?;
+        let compacted = self.build_compacted(self.entries(), self.len())?;
+        let result = compacted.write_blob(&mut w);
+        fs::remove_dir_all(&compacted.directory)?;
+        result
+    }
+
+    fn write_blob<W: Write>(&self, w: &mut W) -> Result<()> {
+        let meta = self.meta_bytes()?;
+        w.write_all(&(meta.len() as u64).to_le_bytes())?;
+        w.write_all(&meta)?;
+        if self.node_count > 0 {
+            io::copy(&mut File::open(db_path(&self.directory, &self.name))?, w)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    // Reads a tree written by `save_to` from `r` into `scratch_dir` and opens it like `open_existing`.
+    // Returns `Error::InvalidConfiguration` if `scratch_dir` already holds a tree, and
+    // `Error::InvalidFileFormat` or `Error::Corrupt` if `r` isn't a complete tree, in which case the
+    // files written to `scratch_dir` are removed again.
+    pub fn load_from<R: Read, P: AsRef<Path>>(mut r: R, scratch_dir: P) -> Result<Self> {
+        let directory = scratch_dir.as_ref();
+        if meta_file_path(directory, "").exists() {
+            return Err(Error::InvalidConfiguration(String::from("directory already contains a BTree")));
+        }
+        let mut length = [0; 8];
+        r.read_exact(&mut length).map_err(|_| Error::InvalidFileFormat { page_nr: None })?;
+        let length = u64::from_le_bytes(length);
+        // Read through `take` instead of allocating `length` bytes, which may be garbage.
+        let mut meta = vec![];
+        r.by_ref().take(length).read_to_end(&mut meta)?;
+        if meta.len() as u64 != length {
+            return Err(Error::InvalidFileFormat { page_nr: None });
+        }
+        let btree = Self::decode_meta(Cursor::new(&meta))?;
+        // The page count and size come from the stream as well.
+        let size = btree
+            .node_count
+            .checked_add(btree.emtpy_pages.len() as u64)
+            .and_then(|page_count| page_count.checked_mul(btree.page_size))
+            .ok_or(Error::InvalidFileFormat { page_nr: None })?;
+
+        fs::create_dir_all(directory)?;
+        let result = Self::load_pages(r, directory, &meta, size);
+        if result.is_err() {
+            // Remove what was written of the tree. Some of the files may not have been written yet, and
+            // the error to return is the one that stopped the load.
+            let paths = [db_path, meta_tmp_file_path, meta_file_path, lock_path].map(|path| path(directory, ""));
+            for path in paths {
+                let _ = fs::remove_file(path);
+            }
+        }
+        result
+    }
+
+    // Writes the `size` bytes of pages that follow in `r` and the metadata `meta` to `directory`, and
+    // opens the tree.
+    fn load_pages<R: Read>(r: R, directory: &Path, meta: &[u8], size: u64) -> Result<Self> {
+        let copied = io::copy(&mut r.take(size), &mut File::create(db_path(directory, ""))?)?;
+        if copied != size {
+            return Err(Error::Corrupt(format!("expected {} bytes of pages, got {}", size, copied)));
+        }
+        write_meta(directory, "", meta)?;
+        BTreeBuilder::new(directory).create(false).open()
+    }
+}
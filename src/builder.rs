@@ -0,0 +1,320 @@
+use crate::codec::{BincodeCodec, PageCodec};
+use crate::error::{Error, Result};
+use crate::store::Store;
+use crate::{lock_path, meta_file_path, BTree, PageAllocation, PageAllocator, DEFAULT_FILL_FACTOR, DEFAULT_PAGE_SIZE};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+
+// The options to open a tree with, see `BTree::builder`:
+//
+//      let btree: BTree<u64, String> = BTree::builder(directory).page_size(8192).wal(true).open()?;
+//
+// The node capacity, page size, fill factor and multimap are stored in the metadata when a tree is
+// created: an existing tree is opened with the values it was created with.
+#[derive(Debug)]
+pub struct BTreeBuilder<K, V, C = BincodeCodec> {
+    directory: PathBuf,
+    name: String,
+    max_key_count: Option<u64>,
+    page_size: u64,
+    fill_factor: f64,
+    multimap: bool,
+    wal: bool,
+    free_page_limit: Option<u64>,
+    page_allocator: Box<dyn PageAllocator>,
+    read_only: bool,
+    lock: bool,
+    create: bool,
+    recover: bool,
+    preallocated_pages: u64,
+    #[cfg(feature = "snapshots")]
+    snapshots: bool,
+    key_type: PhantomData<K>,
+    value_type: PhantomData<V>,
+    codec: PhantomData<C>,
+}
+
+
+impl<K, V, C> BTreeBuilder<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    pub(crate) fn new(directory: &Path) -> Self {
+        BTreeBuilder {
+            directory: PathBuf::from(directory),
+            name: String::new(),
+            max_key_count: None,
+            page_size: DEFAULT_PAGE_SIZE,
+            fill_factor: DEFAULT_FILL_FACTOR,
+            multimap: false,
+            wal: false,
+            free_page_limit: None,
+            page_allocator: Box::new(PageAllocation::default()),
+            read_only: false,
+            lock: true,
+            create: true,
+            recover: false,
+            preallocated_pages: 0,
+            #[cfg(feature = "snapshots")]
+            snapshots: false,
+            key_type: PhantomData,
+            value_type: PhantomData,
+            codec: PhantomData,
+        }
+    }
+
+    // Opens the tree called `name` instead of the tree that has the directory to itself, see
+    // `BTree::open_named`.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        self
+    }
+
+    // The maximum number of keys in a node instead of the number that fits in a page, see
+    // `max_key_count`. It's used for leaves and internal nodes alike. `None` is the number that fits.
+    pub fn max_key_count<N: Into<Option<u64>>>(mut self, max_key_count: N) -> Self {
+        self.max_key_count = max_key_count.into();
+        self
+    }
+
+    // See `BTree::open_with_page_size`.
+    pub fn page_size(mut self, page_size: u64) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    // See `BTree::open_with_fill_factor`.
+    pub fn fill_factor(mut self, fill_factor: f64) -> Self {
+        self.fill_factor = fill_factor;
+        self
+    }
+
+    // See `BTree::open_multimap`.
+    pub fn multimap(mut self, multimap: bool) -> Self {
+        self.multimap = multimap;
+        self
+    }
+
+    // Whether changes go through the write-ahead log, see `BTree::set_wal`.
+    pub fn wal(mut self, wal: bool) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    // See `BTree::set_free_page_limit`.
+    pub fn free_page_limit<N: Into<Option<u64>>>(mut self, limit: N) -> Self {
+        self.free_page_limit = limit.into();
+        self
+    }
+
+    // See `BTree::set_page_allocator`.
+    pub fn page_allocator<A: PageAllocator + 'static>(mut self, allocator: A) -> Self {
+        self.page_allocator = Box::new(allocator);
+        self
+    }
+
+    // Opens an existing tree that can only be read: changing it fails with `Error::ReadOnly` and
+    // nothing is written when it's dropped. Nothing is written when it's opened either: a tree with
+    // a change that was interrupted by a crash (see `BTree::set_wal` and `BTree::compact`) can't be
+    // opened read-only, `Error::ReadOnly` is returned until it's opened for writing once.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Whether the tree is locked while it's open, so that it can't be opened by another process at
+    // the same time: a tree that is opened for writing can't be opened by anyone else, a tree that
+    // is opened read-only only by others that read it (`Error::Locked` otherwise). Enabled by
+    // default. A tree can't be opened twice by the same process either, use `BTree::reader`.
+    //
+    // The lock is advisory: it only keeps out others that lock the tree as well. It's an `flock` on
+    // Unix and a `LockFileEx` on Windows, of a file of its own (`lock`, next to `db`). Network file
+    // systems may not support it. A tree opened read-only from a directory that can't be written
+    // isn't locked.
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    // Whether a new tree is created if there's none, see `BTree::open_existing`. A tree that is
+    // opened read-only is never created.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    // Whether the pages at the end of the db file of an existing tree that can't be read are
+    // discarded, see `BTree::open_with_recovery`. The entries stored in them are lost and the tree is
+    // rebuilt, which can't be done when it's opened read-only.
+    pub fn recover(mut self, recover: bool) -> Self {
+        self.recover = recover;
+        self
+    }
+
+    // See `BTree::with_preallocated`.
+    pub fn preallocate(mut self, pages: u64) -> Self {
+        self.preallocated_pages = pages;
+        self
+    }
+
+    // Whether `BTree::snapshot` can be used. Off by default: the first write to a page that a
+    // snapshot can read copies the page, see `BTree::snapshot`. Not stored in the metadata.
+    #[cfg(feature = "snapshots")]
+    pub fn snapshots(mut self, snapshots: bool) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    // Encodes the nodes with codec `D`, see `BTree::open_with_codec`.
+    pub fn codec<D: PageCodec<K, V>>(self) -> BTreeBuilder<K, V, D> {
+        BTreeBuilder {
+            directory: self.directory,
+            name: self.name,
+            max_key_count: self.max_key_count,
+            page_size: self.page_size,
+            fill_factor: self.fill_factor,
+            multimap: self.multimap,
+            wal: self.wal,
+            free_page_limit: self.free_page_limit,
+            page_allocator: self.page_allocator,
+            read_only: self.read_only,
+            lock: self.lock,
+            create: self.create,
+            recover: self.recover,
+            preallocated_pages: self.preallocated_pages,
+            #[cfg(feature = "snapshots")]
+            snapshots: self.snapshots,
+            key_type: PhantomData,
+            value_type: PhantomData,
+            codec: PhantomData,
+        }
+    }
+
+    pub fn open(self) -> Result<BTree<K, V, C>> {
+        let name = self.name.as_str();
+        check_name(name)?;
+        if self.recover && self.read_only {
+            return Err(Error::InvalidConfiguration(String::from("a tree opened read-only can't be recovered")));
+        }
+        // A tree that is opened read-only is never created.
+        let create = self.create && !self.read_only;
+        let exists = meta_file_path(&self.directory, name).is_file();
+        if !exists && !create {
+            return Err(Error::NotFound);
+        }
+        // Before the write-ahead log is replayed: a writer replays it under the exclusive lock, a
+        // reader doesn't replay it at all.
+        let lock = self.take_lock()?;
+        let mut btree = if self.recover && exists {
+            BTree::recover_existing(&self.directory, name)?
+        } else if create {
+            BTree::open_with(&self.directory, name, self.max_key_count, self.page_size, self.fill_factor)?
+        } else {
+            BTree::load_existing(&self.directory, name, self.read_only)?
+        };
+        if let Some(lock) = lock {
+            btree.store.set_lock(lock);
+        }
+        self.configure(btree)
+    }
+
+    // Builds a new tree from `entries` with the name, node capacity, page size, fill factor and
+    // codec of this builder, see `BTree::bulk_load`. The other options are applied to the tree
+    // that is returned, like `open` does. Fails if the tree already exists.
+    pub fn bulk_load<I>(self, entries: I) -> Result<BTree<K, V, C>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let lock = self.check_bulk_load()?;
+        let (directory, name) = (&self.directory, self.name.as_str());
+        let (page_size, fill_factor) = (self.page_size, self.fill_factor);
+        let entries = entries.into_iter().map(Ok);
+        let mut btree =
+            BTree::build_streaming_with(directory, name, self.max_key_count, page_size, fill_factor, entries)?;
+        if let Some(lock) = lock {
+            btree.store.set_lock(lock);
+        }
+        self.configure(btree)
+    }
+
+    // Like `bulk_load`, without collecting `entries` in memory, see `BTree::build_streaming`.
+    pub fn build_streaming<I>(self, entries: I) -> Result<BTree<K, V, C>>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+    {
+        let lock = self.check_bulk_load()?;
+        let (directory, name) = (&self.directory, self.name.as_str());
+        let (page_size, fill_factor) = (self.page_size, self.fill_factor);
+        let mut btree =
+            BTree::build_streaming_with(directory, name, self.max_key_count, page_size, fill_factor, entries)?;
+        if let Some(lock) = lock {
+            btree.store.set_lock(lock);
+        }
+        self.configure(btree)
+    }
+
+    // The checks before a tree is built from sorted entries, which also takes the lock.
+    fn check_bulk_load(&self) -> Result<Option<(PathBuf, File)>> {
+        check_name(&self.name)?;
+        if self.read_only || self.multimap || self.recover {
+            let msg = "a tree that is built from sorted entries can't be read-only, a multimap or recovered";
+            return Err(Error::InvalidConfiguration(String::from(msg)));
+        }
+        if meta_file_path(&self.directory, &self.name).exists() {
+            return Err(Error::InvalidConfiguration(String::from("directory already contains a BTree")));
+        }
+        self.take_lock()
+    }
+
+    fn take_lock(&self) -> Result<Option<(PathBuf, File)>> {
+        match self.lock {
+            true => {
+                fs::create_dir_all(&self.directory)?;
+                Store::lock(lock_path(&self.directory, &self.name), self.read_only)
+            }
+            false => Ok(None),
+        }
+    }
+
+    // Applies the options that aren't stored in the metadata to an opened tree.
+    fn configure(self, mut btree: BTree<K, V, C>) -> Result<BTree<K, V, C>> {
+        if self.multimap {
+            if btree.node_count == 0 && btree.emtpy_pages.is_empty() && !self.read_only {
+                btree.multimap = true;
+            }
+            if !btree.multimap {
+                let msg = String::from("directory contains a BTree that is not a multimap");
+                return Err(Error::InvalidConfiguration(msg));
+            }
+        }
+        if !self.read_only {
+            btree.set_wal(self.wal);
+            btree.set_free_page_limit(self.free_page_limit);
+            btree.page_allocator = self.page_allocator;
+            if btree.store.page_count()? < self.preallocated_pages {
+                btree.store.set_page_count(self.preallocated_pages)?;
+            }
+        }
+        #[cfg(feature = "snapshots")]
+        {
+            btree.snapshots.enabled = self.snapshots;
+        }
+        Ok(btree)
+    }
+}
+
+
+fn check_name(name: &str) -> Result<()> {
+    if !name.is_empty() && name.contains(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')) {
+        return Err(Error::InvalidConfiguration(format!("invalid tree name {:?}", name)));
+    }
+    Ok(())
+}
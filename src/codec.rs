@@ -0,0 +1,110 @@
+#[cfg(feature = "zstd")]
+use crate::error::Error;
+use crate::error::Result;
+use crate::node::{BTNode, PagePtr, DEFAULT_PAGE_PTR_SIZE};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+
+// The encoding of the nodes in the db file, see `BTree::open_with_codec`. A tree must always be
+// opened with the codec it was created with. Only the nodes are encoded with the codec: the
+// metadata and the overflow pages holding large values are always encoded with bincode.
+//
+// The number of keys in a node is computed from the sizes of `K` and `V` in memory and the size of
+// an encoded empty node (see `max_key_count`), so a full node must not be encoded in more bytes than
+// that. Storing a node that doesn't fit in a page fails with `Error::NodeTooLarge`.
+pub trait PageCodec<K, V> {
+    // How many times the entries that fit in a page a leaf may hold, for a codec that compresses. A
+    // leaf with more entries than fit in a page uncompressed is full when its encoding no longer
+    // fits, or when it holds this many times as many entries, see `BTree::leaf_capacity`. 1 for a
+    // codec that doesn't make the nodes smaller.
+    const MAX_COMPRESSION_RATIO: u64 = 1;
+
+    // The number of bytes the page numbers in a node take, 4 or 8. It's recorded in the metadata, and
+    // the overflow pages use it as well. Opening a tree with a codec that stores page numbers in a
+    // different size fails with `Error::InvalidConfiguration`.
+    const PAGE_PTR_SIZE: u64 = DEFAULT_PAGE_PTR_SIZE;
+
+    fn encode(node: &BTNode<K, V>) -> Result<Vec<u8>>;
+
+    // Decodes the node in page `page_nr` from `bytes`, which is the whole page: it's followed by
+    // padding up to the page size.
+    fn decode(bytes: &[u8], page_nr: PagePtr) -> Result<BTNode<K, V>>;
+}
+
+
+// The default codec, which encodes the nodes with bincode. The page numbers take `PAGE_PTR_SIZE`
+// bytes: `BincodeCodec<4>` fits more keys in a node when they're small, but limits a tree to 2^32
+// pages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BincodeCodec<const PAGE_PTR_SIZE: u64 = DEFAULT_PAGE_PTR_SIZE>;
+
+
+impl<K, V, const PAGE_PTR_SIZE: u64> PageCodec<K, V> for BincodeCodec<PAGE_PTR_SIZE>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+{
+    const PAGE_PTR_SIZE: u64 = PAGE_PTR_SIZE;
+
+    fn encode(node: &BTNode<K, V>) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        node.serialize_with_page_ptr_size(&mut bytes, PAGE_PTR_SIZE)?;
+        Ok(bytes)
+    }
+
+    fn decode(mut bytes: &[u8], page_nr: PagePtr) -> Result<BTNode<K, V>> {
+        BTNode::deserialize_with_page_ptr_size(&mut bytes, page_nr, PAGE_PTR_SIZE)
+    }
+}
+
+
+// Compresses the bincode encoding of the nodes with zstd, at `LEVEL` (1 to 22, 0 is zstd's
+// default). Each page starts with a tag: 1 if the rest is a compressed length (u32, little endian)
+// followed by a zstd frame, 0 if it's the bincode encoding itself, which is used when compressing
+// doesn't make the node smaller. So an encoded node is at most one byte larger than with
+// `BincodeCodec`, and a node that fits in a page uncompressed fits in its page.
+//
+// The leaves are filled until their compressed encoding fills a page, with up to 8 times as many
+// entries as fit uncompressed, see `PageCodec::MAX_COMPRESSION_RATIO`. The internal nodes keep the
+// capacity of `BincodeCodec`. The page numbers take `PAGE_PTR_SIZE` bytes, as with `BincodeCodec`.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ZstdCodec<const LEVEL: i32 = 0, const PAGE_PTR_SIZE: u64 = DEFAULT_PAGE_PTR_SIZE>;
+
+
+#[cfg(feature = "zstd")]
+impl<K, V, const LEVEL: i32, const PAGE_PTR_SIZE: u64> PageCodec<K, V> for ZstdCodec<LEVEL, PAGE_PTR_SIZE>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+{
+    const MAX_COMPRESSION_RATIO: u64 = 8;
+    const PAGE_PTR_SIZE: u64 = PAGE_PTR_SIZE;
+
+    fn encode(node: &BTNode<K, V>) -> Result<Vec<u8>> {
+        let mut bytes = vec![0_u8];
+        node.serialize_with_page_ptr_size(&mut bytes, PAGE_PTR_SIZE)?;
+        let compressed = zstd::bulk::compress(&bytes[1..], LEVEL)?;
+        if compressed.len() + 4 >= bytes.len() - 1 {
+            return Ok(bytes);
+        }
+        let mut bytes = vec![1_u8];
+        bytes.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&compressed);
+        Ok(bytes)
+    }
+
+    fn decode(bytes: &[u8], page_nr: PagePtr) -> Result<BTNode<K, V>> {
+        match bytes.first() {
+            Some(0) => BincodeCodec::<PAGE_PTR_SIZE>::decode(&bytes[1..], page_nr),
+            Some(1) if bytes.len() >= 5 => {
+                let len = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+                let compressed = bytes.get(5..5 + len).ok_or(Error::InvalidFileFormat { page_nr: Some(page_nr) })?;
+                let decompressed = zstd::stream::decode_all(compressed)?;
+                BincodeCodec::<PAGE_PTR_SIZE>::decode(&decompressed, page_nr)
+            }
+            _ => Err(Error::InvalidFileFormat { page_nr: Some(page_nr) }),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use crate::codec::PageCodec;
+use crate::error::{Error, Result};
+use crate::node::{BTNode, PagePtr};
+use crate::writer::{TreeWriter, WriterState};
+use crate::{replace_file, sync_directory, tree_file_path, BTree, BTreeRangeIterator};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fmt::Debug,
+    fs, io,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+
+// The progress of `BTree::compact_step`: the tree being built in the `compact` directory, and the
+// entries that changed after they were copied to it.
+#[derive(Debug)]
+pub(crate) struct Compaction<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    writer: TreeWriter<K, V, C>,
+    // The key of the last entry copied: the next step continues after it.
+    last_key: Option<K>,
+    // The keys up to `last_key` whose entries changed after they were copied. They're copied again
+    // when the new tree is complete.
+    changed: BTreeSet<K>,
+    // The pages that have been deleted since the tree was last flushed and that didn't hold a leaf.
+    // An overflow page that is deleted can hold another value of the same leaf when it's stored.
+    deleted: BTreeSet<PagePtr>,
+    // Whether the progress file holds this state.
+    saved: bool,
+}
+
+
+// The progress file: a compaction as it is stored in the `compact` directory.
+#[derive(Serialize, Deserialize)]
+struct Progress<K: Ord, V> {
+    // the metadata of the tree being built
+    meta: Vec<u8>,
+    writer: WriterState<K, V>,
+    last_key: Option<K>,
+    changed: BTreeSet<K>,
+}
+
+
+// The directory that `compact` and `compact_step` build the new tree of tree `name` in.
+pub(crate) fn compact_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "compact")
+}
+
+
+fn progress_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(&compact_path(directory, name), name, "progress")
+}
+
+
+fn progress_tmp_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(&compact_path(directory, name), name, "progress.tmp")
+}
+
+
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Does part of the work of `compact`: copies entries to the new db file until about `max_pages`
+    // pages have been written to it, at least 1. Returns `true` while there's more to do, and `false`
+    // when the new db file has replaced the current one. The last call also writes the last leaves
+    // and the internal nodes above them. In a multimap, the values of a key are copied in one call.
+    //
+    // The tree can be read and changed between the calls. The entries that change after they have
+    // been copied are copied again when the new tree is complete, unless more than half of the
+    // entries copied so far change: then the next call starts over.
+    //
+    // Every call flushes the tree and stores the progress next to the new db file, so that the tree
+    // can be closed and opened again in between. Changing an entry that has been copied removes the
+    // stored progress until the tree is flushed, so that after a crash the compaction starts over
+    // instead of missing the change. Returns `Error::SnapshotsHeld` while there are snapshots, see
+    // `snapshot`.
+    pub fn compact_step(&mut self, max_pages: usize) -> Result<bool> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        #[cfg(feature = "snapshots")]
+        if !self.snapshots.is_empty() {
+            return Err(Error::SnapshotsHeld);
+        }
+        let mut compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => Box::new(Compaction {
+                writer: TreeWriter::new(self.new_compacted()?),
+                last_key: None,
+                changed: BTreeSet::new(),
+                deleted: BTreeSet::new(),
+                saved: false,
+            }),
+        };
+        let result = self.copy_entries(&mut compaction, max_pages);
+        match result {
+            Ok(true) => {
+                compaction.saved = false;
+                self.compaction = Some(compaction);
+                self.flush()?;
+                Ok(true)
+            }
+            Ok(false) => match self.finish_compaction(*compaction) {
+                Ok(()) => Ok(false),
+                Err(err) => {
+                    self.discard_compaction()?;
+                    Err(err)
+                }
+            },
+            Err(err) => {
+                // The writer may have stopped halfway through a node.
+                self.discard_compaction()?;
+                Err(err)
+            }
+        }
+    }
+
+    // Copies the entries after the last one copied until `max_pages` pages have been written, and
+    // returns whether there are entries left.
+    fn copy_entries(&self, compaction: &mut Compaction<K, V, C>, max_pages: usize) -> Result<bool> {
+        let page_limit = compaction.writer.tree.node_count + max_pages.max(1) as u64;
+        let start = compaction.last_key.clone().map_or(Bound::Unbounded, Bound::Excluded);
+        let mut entries = BTreeRangeIterator::new(self, (start, Bound::Unbounded))?;
+        while let Some(i) = entries.advance() {
+            let i = i?;
+            let leaf = entries.leaf.as_ref().unwrap();
+            let key = leaf.key(i);
+            if compaction.writer.tree.node_count >= page_limit && compaction.last_key.as_ref() != Some(key) {
+                return Ok(true);
+            }
+            compaction.writer.push(key.clone(), leaf.value(self, i)?)?;
+            compaction.last_key = Some(key.clone());
+        }
+        Ok(false)
+    }
+
+    // Writes the last nodes of the new tree, copies the entries that changed again and replaces the
+    // tree with it.
+    fn finish_compaction(&mut self, compaction: Compaction<K, V, C>) -> Result<()> {
+        let mut compacted = compaction.writer.finish()?;
+        for key in compaction.changed {
+            if self.multimap {
+                while compacted.remove(key.clone())?.is_some() {}
+                for value in self.get_all(key.clone())? {
+                    compacted.set(key.clone(), value)?;
+                }
+            } else {
+                match self.get(key.clone())? {
+                    Some(value) => compacted.set(key, value)?,
+                    None => compacted.remove(key)?,
+                };
+            }
+        }
+        compacted.flush()?;
+        if compacted.entry_count != self.entry_count {
+            let msg = format!("expected {} entries, got {}", self.entry_count, compacted.entry_count);
+            return Err(Error::Corrupt(msg));
+        }
+        self.replace_with(compacted)
+    }
+
+    // Notes the entries that change when page `page_nr` is overwritten with `node`, or deleted if
+    // `node` is `None`, by comparing it with the page as it is.
+    pub(crate) fn note_page_change(&mut self, page_nr: PagePtr, node: Option<&BTNode<K, V>>) -> Result<()> {
+        let last_key = match self.compaction.as_ref().and_then(|compaction| compaction.last_key.clone()) {
+            Some(last_key) => last_key,
+            None => return Ok(()),
+        };
+        let empty = || BTNode::new_leaf(page_nr, &[], &[], None).leaf_node();
+        let deleted;
+        let new = match node {
+            // A page in use is only overwritten with an internal node after it has been deleted.
+            Some(BTNode::Internal(_)) => return Ok(()),
+            Some(BTNode::Leaf(leaf)) => leaf,
+            None => {
+                deleted = empty();
+                &deleted
+            }
+        };
+        let old = match self.store.read_page(page_nr, |page| C::decode(page, page_nr)) {
+            Ok(BTNode::Leaf(leaf)) => leaf,
+            Err(Error::Io(err)) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(Error::Io(err)),
+            // A new page, or one that doesn't hold a leaf.
+            _ => {
+                if node.is_none() {
+                    self.compaction.as_mut().unwrap().deleted.insert(page_nr);
+                }
+                empty()
+            }
+        };
+        let compaction = self.compaction.as_mut().unwrap();
+        let changed = old.changed_keys(new, &compaction.deleted)?;
+        let changed: Vec<K> = changed.into_iter().filter(|key| *key <= last_key).collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        compaction.changed.extend(changed);
+        if compaction.changed.len() as u64 > compaction.writer.count / 2 {
+            return self.discard_compaction();
+        }
+        if compaction.saved {
+            compaction.saved = false;
+            fs::remove_file(progress_path(&self.directory, &self.name))?;
+            sync_directory(&compact_path(&self.directory, &self.name))?;
+        }
+        Ok(())
+    }
+
+    // Stores the progress of the compaction, if it has changed. The pages of the new tree go first.
+    pub(crate) fn save_compaction(&mut self) -> Result<()> {
+        let compaction = match self.compaction.as_mut() {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+        // A change doesn't span a flush.
+        compaction.deleted.clear();
+        if compaction.saved {
+            return Ok(());
+        }
+        compaction.writer.tree.store.sync()?;
+        let progress = Progress {
+            meta: compaction.writer.tree.meta_bytes()?,
+            writer: compaction.writer.state(),
+            last_key: compaction.last_key.clone(),
+            changed: compaction.changed.clone(),
+        };
+        let path = progress_path(&self.directory, &self.name);
+        replace_file(&path, &progress_tmp_path(&self.directory, &self.name), &bincode::serialize(&progress)?)?;
+        compaction.saved = true;
+        Ok(())
+    }
+
+    // Picks up the compaction that was in progress when the tree was closed, if any. A progress file
+    // that can't be read is removed with the rest of the compaction, which starts over.
+    pub(crate) fn load_compaction(&mut self) -> Result<()> {
+        let bytes = match fs::read(progress_path(&self.directory, &self.name)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        match self.decode_progress(&bytes) {
+            Ok(compaction) => self.compaction = Some(Box::new(compaction)),
+            Err(Error::Io(err)) => return Err(Error::Io(err)),
+            Err(_) => self.discard_compaction()?,
+        }
+        Ok(())
+    }
+
+    fn decode_progress(&self, bytes: &[u8]) -> Result<Compaction<K, V, C>> {
+        let progress: Progress<K, V> = bincode::deserialize(bytes)?;
+        let compact_directory = compact_path(&self.directory, &self.name);
+        let tree = Self::decode_meta(io::Cursor::new(&progress.meta))?;
+        let tree = tree.located_at(&compact_directory, &self.name, false);
+        // The pages written after the progress was stored are written again.
+        tree.store.set_page_count(tree.node_count)?;
+        Ok(Compaction {
+            writer: TreeWriter::resume(tree, progress.writer),
+            last_key: progress.last_key,
+            changed: progress.changed,
+            deleted: BTreeSet::new(),
+            saved: true,
+        })
+    }
+
+    // Drops the compaction in progress, if any, and removes its directory.
+    pub(crate) fn discard_compaction(&mut self) -> Result<()> {
+        self.compaction = None;
+        let compact_directory = compact_path(&self.directory, &self.name);
+        if compact_directory.exists() {
+            fs::remove_dir_all(&compact_directory)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use crate::error::Result;
+use crate::{BTree, PageCodec};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::Debug,
+    io::{Read, Write},
+};
+
+
+// Import and export of the entries as CSV rows of `key,value`, without a header row. Keys and
+// values must serialize to CSV scalars (numbers, strings, ...), otherwise an error is returned.
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Writes all entries to `w`, in ascending order of the keys.
+    pub fn export_csv<W: Write>(&self, w: W) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+        for entry in self.entries() {
+            writer.serialize(entry)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Sets every row of `r`, returns the number of rows. The rows don't have to be sorted.
+    pub fn import_csv<R: Read>(&mut self, r: R) -> Result<usize> {
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(r);
+        let mut count = 0;
+        for row in reader.deserialize() {
+            let (key, value): (K, V) = row?;
+            self.set(key, value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
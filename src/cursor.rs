@@ -0,0 +1,235 @@
+use crate::codec::PageCodec;
+use crate::error::{Error, Result};
+use crate::node::Leaf;
+use crate::{BTree, BincodeCodec};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+
+// A position in the entries of a tree that can move in both directions, see `BTree::cursor_at`.
+// It's on an entry, or off one of the ends: moving back from after the last entry goes to the last
+// one, and moving forward from before the first entry goes to the first one.
+//
+// The position is kept as the index of the entry as well, because the leaves are only linked
+// forward: moving back from the first entry of a leaf descends from the root to the entry before
+// it, using the entry counts in the internal nodes. That also works for the equal keys of a
+// multimap, which can be spread over several leaves.
+pub struct BTreeCursor<'a, K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: &'a BTree<K, V, C>,
+    position: Position<K, V>,
+}
+
+
+// A cursor that can change the value of the current entry as well, see `BTree::cursor_at_mut`.
+pub struct BTreeCursorMut<'a, K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: &'a mut BTree<K, V, C>,
+    position: Position<K, V>,
+}
+
+
+// The position of `BTreeCursor` and `BTreeCursorMut`, which pass their tree in.
+struct Position<K, V> {
+    // The leaf holding the current entry and the index of the entry in that leaf, `None` when the
+    // cursor is off one of the ends.
+    leaf: Option<(Leaf<K, V>, usize)>,
+    // The index of the current entry in `entries()`: `len()` after the last entry. Before the first
+    // entry, `leaf` is `None` and this is 0.
+    n: u64,
+}
+
+
+impl<K, V> Position<K, V>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+{
+    fn key(&self) -> Option<&K> {
+        self.leaf.as_ref().map(|(leaf, i)| leaf.key(*i))
+    }
+
+    fn value<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<Option<V>> {
+        match &self.leaf {
+            Some((leaf, i)) => Ok(Some(leaf.value(btree, *i)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn seek<C: PageCodec<K, V>>(&mut self, btree: &BTree<K, V, C>, key: &K) -> Result<bool> {
+        self.move_to(btree, btree.rank(key.clone())? as u64)
+    }
+
+    fn move_next<C: PageCodec<K, V>>(&mut self, btree: &BTree<K, V, C>) -> Result<bool> {
+        match &mut self.leaf {
+            Some((leaf, i)) if *i + 1 < leaf.len() => {
+                *i += 1;
+                self.n += 1;
+                Ok(true)
+            }
+            Some((leaf, _)) => {
+                self.n += 1;
+                self.leaf = match leaf.next() {
+                    Some(page_nr) => Some((btree.load_node(page_nr)?.leaf_node(), 0)),
+                    None => None,
+                };
+                Ok(self.leaf.is_some())
+            }
+            None if self.n == 0 => self.move_to(btree, 0),
+            None => Ok(false),
+        }
+    }
+
+    fn move_prev<C: PageCodec<K, V>>(&mut self, btree: &BTree<K, V, C>) -> Result<bool> {
+        match &mut self.leaf {
+            Some((_, i)) if *i > 0 => {
+                *i -= 1;
+                self.n -= 1;
+                Ok(true)
+            }
+            _ if self.n == 0 => {
+                self.leaf = None;
+                Ok(false)
+            }
+            _ => self.move_to(btree, self.n - 1),
+        }
+    }
+
+    // Moves to entry `n`, or after the last entry.
+    fn move_to<C: PageCodec<K, V>>(&mut self, btree: &BTree<K, V, C>, n: u64) -> Result<bool> {
+        self.n = n.min(btree.entry_count);
+        self.leaf = match self.n < btree.entry_count {
+            true => Some(btree.descend_to_index(self.n)?),
+            false => None,
+        };
+        Ok(self.leaf.is_some())
+    }
+}
+
+
+impl<'a, K, V, C> BTreeCursor<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // The key of the current entry, `None` when the cursor is off one of the ends.
+    pub fn key(&self) -> Option<&K> {
+        self.position.key()
+    }
+
+    // The value of the current entry, `None` when the cursor is off one of the ends.
+    pub fn value(&self) -> Result<Option<V>> {
+        self.position.value(self.btree)
+    }
+
+    // Moves to the first entry with a key larger than or equal to `key`, or after the last entry if
+    // there's none. Returns whether the cursor is on an entry.
+    pub fn seek(&mut self, key: &K) -> Result<bool> {
+        self.position.seek(self.btree, key)
+    }
+
+    // Moves to the next entry and returns whether there is one. After the last entry, the cursor
+    // stays there.
+    pub fn move_next(&mut self) -> Result<bool> {
+        self.position.move_next(self.btree)
+    }
+
+    // Moves to the previous entry and returns whether there is one. Before the first entry, the
+    // cursor stays there.
+    pub fn move_prev(&mut self) -> Result<bool> {
+        self.position.move_prev(self.btree)
+    }
+}
+
+
+impl<'a, K, V, C> BTreeCursorMut<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // See `BTreeCursor::key`.
+    pub fn key(&self) -> Option<&K> {
+        self.position.key()
+    }
+
+    // See `BTreeCursor::value`.
+    pub fn value(&self) -> Result<Option<V>> {
+        self.position.value(self.btree)
+    }
+
+    // See `BTreeCursor::seek`.
+    pub fn seek(&mut self, key: &K) -> Result<bool> {
+        self.position.seek(self.btree, key)
+    }
+
+    // See `BTreeCursor::move_next`.
+    pub fn move_next(&mut self) -> Result<bool> {
+        self.position.move_next(self.btree)
+    }
+
+    // See `BTreeCursor::move_prev`.
+    pub fn move_prev(&mut self) -> Result<bool> {
+        self.position.move_prev(self.btree)
+    }
+
+    // Replaces the value of the current entry like `BTree::update` does, which only writes its leaf
+    // (and overflow pages for a large value). The cursor stays on the entry. Returns
+    // `Error::KeyNotFound` when the cursor is off one of the ends, without changing anything.
+    pub fn set_value(&mut self, value: V) -> Result<()> {
+        let (leaf, i) = self.position.leaf.take().ok_or(Error::KeyNotFound)?;
+        let (page_nr, n) = (leaf.page_nr(), self.position.n);
+        let mut split = false;
+        let result = self.btree.atomically(|btree| match leaf.update(btree, i, |current| *current = value)? {
+            Some(leaf) => {
+                split = true;
+                btree.store_overfull_leaf(n - i as u64, leaf)
+            }
+            None => Ok(()),
+        });
+        // Read the leaf as it was stored: values may have moved to or from overflow pages, and with a
+        // codec that compresses, the entry may have moved to a new leaf.
+        self.position.leaf = Some(match split {
+            true => self.btree.descend_to_index(n)?,
+            false => (self.btree.load_node(page_nr)?.leaf_node(), i),
+        });
+        result
+    }
+}
+
+
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Returns a cursor on the first entry with a key larger than or equal to `key`, like `ceil`, or
+    // after the last entry if there's none. For `floor`, move back if the key isn't `key`:
+    //
+    //      let mut cursor = btree.cursor_at(key)?;
+    //      if cursor.key() != Some(&key) {
+    //          cursor.move_prev()?;
+    //      }
+    pub fn cursor_at(&self, key: K) -> Result<BTreeCursor<'_, K, V, C>> {
+        let mut position = Position { leaf: None, n: 0 };
+        position.seek(self, &key)?;
+        Ok(BTreeCursor { btree: self, position })
+    }
+
+    // Returns a cursor like `cursor_at` that can change values, see `BTreeCursorMut::set_value`.
+    pub fn cursor_at_mut(&mut self, key: K) -> Result<BTreeCursorMut<'_, K, V, C>> {
+        let mut position = Position { leaf: None, n: 0 };
+        position.seek(self, &key)?;
+        Ok(BTreeCursorMut { btree: self, position })
+    }
+}
@@ -4,19 +4,64 @@ use std::{
     fmt,
     error,
 };
-use bincode;
 
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 
+// The errors of the libraries underneath come first, wrapped as they are: they're converted with
+// `From`, so that `?` works on them. A new source gets its variant there, and the same in
+// `ErrorKind`. The error of an optional library is boxed, so that the variants don't depend on the
+// features: only its `From` is gated.
 #[derive(Debug)]
 pub enum Error {
     Io(::std::io::Error),
     Serde(bincode::Error),
+    // Reading or writing CSV failed, see `BTree::import_csv`. Holds a `csv::Error`.
+    Csv(Box<dyn error::Error + Send + Sync>),
     KeyNotFound,
+    // There's no BTree in the directory, see `BTree::open_existing`.
+    NotFound,
+    InvalidFileHandle,
+    // The metadata or the page `page_nr` can't be decoded.
+    InvalidFileFormat { page_nr: Option<u64> },
+    InvalidConfiguration(String),
+    UnsupportedVersion { found: u32, expected: u32 },
+    UnsortedInput,
+    Corrupt(String),
+    // A node that doesn't fit in its page: the keys or values are larger than expected.
+    NodeTooLarge { page_nr: u64, needed: u64, available: u64 },
+    // The snapshot doesn't exist (anymore), see `BTree::snapshot`.
+    SnapshotNotFound,
+    // The operation would discard pages that snapshots still read, see `BTree::compact`.
+    SnapshotsHeld,
+    // The tree was opened read-only, see `BTreeBuilder::read_only`.
+    ReadOnly,
+    // Another `BTree` has the tree open for writing, or for reading when opening it for writing. See
+    // `BTreeBuilder::lock`.
+    Locked,
+}
+
+
+// The kind of an `Error`, without the wrapped error. Unlike `Error`, this can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Io,
+    Serde,
+    Csv,
+    KeyNotFound,
+    NotFound,
     InvalidFileHandle,
     InvalidFileFormat,
+    InvalidConfiguration,
+    UnsupportedVersion,
+    UnsortedInput,
+    Corrupt,
+    NodeTooLarge,
+    SnapshotNotFound,
+    SnapshotsHeld,
+    ReadOnly,
+    Locked,
 }
 
 
@@ -24,6 +69,40 @@ impl Error {
     pub fn is_key_not_found(&self) -> bool {
         matches!(*self, Error::KeyNotFound)
     }
+
+    // Adds page `page_nr` to an error that occurred while reading that page: an I/O error keeps
+    // its kind, but its message starts with the page, and an error decoding the page becomes
+    // `InvalidFileFormat` for that page.
+    pub(crate) fn at_page(self, page_nr: u64) -> Error {
+        match self {
+            Error::Io(err) => Error::Io(io::Error::new(err.kind(), format!("page {}: {}", page_nr, err))),
+            Error::Serde(_) | Error::InvalidFileFormat { page_nr: None } => {
+                Error::InvalidFileFormat { page_nr: Some(page_nr) }
+            }
+            err => err,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Io(_) => ErrorKind::Io,
+            Error::Serde(_) => ErrorKind::Serde,
+            Error::Csv(_) => ErrorKind::Csv,
+            Error::KeyNotFound => ErrorKind::KeyNotFound,
+            Error::NotFound => ErrorKind::NotFound,
+            Error::InvalidFileHandle => ErrorKind::InvalidFileHandle,
+            Error::InvalidFileFormat { .. } => ErrorKind::InvalidFileFormat,
+            Error::InvalidConfiguration(_) => ErrorKind::InvalidConfiguration,
+            Error::UnsupportedVersion { .. } => ErrorKind::UnsupportedVersion,
+            Error::UnsortedInput => ErrorKind::UnsortedInput,
+            Error::Corrupt(_) => ErrorKind::Corrupt,
+            Error::NodeTooLarge { .. } => ErrorKind::NodeTooLarge,
+            Error::SnapshotNotFound => ErrorKind::SnapshotNotFound,
+            Error::SnapshotsHeld => ErrorKind::SnapshotsHeld,
+            Error::ReadOnly => ErrorKind::ReadOnly,
+            Error::Locked => ErrorKind::Locked,
+        }
+    }
 }
 
 
@@ -32,9 +111,25 @@ impl fmt::Display for Error {
         match *self {
             Error::Io(ref err) => err.fmt(f),
             Error::Serde(ref err) => err.fmt(f),
+            Error::Csv(ref err) => err.fmt(f),
             Error::KeyNotFound => write!(f, "Key not found"),
+            Error::NotFound => write!(f, "No BTree found"),
             Error::InvalidFileHandle => write!(f, "Programming error: Invalid file handle"),
-            Error::InvalidFileFormat => write!(f, "Invalid file format"),
+            Error::InvalidFileFormat { page_nr: None } => write!(f, "Invalid file format"),
+            Error::InvalidFileFormat { page_nr: Some(page_nr) } => write!(f, "Invalid file format at page {}", page_nr),
+            Error::InvalidConfiguration(ref msg) => write!(f, "Invalid configuration: {}", msg),
+            Error::UnsupportedVersion { found, expected } => {
+                write!(f, "Unsupported file format version {} (expected {})", found, expected)
+            }
+            Error::UnsortedInput => write!(f, "Input is not sorted by key in strictly ascending order"),
+            Error::Corrupt(ref msg) => write!(f, "Corrupt BTree: {}", msg),
+            Error::NodeTooLarge { page_nr, needed, available } => {
+                write!(f, "Node in page {} needs {} bytes, only {} available", page_nr, needed, available)
+            }
+            Error::SnapshotNotFound => write!(f, "Snapshot not found"),
+            Error::SnapshotsHeld => write!(f, "Not possible while snapshots are held"),
+            Error::ReadOnly => write!(f, "The BTree is opened read-only"),
+            Error::Locked => write!(f, "The BTree is in use by another process"),
         }
     }
 }
@@ -45,6 +140,7 @@ impl error::Error for Error {
         match self {
             Error::Io(ref err) => Some(err),
             Error::Serde(ref err) => Some(err),
+            Error::Csv(ref err) => Some(err.as_ref()),
             _ => None,
         }
     }
@@ -63,3 +159,35 @@ impl From<bincode::Error> for Error {
         Error::Serde(err)
     }
 }
+
+
+#[cfg(feature = "csv")]
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Error {
+        Error::Csv(Box::new(err))
+    }
+}
+
+
+// I/O errors are unwrapped again, all other errors are wrapped in an `io::Error`.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::Serde(err) => match *err {
+                bincode::ErrorKind::Io(err) => err,
+                err => io::Error::new(io::ErrorKind::InvalidData, err),
+            },
+            #[cfg(feature = "csv")]
+            Error::Csv(err) if err.is::<csv::Error>() => io::Error::from(*err.downcast::<csv::Error>().unwrap()),
+            Error::Csv(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Error::KeyNotFound | Error::NotFound | Error::SnapshotNotFound => {
+                io::Error::new(io::ErrorKind::NotFound, err)
+            }
+            Error::InvalidConfiguration(_) | Error::UnsortedInput => io::Error::new(io::ErrorKind::InvalidInput, err),
+            Error::ReadOnly => io::Error::new(io::ErrorKind::PermissionDenied, err),
+            Error::Locked => io::Error::new(io::ErrorKind::WouldBlock, err),
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
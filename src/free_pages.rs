@@ -0,0 +1,164 @@
+use crate::node::PagePtr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::BTreeSet, fmt::Debug};
+
+
+// Chooses the page of a new node or overflow page, see `BTree::set_page_allocator`. It's not stored in
+// the metadata: a tree can be opened with another allocator later on. `PageAllocation` has the
+// built-in strategies, an application can implement its own:
+//
+//      #[derive(Debug)]
+//      struct HighestDeleted;
+//
+//      impl PageAllocator for HighestDeleted {
+//          fn allocate(&mut self, free: &FreePages) -> Option<PagePtr> {
+//              free.highest()
+//          }
+//      }
+pub trait PageAllocator: Debug + Send + Sync {
+    // Returns one of the deleted pages in `free`, which the tree takes out of the list, or `None` for a
+    // new page at the end of the file. Returning a page that isn't in `free` panics.
+    fn allocate(&mut self, free: &FreePages) -> Option<PagePtr>;
+
+    // Called when `page_nr` has been deleted, after it has been added to the deleted pages. Pages can
+    // leave the list without being allocated as well, when `shrink_to_fit` or `compact` truncate the
+    // file, so an allocator that keeps its own state checks it against `free`.
+    fn free(&mut self, _page_nr: PagePtr) {}
+}
+
+
+// The built-in allocators.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PageAllocation {
+    // The page deleted last, or a new page at the end of the file if there are none.
+    #[default]
+    LastDeleted,
+    // The lowest deleted page, or a new page. The pages in use gather at the start of the file, which
+    // leaves more deleted pages at the end for `shrink_to_fit`.
+    LowestDeleted,
+    // Always a new page at the end of the file, deleted pages are only given back by `shrink_to_fit`
+    // and `compact`. Pages are never overwritten by another node.
+    Append,
+}
+
+
+impl PageAllocator for PageAllocation {
+    fn allocate(&mut self, free: &FreePages) -> Option<PagePtr> {
+        match self {
+            PageAllocation::LastDeleted => free.last(),
+            PageAllocation::LowestDeleted => free.lowest(),
+            PageAllocation::Append => None,
+        }
+    }
+}
+
+
+// The deleted pages, which are reused before the file grows. They're stored in the metadata as a
+// list with the page to reuse first at the end, and kept in a set as well, so that checking whether
+// a page has been deleted doesn't take time linear in the number of deleted pages.
+#[derive(Debug, Default, Clone)]
+pub struct FreePages {
+    pages: Vec<PagePtr>,
+    set: BTreeSet<PagePtr>,
+}
+
+
+impl FreePages {
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn contains(&self, page_nr: &PagePtr) -> bool {
+        self.set.contains(page_nr)
+    }
+
+    // The deleted pages, from the first one deleted to the last.
+    pub fn iter(&self) -> impl Iterator<Item = &PagePtr> {
+        self.pages.iter()
+    }
+
+    // The page deleted last.
+    pub fn last(&self) -> Option<PagePtr> {
+        self.pages.last().cloned()
+    }
+
+    pub fn lowest(&self) -> Option<PagePtr> {
+        self.set.iter().next().cloned()
+    }
+
+    pub fn highest(&self) -> Option<PagePtr> {
+        self.set.iter().next_back().cloned()
+    }
+
+    pub(crate) fn push(&mut self, page_nr: PagePtr) {
+        self.pages.push(page_nr);
+        self.set.insert(page_nr);
+    }
+
+    // Takes `page_nr` out of the deleted pages, returns false if it isn't one of them. The list is
+    // sorted from the highest page down when the lowest page is taken and isn't at the end, so the
+    // pages that follow are taken in order by `PageAllocation::LowestDeleted`.
+    pub(crate) fn take(&mut self, page_nr: PagePtr) -> bool {
+        if !self.set.remove(&page_nr) {
+            return false;
+        }
+        if self.pages.last() != Some(&page_nr) {
+            if self.set.iter().next().is_none_or(|lowest| page_nr < *lowest) {
+                self.pages.sort_unstable_by(|a, b| b.cmp(a));
+            } else {
+                let i = self.pages.iter().rposition(|p| *p == page_nr).expect("deleted page in the list");
+                self.pages.remove(i);
+                return true;
+            }
+        }
+        self.pages.pop();
+        true
+    }
+
+    // Removes the deleted pages at the end of a file of `page_count` pages and returns how many. The
+    // other pages are sorted from the highest down, so that the lowest pages are reused first.
+    pub(crate) fn remove_trailing(&mut self, page_count: u64) -> u64 {
+        self.pages.sort_unstable_by(|a, b| b.cmp(a));
+        let trailing = self.pages.iter().zip((0..page_count).rev()).take_while(|(a, b)| **a == *b).count();
+        for page_nr in self.pages.drain(..trailing) {
+            self.set.remove(&page_nr);
+        }
+        trailing as u64
+    }
+}
+
+
+impl From<Vec<PagePtr>> for FreePages {
+    fn from(pages: Vec<PagePtr>) -> Self {
+        let set = pages.iter().cloned().collect();
+        FreePages { pages, set }
+    }
+}
+
+
+impl Extend<PagePtr> for FreePages {
+    fn extend<I: IntoIterator<Item = PagePtr>>(&mut self, pages: I) {
+        for page_nr in pages {
+            self.push(page_nr);
+        }
+    }
+}
+
+
+// Stored as the list of pages only.
+impl Serialize for FreePages {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.pages.serialize(serializer)
+    }
+}
+
+
+impl<'de> Deserialize<'de> for FreePages {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(FreePages::from(Vec::<PagePtr>::deserialize(deserializer)?))
+    }
+}
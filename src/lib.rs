@@ -2,24 +2,66 @@
 // #![allow(unused_variables)]
 // #![allow(unused_imports)]
 
+mod blob;
+mod builder;
+mod codec;
+mod compaction;
+mod cursor;
 mod error;
+mod free_pages;
+mod map;
 mod node;
+mod prefix;
+#[cfg(feature = "snapshots")]
+mod snapshot;
+mod store;
+mod sync;
+mod wal;
+mod writer;
+#[cfg(feature = "csv")]
+mod csv_io;
 
-pub use error::{Error, Result};
+pub use builder::BTreeBuilder;
+pub use codec::{BincodeCodec, PageCodec};
+#[cfg(feature = "zstd")]
+pub use codec::ZstdCodec;
+pub use cursor::{BTreeCursor, BTreeCursorMut};
+pub use error::{Error, ErrorKind, Result};
+pub use free_pages::{FreePages, PageAllocation, PageAllocator};
+pub use map::PersistentMap;
 pub use node::{PagePtr, Leaf, BTNode};
+pub use prefix::KeyPrefix;
+pub use store::PageStats;
+#[cfg(feature = "snapshots")]
+pub use snapshot::SnapshotId;
+pub use sync::SyncBTree;
+use compaction::{compact_path, Compaction};
+use node::{OverflowPage, Verification};
+#[cfg(feature = "snapshots")]
+use snapshot::Snapshots;
+#[cfg(feature = "snapshots")]
+use std::{collections::BTreeMap, sync::Arc};
+use store::Store;
+use writer::TreeWriter;
+use bincode::Options;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     fmt::Debug,
     fs::{self, File, OpenOptions},
-    io::{Seek, SeekFrom, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     marker::PhantomData,
     mem,
+    ops::{Bound, RangeBounds},
     path::{Path, PathBuf},
 };
 
 
-const PAGE_SIZE: u64 = 4096;
+const DEFAULT_PAGE_SIZE: u64 = 4096;
+const DEFAULT_FILL_FACTOR: f64 = 0.5;
 const MAGIC_HEADER: &str = "%bptree%";
+const FORMAT_VERSION: u32 = 9;
+// The maximum size of the application's data in the metadata, see `set_user_metadata`.
+const MAX_USER_METADATA_SIZE: usize = 4096;
 
 
 // Computing n (the number of search keys in a node):
@@ -27,8 +69,26 @@ const MAGIC_HEADER: &str = "%bptree%";
 //        Let:  SIZE_K = length of a search key (in bytes)
 //              SIZE_V = length of a pointer    (in bytes)
 //
+//      Leaves and internal nodes hold different pointers, so they get their own n.
 //
-//       In 1 node, there are maximum:
+//      In a leaf, the pointers are values, each preceded by a tag (u8). Values larger than
+//      the inline value size are replaced by a pointer to overflow pages:
+//
+//              SIZE_V = 1 + max(inline value size, size of a page pointer)
+//
+//      In an internal node, every pointer comes with the number of entries in its subtree (u64):
+//
+//              SIZE_V = size of a page pointer + 8
+//
+//      A page pointer is 8 bytes, or 4 with a codec like `BincodeCodec<4>`, see `PageCodec::PAGE_PTR_SIZE`.
+//
+//
+//       In 1 leaf, there are maximum:
+//
+//               n search keys = n × SIZE_K bytes
+//               n values      = n × SIZE_V bytes
+//
+//       In 1 internal node, there are maximum:
 //
 //               n search keys = n     × SIZE_K bytes
 //             n+1 pointers    = (n+1) × SIZE_V bytes
@@ -37,290 +97,2742 @@ const MAGIC_HEADER: &str = "%bptree%";
 //
 //         We must find the largest value of n such that
 //
-//               n × SIZE_K + (n+1) × SIZE_V  ≤  PAGE_SIZE
+//               n × (SIZE_K + SIZE_V)              ≤  PAGE_SIZE      (leaf)
+//               n × SIZE_K + (n+1) × SIZE_V        ≤  PAGE_SIZE      (internal node)
 //
 // BUT:
-//      overhead (with serde) of a BTNode<K, V> is:
-//          u8 -> node type (internal, leaf)                1 byte
-//          2 x u64 -> 2 x size of vector (keys, entries)   16 bytes
-//      overhead = 17 bytes
+//      a serialized BTNode<K, V> has some overhead: the node type (u8), the lengths of the vectors
+//      (keys, entries and the entry counts of an internal node) and in a leaf the page number of
+//      the next leaf (Option<u64>). Instead of hardcoding it, OVERHEAD is measured for each kind
+//      by serializing an empty node, see `BTree::node_overhead`. With the default bincode
+//      configuration, it's 1 + 2 × 8 + 9 = 26 bytes for a leaf and 1 + 3 × 8 = 25 bytes for an
+//      internal node.
 //
 // Therefore:
 //
 //    We must find the largest value of n such that
 //
-//          n*SIZE_K + (n+1)*SIZE_V  ≤  PAGE_SIZE - 17
+//          n*(SIZE_K + SIZE_V)  ≤  PAGE_SIZE - OVERHEAD                                    (leaf)
 //
-//          n*(SIZE_K + SIZE_V) + SIZE_V <= PAGE_SIZE - 17
+//          n <= (PAGE_SIZE - OVERHEAD) / (SIZE_K + SIZE_V)
 //
-//          n <= (PAGE_SIZE - SIZE_V - 17) / (SIZE_K + SIZE_V)
+//          n*SIZE_K + (n+1)*SIZE_V  ≤  PAGE_SIZE - OVERHEAD                       (internal node)
+//
+//          n*(SIZE_K + SIZE_V) + SIZE_V <= PAGE_SIZE - OVERHEAD
+//
+//          n <= (PAGE_SIZE - SIZE_V - OVERHEAD) / (SIZE_K + SIZE_V)
+//
+// SIZE_K is the size of K in memory. Keys only need to be `Clone`, so a `String` or `Vec<u8>` works
+// as well, but its size in memory (24 bytes) only leaves room for 16 bytes of data when serialized.
+// For longer keys, pass an `override_max_key_count` that fits the longest key, otherwise storing a
+// node with long keys fails with `Error::NodeTooLarge`. The override is used for both kinds of nodes.
 
-fn max_key_count(size_key: u64, size_value: u64) -> u64 {
-    (PAGE_SIZE - size_value - 17) / (size_key + size_value)
+fn max_key_count(page_size: u64, size_key: u64, size_value: u64, overhead: u64) -> u64 {
+    page_size.saturating_sub(size_value + overhead) / (size_key + size_value)
 }
 
 
-fn split_at(max_key_count: u64) -> usize {
-    ((max_key_count / 2) + (max_key_count % 2)) as usize
+fn max_leaf_key_count(page_size: u64, size_key: u64, size_value: u64, overhead: u64) -> u64 {
+    page_size.saturating_sub(overhead) / (size_key + size_value)
 }
 
 
-fn meta_file_path(dirname: &Path) -> PathBuf {
-    let mut path = PathBuf::from(dirname);
-    path.push("meta");
-    path
+// Values that serialize to more bytes than this are stored in overflow pages. A value type that's
+// larger than 1/8th of a page is always stored in overflow pages, so that the leaves can still
+// hold a reasonable number of keys.
+fn inline_value_size(page_size: u64, value_size: u64, page_ptr_size: u64) -> u64 {
+    match value_size <= page_size / 8 {
+        true => value_size,
+        false => page_ptr_size,
+    }
+}
+
+
+// A full node is split after `fill_factor` of its keys, rounded up, but at least 1 key has to move
+// to the new node and at least 1 has to stay.
+fn split_at(max_key_count: u64, fill_factor: f64) -> usize {
+    let split_at = (max_key_count as f64 * fill_factor).ceil() as u64;
+    split_at.clamp(1, max_key_count.saturating_sub(1).max(1)) as usize
 }
 
 
-fn db_path(directory: &Path) -> PathBuf {
+// Returns the largest `n` from `lo` to `hi` for which `fits(n)` holds, assuming it holds up to some
+// `n` and not after that, or `lo` if it doesn't hold for any of them.
+fn largest_fitting<F>(mut lo: usize, mut hi: usize, mut fits: F) -> Result<usize>
+where
+    F: FnMut(usize) -> Result<bool>,
+{
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        match fits(mid)? {
+            true => lo = mid,
+            false => hi = mid - 1,
+        }
+    }
+    Ok(lo)
+}
+
+
+// Returns the sizes of the chunks when `total` items are spread as evenly as possible over the
+// smallest number of chunks holding at most `max` items each.
+fn chunk_sizes(total: usize, max: usize) -> impl Iterator<Item = usize> {
+    let chunks = total.div_ceil(max);
+    let (size, rest) = (total / chunks, total % chunks);
+    (0..chunks).map(move |i| if i < rest { size + 1 } else { size })
+}
+
+
+// Returns the path of `file` of the tree called `name` in `directory`. The files of the tree
+// without a name are called just `file`, the files of a named tree `<name>.<file>`, so that one
+// directory can hold several trees. See `BTree::open_named`.
+fn default_page_allocator() -> Box<dyn PageAllocator> {
+    Box::new(PageAllocation::default())
+}
+
+
+fn tree_file_path(directory: &Path, name: &str, file: &str) -> PathBuf {
     let mut path = PathBuf::from(directory);
-    path.push("db");
+    match name {
+        "" => path.push(file),
+        name => path.push(format!("{}.{}", name, file)),
+    }
     path
 }
 
 
+fn meta_file_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "meta")
+}
+
+
+// The metadata is written to this file first and then renamed to `meta`, so that `meta` is never
+// partially written.
+fn meta_tmp_file_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "meta.tmp")
+}
+
+
+fn db_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "db")
+}
+
+
+// The file the lock of a tree is taken on, see `BTreeBuilder::lock`. The lock isn't taken on the db
+// file, because `compact` replaces that.
+fn lock_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "lock")
+}
+
+
+// Replaces the metadata of tree `name` in `directory` with `meta`, a serialized BTree.
+fn write_meta(directory: &Path, name: &str, meta: &[u8]) -> Result<()> {
+    replace_file(&meta_file_path(directory, name), &meta_tmp_file_path(directory, name), meta)
+}
+
+
+// Replaces the file at `path` with `bytes`, which are written to `tmp_path` first, so that the file
+// is never partially written.
+fn replace_file(path: &Path, tmp_path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut fh = File::create(tmp_path)?;
+    fh.write_all(bytes)?;
+    fh.flush()?;
+    fh.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    // The rename itself is only durable once the directory is.
+    sync_directory(path.parent().unwrap_or(Path::new(".")))
+}
+
+
+// Flushes the entries of `directory` to disk, so that the files that were created in or renamed to it
+// survive a crash. Windows can't open a directory as a file: NTFS makes the rename durable itself.
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> Result<()> {
+    File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> Result<()> {
+    Ok(())
+}
+
+
+// The directory a compacted tree is moved to when it's complete, see `BTree::replace_with`.
+fn swap_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "swap")
+}
+
+
+// Moves the db file and the metadata of a compacted tree out of its `swap` directory, over the ones
+// of tree `name`, if a compaction was interrupted after it had become complete. This can be repeated
+// until it's done: a file that is gone has already been moved. See `BTree::replace_with`.
+fn finish_swap(directory: &Path, name: &str) -> Result<()> {
+    let swap_directory = swap_path(directory, name);
+    if !swap_directory.exists() {
+        return Ok(());
+    }
+    // The db file first: the metadata in `swap` is the proof the swap has to be finished.
+    let db = db_path(&swap_directory, name);
+    if db.exists() {
+        fs::rename(&db, db_path(directory, name))?;
+    }
+    let meta = meta_file_path(&swap_directory, name);
+    if meta.exists() {
+        fs::rename(&meta, meta_file_path(directory, name))?;
+    }
+    sync_directory(directory)?;
+    fs::remove_dir_all(&swap_directory)?;
+    Ok(())
+}
+
+
+// Moves the file `from` to `to`, by copying it if it can't be renamed to another file system.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            File::open(to)?.sync_all()?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+
+// The leading fields of the metadata, which are validated before deserializing the rest.
+#[derive(Deserialize)]
+struct MetaHeader {
+    magic_header: String,
+    format_version: u32,
+}
+
+
+// Lookups (`get`, `contains_key`, `floor`, `ceil`) and iteration (`keys`, `values`, `range`) only need
+// `&self`: the handle of the db file is opened lazily behind a `Mutex`. A `BTree` can be shared between
+// threads, but its readers take turns using the file handle. See `reader` for readers with their own.
+//
+// Keys are ordered by `Ord` only, so a tree with `std::cmp::Reverse<K>` keys iterates from the largest
+// key down, and its ranges, `floor` and `ceil` follow that order as well.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct BTree<K, V>
+pub struct BTree<K, V, C = BincodeCodec>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
     magic_header: String,
+    format_version: u32,
     #[serde(skip)]
     pub directory: PathBuf,
+    // Empty for the tree that has the directory to itself, see `open_named`.
+    #[serde(skip)]
+    name: String,
     node_count: u64,
     entry_count: u64,
     root_page_nr: PagePtr,
-    emtpy_pages: Vec<PagePtr>,
+    // Where the chain of leaves starts, for the iterators. Splits and merges keep the first leaf in
+    // its page, so it only moves when the tree is (re)built or its first root is created.
+    first_leaf_page_nr: PagePtr,
+    emtpy_pages: FreePages,
     key_size: u64,
     value_size: u64,
+    inline_value_size: u64,
     key_type: PhantomData<K>,
     value_type: PhantomData<V>,
+    // the capacity of an internal node, see `max_key_count`
     max_key_count: u64,
     split_at: usize,
+    // the number of entries that fit in a leaf uncompressed, see `max_leaf_key_count` and `leaf_capacity`
+    max_leaf_key_count: u64,
+    leaf_split_at: usize,
+    page_size: u64,
+    // measured when the tree is created, see `BTree::node_overhead`
+    leaf_overhead: u64,
+    internal_overhead: u64,
+    // the size of a page number in a page, see `PageCodec::PAGE_PTR_SIZE`
+    page_ptr_size: u64,
+    multimap: bool,
+    // See `set_user_metadata`.
+    user_metadata: Vec<u8>,
+    #[serde(skip)]
+    read_only: bool,
+    // Set when a page has been written or freed: the metadata needs to be stored.
+    #[serde(skip)]
+    dirty: bool,
+    // Whether `set`, `remove` and `update` go through the write-ahead log, see `set_wal`.
+    #[serde(skip)]
+    wal: bool,
+    // See `set_free_page_limit`.
+    #[serde(skip)]
+    free_page_limit: Option<u64>,
+    // See `set_page_allocator`.
+    #[serde(skip, default = "default_page_allocator")]
+    page_allocator: Box<dyn PageAllocator>,
+    #[serde(skip)]
+    store: Store,
+    // What `open_with_recovery` discarded, see `recovery`.
+    #[serde(skip)]
+    recovery: Option<Recovery>,
+    // See `compact_step`. The explicit default keeps serde from requiring `C: Default`.
+    #[serde(skip, default = "Option::default")]
+    compaction: Option<Box<Compaction<K, V, C>>>,
     #[serde(skip)]
-    fh: Option<File>,
+    codec: PhantomData<C>,
+    #[cfg(feature = "snapshots")]
+    #[serde(skip)]
+    snapshots: Snapshots,
+    // Set when this is a snapshot: the copies of the pages that have been overwritten since.
+    #[cfg(feature = "snapshots")]
+    #[serde(skip)]
+    page_copies: Arc<BTreeMap<PagePtr, PagePtr>>,
 }
 
 
+// The constructors of a tree with the default codec, see `open_with_codec` for other codecs.
 impl<K, V> BTree<K, V>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
 {
+    // Returns a builder to open the BTree in `directory` with other options than the defaults. The
+    // `open_*` constructors are shorthands for it.
+    pub fn builder<P: AsRef<Path>>(directory: P) -> BTreeBuilder<K, V> {
+        BTreeBuilder::new(directory.as_ref())
+    }
+
     pub fn open<P: AsRef<Path>>(directory: P, override_max_key_count: Option<u64>) -> Result<Self> {
-        fs::create_dir_all(&directory)?;
-        let meta_path = meta_file_path(directory.as_ref());
-        match &meta_path.exists() {
-            true => Self::load_meta(&meta_path, directory.as_ref()),
-            false => Ok(Self::new(directory.as_ref(), override_max_key_count)),
+        Self::builder(directory).max_key_count(override_max_key_count).open()
+    }
+
+    // Opens the BTree in `directory`, which must already exist: unlike `open`, this doesn't create
+    // the directory or a new tree. Returns `Error::NotFound` if there's no metadata in `directory`
+    // and `Error::InvalidFileFormat` if there is, but it's not the metadata of a BTree.
+    pub fn open_existing<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        Self::builder(directory).create(false).open()
+    }
+
+    // Opens the existing BTree in `directory` like `open_existing`, but pages at the end of the db
+    // file that can't be read, which a crash while writing them can leave behind, are discarded
+    // instead of failing later on. The tree is rebuilt from the entries that are left, see
+    // `BTreeBuilder::recover`. What was discarded is returned by `recovery`.
+    pub fn open_with_recovery<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        Self::builder(directory).create(false).recover(true).open()
+    }
+
+    // Opens the tree called `name` in `directory`, which can hold other trees with other names as
+    // well as the tree opened by `open`. The files of the tree are prefixed by its name, which can
+    // only contain letters, digits, `_` and `-`.
+    pub fn open_named<P: AsRef<Path>>(directory: P, name: &str, override_max_key_count: Option<u64>) -> Result<Self> {
+        if name.is_empty() {
+            return Err(Error::InvalidConfiguration(format!("invalid tree name {:?}", name)));
+        }
+        Self::builder(directory).name(name).max_key_count(override_max_key_count).open()
+    }
+
+    // Opens the BTree in `directory` using pages of `page_size` bytes, which must be a power of two.
+    // The page size is stored in the metadata: an existing tree is always reopened with the page
+    // size it was created with, regardless of `page_size`.
+    pub fn open_with_page_size<P: AsRef<Path>>(
+        directory: P,
+        override_max_key_count: Option<u64>,
+        page_size: u64,
+    ) -> Result<Self> {
+        Self::builder(directory).max_key_count(override_max_key_count).page_size(page_size).open()
+    }
+
+    // Opens the BTree in `directory` like `open`, and makes the db file at least `pages` pages long.
+    // The pages are still used from the start of the file, growing the tree then writes into space
+    // the file system already allocated instead of extending the file. `compact` and `shrink_to_fit`
+    // give the unused pages back.
+    pub fn with_preallocated<P: AsRef<Path>>(
+        directory: P,
+        pages: u64,
+        override_max_key_count: Option<u64>,
+    ) -> Result<Self> {
+        Self::builder(directory).max_key_count(override_max_key_count).preallocate(pages).open()
+    }
+
+    // Opens the BTree in `directory`, creating it with nodes that are split at `fill_factor` (between
+    // 0 and 1, exclusive) of their capacity instead of half of it. Keys that are added in ascending
+    // order leave the nodes this full, so for a tree that is written once, a high fill factor
+    // makes the file smaller. The minimum fill of a node becomes `1 - fill_factor` when that's
+    // lower. Like the page size, the fill factor of an existing tree can't be changed.
+    pub fn open_with_fill_factor<P: AsRef<Path>>(
+        directory: P,
+        override_max_key_count: Option<u64>,
+        fill_factor: f64,
+    ) -> Result<Self> {
+        Self::builder(directory).max_key_count(override_max_key_count).fill_factor(fill_factor).open()
+    }
+
+    // Opens the BTree in `directory` as a multimap: `set` adds a value after the values of the same
+    // key instead of overwriting it. `get` returns the first value of a key, `get_all` all of them
+    // in the order they were added and `remove` removes the first one. Of the entries with the same
+    // key, `ceil` returns the first and `floor` the last one.
+    //
+    // A new tree is created as a multimap, an existing tree must have been created as one. `open`
+    // opens an existing multimap as a multimap as well.
+    pub fn open_multimap<P: AsRef<Path>>(directory: P, override_max_key_count: Option<u64>) -> Result<Self> {
+        Self::builder(directory).max_key_count(override_max_key_count).multimap(true).open()
+    }
+
+    // Builds a new BTree in `directory` from `entries`, which must be sorted by key in strictly
+    // ascending order. This is a lot faster than calling `set` for every entry: the leaves are
+    // filled as evenly as possible (at least half and at most `max_key_count` keys), the internal
+    // levels are built bottom-up and every page is written exactly once, see `build_streaming`.
+    //
+    // If a key is not larger than the previous one, `Error::UnsortedInput` is returned and no
+    // metadata is written, so `directory` doesn't contain a usable tree. `directory` must not
+    // already contain a BTree. Use `BTreeBuilder::bulk_load` for another name, page size, fill
+    // factor or codec.
+    pub fn bulk_load<P, I>(directory: P, entries: I, override_max_key_count: Option<u64>) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Self::builder(directory).max_key_count(override_max_key_count).bulk_load(entries)
+    }
+
+    // Opens the BTree in `directory` and sets all `entries`, which don't have to be sorted. This is
+    // what `FromIterator` would do, if it could be given a directory.
+    pub fn from_entries<P, I>(directory: P, entries: I, override_max_key_count: Option<u64>) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut btree = Self::open(directory, override_max_key_count)?;
+        btree.try_extend(entries)?;
+        Ok(btree)
+    }
+}
+
+
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Opens the BTree in `directory` like `open_with_page_size`, with its nodes encoded by the codec
+    // `C` instead of bincode. See `PageCodec`.
+    pub fn open_with_codec<P: AsRef<Path>>(
+        directory: P,
+        override_max_key_count: Option<u64>,
+        page_size: u64,
+    ) -> Result<Self> {
+        BTreeBuilder::new(directory.as_ref()).max_key_count(override_max_key_count).page_size(page_size).open()
+    }
+
+    fn open_with<P: AsRef<Path>>(
+        directory: P,
+        name: &str,
+        override_max_key_count: Option<u64>,
+        page_size: u64,
+        fill_factor: f64,
+    ) -> Result<Self> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+        // Finish the last change if the process crashed while writing it.
+        finish_swap(directory, name)?;
+        wal::replay(directory, name)?;
+        match meta_file_path(directory, name).exists() {
+            true => Self::load_meta(directory, name, false),
+            false => Self::new(directory, name, override_max_key_count, page_size, fill_factor),
+        }
+    }
+
+    // Opens the existing tree `name` in `directory`, read-only if `read_only` is set.
+    fn load_existing(directory: &Path, name: &str, read_only: bool) -> Result<Self> {
+        if !read_only {
+            finish_swap(directory, name)?;
+            wal::replay(directory, name)?;
+        } else if swap_path(directory, name).exists() || wal::is_pending(directory, name)? {
+            // The change that was interrupted has to be finished, under the lock of a writer.
+            return Err(Error::ReadOnly);
+        }
+        Self::load_meta(directory, name, read_only)
+    }
+
+    // Opens the existing tree `name` in `directory` like `load_existing`, after discarding the pages
+    // at the end of the db file that can't be read.
+    fn recover_existing(directory: &Path, name: &str) -> Result<Self> {
+        finish_swap(directory, name)?;
+        wal::replay(directory, name)?;
+        let mut btree = Self::read_meta(directory, name, false)?;
+        btree.recover()?;
+        btree.validate_root()?;
+        Ok(btree)
+    }
+
+    // Discards the pages in use at the end of the db file that can't be read, and rebuilds the tree
+    // from the entries that don't need them like `compact` does. The entries in a discarded leaf, or
+    // in the leaves below a discarded internal node, are lost.
+    fn recover(&mut self) -> Result<()> {
+        let discarded = self.unreadable_trailing_pages()?;
+        if discarded.is_empty() {
+            return Ok(());
+        }
+        let mut entries = vec![];
+        self.salvage(self.root_page_nr, &discarded, &mut entries)?;
+        let recovery = Recovery { discarded_pages: discarded, lost_entries: self.entry_count - entries.len() as u64 };
+        let count = entries.len();
+        let compacted = self.build_compacted(entries.into_iter(), count)?;
+        self.replace_with(compacted)?;
+        self.recovery = Some(recovery);
+        Ok(())
+    }
+
+    // Returns the pages in use that can't be read as a node or as an overflow page, from the last
+    // page down to the first one that can be read. Deleted pages are skipped.
+    fn unreadable_trailing_pages(&self) -> Result<Vec<PagePtr>> {
+        let mut unreadable = vec![];
+        for page_nr in (0..self.node_count + self.emtpy_pages.len() as u64).rev() {
+            if self.emtpy_pages.contains(&page_nr) {
+                continue;
+            }
+            let readable = match self.store.read_page(page_nr, |page| C::decode(page, page_nr)) {
+                Ok(_) => true,
+                Err(Error::Io(err)) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(Error::Io(err)),
+                Err(_) => {
+                    let page_ptr_size = self.page_ptr_size;
+                    self.store.read_page(page_nr, |page| OverflowPage::deserialize_from(page, page_ptr_size)).is_ok()
+                }
+            };
+            if readable {
+                break;
+            }
+            unreadable.push(page_nr);
+        }
+        Ok(unreadable)
+    }
+
+    // Appends the entries of the subtree rooted at `page_nr` that can be read without the pages
+    // `discarded` to `entries`.
+    fn salvage(&self, page_nr: PagePtr, discarded: &[PagePtr], entries: &mut Vec<(K, V)>) -> Result<()> {
+        if discarded.contains(&page_nr) {
+            return Ok(());
+        }
+        match self.load_node(page_nr)? {
+            BTNode::Internal(internal) => {
+                for i in 0..internal.child_count() {
+                    self.salvage(internal.child(i), discarded, entries)?;
+                }
+            }
+            BTNode::Leaf(leaf) => entries.extend(leaf.salvage_entries(self, discarded)?),
+        }
+        Ok(())
+    }
+
+    // Sets all `entries`, stopping at the first error. See also `Extend`.
+    pub fn try_extend<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in entries {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the tree into a new db file without any deleted pages, which replaces the current
+    // one when it's complete. The tree is rebuilt like `bulk_load` does, so the leaves are filled
+    // as evenly as possible as well. After a crash, the tree is opened as it was before or as it is
+    // after the compaction, never in between.
+    //
+    // Read handles that were created before keep reading the old db file. Returns
+    // `Error::SnapshotsHeld` while there are snapshots, see `snapshot`.
+    pub fn compact(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
+        #[cfg(feature = "snapshots")]
+        if !self.snapshots.is_empty() {
+            return Err(Error::SnapshotsHeld);
+        }
+        // A compaction in progress is discarded, its tree is in the same directory.
+        self.discard_compaction()?;
+        let compacted = self.build_compacted(self.entries(), self.len())?;
+        self.replace_with(compacted)
+    }
+
+    // Builds a tree with the same configuration from the `count` entries of `entries` in a new db file,
+    // in a directory of its own. See `replace_with`.
+    fn build_compacted<I>(&self, entries: I, count: usize) -> Result<Self>
+    where
+        I: Iterator<Item = (K, V)>,
+    {
+        // The metadata is stored with this tree, `finish` doesn't store it in its own directory.
+        let mut writer = TreeWriter::new(self.new_compacted()?);
+        for (key, value) in entries {
+            writer.push(key, value)?;
+        }
+        if writer.count != count as u64 {
+            return Err(Error::Corrupt(format!("expected {} entries, got {}", count, writer.count)));
+        }
+        writer.finish()
+    }
+
+    // Returns an empty tree with the same configuration in the `compact` directory, which is emptied
+    // first. The metadata is never stored there.
+    fn new_compacted(&self) -> Result<Self> {
+        let compact_directory = compact_path(&self.directory, &self.name);
+        if compact_directory.exists() {
+            fs::remove_dir_all(&compact_directory)?;
+        }
+        let mut compacted = Self::new(&compact_directory, &self.name, None, self.page_size, DEFAULT_FILL_FACTOR)?;
+        compacted.max_key_count = self.max_key_count;
+        compacted.split_at = self.split_at;
+        compacted.max_leaf_key_count = self.max_leaf_key_count;
+        compacted.leaf_split_at = self.leaf_split_at;
+        compacted.multimap = self.multimap;
+        compacted.user_metadata = self.user_metadata.clone();
+        fs::create_dir_all(&compact_directory)?;
+        Ok(compacted)
     }
 
+    // Replaces the db file and the metadata of this tree with the ones of `compacted`, see
+    // `build_compacted`. A crash leaves either this tree or the compacted one: the rename of the
+    // complete `compact` directory to `swap` is the only point where the change takes effect, the
+    // files are moved out of `swap` after that, which `finish_swap` completes when the tree is opened.
+    fn replace_with(&mut self, compacted: Self) -> Result<()> {
+        self.commit_compacted(compacted)?;
+        finish_swap(&self.directory, &self.name)
+    }
+
+    // The part of `replace_with` up to the point where the change has taken effect.
+    fn commit_compacted(&mut self, mut compacted: Self) -> Result<()> {
+        let compact_directory = compacted.directory.clone();
+        // An empty tree has no db file, but the one of this tree has to be replaced as well.
+        let compacted_db = db_path(&compact_directory, &self.name);
+        OpenOptions::new().write(true).create(true).truncate(false).open(&compacted_db)?.sync_all()?;
+        compacted.user_metadata = self.user_metadata.clone();
+        compacted.store_meta()?;
+        fs::rename(&compact_directory, swap_path(&self.directory, &self.name))?;
+        sync_directory(&self.directory)?;
+
+        self.store.close()?;
+        self.node_count = compacted.node_count;
+        self.entry_count = compacted.entry_count;
+        self.root_page_nr = compacted.root_page_nr;
+        self.first_leaf_page_nr = compacted.first_leaf_page_nr;
+        self.emtpy_pages = FreePages::default();
+        // The metadata is the one of the compacted tree now.
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Returns the number of entries as stored in the metadata, which takes no time at all. See `count`.
+    // On a 32-bit target, a tree with more than `usize::MAX` entries doesn't fit: see `len_u64`.
     pub fn len(&self) -> usize {
         self.entry_count as usize
     }
 
-    pub fn keys(&mut self) -> BTreeIterator<K, V> {
-        BTreeIterator::new(self).unwrap().into_iter()
+    // Returns the number of entries like `len`, as stored.
+    pub fn len_u64(&self) -> u64 {
+        self.entry_count
     }
 
-    pub fn values(&mut self) -> BTreeValueIterator<K, V> {
-        BTreeValueIterator::new(self).unwrap().into_iter()
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    pub fn get(&mut self, key: K) -> Result<Option<V>> {
-        if self.len() == 0 {
-            return Ok(None);
+    // The maximum number of keys in an internal node: the `override_max_key_count` the tree was created
+    // with, or the number that fits in a page (see the comment at the top of this file). A leaf has
+    // no page pointers to store next to its keys, so it may hold more, see `leaf_capacity`.
+    pub fn max_key_count(&self) -> u64 {
+        self.max_key_count
+    }
+
+    // The number of keys that stay in an internal node when it's split, set by the fill factor. A
+    // leaf is split at the same fraction of its capacity.
+    pub fn split_at(&self) -> usize {
+        self.split_at
+    }
+
+    // The maximum number of children of an internal node.
+    pub fn fanout(&self) -> u64 {
+        self.max_key_count + 1
+    }
+
+    // The maximum number of entries in a leaf. With a codec that compresses, a leaf is full before
+    // that when its encoding no longer fits in a page, see `PageCodec::MAX_COMPRESSION_RATIO`.
+    pub fn leaf_capacity(&self) -> u64 {
+        self.max_leaf_key_count * C::MAX_COMPRESSION_RATIO
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    // Returns the number of entries by counting them in the leaves, to check the number `len` returns.
+    // This reads every leaf, so it takes time linear in the size of the tree: use `len` otherwise.
+    pub fn count(&self) -> Result<usize> {
+        let mut count = 0;
+        let mut page_nr = if self.node_count > 0 { Some(self.first_leaf_page_nr) } else { None };
+        while let Some(leaf_page_nr) = page_nr {
+            let leaf = self.load_node(leaf_page_nr)?.leaf_node();
+            count += leaf.len();
+            page_nr = leaf.next();
         }
-        self.load_node(self.root_page_nr)?.get(self, key)
+        Ok(count)
     }
 
-    pub fn set(&mut self, key: K, value: V) -> Result<Option<V>> {
-        if self.len() == 0 {
-            self.create_first_root(key, value)?;
+    // Returns an iterator over all keys, in ascending order. Like the other iterators, it reads the
+    // leaves as it goes: see `snapshot_keys` for a list that is read right away.
+    pub fn keys(&self) -> BTreeIterator<'_, K, V, C> {
+        BTreeIterator::new(self).unwrap()
+    }
+
+    // Returns all keys in ascending order, read from the leaves right away.
+    //
+    // The iterators read one leaf at a time and follow the link to the next leaf when they get there.
+    // An iterator of a `ReadHandle` that is used while the tree is modified through another handle may
+    // reach leaves that have been split or merged in the meantime, and skip keys or return them twice.
+    // Reading all leaves up front leaves a much smaller window for that, and a leaf that can't be read
+    // is returned as an error instead of ending the iteration. To read a version of the tree that
+    // doesn't change at all, take a `snapshot` (with the `snapshots` feature).
+    pub fn snapshot_keys(&self) -> Result<Vec<K>> {
+        let mut keys = Vec::with_capacity(self.len());
+        let mut page_nr = if self.node_count > 0 { Some(self.first_leaf_page_nr) } else { None };
+        while let Some(leaf_page_nr) = page_nr {
+            let leaf = self.load_node(leaf_page_nr)?.leaf_node();
+            page_nr = leaf.next();
+            keys.extend(leaf.keys());
+        }
+        Ok(keys)
+    }
+
+    pub fn values(&self) -> BTreeValueIterator<'_, K, V, C> {
+        BTreeValueIterator::new(self).unwrap()
+    }
+
+    // Returns an iterator over all key/value pairs, in ascending order of the keys.
+    pub fn entries(&self) -> BTreeRangeIterator<'_, K, V, C> {
+        self.range(..)
+    }
+
+    // Returns an iterator over the key/value pairs with a key in `range`, in ascending order of the keys.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> BTreeRangeIterator<'_, K, V, C> {
+        BTreeRangeIterator::new(self, range).unwrap()
+    }
+
+    // Returns an iterator over the key/value pairs with a key that starts with `prefix`, in ascending
+    // order of the keys. See `KeyPrefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> BTreeRangeIterator<'_, K, V, C>
+    where
+        K: KeyPrefix,
+    {
+        match K::prefix_range(prefix) {
+            Some(range) => self.range(range),
+            None => BTreeRangeIterator { btree: self, leaf: None, index: 0, end: Bound::Unbounded },
+        }
+    }
+
+    // Returns an iterator over the values of the entries with a key in `range`, in ascending order of
+    // the keys. Like `range`, without cloning the keys. A value or leaf that can't be read is returned
+    // as an error instead of panicking, after which the iterator ends.
+    pub fn range_values<R: RangeBounds<K>>(&self, range: R) -> BTreeRangeValueIterator<'_, K, V, C> {
+        BTreeRangeValueIterator { range: self.range(range) }
+    }
+
+    // Returns an iterator over the keys in `range`, in ascending order. Like `range`, without reading
+    // the values: values stored in overflow pages aren't read at all.
+    pub fn range_keys<R: RangeBounds<K>>(&self, range: R) -> BTreeRangeKeyIterator<'_, K, V, C> {
+        BTreeRangeKeyIterator { range: self.range(range) }
+    }
+
+    // Returns an iterator over all key/value pairs, like `entries`, that reads `lookahead` pages at once.
+    // Leaves are often stored in consecutive pages, then a full scan takes a lot fewer reads. The pages
+    // are only deserialized when the next leaf is in them.
+    pub fn scan_buffered(&self, lookahead: usize) -> BTreeScanIterator<'_, K, V, C> {
+        BTreeScanIterator::new(self, lookahead).unwrap()
+    }
+
+    // Returns the number of entries with a key in `start..end`, without reading any values. Using
+    // the entry counts of the subtrees, this only reads the pages on the paths to `start` and `end`:
+    // the exact count takes the reads of two lookups, so there's no cheaper estimate to make.
+    pub fn count_range(&self, start: K, end: K) -> Result<usize> {
+        if start >= end {
+            return Ok(0);
+        }
+        Ok(self.rank(end)? - self.rank(start)?)
+    }
+
+    // Returns an estimate of the number of entries with a key in `start..end`, for query planning.
+    // With the entry counts of the subtrees, the exact count is as cheap as an estimate from the
+    // fill of the leaves in between would be, so this is `count_range`: the "estimate" is exact.
+    pub fn estimate_range_count(&self, start: K, end: K) -> Result<usize> {
+        self.count_range(start, end)
+    }
+
+    // Returns the number of entries with a key smaller than `key`, which is the index of (the first
+    // entry with) `key` in `entries()` if it's present.
+    pub fn rank(&self, key: K) -> Result<usize> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        let mut rank = 0;
+        let mut page_nr = self.root_page_nr;
+        loop {
+            match self.load_node(page_nr)? {
+                BTNode::Leaf(node) => return Ok(rank + node.lower_bound(&key)),
+                BTNode::Internal(node) => {
+                    // All subtrees to the left of this one only hold smaller keys.
+                    let i = node.first_child_index(&key);
+                    rank += node.count_before(i) as usize;
+                    page_nr = node.child(i);
+                }
+            }
+        }
+    }
+
+    // Returns the n-th entry of `entries()` (counting from 0), or `None` if there are no more than
+    // `n` entries.
+    pub fn select(&self, n: usize) -> Result<Option<(K, V)>> {
+        if n >= self.len() {
             return Ok(None);
         }
-        let root = self.load_node(self.root_page_nr)?;
-        let (split, original_value) = root.set(self, key, value)?;
-        if let Some((key, page_nr)) = split {
-            self.create_new_root(key, page_nr)?;
+        let (leaf, i) = self.descend_to_index(n as u64)?;
+        Ok(Some(leaf.entry(self, i)?))
+    }
+
+    // Returns the last `n` entries (or all of them, if there are fewer), in ascending order of the
+    // keys. The entry counts in the internal nodes lead to the first of them, from where the
+    // leaves are read forward: there's no need to walk the tree backward.
+    pub fn last_n(&self, n: usize) -> Result<Vec<(K, V)>> {
+        let n = n.min(self.len());
+        let mut entries = Vec::with_capacity(n);
+        if n == 0 {
+            return Ok(entries);
         }
-        if original_value.is_none() {
-            self.entry_count += 1;
+        let (mut leaf, mut i) = self.descend_to_index((self.len() - n) as u64)?;
+        loop {
+            while i < leaf.len() {
+                entries.push(leaf.entry(self, i)?);
+                i += 1;
+            }
+            match leaf.next() {
+                Some(page_nr) => leaf = self.load_node(page_nr)?.leaf_node(),
+                None => return Ok(entries),
+            }
+            i = 0;
         }
-        Ok(original_value)
     }
 
-    pub fn remove(&mut self, key: K) -> Result<Option<V>> {
-        match self.len() > 0 {
-            true => {
-                let root = self.load_node(self.root_page_nr)?;
-                let original_value = root.remove(self, key)?;
-                if original_value.is_some() {
-                    self.entry_count -= 1;
+    // Returns a handle for reading this tree, with its own handle of the db file: unlike `&BTree`,
+    // readers with separate handles don't have to take turns. The handle (and its clones) can be
+    // shared between threads.
+    //
+    // The handle sees the tree as it was when the handle was created: root page, number of entries,
+    // ... Pages are updated in place though, so a reader that runs while the tree is modified can
+    // see a mix of old and new nodes, miss entries or fail with an error. Create a new handle after
+    // modifying the tree.
+    pub fn reader(&self) -> ReadHandle<K, V, C> {
+        ReadHandle { btree: self.read_only_copy() }
+    }
+
+    // Returns up to `limit` entries following `after`, or the first ones if it's `None`, and the
+    // cursor to pass to get the next page. The cursor is `None` when there are no more entries. The
+    // cursor holds the last key returned, and in a multimap how many of its values have been returned,
+    // so entries added or removed between calls are seen or skipped like the rest of the tree. Returns
+    // `Error::InvalidConfiguration` if `limit` is 0: an empty page can't tell whether entries are left.
+    pub fn page(&self, after: Option<Cursor<K>>, limit: usize) -> Result<Page<K, V>> {
+        if limit == 0 {
+            return Err(Error::InvalidConfiguration(String::from("a page needs a limit of at least 1 entry")));
+        }
+        let start = match &after {
+            Some(cursor) => Bound::Included(cursor.key.clone()),
+            None => Bound::Unbounded,
+        };
+        let mut entries = self.range((start, Bound::Unbounded)).peekable();
+        // Skip the values of the cursor's key that were on the previous pages.
+        if let Some(cursor) = &after {
+            for _ in 0..cursor.values {
+                if entries.next_if(|(key, _)| *key == cursor.key).is_none() {
+                    break;
                 }
-                Ok(original_value)
-            },
-            false => Ok(None),
+            }
+        }
+        let page: Vec<(K, V)> = entries.by_ref().take(limit).collect();
+        let cursor = match (page.last(), entries.next()) {
+            (Some((last, _)), Some(_)) => {
+                let mut values = page.iter().rev().take_while(|(key, _)| key == last).count() as u64;
+                // A page of values of the cursor's key only follows the values skipped above.
+                let full = values == page.len() as u64;
+                if let Some(cursor) = after.filter(|cursor| full && cursor.key == *last) {
+                    values += cursor.values;
+                }
+                Some(Cursor { key: last.clone(), values })
+            }
+            _ => None,
+        };
+        Ok((page, cursor))
+    }
+
+    // Returns an iterator over the keys that are larger than or equal to `start`, in ascending order.
+    pub fn iter_from(&self, start: K) -> BTreeIterator<'_, K, V, C> {
+        BTreeIterator::starting_at(self, &start).unwrap()
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>> {
+        self.get_ref(&key)
+    }
+
+    // Like `get`, for a key the caller wants to keep: it's only compared, never copied.
+    pub fn get_ref(&self, key: &K) -> Result<Option<V>> {
+        match self.find(key)? {
+            Some((leaf, i)) => Ok(Some(leaf.value(self, i)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Returns all values of `key`, in the order they were added. Without multimap, that's at most 1.
+    pub fn get_all(&self, key: K) -> Result<Vec<V>> {
+        let mut values = vec![];
+        let (mut leaf, mut i) = match self.find(&key)? {
+            Some(found) => found,
+            None => return Ok(values),
+        };
+        loop {
+            while i < leaf.len() {
+                if *leaf.key(i) != key {
+                    return Ok(values);
+                }
+                values.push(leaf.value(self, i)?);
+                i += 1;
+            }
+            match leaf.next() {
+                Some(page_nr) => leaf = self.load_node(page_nr)?.leaf_node(),
+                None => return Ok(values),
+            }
+            i = 0;
+        }
+    }
+
+    // Like `get`, but also returns the pages that were read to find `key`, for debugging: the path
+    // from the root down to a leaf, and in a multimap the next leaf when the entries with `key` start
+    // there. See also `dump` and `verify`.
+    pub fn get_with_path(&self, key: K) -> Result<(Option<V>, Vec<PagePtr>)> {
+        let mut path = vec![];
+        if self.is_empty() {
+            return Ok((None, path));
+        }
+        let mut page_nr = self.root_page_nr;
+        let mut leaf = loop {
+            path.push(page_nr);
+            match self.load_node(page_nr)? {
+                BTNode::Leaf(leaf) => break leaf,
+                BTNode::Internal(node) if self.multimap => page_nr = node.child(node.first_child_index(&key)),
+                BTNode::Internal(node) => page_nr = node.child(node.child_index(&key)),
+            }
+        };
+        if self.multimap && leaf.lower_bound(&key) == leaf.len() {
+            match leaf.next() {
+                Some(page_nr) => {
+                    path.push(page_nr);
+                    leaf = self.load_node(page_nr)?.leaf_node();
+                }
+                None => return Ok((None, path)),
+            }
+        }
+        let value = match leaf.position(&key) {
+            Some(i) => Some(leaf.value(self, i)?),
+            None => None,
+        };
+        Ok((value, path))
+    }
+
+    // Like `get`, but a missing key is an error: `Error::KeyNotFound`.
+    pub fn get_or_err(&self, key: K) -> Result<V> {
+        self.get(key)?.ok_or(Error::KeyNotFound)
+    }
+
+    pub fn contains_key(&self, key: K) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    // Returns whether all `keys` are present, stopping at the first one that isn't. The keys are
+    // sorted (and duplicates removed) first, so that keys in the same leaf are found with a single
+    // descent and no values are read. True if `keys` is empty.
+    pub fn contains_all(&self, keys: &[K]) -> Result<bool> {
+        let mut all = true;
+        self.sweep_keys(keys, |present| {
+            all = present;
+            present
+        })?;
+        Ok(all)
+    }
+
+    // Returns whether any of `keys` is present, stopping at the first one that is. Like
+    // `contains_all`, the keys are sorted first. False if `keys` is empty.
+    pub fn contains_any(&self, keys: &[K]) -> Result<bool> {
+        let mut any = false;
+        self.sweep_keys(keys, |present| {
+            any = present;
+            !present
+        })?;
+        Ok(any)
+    }
+
+    // Returns the key of the first entry with `value`, in ascending order of the keys. The tree isn't
+    // indexed by value: this reads all values up to the first match, so it takes time linear in the
+    // number of entries.
+    pub fn find_by_value(&self, value: V) -> Result<Option<K>>
+    where
+        V: PartialEq,
+    {
+        let mut page_nr = if self.node_count > 0 { Some(self.first_leaf_page_nr) } else { None };
+        while let Some(leaf_page_nr) = page_nr {
+            let leaf = self.load_node(leaf_page_nr)?.leaf_node();
+            for i in 0..leaf.len() {
+                if leaf.value(self, i)? == value {
+                    return Ok(Some(leaf.key(i).clone()));
+                }
+            }
+            page_nr = leaf.next();
+        }
+        Ok(None)
+    }
+
+    // Returns the entry with the smallest value, the one with the smallest key if there are several.
+    // Like `find_by_value`, this reads all values, so it takes time linear in the number of entries.
+    pub fn min_by_value(&self) -> Result<Option<(K, V)>>
+    where
+        V: Ord,
+    {
+        self.extreme_by_value(|value, min| value < min)
+    }
+
+    // Returns the entry with the largest value, the one with the smallest key if there are several.
+    // See `min_by_value`.
+    pub fn max_by_value(&self) -> Result<Option<(K, V)>>
+    where
+        V: Ord,
+    {
+        self.extreme_by_value(|value, max| value > max)
+    }
+
+    // Returns the first entry for which no later entry has a value that `replaces` its value.
+    fn extreme_by_value<F>(&self, replaces: F) -> Result<Option<(K, V)>>
+    where
+        F: Fn(&V, &V) -> bool,
+    {
+        let mut extreme: Option<(K, V)> = None;
+        let mut page_nr = if self.node_count > 0 { Some(self.first_leaf_page_nr) } else { None };
+        while let Some(leaf_page_nr) = page_nr {
+            let leaf = self.load_node(leaf_page_nr)?.leaf_node();
+            for i in 0..leaf.len() {
+                let value = leaf.value(self, i)?;
+                if extreme.as_ref().is_none_or(|(_, extreme)| replaces(&value, extreme)) {
+                    extreme = Some((leaf.key(i).clone(), value));
+                }
+            }
+            page_nr = leaf.next();
+        }
+        Ok(extreme)
+    }
+
+    // Returns the value of `key`, or sets it to `f()` and returns that if `key` isn't present. `f` is
+    // only called for a missing key. Like `set_if_absent`, this takes a single descent.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> Result<V>
+    where
+        F: FnOnce() -> V,
+    {
+        let mut inserted = None;
+        let present = self.insert_if_absent(key, || {
+            let value = f();
+            inserted = Some(value.clone());
+            value
+        })?;
+        Ok(present.or(inserted).unwrap())
+    }
+
+    // Returns the entry with the largest key that is smaller than or equal to `key`: "the reading at
+    // or just before `key`". The value comes from the leaf the key is found in, there's no need to
+    // `get` it afterwards. When every key of the leaf on the path of `key` is larger, the entry is the
+    // last one of the leaf before it, which takes a second descent from the closest subtree to the
+    // left, but never from the root.
+    pub fn floor(&self, key: K) -> Result<Option<(K, V)>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let (leaf, left) = self.descend_to_leaf(&key)?;
+        match (leaf.floor(self, &key)?, left) {
+            (Some(entry), _) => Ok(Some(entry)),
+            (None, Some(page_nr)) => self.last_leaf(page_nr)?.last(self),
+            (None, None) => Ok(None),
+        }
+    }
+
+    // Returns the entry with the smallest key that is larger than or equal to `key`, with its value
+    // like `floor`. When it's not in the leaf on the path of `key`, it's the first one of the next leaf.
+    pub fn ceil(&self, key: K) -> Result<Option<(K, V)>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let leaf = self.descend_to_first(&key)?;
+        match (leaf.ceil(self, &key)?, leaf.next()) {
+            (Some(entry), _) => Ok(Some(entry)),
+            (None, Some(page_nr)) => self.load_node(page_nr)?.leaf_node().first(self),
+            (None, None) => Ok(None),
+        }
+    }
+
+    // Enables or disables the write-ahead log. With the log, every `set`, `remove` and `update` is
+    // crash-atomic: the pages it changes and the new metadata are written to the log and synced
+    // before the db file is touched, and the tree is flushed afterwards. A change that was
+    // interrupted while being applied is finished when the tree is opened again. This costs a few
+    // fsyncs per change, so it's disabled by default and not stored in the metadata.
+    pub fn set_wal(&mut self, enabled: bool) {
+        self.wal = enabled;
+    }
+
+    // Limits the number of deleted pages that are stored in the metadata. When there are more,
+    // `flush` (and `close`) first truncates the deleted pages at the end of the db file with
+    // `shrink_to_fit` and, if that's not enough, rebuilds the tree with `compact`, which takes time
+    // linear in the size of the tree. `None`, the default, is no limit. Like the write-ahead log, this
+    // isn't stored in the metadata.
+    pub fn set_free_page_limit(&mut self, limit: Option<u64>) {
+        self.free_page_limit = limit;
+    }
+
+    // Chooses which page a new node or overflow page is written to: one of the built-in
+    // `PageAllocation`s or an allocator of the application's own, see `PageAllocator`. The default
+    // reuses the page deleted last. Like the free page limit, this isn't stored in the metadata.
+    pub fn set_page_allocator<A: PageAllocator + 'static>(&mut self, allocator: A) {
+        self.page_allocator = Box::new(allocator);
+    }
+
+    // Stores `bytes` in the metadata of the tree, for the application's own use: a schema version, an
+    // id, ... It replaces what was stored before, and is stored with the metadata by `flush` (or when
+    // the tree is dropped). Returns `Error::InvalidConfiguration` if it's larger than 4 KiB.
+    pub fn set_user_metadata(&mut self, bytes: Vec<u8>) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if bytes.len() > MAX_USER_METADATA_SIZE {
+            return Err(Error::InvalidConfiguration(format!(
+                "user metadata of {} bytes is larger than {} bytes",
+                bytes.len(),
+                MAX_USER_METADATA_SIZE
+            )));
+        }
+        self.user_metadata = bytes;
+        self.dirty = true;
+        Ok(())
+    }
+
+    // The bytes stored with `set_user_metadata`, empty if nothing has been stored.
+    pub fn user_metadata(&self) -> &[u8] {
+        &self.user_metadata
+    }
+
+    pub fn set(&mut self, key: K, value: V) -> Result<Option<V>> {
+        self.atomically(|btree| btree.set_entry(key, value))
+    }
+
+    // Like `set`, for a key and value the caller wants to keep. The leaf holds its own copy of both,
+    // so they're cloned once, when the entry is stored.
+    pub fn set_ref(&mut self, key: &K, value: &V) -> Result<Option<V>> {
+        self.set(key.clone(), value.clone())
+    }
+
+    // Sets `key` to `value` like `set`, for when the value it replaces doesn't matter. Unlike this,
+    // `PersistentMap::insert` returns the replaced value like `BTreeMap::insert` does.
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        self.set(key, value)?;
+        Ok(())
+    }
+
+    // Sets `key` to `value` if `key` isn't present yet, and returns whether it was set. Unlike `set`,
+    // the value of a key that is present is left as it is, and its leaf isn't written. This takes a
+    // single descent, except in a multimap.
+    pub fn set_if_absent(&mut self, key: K, value: V) -> Result<bool> {
+        Ok(self.insert_if_absent(key, || value)?.is_none())
+    }
+
+    // Sets `key` to `value()` if it isn't present, in one descent. Returns the value of a key that is
+    // present, without calling `value`.
+    fn insert_if_absent<F: FnOnce() -> V>(&mut self, key: K, value: F) -> Result<Option<V>> {
+        if self.multimap {
+            // The entries with `key` can be in a leaf before the one an insert descends to.
+            if let Some((leaf, i)) = self.find(&key)? {
+                return Ok(Some(leaf.value(self, i)?));
+            }
+            self.set(key, value())?;
+            return Ok(None);
+        }
+        self.atomically(|btree| {
+            if btree.is_empty() {
+                btree.create_first_root(key, value())?;
+                return Ok(None);
+            }
+            let root = btree.load_node(btree.root_page_nr)?;
+            let (split, present) = root.set_if_absent(btree, key, value)?;
+            if present.is_some() {
+                return Ok(present);
+            }
+            btree.entry_count += 1;
+            if let Some(split) = split {
+                btree.grow_root(vec![split])?;
+            }
+            Ok(None)
+        })
+    }
+
+    fn set_entry(&mut self, key: K, value: V) -> Result<Option<V>> {
+        if self.is_empty() {
+            self.create_first_root(key, value)?;
+            return Ok(None);
+        }
+        let root = self.load_node(self.root_page_nr)?;
+        let (split, original_value) = root.set(self, key, value)?;
+        if original_value.is_none() {
+            self.entry_count += 1;
+        }
+        if let Some(split) = split {
+            self.grow_root(vec![split])?;
+        }
+        Ok(original_value)
+    }
+
+    // Sets all `pairs` like calling `set` for each of them, in order. Instead of a descent per pair,
+    // the pairs are sorted and every node that receives new entries is loaded and stored once,
+    // split into as many nodes as it takes. This works on a tree that already has entries, but
+    // unlike `bulk_load` the nodes are filled as if the pairs were set in ascending order.
+    pub fn set_many(&mut self, pairs: &[(K, V)]) -> Result<()> {
+        let mut entries = pairs.to_vec();
+        // a stable sort, so the last of the pairs with the same key wins (or comes last, in a multimap)
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        self.atomically(|btree| {
+            if entries.is_empty() {
+                return Ok(());
+            }
+            if btree.is_empty() {
+                let (key, value) = entries.remove(0);
+                btree.create_first_root(key, value)?;
+            }
+            let root = btree.load_node(btree.root_page_nr)?;
+            let (splits, added) = root.set_many(btree, entries)?;
+            btree.entry_count += added;
+            btree.grow_root(splits)
+        })
+    }
+
+    // Replaces the value of `key` with `value` and returns the old value, if `key` is present. Unlike
+    // `set`, nothing is inserted when it isn't, and `None` is returned. In a multimap, the first
+    // value of `key` is replaced.
+    pub fn replace(&mut self, key: K, value: V) -> Result<Option<V>> {
+        self.atomically(|btree| match btree.find(&key)? {
+            Some((leaf, i)) => {
+                let mut original_value = None;
+                if let Some(leaf) = leaf.update(btree, i, |v| original_value = Some(mem::replace(v, value)))? {
+                    let n = btree.rank(key)? - i;
+                    btree.store_overfull_leaf(n as u64, leaf)?;
+                }
+                Ok(original_value)
+            }
+            None => Ok(None),
+        })
+    }
+
+    // Modifies the value of `key` in place with `f`, which only stores the leaf holding `key`.
+    // Returns whether `key` is present: if it isn't, `f` is not called.
+    pub fn update<F>(&mut self, key: K, f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut V),
+    {
+        self.atomically(|btree| match btree.find(&key)? {
+            Some((leaf, i)) => {
+                if let Some(leaf) = leaf.update(btree, i, f)? {
+                    let n = btree.rank(key)? - i;
+                    btree.store_overfull_leaf(n as u64, leaf)?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        })
+    }
+
+    // Replaces every value with `f(key, value)`, in ascending order of the keys. Every leaf is read
+    // and stored once, the internal nodes aren't touched. This is not atomic: if storing a leaf
+    // fails, the leaves before it have the new values and the error is returned. With the
+    // write-ahead log (see `set_wal`) the whole operation is a single change, which keeps every page
+    // of the tree in memory until it's written.
+    pub fn map_values<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&K, V) -> V,
+    {
+        self.atomically(|btree| {
+            let mut page_nr = if btree.node_count > 0 { Some(btree.first_leaf_page_nr) } else { None };
+            // The index of the first entry of the leaf, in case it has to be split.
+            let mut n = 0;
+            while let Some(leaf_page_nr) = page_nr {
+                let leaf = btree.load_node(leaf_page_nr)?.leaf_node();
+                page_nr = leaf.next();
+                let len = leaf.len() as u64;
+                if let Some(leaf) = leaf.map_values(btree, &mut f)? {
+                    btree.store_overfull_leaf(n, leaf)?;
+                }
+                n += len;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove(&mut self, key: K) -> Result<Option<V>> {
+        Ok(self.remove_entry(key)?.map(|(_, value)| value))
+    }
+
+    // Removes `key` and returns the key as it was stored together with its value, like
+    // `BTreeMap::remove_entry`. The stored key differs from `key` when `Ord` considers different
+    // keys equal, e.g. strings that are compared case-insensitively.
+    pub fn remove_entry(&mut self, key: K) -> Result<Option<(K, V)>> {
+        self.atomically(|btree| btree.remove_stored_entry(key))
+    }
+
+    // Removes the entries with a key smaller than `key` and returns how many. Unlike removing them
+    // one by one, the subtrees that only hold smaller keys are deleted as a whole: only the nodes on
+    // the path to `key` are rebalanced, with their right siblings.
+    pub fn truncate_below(&mut self, key: K) -> Result<usize> {
+        self.atomically(|btree| {
+            if btree.is_empty() {
+                return Ok(0);
+            }
+            let removed = btree.load_node(btree.root_page_nr)?.truncate_below(btree, &key)?;
+            while let BTNode::Internal(root) = btree.load_node(btree.root_page_nr)? {
+                if root.child_count() > 1 {
+                    break;
+                }
+                btree.on_page_deleted(btree.root_page_nr)?;
+                btree.root_page_nr = root.child(0);
+            }
+            let mut page_nr = btree.root_page_nr;
+            while let BTNode::Internal(node) = btree.load_node(page_nr)? {
+                page_nr = node.child(0);
+            }
+            btree.first_leaf_page_nr = page_nr;
+            debug_assert!(removed <= btree.entry_count, "Programming error: removed more entries than counted");
+            btree.entry_count = btree.entry_count.saturating_sub(removed);
+            Ok(removed as usize)
+        })
+    }
+
+    fn remove_stored_entry(&mut self, key: K) -> Result<Option<(K, V)>> {
+        match !self.is_empty() {
+            true => {
+                let root = self.load_node(self.root_page_nr)?;
+                let original_entry = root.remove_entry(self, key)?;
+                if original_entry.is_some() {
+                    debug_assert!(self.entry_count > 0, "Programming error: removed more entries than counted");
+                    self.entry_count = self.entry_count.saturating_sub(1);
+                }
+                Ok(original_entry)
+            },
+            false => Ok(None),
+        }
+    }
+
+    // Flushes the tree and closes the db file, which releases its file descriptor and the lock (see
+    // `BTreeBuilder::lock`) until the tree is used again: the file is opened and locked again on the
+    // next read or write. Unlike dropping the tree, this returns the error if the tree can't be stored.
+    pub fn close(&mut self) -> Result<()> {
+        let result = self.flush();
+        // The error has been returned, don't try again when dropping.
+        self.dirty = false;
+        self.store.release()?;
+        result
+    }
+
+    // Reads the metadata again, to pick up the changes that another process has flushed to the tree
+    // since it was opened (with `lock(false)`, see `BTreeBuilder::lock`). The db file is opened again
+    // as well, in case it has been replaced by `compact`. Changes to this tree are flushed first, and
+    // the write-ahead log of the other process is left alone: only flushed changes are seen.
+    //
+    // Without locking, the other process may be writing while the tree is read, and the pages read may
+    // not match the metadata yet: a torn update. With locking, the writer has to `close` the tree to
+    // let others in, and so does the reader before the next change can be made. Returns
+    // `Error::SnapshotsHeld` while there are snapshots. A compaction in progress continues from the
+    // progress stored by the flush, see `compact_step`.
+    pub fn reopen(&mut self) -> Result<()> {
+        #[cfg(feature = "snapshots")]
+        if !self.snapshots.is_empty() {
+            return Err(Error::SnapshotsHeld);
+        }
+        self.flush()?;
+        let mut btree = Self::read_meta(&self.directory, &self.name, self.read_only)?;
+        btree.validate_root()?;
+        // Keep the lock and the page statistics.
+        mem::swap(&mut btree.store, &mut self.store);
+        btree.store.close()?;
+        btree.wal = self.wal;
+        btree.free_page_limit = self.free_page_limit;
+        btree.recovery = self.recovery.take();
+        mem::swap(&mut btree.page_allocator, &mut self.page_allocator);
+        #[cfg(feature = "snapshots")]
+        {
+            btree.snapshots.enabled = self.snapshots.enabled;
+        }
+        *self = btree;
+        if !self.read_only {
+            self.load_compaction()?;
+        }
+        Ok(())
+    }
+
+    // Moves the files of the tree to `directory`, which is created if needed, and keeps using the tree
+    // from there. The tree is flushed first. A file is copied and then removed if it can't be renamed
+    // to another file system. The lock (see `BTreeBuilder::lock`) moves along.
+    //
+    // Returns `Error::InvalidConfiguration` if `directory` already contains a tree with this name, and
+    // `Error::SnapshotsHeld` while there are snapshots. A compaction in progress is discarded, see
+    // `compact_step`. Read handles keep reading the files they have open.
+    pub fn relocate<P: AsRef<Path>>(&mut self, directory: P) -> Result<()> {
+        let directory = directory.as_ref();
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        #[cfg(feature = "snapshots")]
+        if !self.snapshots.is_empty() {
+            return Err(Error::SnapshotsHeld);
+        }
+        let same = match (fs::canonicalize(directory), fs::canonicalize(&self.directory)) {
+            (Ok(directory), Ok(current)) => directory == current,
+            _ => false,
+        };
+        if same {
+            return Ok(());
+        }
+        if meta_file_path(directory, &self.name).exists() || db_path(directory, &self.name).exists() {
+            return Err(Error::InvalidConfiguration(String::from("directory already contains a BTree")));
+        }
+        self.discard_compaction()?;
+        self.flush()?;
+        fs::create_dir_all(directory)?;
+        let lock = match self.store.is_locked() {
+            true => Store::lock(lock_path(directory, &self.name), false)?,
+            false => None,
+        };
+        self.store.release()?;
+
+        // The metadata goes last: the tree is where its metadata is.
+        let (db, new_db) = (db_path(&self.directory, &self.name), db_path(directory, &self.name));
+        let (meta, new_meta) = (meta_file_path(&self.directory, &self.name), meta_file_path(directory, &self.name));
+        if db.exists() {
+            move_file(&db, &new_db)?;
+        }
+        if let Err(err) = move_file(&meta, &new_meta) {
+            if new_db.exists() {
+                move_file(&new_db, &db)?;
+            }
+            return Err(err);
+        }
+        self.store.set_path(new_db);
+        if let Some(lock) = lock {
+            self.store.set_lock(lock);
+            fs::remove_file(lock_path(&self.directory, &self.name))?;
+        }
+        self.directory = PathBuf::from(directory);
+        Ok(())
+    }
+
+    // Removes every entry for which `f` returns `false`, returns the number of removed entries. See
+    // `remove_where`.
+    pub fn retain<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut removed = 0;
+        self.remove_where(|key, value| !f(key, value), |_, _| removed += 1)?;
+        Ok(removed)
+    }
+
+    // Removes every entry for which `f` returns `true` and returns them in ascending order of the
+    // keys. Unlike `retain`, which drops them, this keeps the removed entries in memory. See
+    // `remove_where`.
+    pub fn extract_if<F>(&mut self, f: F) -> Result<Vec<(K, V)>>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut extracted = vec![];
+        self.remove_where(f, |key, value| extracted.push((key, value)))?;
+        Ok(extracted)
+    }
+
+    // Removes every entry for which `f` returns `true` and passes it to `removed`.
+    //
+    // The entries to remove are collected in a scan first and then removed one by one, so the tree
+    // is rebalanced as usual. In a multimap, `remove` can only remove the first value of a key: all
+    // values of a key with values to remove are removed, and the ones to keep are added again.
+    fn remove_where<F, G>(&mut self, mut f: F, mut removed: G) -> Result<()>
+    where
+        F: FnMut(&K, &V) -> bool,
+        G: FnMut(K, V),
+    {
+        // For every key with values to remove: the key and whether to remove each of its values.
+        let mut changes: Vec<(K, Vec<bool>)> = vec![];
+        let mut group: Option<(K, Vec<bool>)> = None;
+        for (key, value) in self.entries() {
+            if group.as_ref().is_none_or(|(k, _)| *k != key) {
+                changes.extend(group.take().filter(|(_, remove)| remove.contains(&true)));
+                group = Some((key.clone(), vec![]));
+            }
+            group.as_mut().unwrap().1.push(f(&key, &value));
+        }
+        changes.extend(group.filter(|(_, remove)| remove.contains(&true)));
+
+        for (key, remove) in changes {
+            let mut kept = vec![];
+            for remove in remove {
+                if let Some(value) = self.remove(key.clone())? {
+                    match remove {
+                        true => removed(key.clone(), value),
+                        false => kept.push(value),
+                    }
+                }
+            }
+            for value in kept {
+                self.set(key.clone(), value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Sets all entries of `other` in this tree: for a key in both trees, the value of `other` wins.
+    // In a multimap, the values of `other` are added after the values of the same key. `other` is
+    // only read, both trees can be used afterwards.
+    pub fn merge<D: PageCodec<K, V>>(&mut self, other: &BTree<K, V, D>) -> Result<()> {
+        match self.multimap {
+            true => self.try_extend(other.entries()),
+            false => self.merge_with(other, |_, _, value| value),
+        }
+    }
+
+    // Like `merge`, but for a key in both trees the value becomes `f(key, value, other_value)`. In a
+    // multimap, every value of `other` is combined with the first value of the key in this tree.
+    pub fn merge_with<D, F>(&mut self, other: &BTree<K, V, D>, mut f: F) -> Result<()>
+    where
+        D: PageCodec<K, V>,
+        F: FnMut(&K, V, V) -> V,
+    {
+        for (key, value) in other.entries() {
+            let mut value = Some(value);
+            let found = self.update(key.clone(), |current| {
+                *current = f(&key, mem::take(current), value.take().unwrap());
+            })?;
+            if !found {
+                self.set(key, value.take().unwrap())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the pages and the metadata to disk, as `Drop` does. After this, reopening the directory
+    // sees all changes so far, even if the process is killed before the tree is dropped.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if self.free_page_limit.is_some_and(|limit| self.emtpy_pages.len() as u64 > limit) {
+            self.shrink_to_fit()?;
+            #[cfg(feature = "snapshots")]
+            let compact = self.snapshots.is_empty();
+            #[cfg(not(feature = "snapshots"))]
+            let compact = true;
+            if compact && self.free_page_limit.is_some_and(|limit| self.emtpy_pages.len() as u64 > limit) {
+                self.compact()?;
+            }
+        }
+        // The pages go first: the metadata must not refer to pages that aren't on disk.
+        self.store.sync()?;
+        self.store_meta()?;
+        self.dirty = false;
+        // After the tree: the progress must not include entries that the stored tree doesn't have.
+        self.save_compaction()
+    }
+
+    // Truncates the db file after the last page in use, if the pages after it have been deleted or
+    // were preallocated (see `with_preallocated`). Unlike `compact`, this doesn't move any pages, so
+    // nothing changes if the last page of the file is in use, or while there are snapshots.
+    pub fn shrink_to_fit(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        #[cfg(feature = "snapshots")]
+        if !self.snapshots.is_empty() {
+            return Ok(());
+        }
+        let page_count = self.node_count + self.emtpy_pages.len() as u64;
+        let trailing = self.emtpy_pages.remove_trailing(page_count);
+        if trailing > 0 {
+            self.dirty = true;
+        }
+        if trailing > 0 || self.store.page_count()? > page_count {
+            self.store.set_page_count(page_count - trailing)?;
+        }
+        Ok(())
+    }
+
+    // Walks the whole tree and checks its structural invariants: keys are strictly ascending and
+    // within the bounds set by the parent, nodes aren't overfull, non-root leaves are at least half
+    // full, all leaves are at the same depth and linked in order through `next`, and the number of
+    // pages (including overflow pages) and entries matches the metadata. The first violation found
+    // is returned as `Error::Corrupt`.
+    pub fn verify(&self) -> Result<()> {
+        if self.node_count == 0 {
+            return Ok(());
+        }
+        let mut verification = Verification::default();
+        self.load_node(self.root_page_nr)?.verify(self, None, None, 0, &mut verification)?;
+        verification.finish(self)
+    }
+
+    // Counts the entries in the leaves (see `count`) and stores that as the number of entries,
+    // returns the count. `verify` reports a number of entries that doesn't match, this repairs it.
+    pub fn recount(&mut self) -> Result<usize> {
+        let count = self.count()?;
+        if count as u64 != self.entry_count {
+            self.entry_count = count as u64;
+            self.dirty = true;
+        }
+        Ok(count)
+    }
+
+    // Like `remove`, but a missing key is an error: `Error::KeyNotFound`.
+    pub fn remove_or_err(&mut self, key: K) -> Result<V> {
+        self.remove(key)?.ok_or(Error::KeyNotFound)
+    }
+
+    fn create_first_root(&mut self, key: K, value: V) -> Result<()> {
+        // An empty tree still has its root leaf, unless nothing has been stored yet.
+        let root = match self.node_count {
+            0 => {
+                self.root_page_nr = self.next_page_nr();
+                self.first_leaf_page_nr = self.root_page_nr;
+                BTNode::new_leaf(self.root_page_nr, &[], &[], None)
+            }
+            _ => self.load_node(self.root_page_nr)?,
+        };
+        root.set(self, key, value)?;
+        self.entry_count += 1;
+        Ok(())
+    }
+
+    // Adds roots above the old root, which has been split into several nodes: `splits` holds the
+    // (key, new_page_nr, new_count) triple of each new node. A new root with too many children is
+    // split as well, until the top one fits.
+    fn grow_root(&mut self, mut splits: Vec<(K, PagePtr, u64)>) -> Result<()> {
+        while !splits.is_empty() {
+            let new_count: u64 = splits.iter().map(|(_, _, count)| count).sum();
+            let mut keys = vec![];
+            let mut page_nrs = vec![self.root_page_nr];
+            let mut counts = vec![self.entry_count - new_count];
+            for (key, page_nr, count) in splits {
+                keys.push(key);
+                page_nrs.push(page_nr);
+                counts.push(count);
+            }
+            self.root_page_nr = self.next_page_nr();
+            splits = BTNode::new_internal(self.root_page_nr, &keys, &page_nrs, &counts).store_split(self)?;
+        }
+        Ok(())
+    }
+
+    // Stores `leaf`, whose first entry is the n-th entry of the tree, after its values changed and no
+    // longer fit in it: it's split, and so are the nodes above it that get too many children.
+    pub(crate) fn store_overfull_leaf(&mut self, n: u64, leaf: Leaf<K, V>) -> Result<()> {
+        let root = self.load_node(self.root_page_nr)?;
+        let splits = root.store_leaf_at(self, n, leaf)?;
+        self.grow_root(splits)
+    }
+
+    // Descends from the root to the leaf that may contain `key`.
+    //
+    // Besides the leaf, this returns the root of the closest subtree to the left of the path
+    // (if any): the last leaf of that subtree is the predecessor of the returned leaf.
+    //
+    fn descend_to_leaf(&self, key: &K) -> Result<(Leaf<K, V>, Option<PagePtr>)> {
+        let mut left = None;
+        let mut page_nr = self.root_page_nr;
+        loop {
+            match self.load_node(page_nr)? {
+                BTNode::Leaf(node) => return Ok((node, left)),
+                BTNode::Internal(node) => {
+                    let i = node.child_index(key);
+                    if i > 0 {
+                        left = Some(node.child(i - 1));
+                    }
+                    page_nr = node.child(i);
+                }
+            }
+        }
+    }
+
+    // Descends from the root to the leaf that may contain the first entry with `key`. In a multimap,
+    // that leaf can end before the entries with `key`, which then start in the next leaf.
+    fn descend_to_first(&self, key: &K) -> Result<Leaf<K, V>> {
+        if !self.multimap {
+            return Ok(self.descend_to_leaf(key)?.0);
+        }
+        let mut page_nr = self.root_page_nr;
+        loop {
+            match self.load_node(page_nr)? {
+                BTNode::Leaf(node) => return Ok(node),
+                BTNode::Internal(node) => page_nr = node.child(node.first_child_index(key)),
+            }
+        }
+    }
+
+    // Returns the leaf where iterating from `start` begins and the index of the first entry after
+    // `start` in it, or `None` if the tree is empty. The index is the length of the leaf when the
+    // entry is the first one of the next leaf. All iterators start here.
+    fn seek(&self, start: Bound<&K>) -> Result<Option<(Leaf<K, V>, usize)>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let position = match start {
+            Bound::Included(start) => {
+                let leaf = self.descend_to_first(start)?;
+                let index = leaf.lower_bound(start);
+                (leaf, index)
+            }
+            Bound::Excluded(start) => {
+                let (leaf, _) = self.descend_to_leaf(start)?;
+                let index = leaf.upper_bound(start);
+                (leaf, index)
+            }
+            Bound::Unbounded => (self.load_node(self.first_leaf_page_nr)?.leaf_node(), 0),
+        };
+        Ok(Some(position))
+    }
+
+    // Returns the leaf holding the first entry with `key` and the index of that entry in the leaf.
+    fn find(&self, key: &K) -> Result<Option<(Leaf<K, V>, usize)>> {
+        Ok(self.find_leaf(key)?.and_then(|leaf| leaf.position(key).map(|i| (leaf, i))))
+    }
+
+    // Returns the leaf that holds the first entry with `key` if it's present, or `None` if it can't
+    // be present.
+    fn find_leaf(&self, key: &K) -> Result<Option<Leaf<K, V>>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let leaf = self.descend_to_first(key)?;
+        if self.multimap && leaf.lower_bound(key) == leaf.len() {
+            return match leaf.next() {
+                Some(page_nr) => Ok(Some(self.load_node(page_nr)?.leaf_node())),
+                None => Ok(None),
+            };
+        }
+        Ok(Some(leaf))
+    }
+
+    // Looks up the sorted and deduplicated `keys` in ascending order and calls `f` with whether each
+    // one is present, until it returns false. A key within the keys of the leaf of the previous key
+    // is looked up in that leaf, without descending from the root again.
+    fn sweep_keys<F>(&self, keys: &[K], mut f: F) -> Result<()>
+    where
+        F: FnMut(bool) -> bool,
+    {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut leaf: Option<Leaf<K, V>> = None;
+        for key in &keys {
+            let covered = leaf
+                .as_ref()
+                .is_some_and(|leaf| leaf.len() > 0 && leaf.key(0) <= key && key <= leaf.key(leaf.len() - 1));
+            if !covered {
+                leaf = self.find_leaf(key)?;
+            }
+            if !f(leaf.as_ref().is_some_and(|leaf| leaf.position(key).is_some())) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Descends from the root to the leaf holding the n-th entry (counting from 0), which must exist,
+    // and returns the leaf and the index of the entry in it.
+    fn descend_to_index(&self, mut n: u64) -> Result<(Leaf<K, V>, usize)> {
+        let mut page_nr = self.root_page_nr;
+        loop {
+            match self.load_node(page_nr)? {
+                BTNode::Leaf(node) => return Ok((node, n as usize)),
+                BTNode::Internal(node) => {
+                    let (i, rest) = node.select_child(n);
+                    n = rest;
+                    page_nr = node.child(i);
+                }
+            }
+        }
+    }
+
+    // Returns the last (rightmost) leaf of the subtree rooted at `page_nr`.
+    fn last_leaf(&self, mut page_nr: PagePtr) -> Result<Leaf<K, V>> {
+        loop {
+            match self.load_node(page_nr)? {
+                BTNode::Leaf(node) => return Ok(node),
+                BTNode::Internal(node) => page_nr = node.last_child(),
+            }
+        }
+    }
+
+    // Returns a copy of the metadata that can only be used for reading, with its own file handle.
+    fn read_only_copy(&self) -> Self {
+        // Sticks to the current db file, even if it's replaced by `compact`.
+        self.read_only_view(Store::read_only(db_path(&self.directory, &self.name), self.page_size))
+    }
+
+    // Returns a copy of the metadata that can only be used for reading, which reads through `store`.
+    fn read_only_view(&self, store: Store) -> Self {
+        Self {
+            magic_header: self.magic_header.clone(),
+            format_version: self.format_version,
+            directory: self.directory.clone(),
+            name: self.name.clone(),
+            node_count: self.node_count,
+            entry_count: self.entry_count,
+            root_page_nr: self.root_page_nr,
+            first_leaf_page_nr: self.first_leaf_page_nr,
+            emtpy_pages: self.emtpy_pages.clone(),
+            key_size: self.key_size,
+            value_size: self.value_size,
+            inline_value_size: self.inline_value_size,
+            key_type: PhantomData,
+            value_type: PhantomData,
+            max_key_count: self.max_key_count,
+            split_at: self.split_at,
+            max_leaf_key_count: self.max_leaf_key_count,
+            leaf_split_at: self.leaf_split_at,
+            page_size: self.page_size,
+            leaf_overhead: self.leaf_overhead,
+            internal_overhead: self.internal_overhead,
+            page_ptr_size: self.page_ptr_size,
+            multimap: self.multimap,
+            user_metadata: self.user_metadata.clone(),
+            read_only: true,
+            dirty: false,
+            wal: false,
+            free_page_limit: None,
+            page_allocator: default_page_allocator(),
+            store,
+            recovery: None,
+            compaction: None,
+            codec: PhantomData,
+            #[cfg(feature = "snapshots")]
+            snapshots: Snapshots::default(),
+            #[cfg(feature = "snapshots")]
+            page_copies: self.page_copies.clone(),
+        }
+    }
+
+    // The minimum number of keys in a leaf other than the root: a split leaves `leaf_split_at` keys in
+    // the old leaf and the rest in the new one. It's at most half of `max_leaf_key_count` (rounded up),
+    // so that a leaf with too few keys and a sibling at the minimum fit in one leaf.
+    fn min_leaf_key_count(&self) -> usize {
+        self.leaf_split_at.min(self.max_leaf_key_count as usize + 1 - self.leaf_split_at)
+    }
+
+    // The minimum number of keys in an internal node other than the root. One key of a full node
+    // moves up to the parent when it's split, so the new node gets `max_key_count - split_at` keys.
+    fn min_internal_key_count(&self) -> usize {
+        self.split_at.min(self.max_key_count as usize - self.split_at)
+    }
+
+    // Changes the node capacity of the tree, of leaves and internal nodes alike, so that a unit test
+    // can build a tree of a precise shape. The nodes that are written from now on follow it, the ones
+    // written before keep their keys. The split points are lowered to fit if needed.
+    #[cfg(test)]
+    pub(crate) fn set_max_key_count(&mut self, max_key_count: u64) {
+        assert!(max_key_count >= 2, "Programming error: a node must be able to hold at least 2 keys");
+        self.max_key_count = max_key_count;
+        self.max_leaf_key_count = max_key_count;
+        self.split_at = self.split_at.min(max_key_count as usize - 1);
+        self.leaf_split_at = self.leaf_split_at.min(max_key_count as usize - 1);
+    }
+
+    // Changes where full nodes are split, see `set_max_key_count`: a leaf keeps `split_at` keys, an
+    // internal node moves key `split_at` up to its parent.
+    #[cfg(test)]
+    pub(crate) fn set_split_at(&mut self, split_at: usize) {
+        let max_key_count = self.max_key_count.min(self.max_leaf_key_count);
+        assert!(
+            split_at >= 1 && split_at < max_key_count as usize,
+            "Programming error: split_at {} is not between 1 and {}",
+            split_at,
+            max_key_count - 1
+        );
+        self.split_at = split_at;
+        self.leaf_split_at = split_at;
+    }
+
+    // `node_count` is the number of pages in use (nodes and overflow pages), so together with the
+    // deleted pages it's the number of pages in the db file.
+    fn next_page_nr(&mut self) -> u64 {
+        let deleted = self.page_allocator.allocate(&self.emtpy_pages);
+        if let Some(page_nr) = deleted {
+            let deleted = self.emtpy_pages.take(page_nr);
+            assert!(deleted, "page {} allocated by {:?} isn't deleted", page_nr, self.page_allocator);
+        }
+        let page_nr = deleted.unwrap_or(self.node_count + self.emtpy_pages.len() as u64);
+        self.node_count += 1;
+        page_nr
+    }
+
+    // The number of pages in use by the tree itself, without the copies kept for snapshots.
+    pub(crate) fn tree_node_count(&self) -> u64 {
+        #[cfg(feature = "snapshots")]
+        return self.node_count - self.snapshots.page_count();
+        #[cfg(not(feature = "snapshots"))]
+        self.node_count
+    }
+
+    fn on_page_deleted(&mut self, page_nr: PagePtr) -> Result<()> {
+        self.note_page_change(page_nr, None)?;
+        self.dirty = true;
+        self.emtpy_pages.push(page_nr);
+        self.page_allocator.free(page_nr);
+        self.node_count -= 1;
+        Ok(())
+    }
+
+    fn new(
+        directory: &Path,
+        name: &str,
+        override_max_key_count: Option<u64>,
+        page_size: u64,
+        fill_factor: f64,
+    ) -> Result<Self> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::InvalidConfiguration(format!("page size {} is not a power of two", page_size)));
+        }
+        if !(fill_factor > 0.0 && fill_factor < 1.0) {
+            return Err(Error::InvalidConfiguration(format!("fill factor {} is not between 0 and 1", fill_factor)));
+        }
+        if C::PAGE_PTR_SIZE != 4 && C::PAGE_PTR_SIZE != 8 {
+            let msg = format!("page pointer size {} is not 4 or 8 bytes", C::PAGE_PTR_SIZE);
+            return Err(Error::InvalidConfiguration(msg));
+        }
+        let key_size = mem::size_of::<K>() as u64;
+        let value_size = mem::size_of::<V>() as u64;
+        let pointer_size = C::PAGE_PTR_SIZE;
+        let inline_value_size = inline_value_size(page_size, value_size, pointer_size);
+        let leaf_slot_size = 1 + inline_value_size.max(pointer_size);
+        let internal_slot_size = pointer_size + mem::size_of::<u64>() as u64;
+        let (leaf_overhead, internal_overhead) = Self::node_overhead()?;
+        let (max_leaf_key_count, max_key_count) = match override_max_key_count {
+            None => match (
+                max_leaf_key_count(page_size, key_size, leaf_slot_size, leaf_overhead),
+                max_key_count(page_size, key_size, internal_slot_size, internal_overhead),
+            ) {
+                (leaf, internal) if leaf < 2 || internal < 2 => {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "page size {} is too small: a node must be able to hold at least 2 keys",
+                        page_size
+                    )))
+                }
+                capacities => capacities,
+            },
+            Some(n) if n < 2 => {
+                let msg = format!("max key count {} is too small: a node must be able to hold at least 2 keys", n);
+                return Err(Error::InvalidConfiguration(msg));
+            }
+            Some(n) => (n, n),
+        };
+        Ok(Self {
+            magic_header: String::from(MAGIC_HEADER),
+            format_version: FORMAT_VERSION,
+            directory: PathBuf::from(directory),
+            name: String::from(name),
+            node_count: 0,
+            entry_count: 0,
+            root_page_nr: 0,
+            first_leaf_page_nr: 0,
+            emtpy_pages: FreePages::default(),
+            key_size,
+            value_size,
+            inline_value_size,
+            max_key_count,
+            split_at: split_at(max_key_count, fill_factor),
+            max_leaf_key_count,
+            leaf_split_at: split_at(max_leaf_key_count, fill_factor),
+            page_size,
+            leaf_overhead,
+            internal_overhead,
+            page_ptr_size: pointer_size,
+            multimap: false,
+            user_metadata: vec![],
+            key_type: PhantomData,
+            value_type: PhantomData,
+            read_only: false,
+            dirty: false,
+            wal: false,
+            free_page_limit: None,
+            page_allocator: default_page_allocator(),
+            store: Store::new(db_path(directory, name), page_size),
+            recovery: None,
+            compaction: None,
+            codec: PhantomData,
+            #[cfg(feature = "snapshots")]
+            snapshots: Snapshots::default(),
+            #[cfg(feature = "snapshots")]
+            page_copies: Arc::default(),
+        })
+    }
+
+    // Returns the number of bytes of a serialized leaf and internal node that are not used by keys,
+    // values or page pointers, by serializing an empty node of each kind.
+    fn node_overhead() -> Result<(u64, u64)> {
+        let leaf = C::encode(&BTNode::new_leaf(0, &[], &[], Some(0)))?;
+        let internal = C::encode(&BTNode::new_internal(0, &[], &[], &[]))?;
+        Ok((leaf.len() as u64, internal.len() as u64))
+    }
+
+    // Reads the metadata and checks it against the db file. A writer picks up the compaction that
+    // was in progress, see `compact_step`.
+    fn load_meta(directory: &Path, name: &str, read_only: bool) -> Result<Self> {
+        let mut btree = Self::read_meta(directory, name, read_only)?;
+        btree.validate_root()?;
+        if !read_only {
+            btree.load_compaction()?;
+        }
+        Ok(btree)
+    }
+
+    // Reads the metadata like `load_meta`, without checking it against the db file.
+    fn read_meta(directory: &Path, name: &str, read_only: bool) -> Result<Self> {
+        Ok(Self::decode_meta(File::open(meta_file_path(directory, name))?)?.located_at(directory, name, read_only))
+    }
+
+    // Gives decoded metadata the files of tree `name` in `directory`.
+    fn located_at(mut self, directory: &Path, name: &str, read_only: bool) -> Self {
+        self.directory = PathBuf::from(directory);
+        self.name = String::from(name);
+        self.read_only = read_only;
+        self.store = match read_only {
+            true => Store::read_only(db_path(directory, name), self.page_size),
+            false => Store::new(db_path(directory, name), self.page_size),
+        };
+        self
+    }
+
+    // Deserializes the metadata from `r` after checking its header. The tree still has to be given its
+    // directory and store.
+    fn decode_meta<R: Read + Seek>(mut r: R) -> Result<Self> {
+        // Limit the size of the header so that garbage can't trigger a huge allocation.
+        let header: MetaHeader = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(64)
+            .deserialize_from(&mut r)
+            .map_err(|_| Error::InvalidFileFormat { page_nr: None })?;
+        if header.magic_header != MAGIC_HEADER {
+            return Err(Error::InvalidFileFormat { page_nr: None });
+        }
+        if header.format_version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion { found: header.format_version, expected: FORMAT_VERSION });
+        }
+        r.seek(SeekFrom::Start(0))?;
+        let btree: Self = bincode::deserialize_from(r)?;
+        if btree.page_ptr_size != C::PAGE_PTR_SIZE {
+            return Err(Error::InvalidConfiguration(format!(
+                "the tree stores page numbers in {} bytes, but the codec in {}",
+                btree.page_ptr_size,
+                C::PAGE_PTR_SIZE
+            )));
+        }
+        Ok(btree)
+    }
+
+    // Checks that the root and the first leaf are pages in use and that the root can be read, so
+    // that metadata that doesn't match the db file is reported when the tree is opened.
+    fn validate_root(&self) -> Result<()> {
+        if self.node_count == 0 {
+            return Ok(());
+        }
+        let page_count = self.node_count + self.emtpy_pages.len() as u64;
+        for (node, page_nr) in [("root", self.root_page_nr), ("first leaf", self.first_leaf_page_nr)] {
+            if page_nr >= page_count || self.emtpy_pages.contains(&page_nr) {
+                return Err(Error::Corrupt(format!("the {} is page {}, which is not in use", node, page_nr)));
+            }
+        }
+        match self.store.read_page(self.root_page_nr, |page| C::decode(page, self.root_page_nr)) {
+            Ok(_) => Ok(()),
+            Err(Error::Io(err)) => Err(Error::Io(err)),
+            Err(err) => Err(Error::Corrupt(format!("the root can't be read from page {}: {}", self.root_page_nr, err))),
+        }
+    }
+
+    // Replaces the metadata atomically: a crash leaves either the old or the new `meta` file.
+    fn store_meta(&self) -> Result<()> {
+        write_meta(&self.directory, &self.name, &self.meta_bytes()?)
+    }
+
+    // Serializes the metadata. The pages holding copies for snapshots are stored as deleted pages:
+    // snapshots only live in memory.
+    fn meta_bytes(&self) -> Result<Vec<u8>> {
+        #[cfg(feature = "snapshots")]
+        if self.snapshots.page_count() > 0 {
+            let mut meta = self.read_only_copy();
+            meta.node_count -= self.snapshots.page_count();
+            meta.emtpy_pages.extend(self.snapshots.copy_pages());
+            return Ok(bincode::serialize(&meta)?);
+        }
+        Ok(bincode::serialize(self)?)
+    }
+
+    // Runs `f`, which changes the tree, through the write-ahead log if it's enabled: the pages
+    // written by `f` are kept in memory, written to the log together with the new metadata, and
+    // only then written to the db file. Nested calls are part of the outer change.
+    fn atomically<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !self.wal || self.store.is_pending()? {
+            return f(self);
+        }
+        // The metadata as it is before the change, to undo it if it fails.
+        let meta = bincode::serialize(&*self)?;
+        #[cfg(feature = "snapshots")]
+        let snapshots = self.snapshots.clone();
+        let dirty = self.dirty;
+        self.store.begin()?;
+        let result = f(self);
+        let pages = self.store.take_pending()?;
+        if result.is_err() {
+            // The tree may be half changed: none of its pages are written, so it's as it was before.
+            self.restore_meta(&meta, dirty)?;
+            #[cfg(feature = "snapshots")]
+            {
+                self.snapshots = snapshots;
+            }
+            return result;
+        }
+        if !pages.is_empty() {
+            wal::write(&self.directory, &self.name, self.page_size, &pages, &self.meta_bytes()?)?;
+            for (page_nr, page) in &pages {
+                self.store.write_raw(*page_nr, page)?;
+            }
+            self.store.sync()?;
+            self.store_meta()?;
+            wal::clear(&self.directory, &self.name)?;
+            self.dirty = false;
+        }
+        result
+    }
+
+    // Undoes a change of which no page has been written: the tree gets the metadata `meta` back, which
+    // was serialized before the change, see `atomically`. What isn't stored in the metadata is kept.
+    fn restore_meta(&mut self, meta: &[u8], dirty: bool) -> Result<()> {
+        let mut changed = Self::decode_meta(io::Cursor::new(meta))?;
+        mem::swap(self, &mut changed);
+        self.directory = mem::take(&mut changed.directory);
+        self.name = mem::take(&mut changed.name);
+        self.read_only = changed.read_only;
+        self.dirty = dirty;
+        self.wal = changed.wal;
+        self.free_page_limit = changed.free_page_limit;
+        mem::swap(&mut self.page_allocator, &mut changed.page_allocator);
+        mem::swap(&mut self.store, &mut changed.store);
+        self.recovery = changed.recovery.take();
+        self.compaction = changed.compaction.take();
+        #[cfg(feature = "snapshots")]
+        {
+            self.page_copies = Arc::clone(&changed.page_copies);
+        }
+        // Nothing of the changed tree is stored when it's dropped.
+        changed.dirty = false;
+        Ok(())
+    }
+
+    // Returns the root node, or an empty leaf if nothing has been stored yet.
+    pub fn root(&self) -> Result<BTNode<K, V>> {
+        match self.node_count {
+            0 => Ok(BTNode::new_leaf(self.root_page_nr, &[], &[], None)),
+            _ => self.load_node(self.root_page_nr),
+        }
+    }
+
+    // What was discarded when the tree was opened with `open_with_recovery` (or `BTreeBuilder::recover`),
+    // for the application to log or act on. `None` if nothing had to be discarded.
+    pub fn recovery(&self) -> Option<&Recovery> {
+        self.recovery.as_ref()
+    }
+
+    // Describes the root without returning the node itself, for debugging and tests. Unlike `root`,
+    // there's no page number if nothing has been stored yet.
+    pub fn root_info(&self) -> Result<RootInfo> {
+        if self.node_count == 0 {
+            return Ok(RootInfo { page_nr: None, is_leaf: true, key_count: 0 });
+        }
+        let root = self.load_node(self.root_page_nr)?;
+        let is_leaf = matches!(root, BTNode::Leaf(_));
+        Ok(RootInfo { page_nr: Some(self.root_page_nr), is_leaf, key_count: root.len() })
+    }
+
+    // Writes the nodes of the tree to `w` for debugging: the internal nodes level by level, then the
+    // leaves, one node per line.
+    pub fn dump_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        self.root()?.dump_to(self, w)
+    }
+
+    // Like `dump_to`, printing the nodes on stdout.
+    pub fn dump(&self) -> Result<()> {
+        self.dump_to(&mut io::stdout())
+    }
+
+    // Returns the number of pages read and written through this tree, to measure how many pages an
+    // operation touches. Read handles have their own file handle and numbers, see `reader`.
+    pub fn page_stats(&self) -> PageStats {
+        self.store.stats()
+    }
+
+    pub fn reset_page_stats(&self) {
+        self.store.reset_stats()
+    }
+
+    // Reports how much of the db file is in use, to decide whether `shrink_to_fit` (for free pages
+    // at the end of the file) or `compact` (for free pages and padding) is worth it. The padding is
+    // measured by encoding every node again, so this reads the whole tree.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let used_bytes = match self.node_count {
+            0 => 0,
+            _ => self.used_bytes(self.root_page_nr)?,
+        };
+        Ok(DiskUsage {
+            file_bytes: self.store.len()?,
+            live_pages: self.node_count,
+            free_pages: self.emtpy_pages.len() as u64,
+            padding_bytes: self.tree_node_count() * self.page_size - used_bytes,
+        })
+    }
+
+    // Returns the number of bytes in the pages of the subtree rooted at `page_nr` that hold data.
+    // The overhead of an overflow page includes the next page, also in the last page of a chain.
+    fn used_bytes(&self, page_nr: PagePtr) -> Result<u64> {
+        let node = self.load_node(page_nr)?;
+        let mut used_bytes = C::encode(&node)?.len() as u64;
+        match &node {
+            BTNode::Internal(internal) => {
+                for i in 0..internal.child_count() {
+                    used_bytes += self.used_bytes(internal.child(i))?;
+                }
+            }
+            BTNode::Leaf(leaf) => {
+                for first_page_nr in leaf.overflow_pages() {
+                    let mut page_nr = Some(first_page_nr);
+                    while let Some(overflow_page_nr) = page_nr {
+                        let page = self.load_overflow_page(overflow_page_nr)?;
+                        used_bytes += OverflowPage::overhead(self.page_ptr_size) + page.data.len() as u64;
+                        page_nr = page.next;
+                    }
+                }
+            }
+        }
+        Ok(used_bytes)
+    }
+
+    pub fn load_node(&self, page_nr: u64) -> Result<BTNode<K, V>> {
+        assert!(!self.emtpy_pages.contains(&page_nr), "Programming error: Page {:?} requested, but it has been deleted", page_nr);
+        self.store
+            .read_page(self.page_location(page_nr), |page| C::decode(page, page_nr))
+            .map_err(|err| err.at_page(page_nr))
+    }
+
+    fn store_node(&mut self, mut node: BTNode<K, V>) -> Result<()> {
+        // Catch a split or merge that goes wrong where it happens, instead of in a later `verify`.
+        #[cfg(debug_assertions)]
+        if let Err(err) = node.check_keys(self) {
+            panic!("Programming error: storing a node that is not valid: {}", err);
+        }
+        self.dirty = true;
+        if let BTNode::Leaf(leaf) = &mut node {
+            leaf.spill(self)?;
+        }
+        let mut bytes = C::encode(&node)?;
+        // With a codec that compresses, a leaf that isn't checked before it's stored can still be too
+        // large, like the second leaf of a split or one that passed entries on to a sibling: its
+        // largest values move to overflow pages until it fits.
+        while C::MAX_COMPRESSION_RATIO > 1 && bytes.len() as u64 > self.page_size {
+            let spilled = match &mut node {
+                BTNode::Leaf(leaf) => leaf.spill_largest(self)?,
+                BTNode::Internal(_) => false,
+            };
+            if !spilled {
+                break;
+            }
+            bytes = C::encode(&node)?;
+        }
+        self.note_page_change(node.page_nr(), Some(&node))?;
+        #[cfg(feature = "snapshots")]
+        self.preserve_page(node.page_nr())?;
+        self.store.write_page(node.page_nr(), |page| {
+            page.extend_from_slice(&bytes);
+            Ok(())
+        })
+    }
+
+    // Writes `data` to a new chain of overflow pages and returns the page number of the first one.
+    pub(crate) fn store_overflow(&mut self, data: &[u8]) -> Result<PagePtr> {
+        self.dirty = true;
+        let chunk_size = (self.page_size - OverflowPage::overhead(self.page_ptr_size)) as usize;
+        let page_count = data.len().div_ceil(chunk_size);
+        let page_nrs: Vec<PagePtr> = (0..page_count).map(|_| self.next_page_nr()).collect();
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            #[cfg(feature = "snapshots")]
+            self.preserve_page(page_nrs[i])?;
+            let page = OverflowPage { next: page_nrs.get(i + 1).cloned(), data: chunk.to_vec() };
+            self.store.write_page(page_nrs[i], |fh| page.serialize_into(fh, self.page_ptr_size))?;
+        }
+        Ok(page_nrs[0])
+    }
+
+    fn load_overflow_page(&self, page_nr: PagePtr) -> Result<OverflowPage> {
+        assert!(!self.emtpy_pages.contains(&page_nr), "Programming error: Page {:?} requested, but it has been deleted", page_nr);
+        self.store
+            .read_page(self.page_location(page_nr), |fh| OverflowPage::deserialize_from(fh, self.page_ptr_size))
+            .map_err(|err| err.at_page(page_nr))
+    }
+
+    // Returns where page `page_nr` is read from: in a snapshot, a page that has been overwritten since
+    // is read from its copy.
+    fn page_location(&self, page_nr: PagePtr) -> PagePtr {
+        #[cfg(feature = "snapshots")]
+        if let Some(copy) = self.page_copies.get(&page_nr) {
+            return *copy;
+        }
+        page_nr
+    }
+
+    // Reads the value stored in the chain of overflow pages starting at `page_nr`.
+    pub(crate) fn load_overflow(&self, mut page_nr: PagePtr) -> Result<V> {
+        let mut data = vec![];
+        loop {
+            let page = self.load_overflow_page(page_nr)?;
+            data.extend(page.data);
+            match page.next {
+                Some(next) => page_nr = next,
+                None => return Ok(bincode::deserialize(&data)?),
+            }
+        }
+    }
+
+    // Returns the page numbers of the chain of overflow pages starting at `page_nr`.
+    pub(crate) fn overflow_chain(&self, page_nr: PagePtr) -> Result<Vec<PagePtr>> {
+        let mut chain = vec![page_nr];
+        while let Some(next) = self.load_overflow_page(chain[chain.len() - 1])?.next {
+            chain.push(next);
+        }
+        Ok(chain)
+    }
+
+    pub(crate) fn free_overflow(&mut self, page_nr: PagePtr) -> Result<()> {
+        for page_nr in self.overflow_chain(page_nr)? {
+            self.on_page_deleted(page_nr)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Make sure the meta data for the BTree is written to disk, also when the tree has become empty.
+impl<K, V, C> Drop for BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Errors can't be returned from here and panicking could abort the process, so they're ignored,
+    // like `BufWriter` does. Use `close` (or `flush`) to handle them.
+    fn drop(&mut self) {
+        if !self.read_only && self.dirty && self.store_meta().is_err() {
+            return;
+        }
+        let _ = self.save_compaction();
+    }
+}
+
+
+// Sets all entries with `set`. This panics on the first error: use `try_extend` to handle errors.
+impl<K, V, C> Extend<(K, V)> for BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, entries: I) {
+        self.try_extend(entries).unwrap()
+    }
+}
+
+
+// The position after the last entry of a page, see `BTree::page`. It can be serialized to hand it
+// to a client, but its contents are not part of the API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor<K> {
+    key: K,
+    // the number of values of `key` that have been returned, more than 1 only in a multimap
+    values: u64,
+}
+
+
+// The entries of a page and the cursor of the next page, see `BTree::page`.
+pub type Page<K, V> = (Vec<(K, V)>, Option<Cursor<K>>);
+
+
+// How the db file is used, see `BTree::disk_usage`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    // the length of the db file, which can be longer than the pages when it was preallocated
+    pub file_bytes: u64,
+    // pages in use by nodes and overflow pages (and copies kept for snapshots)
+    pub live_pages: u64,
+    // deleted pages, which are reused before the file grows
+    pub free_pages: u64,
+    // the bytes of the pages in use that don't hold data, after the encoded node or overflow data
+    pub padding_bytes: u64,
+}
+
+
+// The root of a tree, see `BTree::root_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootInfo {
+    // `None` if no page has been stored yet
+    pub page_nr: Option<PagePtr>,
+    pub is_leaf: bool,
+    pub key_count: usize,
+}
+
+
+// The pages discarded by `BTree::open_with_recovery`, see `BTree::recovery`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovery {
+    // the unreadable pages at the end of the db file, from the last one down
+    pub discarded_pages: Vec<PagePtr>,
+    // the entries that were stored in the discarded pages, or below them
+    pub lost_entries: u64,
+}
+
+
+// A read-only handle of a BTree, see `BTree::reader`.
+#[derive(Debug)]
+pub struct ReadHandle<K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: BTree<K, V, C>,
+}
+
+
+impl<K, V, C> ReadHandle<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    pub fn len(&self) -> usize {
+        self.btree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.btree.is_empty()
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>> {
+        self.btree.get(key)
+    }
+
+    pub fn get_ref(&self, key: &K) -> Result<Option<V>> {
+        self.btree.get_ref(key)
+    }
+
+    pub fn contains_key(&self, key: K) -> Result<bool> {
+        self.btree.contains_key(key)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> BTreeRangeIterator<'_, K, V, C> {
+        self.btree.range(range)
+    }
+
+    // See `BTree::snapshot_keys`.
+    pub fn snapshot_keys(&self) -> Result<Vec<K>> {
+        self.btree.snapshot_keys()
+    }
+
+    pub fn page_stats(&self) -> PageStats {
+        self.btree.page_stats()
+    }
+}
+
+
+// A clone sees the same version of the tree, but has its own handle of the db file. That file is
+// opened again, so a handle created before `compact` should not be cloned afterwards.
+impl<K, V, C> Clone for ReadHandle<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    fn clone(&self) -> Self {
+        Self { btree: self.btree.read_only_copy() }
+    }
+}
+
+
+pub struct BTreeRangeIterator<'a, K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: &'a BTree<K, V, C>,
+    // `None` when the iterator is exhausted
+    leaf: Option<Leaf<K, V>>,
+    index: usize,
+    end: Bound<K>,
+}
+
+
+impl<'a, K, V, C> BTreeRangeIterator<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    fn new<R: RangeBounds<K>>(btree: &'a BTree<K, V, C>, range: R) -> Result<Self> {
+        let end = range.end_bound().cloned();
+        Ok(match btree.seek(range.start_bound())? {
+            Some((leaf, index)) => Self { btree, leaf: Some(leaf), index, end },
+            None => Self { btree, leaf: None, index: 0, end },
+        })
+    }
+
+    // Moves to the next entry in the range and returns its index in `self.leaf`.
+    fn advance(&mut self) -> Option<Result<usize>> {
+        loop {
+            let leaf = self.leaf.as_ref()?;
+            if self.index < leaf.len() {
+                let key = leaf.key(self.index);
+                let in_range = match &self.end {
+                    Bound::Included(end) => key <= end,
+                    Bound::Excluded(end) => key < end,
+                    Bound::Unbounded => true,
+                };
+                if !in_range {
+                    self.leaf = None;
+                    return None;
+                }
+                self.index += 1;
+                return Some(Ok(self.index - 1));
+            }
+            self.leaf = match leaf.next().map(|page_nr| self.btree.load_node(page_nr)) {
+                Some(Ok(node)) => Some(node.leaf_node()),
+                Some(Err(err)) => {
+                    self.leaf = None;
+                    return Some(Err(err));
+                }
+                None => None,
+            };
+            self.index = 0;
         }
     }
+}
 
-    fn create_first_root(&mut self, key: K, value: V) -> Result<()> {
-        // FIXME: remove this line by eliminating function "root"
-        self.node_count = 0;
-        let root = self.root()?;
-        root.set(self, key, value)?;
-        self.entry_count += 1;
-        Ok(())
-    }
 
-    fn create_new_root(&mut self, key: K, new_page_nr: u64) -> Result<()> {
-        let old_root_page_nr = self.root_page_nr;
-        self.root_page_nr = self.next_page_nr();
-        let new_root = BTNode::new_internal(self.root_page_nr, &[key], &[old_root_page_nr, new_page_nr]);
-        self.store_node(&new_root)?;
-        Ok(())
-    }
+impl<'a, K, V, C> Iterator for BTreeRangeIterator<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    type Item = (K, V);
 
-    fn next_page_nr(&mut self) -> u64 {
-        let page_nr = self.node_count;
-        self.node_count += 1;
-        page_nr
+    fn next(&mut self) -> Option<Self::Item> {
+        let i = self.advance()?.unwrap();
+        Some(self.leaf.as_ref().unwrap().entry(self.btree, i).unwrap())
     }
+}
 
-    fn on_page_deleted(&mut self, page_nr: PagePtr) {
-        self.emtpy_pages.push(page_nr);
-        self.node_count -= 1;
-    }
 
-    fn new(directory: &Path, override_max_key_count: Option<u64>) -> Self {
-        let key_size = mem::size_of::<K>() as u64;
-        let value_size = mem::size_of::<V>() as u64;
-        let max_key_count = match override_max_key_count {
-            None => max_key_count(key_size, value_size),
-            Some(n) => n,
-        };
-        let split_at = split_at(max_key_count);
-        Self {
-            magic_header: String::from(MAGIC_HEADER),
-            directory: PathBuf::from(directory),
-            node_count: 0,
-            entry_count: 0,
-            root_page_nr: 0,
-            emtpy_pages: vec![],
-            key_size,
-            value_size,
-            max_key_count,
-            split_at,
-            key_type: PhantomData,
-            value_type: PhantomData,
-            fh: None,
-        }
-    }
+// Iterates over the keys in a range, see `BTree::range_keys`.
+pub struct BTreeRangeKeyIterator<'a, K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    range: BTreeRangeIterator<'a, K, V, C>,
+}
 
-    fn load_meta(path: &Path, directory: &Path) -> Result<Self> {
-        let fh = File::open(path)?;
-        let mut btree: Self = bincode::deserialize_from(fh)?;
-        btree.directory = PathBuf::from(directory);
-        Ok(btree)
-    }
 
-    fn store_meta(&self) -> Result<()> {
-        let fh = File::create(meta_file_path(&self.directory))?;
-        bincode::serialize_into(fh, self)?;
-        Ok(())
+impl<'a, K, V, C> Iterator for BTreeRangeKeyIterator<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let i = self.range.advance()?.unwrap();
+        Some(self.range.leaf.as_ref().unwrap().key(i).clone())
     }
+}
 
-    pub fn root(&mut self) -> Result<BTNode<K, V>> {
-        self.load_node(self.root_page_nr).or_else(|_err| {
-            // FIXME: better error checking here?
-            self.root_page_nr = self.next_page_nr();
-            Ok(BTNode::new_leaf(self.root_page_nr, &[], &[], None))
-        })
+
+// Iterates over the values of the entries in a range, see `BTree::range_values`.
+pub struct BTreeRangeValueIterator<'a, K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    range: BTreeRangeIterator<'a, K, V, C>,
+}
+
+
+impl<'a, K, V, C> Iterator for BTreeRangeValueIterator<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    type Item = Result<V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = &mut self.range;
+        Some(range.advance()?.and_then(|i| range.leaf.as_ref().unwrap().value(range.btree, i)))
     }
+}
 
-    pub fn load_node(&mut self, page_nr: u64) -> Result<BTNode<K, V>> {
-        assert!(!self.emtpy_pages.contains(&page_nr), "Programming error: Page {:?} requested, but it has been deleted", page_nr);
-        if self.fh.is_none() {
-            self.fh = Some(OpenOptions::new().read(true).write(true).create(true).open(db_path(&self.directory))?);
+
+pub struct BTreeScanIterator<'a, K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: &'a BTree<K, V, C>,
+    lookahead: u64,
+    // consecutive pages starting at page `buffer_start`
+    buffer: Vec<u8>,
+    buffer_start: PagePtr,
+    // `None` when the iterator is exhausted
+    leaf: Option<Leaf<K, V>>,
+    index: usize,
+}
+
+
+impl<'a, K, V, C> BTreeScanIterator<'a, K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    fn new(btree: &'a BTree<K, V, C>, lookahead: usize) -> Result<Self> {
+        let lookahead = lookahead.max(1) as u64;
+        let mut iterator = Self { btree, lookahead, buffer: vec![], buffer_start: 0, leaf: None, index: 0 };
+        if !btree.is_empty() {
+            iterator.leaf = Some(iterator.load_leaf(btree.first_leaf_page_nr)?);
         }
-        let fh = self.fh.as_mut().ok_or(Error::InvalidFileHandle)?;
-        let offset = PAGE_SIZE * page_nr;
-        fh.seek(SeekFrom::Start(offset))?;
-        let node = BTNode::deserialize_from(fh, page_nr)?;
-        Ok(node)
+        Ok(iterator)
     }
 
-    fn store_node(&mut self, node: &BTNode<K, V>) -> Result<()> {
-        if self.fh.is_none() {
-            self.fh = Some(OpenOptions::new().read(true).write(true).create(true).open(db_path(&self.directory))?);
+    // Returns the leaf in page `page_nr` from the buffer, refilling the buffer if it doesn't hold that page.
+    fn load_leaf(&mut self, page_nr: PagePtr) -> Result<Leaf<K, V>> {
+        let page_size = self.btree.page_size;
+        let buffered = self.buffer.len() as u64 / page_size;
+        if page_nr < self.buffer_start || page_nr >= self.buffer_start + buffered {
+            self.buffer = self.btree.store.read_pages(page_nr, self.lookahead)?;
+            self.buffer_start = page_nr;
         }
-        let fh = self.fh.as_mut().ok_or(Error::InvalidFileHandle)?;
-        let offset = PAGE_SIZE * node.page_nr();
-        fh.seek(SeekFrom::Start(offset))?;
-        node.serialize_into(fh)?;
-        // fh.sync_all()?;
-        let pos = fh.seek(SeekFrom::Current(0))?;
-        assert!(pos < offset + PAGE_SIZE, "{:?} - pos = {}, offset+PAGE_SIZE = {}", node, pos, offset + PAGE_SIZE);
-        let padding = offset + PAGE_SIZE - pos;
-        if padding > 0 {
-            fh.write_all(&vec![0u8; padding as usize])?;
-        }
-        Ok(())
+        let offset = ((page_nr - self.buffer_start) * page_size) as usize;
+        let end = (offset + page_size as usize).min(self.buffer.len());
+        Ok(C::decode(&self.buffer[offset..end], page_nr)?.leaf_node())
     }
 }
 
 
-// Make sure the meta data for the BTree is written to disk
-impl<K, V> Drop for BTree<K, V>
+impl<'a, K, V, C> Iterator for BTreeScanIterator<'a, K, V, C>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
-    fn drop(&mut self) {
-        if self.len() > 0 {
-            self.store_meta().unwrap()
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let leaf = self.leaf.as_ref()?;
+            if self.index < leaf.len() {
+                let entry = leaf.entry(self.btree, self.index).unwrap();
+                self.index += 1;
+                return Some(entry);
+            }
+            let next = leaf.next();
+            self.leaf = next.map(|page_nr| self.load_leaf(page_nr).unwrap());
+            self.index = 0;
         }
     }
 }
 
 
-pub struct BTreeIterator<'a, K, V>
+pub struct BTreeIterator<'a, K, V, C = BincodeCodec>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
-    btree: &'a mut BTree<K, V>,
+    btree: &'a BTree<K, V, C>,
     next_node: Option<PagePtr>,
     current_iterator: std::vec::IntoIter<K>,
 }
 
 
-impl<'a, K, V> BTreeIterator<'a, K, V>
+impl<'a, K, V, C> BTreeIterator<'a, K, V, C>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone +  Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
 
-    fn new(btree: &'a mut BTree<K, V>) -> Result<Self> {
-        let current_node = match btree.load_node(0)? {
-            BTNode::Internal(_) => panic!("Programming error: page 0 should not be Interal"),
-            BTNode::Leaf(node) => node,
+    fn new(btree: &'a BTree<K, V, C>) -> Result<Self> {
+        Self::starting_at_bound(btree, Bound::Unbounded)
+    }
+
+    fn starting_at(btree: &'a BTree<K, V, C>, start: &K) -> Result<Self> {
+        Self::starting_at_bound(btree, Bound::Included(start))
+    }
+
+    fn starting_at_bound(btree: &'a BTree<K, V, C>, start: Bound<&K>) -> Result<Self> {
+        let (leaf, i) = match btree.seek(start)? {
+            Some(position) => position,
+            None => return Ok(Self { btree, next_node: None, current_iterator: vec![].into_iter() }),
         };
-        let next_node = current_node.next();
-        let keys: Vec<K> = current_node.keys().collect();
-        let current_iterator = keys.into_iter();
+        let next_node = leaf.next();
+        let mut current_iterator = leaf.keys();
+        if i > 0 {
+            current_iterator.nth(i - 1);
+        }
         Ok(Self { btree, next_node, current_iterator })
     }
 
 }
 
 
-impl<'a, K, V> Iterator for BTreeIterator<'a, K, V>
+impl<'a, K, V, C> Iterator for BTreeIterator<'a, K, V, C>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
     type Item = K;
 
@@ -346,41 +2858,43 @@ where
 }
 
 
-pub struct BTreeValueIterator<'a, K, V>
+pub struct BTreeValueIterator<'a, K, V, C = BincodeCodec>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
-    btree: &'a mut BTree<K, V>,
+    btree: &'a BTree<K, V, C>,
     next_node: Option<PagePtr>,
     current_iterator: std::vec::IntoIter<V>,
 }
 
 
-impl<'a, K, V> BTreeValueIterator<'a, K, V>
+impl<'a, K, V, C> BTreeValueIterator<'a, K, V, C>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
 
-    fn new(btree: &'a mut BTree<K, V>) -> Result<Self> {
-        let current_node = match btree.load_node(0)? {
-            BTNode::Internal(_) => panic!("Programming error: page 0 should not be Interal"),
-            BTNode::Leaf(node) => node,
+    fn new(btree: &'a BTree<K, V, C>) -> Result<Self> {
+        let (leaf, _) = match btree.seek(Bound::Unbounded)? {
+            Some(position) => position,
+            None => return Ok(Self { btree, next_node: None, current_iterator: vec![].into_iter() }),
         };
-        let next_node = current_node.next();
-        let values: Vec<V> = current_node.values().collect();
-        let current_iterator = values.into_iter();
+        let next_node = leaf.next();
+        let current_iterator = leaf.values(btree)?;
         Ok(Self { btree, next_node, current_iterator })
     }
 
 }
 
 
-impl<'a, K, V> Iterator for BTreeValueIterator<'a, K, V>
+impl<'a, K, V, C> Iterator for BTreeValueIterator<'a, K, V, C>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
 {
     type Item = V;
 
@@ -395,7 +2909,7 @@ where
                             BTNode::Leaf(node) => node,
                         };
                         self.next_node = node.next();
-                        self.current_iterator = node.values().collect::<Vec<V>>().into_iter();
+                        self.current_iterator = node.values(self.btree).unwrap();
                         self.current_iterator.next()
                     },
                     None => None
@@ -406,12 +2920,76 @@ where
 }
 
 
+// Iterates over the entries of a tree it owns, see `IntoIterator for BTree`.
+pub struct BTreeIntoIterator<K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: BTree<K, V, C>,
+    next_node: Option<PagePtr>,
+    current_iterator: std::vec::IntoIter<(K, V)>,
+}
+
+
+impl<K, V, C> Iterator for BTreeIntoIterator<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.current_iterator.next() {
+                return Some(entry);
+            }
+            let leaf = self.btree.load_node(self.next_node?).unwrap().leaf_node();
+            self.next_node = leaf.next();
+            self.current_iterator = leaf.into_entries(&self.btree).unwrap();
+        }
+    }
+}
+
+
+// Consumes the tree and returns its key/value pairs in ascending order of the keys. Like the other
+// iterators, this reads the leaves from disk one at a time as it goes, so it doesn't hold more
+// than one leaf in memory. The files of the tree are left as they are.
+impl<K, V, C> IntoIterator for BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    type Item = (K, V);
+    type IntoIter = BTreeIntoIterator<K, V, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let next_node = if self.is_empty() { None } else { Some(self.first_leaf_page_nr) };
+        BTreeIntoIterator { btree: self, next_node, current_iterator: vec![].into_iter() }
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
     use tempfile::TempDir;
 
+    // Like a process that is killed: the tree is never dropped, the OS only releases its lock.
+    fn crash<K, V, C>(mut bt: BTree<K, V, C>)
+    where
+        K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+        V: Debug + Default + Clone + Serialize + DeserializeOwned,
+        C: PageCodec<K, V>,
+    {
+        bt.store.release().unwrap();
+        mem::forget(bt);
+    }
+
     #[test]
     fn test_len() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -424,7 +3002,7 @@ mod tests {
     #[test]
     fn test_root() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
         println!("{:?}", bt);
         let root = bt.root()?;
         assert_eq!(root.page_nr(), 0);
@@ -433,4 +3011,418 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_page_ptr_size() -> Result<()> {
+        check_page_ptr_size::<BincodeCodec>(8)?;
+        check_page_ptr_size::<BincodeCodec<4>>(4)?;
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let result = BTree::<u64, u64, BincodeCodec<2>>::open_with_codec(temp_dir.path(), None, 4096);
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))));
+
+        Ok(())
+    }
+
+    fn check_page_ptr_size<C: PageCodec<u64, u64>>(pointer_size: u64) -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u64, u64, C> = BTree::open_with_codec(temp_dir.path(), None, 4096)?;
+        // A small value takes a tag and the size of a page pointer, a child a page pointer and a count.
+        let slot_size = pointer_size + 8;
+        assert_eq!(bt.page_ptr_size, pointer_size);
+        assert_eq!(bt.leaf_overhead, 18 + pointer_size);
+        assert_eq!(bt.max_leaf_key_count, max_leaf_key_count(4096, 8, 9, bt.leaf_overhead));
+        assert_eq!(bt.max_key_count, max_key_count(4096, 8, slot_size, bt.internal_overhead));
+        for i in 0..1000 {
+            bt.set(i, i)?;
+        }
+        bt.verify()?;
+
+        bt.page_ptr_size = 12 - pointer_size;
+        bt.close()?;
+        drop(bt);
+        let result = BTree::<u64, u64, C>::open_with_codec(temp_dir.path(), None, 4096);
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_size() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let result = BTree::<u128, u128>::open_with_page_size(temp_dir.path(), None, 5000);
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))));
+        let result = BTree::<u128, u128>::open_with_page_size(temp_dir.path(), None, 64);
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))));
+
+        let mut bt: BTree<u128, u128> = BTree::open_with_page_size(temp_dir.path(), None, 16384)?;
+        assert_eq!(bt.leaf_overhead, 26);
+        assert_eq!(bt.internal_overhead, 25);
+        assert_eq!(bt.max_leaf_key_count, max_leaf_key_count(16384, 16, 17, bt.leaf_overhead));
+        assert_eq!(bt.max_key_count, max_key_count(16384, 16, 16, bt.internal_overhead));
+        for i in 0..1000 {
+            bt.set(i, i * 10)?;
+        }
+        drop(bt);
+        assert_eq!(fs::metadata(db_path(temp_dir.path(), ""))?.len() % 16384, 0);
+
+        // The page size is taken from the metadata when reopening.
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        assert_eq!(bt.page_size, 16384);
+        for i in 0..1000 {
+            assert_eq!(bt.get(i)?, Some(i * 10));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta_validation() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        bt.set(1, 10)?;
+        drop(bt);
+        let meta_path = meta_file_path(temp_dir.path(), "");
+        let good_meta = fs::read(&meta_path)?;
+
+        // The magic header is stored as its length (u64) followed by its bytes.
+        let mut meta = good_meta.clone();
+        meta[8..16].copy_from_slice(b"%bogus%%");
+        fs::write(&meta_path, &meta)?;
+        let result = BTree::<u128, u128>::open(temp_dir.path(), None);
+        assert!(matches!(result, Err(Error::InvalidFileFormat { page_nr: None })));
+
+        // The format version directly follows the magic header.
+        let mut meta = good_meta.clone();
+        meta[16..20].copy_from_slice(&99_u32.to_le_bytes());
+        fs::write(&meta_path, &meta)?;
+        let result = BTree::<u128, u128>::open(temp_dir.path(), None);
+        assert!(matches!(result, Err(Error::UnsupportedVersion { found: 99, expected: FORMAT_VERSION })));
+
+        fs::write(&meta_path, &good_meta)?;
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        assert_eq!(bt.get(1)?, Some(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_root() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        for i in 0..100 {
+            bt.set(i, i * 10)?;
+        }
+        bt.remove(0)?;
+        let (root_page_nr, page_size) = (bt.root_page_nr, bt.page_size);
+        let page_count = bt.node_count + bt.emtpy_pages.len() as u64;
+        let deleted_page_nr = *bt.emtpy_pages.iter().next().unwrap();
+        drop(bt);
+        let meta_path = meta_file_path(temp_dir.path(), "");
+        let good_meta = fs::read(&meta_path)?;
+
+        // The root or the first leaf is past the end of the file or a deleted page.
+        for (root, first_leaf) in [(page_count, 0), (u64::MAX, 0), (deleted_page_nr, 0), (root_page_nr, page_count)] {
+            let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+            bt.root_page_nr = root;
+            bt.first_leaf_page_nr = first_leaf;
+            bt.store_meta()?;
+            drop(bt);
+            let result = BTree::<u128, u128>::open(temp_dir.path(), None);
+            assert!(matches!(result, Err(Error::Corrupt(_))), "{:?}", result);
+            fs::write(&meta_path, &good_meta)?;
+        }
+
+        // The root page doesn't hold a node.
+        let db = fs::read(db_path(temp_dir.path(), ""))?;
+        let mut garbage = db.clone();
+        let offset = (root_page_nr * page_size) as usize;
+        garbage[offset..offset + page_size as usize].fill(0xff);
+        fs::write(db_path(temp_dir.path(), ""), &garbage)?;
+        let result = BTree::<u128, u128>::open(temp_dir.path(), None);
+        assert!(matches!(result, Err(Error::Corrupt(_))), "{:?}", result);
+
+        fs::write(db_path(temp_dir.path(), ""), &db)?;
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        bt.verify()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_offset_overflow() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u64, u64> = BTree::open(temp_dir.path(), None)?;
+        bt.set(1, 10)?;
+        // Far past the end of the file, but still a valid offset: nothing is read.
+        assert!(bt.store.read_pages(1 << 30, 1)?.is_empty());
+        let result = bt.store.read_pages(u64::MAX / 2, 1);
+        assert!(matches!(result, Err(Error::Corrupt(_))), "{:?}", result);
+        let result = bt.store.write_raw(u64::MAX / 2, &vec![0; bt.page_size as usize]);
+        assert!(matches!(result, Err(Error::Corrupt(_))), "{:?}", result);
+        assert!(matches!(bt.load_node(u64::MAX / 2), Err(Error::Corrupt(_))));
+        assert_eq!(bt.get(1)?, Some(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_meta_write() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        bt.set(1, 10)?;
+        drop(bt);
+        assert!(!meta_tmp_file_path(temp_dir.path(), "").exists());
+
+        // A crash while storing the metadata leaves a partial temporary file, which isn't renamed.
+        let meta = fs::read(meta_file_path(temp_dir.path(), ""))?;
+        fs::write(meta_tmp_file_path(temp_dir.path(), ""), &meta[..meta.len() / 2])?;
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        assert_eq!(bt.get(1)?, Some(10));
+
+        // The next store replaces it.
+        bt.set(2, 20)?;
+        drop(bt);
+        assert!(!meta_tmp_file_path(temp_dir.path(), "").exists());
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        assert_eq!(bt.get(2)?, Some(20));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recount() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        assert_eq!(bt.recount()?, 0);
+        for i in 0..100 {
+            bt.set(i, i * 10)?;
+        }
+        bt.entry_count = 90;
+        assert!(matches!(bt.verify(), Err(Error::Corrupt(_))));
+        assert_eq!(bt.len(), 90);
+        assert_eq!(bt.count()?, 100);
+        assert_eq!(bt.recount()?, 100);
+        assert_eq!(bt.len(), 100);
+        bt.verify()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_leaf() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        // Three freed pages: the first root leaf takes the last one, the leaves to its right the others.
+        bt.emtpy_pages = FreePages::from(vec![0, 1, 2]);
+        for i in 0..100 {
+            bt.set(i, i * 10)?;
+        }
+        assert_eq!(bt.first_leaf_page_nr, 2);
+        bt.verify()?;
+        let expected: Vec<u128> = (0..100).collect();
+        assert_eq!(bt.keys().collect::<Vec<_>>(), expected);
+        assert_eq!(bt.values().collect::<Vec<_>>(), expected.iter().map(|i| i * 10).collect::<Vec<_>>());
+        assert_eq!(bt.entries().map(|(k, _)| k).collect::<Vec<_>>(), expected);
+        assert_eq!(bt.scan_buffered(4).map(|(k, _)| k).collect::<Vec<_>>(), expected);
+        assert_eq!(bt.recount()?, 100);
+        drop(bt);
+
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        assert_eq!(bt.first_leaf_page_nr, 2);
+        assert_eq!(bt.keys().collect::<Vec<_>>(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_to() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        let mut out = vec![];
+        bt.dump_to(&mut out)?;
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1);
+
+        for i in 0..100 {
+            bt.set(i, i * 10)?;
+        }
+        let mut out = vec![];
+        bt.dump_to(&mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        // Every node once: the root first, the leaves last.
+        assert_eq!(out.lines().count() as u64, bt.node_count);
+        assert!(out.lines().next().unwrap().starts_with("Internal"));
+        assert!(out.lines().last().unwrap().starts_with("Leaf"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = |directory: &Path| BTree::<u128, u128>::open(directory, Some(4));
+        // Where the compaction stops: while building, with the metadata written, after the rename to
+        // `swap`, after moving the db file and after moving the metadata as well.
+        for step in 0..5 {
+            let directory = temp_dir.path().join(step.to_string());
+            let mut bt = open(&directory)?;
+            for i in 0..200 {
+                bt.set(i, i * 10)?;
+            }
+            for i in (0..200).step_by(2) {
+                bt.remove(i)?;
+            }
+            bt.flush()?;
+            let compacted = bt.build_compacted(bt.entries(), bt.len())?;
+            let swap_directory = swap_path(&directory, "");
+            match step {
+                0 => drop(compacted),
+                1 => compacted.store_meta()?,
+                _ => bt.commit_compacted(compacted)?,
+            }
+            if step >= 3 {
+                fs::rename(swap_directory.join("db"), directory.join("db"))?;
+            }
+            if step >= 4 {
+                fs::rename(swap_directory.join("meta"), directory.join("meta"))?;
+            }
+            crash(bt);
+
+            if step >= 2 {
+                // The swap has to be finished, which a tree that is opened read-only can't do.
+                let result = BTree::<u128, u128>::builder(&directory).read_only(true).open();
+                assert_eq!(result.unwrap_err().kind(), ErrorKind::ReadOnly);
+            }
+            let mut bt = open(&directory)?;
+            assert!(!swap_directory.exists());
+            assert_eq!(bt.emtpy_pages.is_empty(), step >= 2, "step {}", step);
+            assert_eq!(bt.len(), 100);
+            assert!(bt.entries().eq((0..100).map(|i| (2 * i + 1, (2 * i + 1) * 10))));
+            bt.verify()?;
+            // The tree that was left behind doesn't get in the way of the next compaction.
+            bt.compact()?;
+            assert!(bt.emtpy_pages.is_empty());
+            crash(bt);
+            let bt = open(&directory)?;
+            assert_eq!(bt.len(), 100);
+            bt.verify()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_progress() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || BTree::<u128, u128>::open(temp_dir.path(), Some(4));
+        let progress = temp_dir.path().join("compact").join("progress");
+        let mut bt = open()?;
+        bt.set_wal(true);
+        for i in 0..400 {
+            bt.set(i, i * 10)?;
+        }
+        bt.retain(|key, _| key % 2 == 1)?;
+        assert!(bt.compact_step(5)?);
+        assert!(progress.exists());
+
+        // A change after the entries copied so far leaves the progress as it is, a change of an entry
+        // that has been copied removes it until the next flush.
+        bt.set(1000, 1)?;
+        assert!(progress.exists());
+        bt.set(1, 1)?;
+        assert!(!progress.exists());
+        crash(bt);
+
+        // The change is in the log, the progress is gone: the compaction starts over.
+        let mut bt = open()?;
+        bt.set_wal(true);
+        assert!(bt.compaction.is_none());
+        assert!(bt.compact_step(5)?);
+        bt.remove(3)?;
+        bt.flush()?;
+        crash(bt);
+
+        let mut bt = open()?;
+        assert!(bt.compaction.is_some());
+        while bt.compact_step(5)? {}
+        bt.verify()?;
+        let value = |key| if key == 1 { 1 } else { key * 10 };
+        let expected = (0..200).map(|i| 2 * i + 1).filter(|&key| key != 3).map(|key| (key, value(key)));
+        assert!(bt.entries().eq(expected.chain([(1000, 1)])));
+        assert!(!progress.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        bt.set_wal(true);
+        for i in 0..100 {
+            bt.set(i, i * 10)?;
+        }
+        bt.remove(50)?;
+        assert_eq!(fs::metadata(wal::wal_path(temp_dir.path(), ""))?.len(), 0);
+        // Every change is on disk as soon as it returns.
+        crash(bt);
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        assert_eq!(bt.len(), 99);
+        bt.verify()?;
+
+        // A crash after writing the log, before any of the pages has been written to the db file.
+        bt.store.begin()?;
+        for i in 100..110 {
+            bt.set_entry(i, i * 10)?;
+        }
+        bt.remove_stored_entry(0)?;
+        let pages = bt.store.take_pending()?;
+        wal::write(temp_dir.path(), "", bt.page_size, &pages, &bincode::serialize(&bt)?)?;
+        crash(bt);
+        let complete_wal = fs::read(wal::wal_path(temp_dir.path(), ""))?;
+
+        // A log without the commit marker is ignored.
+        fs::write(wal::wal_path(temp_dir.path(), ""), &complete_wal[..complete_wal.len() - 1])?;
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        assert!(!wal::wal_path(temp_dir.path(), "").exists());
+        assert_eq!(bt.len(), 99);
+        assert_eq!(bt.get(0)?, Some(0));
+        assert_eq!(bt.get(100)?, None);
+        bt.verify()?;
+        crash(bt);
+
+        // A complete log is replayed.
+        fs::write(wal::wal_path(temp_dir.path(), ""), &complete_wal)?;
+        let mut bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        assert_eq!(bt.len(), 108);
+        assert_eq!(bt.get(0)?, None);
+        assert_eq!(bt.get(50)?, None);
+        assert_eq!(bt.get(109)?, Some(1090));
+        bt.verify()?;
+
+        // A change that fails is undone: none of its pages are written, the metadata is restored.
+        bt.set_wal(true);
+        let db = fs::read(db_path(temp_dir.path(), ""))?;
+        let result: Result<()> = bt.atomically(|bt| {
+            for i in 200..300 {
+                bt.set_entry(i, i * 10)?;
+            }
+            bt.remove_stored_entry(1)?;
+            Err(Error::Corrupt(String::from("failed halfway")))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Corrupt);
+        assert_eq!(fs::read(db_path(temp_dir.path(), ""))?, db);
+        assert_eq!(bt.len(), 108);
+        assert_eq!(bt.get(1)?, Some(10));
+        assert_eq!(bt.get(200)?, None);
+        bt.verify()?;
+        bt.set(200, 2000)?;
+        crash(bt);
+        let bt: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+        assert_eq!(bt.len(), 109);
+        assert_eq!(bt.get(200)?, Some(2000));
+        bt.verify()?;
+
+        Ok(())
+    }
+
 }
@@ -0,0 +1,111 @@
+use crate::codec::PageCodec;
+use crate::error::Result;
+use crate::{BTree, BTreeRangeIterator};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{btree_map, BTreeMap},
+    fmt::Debug,
+    iter,
+};
+
+
+// The operations of a sorted map, so that code can be written for both a `BTree` and a
+// `std::collections::BTreeMap`. The methods are named after the ones of `BTreeMap`: `insert` is
+// `BTree::set`. Lookups take the key by reference like `BTreeMap` does, a `BTree` clones it.
+pub trait PersistentMap<K, V> {
+    type Iter<'a>: Iterator<Item = (K, V)>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Result<Option<V>>;
+
+    // Returns the value that was replaced, if any.
+    fn insert(&mut self, key: K, value: V) -> Result<Option<V>>;
+
+    fn remove(&mut self, key: &K) -> Result<Option<V>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &K) -> Result<bool>;
+
+    // The entries in ascending order of the keys.
+    fn iter(&self) -> Self::Iter<'_>;
+}
+
+
+impl<K, V, C> PersistentMap<K, V> for BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    type Iter<'a>
+        = BTreeRangeIterator<'a, K, V, C>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Result<Option<V>> {
+        BTree::get(self, key.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        self.set(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        BTree::remove(self, key.clone())
+    }
+
+    fn len(&self) -> usize {
+        BTree::len(self)
+    }
+
+    fn contains_key(&self, key: &K) -> Result<bool> {
+        BTree::contains_key(self, key.clone())
+    }
+
+    fn iter(&self) -> Self::Iter<'_> {
+        self.entries()
+    }
+}
+
+
+// Never fails.
+impl<K, V> PersistentMap<K, V> for BTreeMap<K, V>
+where
+    K: Clone + Ord,
+    V: Clone,
+{
+    type Iter<'a>
+        = iter::Map<btree_map::Iter<'a, K, V>, fn((&'a K, &'a V)) -> (K, V)>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Result<Option<V>> {
+        Ok(BTreeMap::get(self, key).cloned())
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        Ok(BTreeMap::insert(self, key, value))
+    }
+
+    fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        Ok(BTreeMap::remove(self, key))
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn contains_key(&self, key: &K) -> Result<bool> {
+        Ok(BTreeMap::contains_key(self, key))
+    }
+
+    fn iter(&self) -> Self::Iter<'_> {
+        BTreeMap::iter(self).map(|(key, value)| (key.clone(), value.clone()))
+    }
+}
@@ -2,20 +2,122 @@
 // #![allow(unused_variables)]
 // #![allow(unused_imports)]
 
+use crate::codec::PageCodec;
 use crate::error::{Error, Result};
-use crate::BTree;
+use crate::{largest_fitting, BTree};
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt::Debug, fs::File, io::Read, mem};
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    fmt::Debug,
+    io::{Read, Write},
+    mem,
+};
 
 
 pub type PagePtr = u64;
 
 
+// The size of a page number as it's stored in a page when it isn't given: the child pointers of
+// internal nodes, the next leaf and the overflow pages. A tree records the size it was created with
+// in its metadata, see `PageCodec::PAGE_PTR_SIZE`.
+pub(crate) const DEFAULT_PAGE_PTR_SIZE: u64 = 8;
+
+
+// Page numbers are stored in `page_ptr_size` bytes, which is either 4 or 8. Storing them in 4 bytes
+// fits more keys in a node when they're small, but limits a tree to 2^32 pages.
+fn write_ptr<W: Write>(mut fh: W, page_nr: PagePtr, page_ptr_size: u64) -> Result<()> {
+    match page_ptr_size {
+        4 => bincode::serialize_into(&mut fh, &to_u32(page_nr)?)?,
+        _ => bincode::serialize_into(&mut fh, &page_nr)?,
+    }
+    Ok(())
+}
+
+
+fn read_ptr<R: Read>(fh: &mut R, page_ptr_size: u64) -> Result<PagePtr> {
+    Ok(match page_ptr_size {
+        4 => bincode::deserialize_from::<_, u32>(fh)? as PagePtr,
+        _ => bincode::deserialize_from(fh)?,
+    })
+}
+
+
+// Encoded like an `Option<u32>` or `Option<u64>`.
+fn write_opt_ptr<W: Write>(mut fh: W, page_nr: Option<PagePtr>, page_ptr_size: u64) -> Result<()> {
+    bincode::serialize_into(&mut fh, &(page_nr.is_some() as u8))?;
+    match page_nr {
+        Some(page_nr) => write_ptr(fh, page_nr, page_ptr_size),
+        None => Ok(()),
+    }
+}
+
+
+fn read_opt_ptr<R: Read>(fh: &mut R, page_ptr_size: u64) -> Result<Option<PagePtr>> {
+    Ok(match page_ptr_size {
+        4 => bincode::deserialize_from::<_, Option<u32>>(fh)?.map(|page_nr| page_nr as PagePtr),
+        _ => bincode::deserialize_from(fh)?,
+    })
+}
+
+
+// Encoded like a `Vec<u32>` or `Vec<u64>`.
+fn write_ptrs<W: Write>(mut fh: W, page_nrs: &[PagePtr], page_ptr_size: u64) -> Result<()> {
+    match page_ptr_size {
+        4 => {
+            let page_nrs = page_nrs.iter().map(|page_nr| to_u32(*page_nr)).collect::<Result<Vec<_>>>()?;
+            bincode::serialize_into(&mut fh, &page_nrs)?
+        }
+        _ => bincode::serialize_into(&mut fh, page_nrs)?,
+    }
+    Ok(())
+}
+
+
+fn read_ptrs<R: Read>(fh: &mut R, page_ptr_size: u64) -> Result<Vec<PagePtr>> {
+    Ok(match page_ptr_size {
+        4 => bincode::deserialize_from::<_, Vec<u32>>(fh)?.into_iter().map(|page_nr| page_nr as PagePtr).collect(),
+        _ => bincode::deserialize_from(fh)?,
+    })
+}
+
+
+fn to_u32(page_nr: PagePtr) -> Result<u32> {
+    u32::try_from(page_nr).map_err(|_| {
+        Error::InvalidConfiguration(format!("page {} doesn't fit in a 32 bit page pointer", page_nr))
+    })
+}
+
+
+// The (split_key, new_page_nr, entry_count) triple returned when a node has been split in two, where
+// `entry_count` is the number of entries in the new node and its subtree.
+type Split<K> = Option<(K, PagePtr, u64)>;
+
+
+// The triples of the new nodes when a node that received several entries at once has been split
+// into more than two, in ascending key order.
+type Splits<K> = Vec<(K, PagePtr, u64)>;
+
+
+// The (removed_entry, deleted_page_nr) pair returned by `remove`, where `deleted_page_nr` is the
+// page of the child that was merged into a sibling.
+type Removal<K, V> = (Option<(K, V)>, Option<PagePtr>);
+
+
+// A value in a leaf. Values that serialize to more than `BTree::inline_value_size` bytes are moved
+// to a chain of overflow pages when the leaf is stored, the leaf only keeps the first page number.
+#[derive(Debug, Clone)]
+enum Slot<V> {
+    Inline(V),
+    Overflow(PagePtr),
+}
+
+
 #[derive(Debug)]
 pub struct Leaf<K, V> {
     page_nr: PagePtr,
     keys: Vec<K>,
-    entries: Vec<V>,
+    entries: Vec<Slot<V>>,
     next: Option<PagePtr>,
 }
 
@@ -26,108 +128,414 @@ where
     V: Debug + Default + Clone + Serialize + DeserializeOwned,
 {
 
-    // Returns the associated value for `key` as `Some(value)` or `None` if it's not present.
+    // Returns the index of the first occurrence of `key`, or `None` if it's not present.
     //
-    fn get(&self, key: &K) -> Option<V> {
-        match self.keys.binary_search(key) {
-            Ok(i) => Some(self.entries[i].clone()),
-            Err(_) => None,
+    pub(crate) fn position(&self, key: &K) -> Option<usize> {
+        let i = self.lower_bound(key);
+        match self.keys.get(i) {
+            Some(k) if k == key => Some(i),
+            _ => None,
         }
     }
 
+    // Returns the entry with the largest key that is smaller than or equal to `key`,
+    // or `None` if all keys in this leaf are larger than `key`.
+    //
+    pub(crate) fn floor<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>, key: &K) -> Result<Option<(K, V)>> {
+        match self.upper_bound(key) {
+            0 => Ok(None),
+            i => Ok(Some(self.entry(btree, i - 1)?)),
+        }
+    }
+
+    // Returns the entry with the smallest key that is larger than or equal to `key`,
+    // or `None` if all keys in this leaf are smaller than `key`.
+    //
+    pub(crate) fn ceil<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>, key: &K) -> Result<Option<(K, V)>> {
+        match self.lower_bound(key) {
+            i if i < self.keys.len() => Ok(Some(self.entry(btree, i)?)),
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the index of the first key that is larger than or equal to `key`.
+    // In a multimap, keys can occur more than once, so this doesn't use a binary search.
+    pub(crate) fn lower_bound(&self, key: &K) -> usize {
+        self.keys.partition_point(|k| k < key)
+    }
+
+    // Returns the index of the first key that is larger than `key`.
+    pub(crate) fn upper_bound(&self, key: &K) -> usize {
+        self.keys.partition_point(|k| k <= key)
+    }
+
+    pub(crate) fn page_nr(&self) -> PagePtr {
+        self.page_nr
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub(crate) fn key(&self, i: usize) -> &K {
+        &self.keys[i]
+    }
+
+    pub(crate) fn first<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<Option<(K, V)>> {
+        match self.keys.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(self.entry(btree, 0)?)),
+        }
+    }
+
+    pub(crate) fn last<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<Option<(K, V)>> {
+        match self.keys.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(self.entry(btree, self.keys.len() - 1)?)),
+        }
+    }
+
+    pub(crate) fn entry<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>, i: usize) -> Result<(K, V)> {
+        Ok((self.keys[i].clone(), self.value(btree, i)?))
+    }
+
+    // Returns the i-th value, reading it from its overflow pages if needed.
+    pub(crate) fn value<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>, i: usize) -> Result<V> {
+        match &self.entries[i] {
+            Slot::Inline(value) => Ok(value.clone()),
+            Slot::Overflow(page_nr) => btree.load_overflow(*page_nr),
+        }
+    }
+
+    // Returns the value of a slot that is taken out of the tree, freeing its overflow pages.
+    fn take_value<C: PageCodec<K, V>>(btree: &mut BTree<K, V, C>, slot: Slot<V>) -> Result<V> {
+        match slot {
+            Slot::Inline(value) => Ok(value),
+            Slot::Overflow(page_nr) => {
+                let value = btree.load_overflow(page_nr)?;
+                btree.free_overflow(page_nr)?;
+                Ok(value)
+            }
+        }
+    }
+
+    // Moves the values that are too large to be stored inline to overflow pages.
+    pub(crate) fn spill<C: PageCodec<K, V>>(&mut self, btree: &mut BTree<K, V, C>) -> Result<()> {
+        for slot in self.entries.iter_mut() {
+            if let Slot::Inline(value) = slot {
+                if bincode::serialized_size(value)? > btree.inline_value_size {
+                    *slot = Slot::Overflow(btree.store_overflow(&bincode::serialize(value)?)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Moves the largest value that is stored inline to overflow pages, and returns whether there was
+    // one. See `BTree::store_node`.
+    pub(crate) fn spill_largest<C: PageCodec<K, V>>(&mut self, btree: &mut BTree<K, V, C>) -> Result<bool> {
+        let mut largest = None;
+        for (i, slot) in self.entries.iter().enumerate() {
+            if let Slot::Inline(value) = slot {
+                let size = bincode::serialized_size(value)?;
+                if largest.is_none_or(|(_, largest_size)| size > largest_size) {
+                    largest = Some((i, size));
+                }
+            }
+        }
+        let i = match largest {
+            Some((i, _)) => i,
+            None => return Ok(false),
+        };
+        if let Slot::Inline(value) = &self.entries[i] {
+            self.entries[i] = Slot::Overflow(btree.store_overflow(&bincode::serialize(value)?)?);
+        }
+        Ok(true)
+    }
+
+    // Returns whether the first `n` entries take at most `size` bytes when they're encoded as a leaf of
+    // their own. The values that `spill` moves to overflow pages count as page numbers.
+    pub(crate) fn prefix_fits<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>, n: usize, size: u64) -> Result<bool> {
+        let mut entries = self.entries[..n].to_vec();
+        for slot in entries.iter_mut() {
+            if let Slot::Inline(value) = slot {
+                if bincode::serialized_size(value)? > btree.inline_value_size {
+                    *slot = Slot::Overflow(self.page_nr);
+                }
+            }
+        }
+        let leaf = Leaf { page_nr: self.page_nr, keys: self.keys[..n].to_vec(), entries, next: self.next };
+        Ok(C::encode(&BTNode::Leaf(leaf))?.len() as u64 <= size)
+    }
+
+    // Returns the first page of every overflow chain referenced by this leaf.
+    pub(crate) fn overflow_pages(&self) -> impl Iterator<Item = PagePtr> + '_ {
+        self.entries.iter().filter_map(|slot| match slot {
+            Slot::Inline(_) => None,
+            Slot::Overflow(page_nr) => Some(*page_nr),
+        })
+    }
+
+    // Returns the keys that don't have the same values in this leaf and in `other`, in ascending
+    // order. A key that is in only one of them has changed as well. A value in overflow pages has
+    // changed if its first page is a different one, or if that page is in `rewritten`.
+    pub(crate) fn changed_keys(&self, other: &Leaf<K, V>, rewritten: &BTreeSet<PagePtr>) -> Result<Vec<K>> {
+        let mut changed = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < self.keys.len() || j < other.keys.len() {
+            let key = match (self.keys.get(i), other.keys.get(j)) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => a.or(b).unwrap(),
+            };
+            let ours = self.keys[i..].iter().take_while(|k| *k == key).count();
+            let theirs = other.keys[j..].iter().take_while(|k| *k == key).count();
+            let mut same = ours == theirs;
+            for n in 0..ours.min(theirs) {
+                same = same && Self::same_slot(&self.entries[i + n], &other.entries[j + n], rewritten)?;
+            }
+            if !same {
+                changed.push(key.clone());
+            }
+            i += ours;
+            j += theirs;
+        }
+        Ok(changed)
+    }
+
+    fn same_slot(a: &Slot<V>, b: &Slot<V>, rewritten: &BTreeSet<PagePtr>) -> Result<bool> {
+        Ok(match (a, b) {
+            (Slot::Inline(a), Slot::Inline(b)) => bincode::serialize(a)? == bincode::serialize(b)?,
+            (Slot::Overflow(a), Slot::Overflow(b)) => a == b && !rewritten.contains(a),
+            _ => false,
+        })
+    }
+
     // Inserts a `key`/`value` pair
     //
     // This method returns different kinds of information depending on the situation:
     //   - If the key is already present, the value will be overwritten and the
     //     old value will be returned as `Ok((None, Some(old_value)))`.
+    //     Without `overwrite`, nothing changes and the stored value is returned instead.
+    //     In a multimap, the pair is inserted after the entries with the same key instead.
     //   - If the key is new, the key/value pair is inserted. Now we have 2 cases to consider:
-    //     1. The node is not yet full: nothing more to do, return `Ok((None, None))`.
-    //     2. The node is full: it needs to be split up, return `Ok((Some((split_key, new_page_nr)), None))`.
+    //     1. The node still fits in a page: nothing more to do, return `Ok((None, None))`.
+    //     2. The node is overfull: it needs to be split up, return
+    //        `Ok((Some((split_key, new_page_nr, entry_count)), None))`.
     //
-    fn set(mut self, btree: &mut BTree<K, V>, key: K, value: V) -> Result<(Option<(K, PagePtr)>, Option<V>)>
+    // The value is made by `value`, which is only called when it's stored.
+    //
+    fn set<C: PageCodec<K, V>, F: FnOnce() -> V>(
+        mut self,
+        btree: &mut BTree<K, V, C>,
+        key: K,
+        value: F,
+        overwrite: bool,
+    ) -> Result<(Split<K>, Option<V>)>
     where
         V: Debug + Clone + Serialize + DeserializeOwned,
     {
-        match self.keys.binary_search(&key) {
+        let found = match btree.multimap {
+            true => Err(self.upper_bound(&key)),
+            false => self.keys.binary_search(&key),
+        };
+        match found {
+            Ok(i) if !overwrite => Ok((None, Some(self.value(btree, i)?))),
             Ok(i) => {
                 // exact match -> overwrite and return original value
-                let original_value = mem::replace(&mut self.entries[i], value);
-                btree.store_node(&BTNode::Leaf(self))?;
-                Ok((None, Some(original_value)))
-            }
-            Err(i) => match self.is_full(btree.max_key_count) {
-                true => {
-                    let (split_key, mut new_leaf) = self.split(btree.next_page_nr(), btree.split_at);
-                    let split_page_nr = new_leaf.page_nr;
-                    match i < btree.split_at {
-                        true => self.insert(i, key, value),
-                        false => new_leaf.insert(i - btree.split_at, key, value),
-                    }
-                    btree.store_node(&BTNode::Leaf(self))?;
-                    btree.store_node(&BTNode::Leaf(new_leaf))?;
-                    Ok((Some((split_key, split_page_nr)), None))
+                let original_slot = mem::replace(&mut self.entries[i], Slot::Inline(value()));
+                let original_value = Self::take_value(btree, original_slot)?;
+                // With a codec that compresses, the new value can take more room.
+                let split = self.store_or_split(btree)?;
+                Ok((split, Some(original_value)))
+            }
+            Err(i) => {
+                self.insert(i, key, value());
+                Ok((self.store_or_split(btree)?, None))
+            }
+        }
+    }
+
+    // Stores the leaf, or splits it in two if it's overfull.
+    fn store_or_split<C: PageCodec<K, V>>(mut self, btree: &mut BTree<K, V, C>) -> Result<Split<K>> {
+        if !self.is_overfull(btree)? {
+            btree.store_node(BTNode::Leaf(self))?;
+            return Ok(None);
+        }
+        let split_at = self.split_point(btree)?;
+        let (split_key, new_leaf) = self.split(btree.next_page_nr(), split_at);
+        let split_page_nr = new_leaf.page_nr;
+        let split_count = new_leaf.keys.len() as u64;
+        btree.store_node(BTNode::Leaf(self))?;
+        btree.store_node(BTNode::Leaf(new_leaf))?;
+        Ok(Some((split_key, split_page_nr, split_count)))
+    }
+
+    // Inserts `entries`, which are sorted by key and all belong in this leaf, like `set` does one by
+    // one, and returns the new nodes and the number of entries that were added (not overwritten).
+    // The leaf is stored once, or split into as many nodes as it takes.
+    fn set_many<C>(mut self, btree: &mut BTree<K, V, C>, entries: Vec<(K, V)>) -> Result<(Splits<K>, u64)>
+    where
+        C: PageCodec<K, V>,
+    {
+        let mut stored = mem::take(&mut self.keys).into_iter().zip(mem::take(&mut self.entries)).peekable();
+        let mut added = 0;
+        for (key, value) in entries {
+            while let Some((k, _)) = stored.peek() {
+                if k > &key || (k == &key && !btree.multimap) {
+                    break;
                 }
-                false => {
-                    self.insert(i, key, value);
-                    btree.store_node(&BTNode::Leaf(self))?;
-                    Ok((None, None))
+                let (k, slot) = stored.next().unwrap();
+                self.keys.push(k);
+                self.entries.push(slot);
+            }
+            let original_slot = match stored.peek() {
+                Some((k, _)) if k == &key && !btree.multimap => {
+                    let (k, slot) = stored.next().unwrap();
+                    self.keys.push(k);
+                    self.entries.push(Slot::Inline(value));
+                    Some(slot)
+                }
+                // the same key earlier in the batch
+                _ if self.keys.last() == Some(&key) && !btree.multimap => {
+                    Some(mem::replace(self.entries.last_mut().unwrap(), Slot::Inline(value)))
+                }
+                _ => {
+                    self.insert(self.keys.len(), key, value);
+                    added += 1;
+                    None
                 }
-            },
+            };
+            if let Some(Slot::Overflow(page_nr)) = original_slot {
+                btree.free_overflow(page_nr)?;
+            }
+        }
+        for (k, slot) in stored {
+            self.keys.push(k);
+            self.entries.push(slot);
         }
+        Ok((BTNode::Leaf(self).store_split(btree)?, added))
     }
 
-    fn remove(
+    // Applies `f` to the i-th value and stores the leaf.
+    //
+    // Unlike `set`, this doesn't split the leaf: a value that no longer fits inline is moved to
+    // overflow pages when the leaf is stored. With a codec that compresses, a leaf that no longer
+    // fits is returned instead, see `store_in_place`.
+    //
+    pub(crate) fn update<F, C>(mut self, btree: &mut BTree<K, V, C>, i: usize, f: F) -> Result<Option<Self>>
+    where
+        C: PageCodec<K, V>,
+        F: FnOnce(&mut V),
+    {
+        match &mut self.entries[i] {
+            Slot::Inline(value) => f(value),
+            Slot::Overflow(page_nr) => {
+                let page_nr = *page_nr;
+                let mut value = btree.load_overflow(page_nr)?;
+                btree.free_overflow(page_nr)?;
+                f(&mut value);
+                self.entries[i] = Slot::Inline(value);
+            }
+        }
+        self.store_in_place(btree)
+    }
+
+    // Replaces every value with `f(key, value)` and stores the leaf once. Like `update`, the values
+    // that no longer fit inline are moved to overflow pages when the leaf is stored.
+    pub(crate) fn map_values<F, C>(mut self, btree: &mut BTree<K, V, C>, f: &mut F) -> Result<Option<Self>>
+    where
+        F: FnMut(&K, V) -> V,
+        C: PageCodec<K, V>,
+    {
+        for (key, slot) in self.keys.iter().zip(self.entries.iter_mut()) {
+            let value = Self::take_value(btree, mem::replace(slot, Slot::Overflow(0)))?;
+            *slot = Slot::Inline(f(key, value));
+        }
+        self.store_in_place(btree)
+    }
+
+    // Stores the leaf after its values changed. With a codec that compresses, the new values may no
+    // longer fit in the leaf: then it isn't stored but returned, for `BTree::store_overfull_leaf`.
+    fn store_in_place<C: PageCodec<K, V>>(self, btree: &mut BTree<K, V, C>) -> Result<Option<Self>> {
+        if self.is_overfull(btree)? {
+            return Ok(Some(self));
+        }
+        btree.store_node(BTNode::Leaf(self))?;
+        Ok(None)
+    }
+
+    // Removes the entries with a key smaller than `key` and returns how many, without storing the leaf.
+    fn truncate_below<C: PageCodec<K, V>>(&mut self, btree: &mut BTree<K, V, C>, key: &K) -> Result<u64> {
+        let n = self.lower_bound(key);
+        self.keys.drain(..n);
+        for slot in self.entries.drain(..n) {
+            if let Slot::Overflow(page_nr) = slot {
+                btree.free_overflow(page_nr)?;
+            }
+        }
+        Ok(n as u64)
+    }
+
+    fn remove<C: PageCodec<K, V>>(
         mut self,
-        btree: &mut BTree<K, V>,
+        btree: &mut BTree<K, V, C>,
         key: K,
-        parent: Option<&mut Internal<K>>,
+        mut parent: Option<&mut Internal<K>>,
         path_info: Option<&ChildNodeInfo>,
-    ) -> Result<(Option<V>, Option<PagePtr>)> {
-        match self.keys.binary_search(&key) {
-            Err(_) => Ok((None, None)),
-            Ok(i) => {
-                self.keys.remove(i);
-                let original_value = Some(self.entries.remove(i));
+    ) -> Result<Removal<K, V>> {
+        match self.position(&key) {
+            None => Ok((None, None)),
+            Some(i) => {
+                let original_key = self.keys.remove(i);
+                let original_value = Some((original_key, Self::take_value(btree, self.entries.remove(i))?));
+                if let (Some(parent), Some(path_info)) = (parent.as_mut(), path_info) {
+                    parent.counts[path_info.index] -= 1;
+                }
                 let mut deleted_page = None;
-                if self.keys.len() < btree.split_at as usize && parent.is_some() {
-                    // else this is the root node => nothing more to dead_code
-                    let parent = parent.unwrap();
+                // if there is no parent, this is the root node => nothing more to do
+                let min_key_count = btree.min_leaf_key_count();
+                if let Some(parent) = parent.filter(|_| self.keys.len() < min_key_count) {
                     let path_info = path_info.unwrap();
                     let mut done = false;
-                    if path_info.lsibling.is_some() {
+                    if let Some(lsibling) = path_info.lsibling {
                         // try to transfer a key/value pair from left sibling
-                        let mut node = btree.load_node(path_info.lsibling.unwrap())?.leaf_node();
-                        if node.keys.len() > btree.split_at as usize {
+                        let mut node = btree.load_node(lsibling)?.leaf_node();
+                        if node.keys.len() > min_key_count {
                             let k = node.keys.pop().unwrap();
                             let v = node.entries.pop().unwrap();
                             self.keys.insert(0, k.clone());
                             self.entries.insert(0, v);
                             parent.keys[path_info.rparent.unwrap()] = k;
-                            btree.store_node(&BTNode::Leaf(node))?;
+                            parent.counts[path_info.index - 1] -= 1;
+                            parent.counts[path_info.index] += 1;
+                            btree.store_node(BTNode::Leaf(node))?;
                             done = true;
                         }
                     }
-                    if !done && path_info.rsibling.is_some() {
+                    if let Some(rsibling) = path_info.rsibling.filter(|_| !done) {
                         // try to transfer a key/value pair from right sibling
-                        let mut node = btree.load_node(path_info.rsibling.unwrap())?.leaf_node();
-                        if node.keys.len() > btree.split_at as usize {
+                        let mut node = btree.load_node(rsibling)?.leaf_node();
+                        if node.keys.len() > min_key_count {
                             let k = node.keys.remove(0);
                             let v = node.entries.remove(0);
                             self.keys.push(k);
                             self.entries.push(v);
                             parent.keys[path_info.lparent.unwrap()] = node.keys[0].clone();
-                            btree.store_node(&BTNode::Leaf(node))?;
+                            parent.counts[path_info.index + 1] -= 1;
+                            parent.counts[path_info.index] += 1;
+                            btree.store_node(BTNode::Leaf(node))?;
                             done = true;
                         }
                     }
                     if !done {
-                        if path_info.lsibling.is_some() {
+                        if let Some(lsibling) = path_info.lsibling {
                             // merge this node into the left sibling
-                            let mut node = btree.load_node(path_info.lsibling.unwrap())?.leaf_node();
+                            let mut node = btree.load_node(lsibling)?.leaf_node();
                             node.keys.extend(self.keys);
                             node.entries.extend(self.entries);
                             node.next = self.next;
-                            btree.on_page_deleted(self.page_nr);
+                            parent.counts[path_info.index - 1] += parent.counts[path_info.index];
+                            btree.on_page_deleted(self.page_nr)?;
                             deleted_page = Some(self.page_nr);
                             self = node;
                         } else {
@@ -138,12 +546,13 @@ where
                             self.keys.extend(right_node.keys);
                             self.entries.extend(right_node.entries);
                             self.next = right_node.next;
-                            btree.on_page_deleted(right_node.page_nr);
+                            parent.counts[path_info.index] += parent.counts[path_info.index + 1];
+                            btree.on_page_deleted(right_node.page_nr)?;
                             deleted_page = Some(right_node.page_nr);
                         }
                     }
                 }
-                btree.store_node(&BTNode::Leaf(self))?;
+                btree.store_node(BTNode::Leaf(self))?;
                 Ok((original_value, deleted_page))
             }
         }
@@ -151,44 +560,92 @@ where
 
     fn new(page_nr: u64, keys: &[K], entries: &[V], next: Option<PagePtr>) -> Self {
         // let padding = (size - 2 * order * (mem::size_of::<K>() + mem::size_of::<V>()) - mem::size_of::<PagePtr>()) as u64;
-        Leaf { page_nr, keys: keys.to_vec(), entries: entries.to_vec(), next }
+        let entries = entries.iter().cloned().map(Slot::Inline).collect();
+        Leaf { page_nr, keys: keys.to_vec(), entries, next }
+    }
+
+    // A leaf may temporarily hold too many entries, until it's split: more than `leaf_capacity`, or
+    // with a codec that compresses, more than fit in a page uncompressed in an encoding that doesn't
+    // fit. A leaf that fits uncompressed isn't encoded to check.
+    fn is_overfull<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<bool> {
+        if self.keys.len() > btree.leaf_capacity() as usize {
+            return Ok(true);
+        }
+        if C::MAX_COMPRESSION_RATIO == 1 || self.keys.len() <= btree.max_leaf_key_count as usize {
+            return Ok(false);
+        }
+        Ok(!self.prefix_fits(btree, self.keys.len(), btree.page_size)?)
     }
 
-    fn is_full(&self, max_key_count: u64) -> bool {
-        self.keys.len() >= max_key_count as usize
+    // Returns how many entries an overfull leaf keeps when it's split. Without compression, that's
+    // `leaf_split_at`. With compression, it's as many as fit in the same fraction of a page, so that
+    // both leaves keep at least `min_leaf_key_count` entries.
+    fn split_point<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<usize> {
+        if C::MAX_COMPRESSION_RATIO == 1 {
+            return Ok(btree.leaf_split_at);
+        }
+        let min_key_count = btree.min_leaf_key_count();
+        let max = (self.keys.len() - min_key_count).min(btree.leaf_split_at * C::MAX_COMPRESSION_RATIO as usize);
+        let size = btree.page_size * btree.leaf_split_at as u64 / btree.max_leaf_key_count;
+        largest_fitting(min_key_count, max, |n| self.prefix_fits(btree, n, size))
     }
 
     // keys and entries have same length
-    // [k0, k1, k2, k3] -> [k0, k1] | [k2, k3]  split_key == k2
-    // [v0, v1, v2, v3] -> [v0, v1] | [v2, v3]
+    // [k0, k1, k2, k3, k4] -> [k0, k1] | [k2, k3, k4]  split_key == k2
+    // [v0, v1, v2, v3, v4] -> [v0, v1] | [v2, v3, v4]
     fn split(&mut self, page_nr: u64, split_at: usize) -> (K, Self) {
         let split_key = self.keys[split_at].clone();
-        let node = Self::new(page_nr, &self.keys[split_at..], &self.entries[split_at..], self.next);
+        let node = Leaf {
+            page_nr,
+            keys: self.keys.split_off(split_at),
+            entries: self.entries.split_off(split_at),
+            next: self.next,
+        };
         self.next = Some(page_nr);
-        self.keys.drain(split_at..);
-        self.entries.drain(split_at..);
         (split_key, node)
     }
 
     fn insert(&mut self, i: usize, key: K, value: V) {
         self.keys.insert(i, key);
-        self.entries.insert(i, value);
+        self.entries.insert(i, Slot::Inline(value));
     }
 
-    fn serialize_into(&self, fh: &File) -> Result<()> {
-        bincode::serialize_into(fh, &self.keys)?;
-        bincode::serialize_into(fh, &self.entries)?;
-        bincode::serialize_into(fh, &self.next)?;
-        Ok(())
+    // Every value is preceded by a tag: 0 for an inline value, 1 for the page number of an overflow chain.
+    fn serialize_into<W: Write>(&self, mut fh: W, page_ptr_size: u64) -> Result<()> {
+        bincode::serialize_into(&mut fh, &self.keys)?;
+        bincode::serialize_into(&mut fh, &(self.entries.len() as u64))?;
+        for slot in &self.entries {
+            match slot {
+                Slot::Inline(value) => {
+                    bincode::serialize_into(&mut fh, &0_u8)?;
+                    bincode::serialize_into(&mut fh, value)?;
+                }
+                Slot::Overflow(page_nr) => {
+                    bincode::serialize_into(&mut fh, &1_u8)?;
+                    write_ptr(&mut fh, *page_nr, page_ptr_size)?;
+                }
+            }
+        }
+        write_opt_ptr(fh, self.next, page_ptr_size)
     }
 
-    fn deserialize_from(fh: &File, page_nr: u64) -> Result<Self> {
-        let node = Self {
-            page_nr,
-            keys: bincode::deserialize_from(fh)?,
-            entries: bincode::deserialize_from(fh)?,
-            next: bincode::deserialize_from(fh)?,
-        };
+    fn deserialize_from<R: Read>(fh: &mut R, page_nr: u64, page_ptr_size: u64) -> Result<Self> {
+        let keys: Vec<K> = bincode::deserialize_from(&mut *fh)?;
+        let len: u64 = bincode::deserialize_from(&mut *fh)?;
+        if len != keys.len() as u64 {
+            return Err(Error::InvalidFileFormat { page_nr: Some(page_nr) });
+        }
+        let mut entries = Vec::with_capacity(keys.len());
+        for _ in 0..len {
+            let tag: u8 = bincode::deserialize_from(&mut *fh)?;
+            entries.push(match tag {
+                0 => Slot::Inline(bincode::deserialize_from(&mut *fh)?),
+                1 => Slot::Overflow(read_ptr(fh, page_ptr_size)?),
+                _ => return Err(Error::InvalidFileFormat { page_nr: Some(page_nr) }),
+            });
+        }
+        let next = read_opt_ptr(fh, page_ptr_size)?;
+        let node = Self { page_nr, keys, entries, next };
         Ok(node)
     }
 
@@ -196,8 +653,42 @@ where
         self.keys.into_iter()
     }
 
-    pub fn values(self) -> std::vec::IntoIter<V> {
-        self.entries.into_iter()
+    pub fn values<C: PageCodec<K, V>>(self, btree: &BTree<K, V, C>) -> Result<std::vec::IntoIter<V>> {
+        let values = (0..self.entries.len()).map(|i| self.value(btree, i)).collect::<Result<Vec<V>>>()?;
+        Ok(values.into_iter())
+    }
+
+    // Returns the key/value pairs of this leaf, reading the values stored in overflow pages.
+    pub(crate) fn into_entries<C: PageCodec<K, V>>(self, btree: &BTree<K, V, C>) -> Result<std::vec::IntoIter<(K, V)>> {
+        let values = (0..self.entries.len()).map(|i| self.value(btree, i)).collect::<Result<Vec<V>>>()?;
+        Ok(self.keys.into_iter().zip(values).collect::<Vec<_>>().into_iter())
+    }
+
+    // Returns the key/value pairs of this leaf like `into_entries`, without the pairs whose value is
+    // stored in one of the pages `discarded`. See `BTree::open_with_recovery`.
+    pub(crate) fn salvage_entries<C: PageCodec<K, V>>(
+        self,
+        btree: &BTree<K, V, C>,
+        discarded: &[PagePtr],
+    ) -> Result<Vec<(K, V)>> {
+        let mut entries = Vec::with_capacity(self.keys.len());
+        'entries: for (key, slot) in self.keys.into_iter().zip(self.entries) {
+            let value = match slot {
+                Slot::Inline(value) => value,
+                Slot::Overflow(first_page_nr) => {
+                    let mut page_nr = Some(first_page_nr);
+                    while let Some(overflow_page_nr) = page_nr {
+                        if discarded.contains(&overflow_page_nr) {
+                            continue 'entries;
+                        }
+                        page_nr = btree.load_overflow_page(overflow_page_nr)?.next;
+                    }
+                    btree.load_overflow(first_page_nr)?
+                }
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
     }
 
     pub fn next(&self) -> Option<PagePtr> {
@@ -206,27 +697,20 @@ where
 }
 
 
-// impl<K, V> IntoIterator for Leaf<K, V> {
-//     type Item = K;
-//     type IntoIter = std::vec::IntoIter<Self::Item>;
-//
-//     fn into_iter(self) -> Self::IntoIter {
-//         self.keys.into_iter()
-//     }
-// }
-
-
 #[derive(Debug)]
 pub struct Internal<K> {
     page_nr: PagePtr,
     keys: Vec<K>,
     entries: Vec<PagePtr>,
+    // the number of entries in the subtree of each of the entries
+    counts: Vec<u64>,
 }
 
 
 #[derive(Debug)]
 struct ChildNodeInfo {
     page_nr: PagePtr,
+    index: usize, // entries[index] == page_nr
     lparent: Option<usize>, // LeftSubtree(keys[lparent]) == page_nr
     rparent: Option<usize>, // RightSubtree(keys[rparent]) == page_nr
     lsibling: Option<PagePtr>,
@@ -238,89 +722,273 @@ impl<K> Internal<K>
 where
     K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
 {
-    fn get(&self, key: &K) -> PagePtr {
-        match self.keys.binary_search(key) {
-            Ok(i) => self.entries[i + 1], // keys[i] == key -> right subtree
-            Err(i) => self.entries[i],    // keys[i] > key -> left subtree
+    // Returns the index in `entries` of the subtree that may contain `key`: the subtree to the right
+    // of the separator keys that are smaller than or equal to `key`.
+    //
+    // In a multimap, entries with a key equal to a separator key can be found on both sides of it,
+    // this is the subtree that holds the last of them.
+    pub(crate) fn child_index(&self, key: &K) -> usize {
+        self.keys.partition_point(|k| k <= key)
+    }
+
+    // Returns the index in `entries` of the first subtree that may contain `key`. Unlike `child_index`,
+    // this stops at a separator key equal to `key`.
+    pub(crate) fn first_child_index(&self, key: &K) -> usize {
+        self.keys.partition_point(|k| k < key)
+    }
+
+    pub(crate) fn child(&self, i: usize) -> PagePtr {
+        self.entries[i]
+    }
+
+    pub(crate) fn last_child(&self) -> PagePtr {
+        self.entries[self.entries.len() - 1]
+    }
+
+    pub(crate) fn child_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Returns the number of entries in the subtrees before the i-th one.
+    pub(crate) fn count_before(&self, i: usize) -> u64 {
+        self.counts[..i].iter().sum()
+    }
+
+    // Returns the index of the subtree holding the n-th entry of this subtree (counting from 0),
+    // and the index of that entry within the subtree.
+    pub(crate) fn select_child(&self, mut n: u64) -> (usize, u64) {
+        for (i, count) in self.counts.iter().enumerate() {
+            if n < *count {
+                return (i, n);
+            }
+            n -= count;
         }
+        panic!("Programming error: entry {} requested, but the subtree has fewer entries", n);
     }
 
-    fn set<V>(mut self, btree: &mut BTree<K, V>, key: K, value: V) -> Result<(Option<(K, PagePtr)>, Option<V>)>
+    fn set<V, C, F>(
+        mut self,
+        btree: &mut BTree<K, V, C>,
+        key: K,
+        value: F,
+        overwrite: bool,
+    ) -> Result<(Split<K>, Option<V>)>
     where
         V: Debug + Default + Clone +  Serialize + DeserializeOwned,
+        C: PageCodec<K, V>,
+        F: FnOnce() -> V,
     {
-        let next_level_page_nr = self.get(&key);
-        let return_value = match btree.load_node(next_level_page_nr)? {
-            BTNode::Internal(node) => node.set(btree, key, value)?,
-            BTNode::Leaf(node) => node.set(btree, key, value)?,
+        let i = self.child_index(&key);
+        let (split, original_value) = match btree.load_node(self.entries[i])? {
+            BTNode::Internal(node) => node.set(btree, key, value, overwrite)?,
+            BTNode::Leaf(node) => node.set(btree, key, value, overwrite)?,
         };
-        match return_value {
-            (None, v) => Ok((None, v)),
-            (Some((key, page_nr)), _) => match self.keys.binary_search(&key) {
-                Err(i) => match self.is_full(btree.max_key_count) {
-                    true => {
-                        let (split_key, mut new_node) = self.split(btree.next_page_nr(), btree.split_at);
-                        let split_page_nr = new_node.page_nr;
-                        match i < btree.split_at {
-                            true => self.insert(i, key, page_nr),
-                            // minus 1 because we're taking the split_key out!
-                            false => new_node.insert(i - btree.split_at - 1, key, page_nr),
-                        }
-                        btree.store_node(&BTNode::Internal(self))?;
-                        btree.store_node(&BTNode::Internal(new_node))?;
-                        Ok((Some((split_key, split_page_nr)), None))
-                    }
-                    false => {
-                        self.insert(i, key, page_nr);
-                        btree.store_node(&BTNode::Internal(self))?;
-                        Ok((None, None))
-                    }
-                },
-                Ok(_) => panic!("Programming error: key should not be present!"),
-            },
+        match (&split, &original_value) {
+            // a value has been overwritten (or not, see `Leaf::set`), the number of entries didn't change
+            (None, Some(_)) => return Ok((None, original_value)),
+            (_, Some(_)) => {}
+            (_, None) => self.counts[i] += 1,
+        }
+        if let Some((key, page_nr, count)) = split {
+            // the new node goes right after the child that has been split
+            self.counts[i] -= count;
+            self.insert(i, key, page_nr, count);
+        }
+        match self.is_overfull(btree.max_key_count) {
+            true => {
+                let (split_key, new_node) = self.split(btree.next_page_nr(), btree.split_at);
+                let split_page_nr = new_node.page_nr;
+                let split_count = new_node.counts.iter().sum();
+                btree.store_node(BTNode::Internal(self))?;
+                btree.store_node(BTNode::Internal(new_node))?;
+                Ok((Some((split_key, split_page_nr, split_count)), original_value))
+            }
+            false => {
+                btree.store_node(BTNode::Internal(self))?;
+                Ok((None, original_value))
+            }
         }
     }
 
-    fn get_child_node_info(&self, key: &K) -> ChildNodeInfo {
-        match self.keys.binary_search(key) {
-            Ok(i) => {
-                // exact match -> right subtree
-                ChildNodeInfo {
-                    page_nr: self.entries[i + 1],
-                    lparent: if i < self.keys.len() - 1 { Some(i + 1) } else { None },
-                    rparent: Some(i),
-                    lsibling: Some(self.entries[i]),
-                    rsibling: if i < self.entries.len() - 2 { Some(self.entries[i + 2]) } else { None },
+    // Inserts `entries`, which are sorted by key, into the subtrees they belong in: each child is
+    // loaded and stored once, whatever the number of entries it receives. Returns the new nodes
+    // and the number of entries that were added, like `Leaf::set_many`.
+    fn set_many<V, C>(mut self, btree: &mut BTree<K, V, C>, mut entries: Vec<(K, V)>) -> Result<(Splits<K>, u64)>
+    where
+        V: Debug + Default + Clone +  Serialize + DeserializeOwned,
+        C: PageCodec<K, V>,
+    {
+        // The entries of the i-th child are smaller than keys[i], see `child_index`.
+        let mut batches = vec![];
+        for (i, separator) in self.keys.iter().enumerate() {
+            let rest = entries.split_off(entries.partition_point(|(k, _)| k < separator));
+            batches.push((i, mem::replace(&mut entries, rest)));
+        }
+        batches.push((self.keys.len(), entries));
+        let mut added = 0;
+        // From the last child down, so inserting the new nodes doesn't move the children still to do.
+        for (i, batch) in batches.into_iter().rev().filter(|(_, batch)| !batch.is_empty()) {
+            let (splits, n) = match btree.load_node(self.entries[i])? {
+                BTNode::Internal(node) => node.set_many(btree, batch)?,
+                BTNode::Leaf(node) => node.set_many(btree, batch)?,
+            };
+            self.counts[i] += n;
+            added += n;
+            for (key, page_nr, count) in splits.into_iter().rev() {
+                self.counts[i] -= count;
+                self.insert(i, key, page_nr, count);
+            }
+        }
+        Ok((BTNode::Internal(self).store_split(btree)?, added))
+    }
+
+    // Returns the index in `entries` of the subtree to remove `key` from. In a multimap, that's the
+    // subtree holding the first entry with `key`: when a separator key equals `key`, the subtree to
+    // its left only holds `key` if that's its largest key.
+    fn remove_child_index<V, C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>, key: &K) -> Result<usize>
+    where
+        V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    {
+        if !btree.multimap {
+            return Ok(self.child_index(key));
+        }
+        let mut i = self.first_child_index(key);
+        while i < self.keys.len() && self.keys[i] == *key && btree.last_leaf(self.entries[i])?.keys.last() < Some(key) {
+            i += 1;
+        }
+        Ok(i)
+    }
+
+    // Removes the entries with a key smaller than `key` from this subtree and returns how many. The
+    // subtrees that only hold smaller keys are deleted as a whole, the first remaining one is
+    // truncated in turn and refilled from its right sibling. This node isn't stored, and it's left
+    // with a single child if that's the only one remaining.
+    fn truncate_below<V, C: PageCodec<K, V>>(&mut self, btree: &mut BTree<K, V, C>, key: &K) -> Result<u64>
+    where
+        V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    {
+        let i = self.first_child_index(key);
+        for page_nr in self.entries.drain(..i) {
+            btree.load_node(page_nr)?.delete(btree)?;
+        }
+        self.keys.drain(..i);
+        let mut removed: u64 = self.counts.drain(..i).sum();
+
+        let mut first = btree.load_node(self.entries[0])?;
+        let n = match &mut first {
+            BTNode::Internal(node) => node.truncate_below(btree, key)?,
+            BTNode::Leaf(node) => node.truncate_below(btree, key)?,
+        };
+        if n > 0 {
+            self.counts[0] -= n;
+            removed += n;
+            self.fix_first_child(btree, first)?;
+        }
+        Ok(removed)
+    }
+
+    // Stores `first`, the first child of this node, after merging it with the second child or moving
+    // entries from the second child into it if it's underfull. Only the first child can be underfull,
+    // after `truncate_below`.
+    fn fix_first_child<V, C: PageCodec<K, V>>(&mut self, btree: &mut BTree<K, V, C>, first: BTNode<K, V>) -> Result<()>
+    where
+        V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    {
+        if self.entries.len() < 2 || !first.is_underfull(btree) {
+            return btree.store_node(first);
+        }
+        match (first, btree.load_node(self.entries[1])?) {
+            (BTNode::Leaf(mut first), BTNode::Leaf(mut second)) => {
+                if first.keys.len() + second.keys.len() <= btree.max_leaf_key_count as usize {
+                    // merge the second child into the first one
+                    first.keys.extend(second.keys);
+                    first.entries.extend(second.entries);
+                    first.next = second.next;
+                    self.keys.remove(0);
+                    self.entries.remove(1);
+                    self.counts[0] += self.counts.remove(1);
+                    btree.on_page_deleted(second.page_nr)?;
+                } else {
+                    // move the first entries of the second child
+                    let n = btree.min_leaf_key_count() - first.keys.len();
+                    first.keys.extend(second.keys.drain(..n));
+                    first.entries.extend(second.entries.drain(..n));
+                    self.keys[0] = second.keys[0].clone();
+                    self.counts[0] += n as u64;
+                    self.counts[1] -= n as u64;
+                    btree.store_node(BTNode::Leaf(second))?;
                 }
+                btree.store_node(BTNode::Leaf(first))
             }
-            Err(i) => {
-                // not found: keys(i) > key -> left subtree
-                ChildNodeInfo {
-                    page_nr: self.entries[i],
-                    lparent: Some(i),
-                    rparent: if i > 0 { Some(i - 1) } else { None },
-                    lsibling: if i > 0 { Some(self.entries[i - 1]) } else { None },
-                    rsibling: if i < self.entries.len() - 1 { Some(self.entries[i + 1]) } else { None },
+            (BTNode::Internal(mut first), BTNode::Internal(mut second)) => {
+                if first.keys.len() + 1 + second.keys.len() <= btree.max_key_count as usize {
+                    // merge the second child into the first one
+                    first.keys.push(self.keys.remove(0));
+                    first.keys.extend(second.keys);
+                    first.entries.extend(second.entries);
+                    first.counts.extend(second.counts);
+                    self.entries.remove(1);
+                    self.counts[0] += self.counts.remove(1);
+                    btree.on_page_deleted(second.page_nr)?;
+                } else {
+                    // rotate the first children of the second child through this node
+                    let n = btree.min_internal_key_count() - first.keys.len();
+                    for _ in 0..n {
+                        let k = second.keys.remove(0);
+                        first.keys.push(mem::replace(&mut self.keys[0], k));
+                        first.entries.push(second.entries.remove(0));
+                        let count = second.counts.remove(0);
+                        first.counts.push(count);
+                        self.counts[0] += count;
+                        self.counts[1] -= count;
+                    }
+                    btree.store_node(BTNode::Internal(second))?;
+                }
+                // If it had a single child, the first child of `first` can be underfull as well.
+                let grandchild = btree.load_node(first.entries[0])?;
+                if grandchild.is_underfull(btree) {
+                    first.fix_first_child(btree, grandchild)?;
+                    // Merging the grandchild with its sibling can leave `first` underfull again.
+                    return self.fix_first_child(btree, BTNode::Internal(first));
                 }
+                btree.store_node(BTNode::Internal(first))
             }
+            _ => Err(Error::Corrupt(format!("page {}: children at different depths", self.page_nr))),
+        }
+    }
+
+    fn child_node_info(&self, i: usize) -> ChildNodeInfo {
+        ChildNodeInfo {
+            page_nr: self.entries[i],
+            index: i,
+            lparent: if i < self.keys.len() { Some(i) } else { None },
+            rparent: if i > 0 { Some(i - 1) } else { None },
+            lsibling: if i > 0 { Some(self.entries[i - 1]) } else { None },
+            rsibling: if i < self.entries.len() - 1 { Some(self.entries[i + 1]) } else { None },
         }
     }
 
-    fn remove<V>(
+    fn remove<V, C: PageCodec<K, V>>(
         mut self,
-        btree: &mut BTree<K, V>,
+        btree: &mut BTree<K, V, C>,
         key: K,
-        parent: Option<&mut Internal<K>>,
+        mut parent: Option<&mut Internal<K>>,
         path_info: Option<&ChildNodeInfo>,
-    ) -> Result<(Option<V>, Option<PagePtr>)>
+    ) -> Result<Removal<K, V>>
     where
         V: Debug + Default + Clone +  Serialize + DeserializeOwned,
     {
-        let child_info = self.get_child_node_info(&key);
+        let child_info = self.child_node_info(self.remove_child_index(btree, &key)?);
         let (original_value, deleted_page) = match btree.load_node(child_info.page_nr)? {
             BTNode::Internal(node) => node.remove(btree, key, Some(&mut self), Some(&child_info))?,
             BTNode::Leaf(node) => node.remove(btree, key, Some(&mut self), Some(&child_info))?,
         };
+        if original_value.is_none() {
+            return Ok((None, None));
+        }
+        if let (Some(parent), Some(path_info)) = (parent.as_mut(), path_info) {
+            parent.counts[path_info.index] -= 1;
+        }
 
         let result = match deleted_page {
             None => Ok((original_value, None)),
@@ -329,13 +997,13 @@ where
                 Ok((original_value, deleted_page))
             }
         };
-        btree.store_node(&BTNode::Internal(self))?;
+        btree.store_node(BTNode::Internal(self))?;
         result
     }
 
-    fn remove_page<V>(
+    fn remove_page<V, C: PageCodec<K, V>>(
         &mut self,
-        btree: &mut BTree<K, V>,
+        btree: &mut BTree<K, V, C>,
         page_nr: PagePtr,
         parent: Option<&mut Internal<K>>,
         path_info: Option<&ChildNodeInfo>,
@@ -343,21 +1011,23 @@ where
     where
         V: Debug + Default + Clone +  Serialize + DeserializeOwned,
     {
-        match self.entries.binary_search(&page_nr) {
-            Err(_) => panic!("Programming error: deleted page should be present!"),
-            Ok(i) => {
+        // Page numbers are only ascending in a tree that has been filled in ascending key order.
+        match self.entries.iter().position(|entry| *entry == page_nr) {
+            None => panic!("Programming error: deleted page should be present!"),
+            Some(i) => {
                 self.keys.remove(i - 1);
                 self.entries.remove(i);
+                self.counts.remove(i);
 
                 let deleted_page = match parent {
                     None => {
                         // This is the root node!
-                        if self.keys.len() == 0 {
+                        if self.keys.is_empty() {
                             // We're at the root and it's last key has just been removed
                             // The tree collapses into 1 leaf node.
                             let new_root_page_nr = self.entries[0];
                             btree.root_page_nr = new_root_page_nr;
-                            btree.on_page_deleted(self.page_nr);
+                            btree.on_page_deleted(self.page_nr)?;
                             Some(self.page_nr)
                         } else {
                             None
@@ -366,55 +1036,68 @@ where
 
                     Some(parent) => {
                         let mut deleted_page = None;
-                        if self.keys.len() < btree.split_at as usize {
+                        let min_key_count = btree.min_internal_key_count();
+                        if self.keys.len() < min_key_count {
                             let path_info = path_info.unwrap();
                             let mut done = false;
-                            if path_info.lsibling.is_some() {
-                                // try to transfer a key/value pair from left sibling
-                                let mut node = btree.load_node(path_info.lsibling.unwrap())?.internal_node();
-                                if node.keys.len() > btree.split_at as usize {
+                            if let Some(lsibling) = path_info.lsibling {
+                                // try to rotate a key/value pair from left sibling through the parent
+                                let mut node = btree.load_node(lsibling)?.internal_node();
+                                if node.keys.len() > min_key_count {
                                     let k = node.keys.pop().unwrap();
                                     let v = node.entries.pop().unwrap();
-                                    self.keys.insert(0, k.clone());
+                                    let count = node.counts.pop().unwrap();
+                                    let parent_key = mem::replace(&mut parent.keys[path_info.rparent.unwrap()], k);
+                                    self.keys.insert(0, parent_key);
                                     self.entries.insert(0, v);
-                                    parent.keys[path_info.rparent.unwrap()] = k;
-                                    btree.store_node(&BTNode::Internal(node))?;
+                                    self.counts.insert(0, count);
+                                    parent.counts[path_info.index - 1] -= count;
+                                    parent.counts[path_info.index] += count;
+                                    btree.store_node(BTNode::Internal(node))?;
                                     done = true;
                                 }
                             }
 
-                            if !done && path_info.rsibling.is_some() {
-                                // try to transfer a key/value pair from right sibling
-                                let mut node = btree.load_node(path_info.rsibling.unwrap())?.internal_node();
-                                if node.keys.len() > btree.split_at {
+                            if let Some(rsibling) = path_info.rsibling.filter(|_| !done) {
+                                // try to rotate a key/value pair from right sibling through the parent
+                                let mut node = btree.load_node(rsibling)?.internal_node();
+                                if node.keys.len() > min_key_count {
                                     let k = node.keys.remove(0);
                                     let v = node.entries.remove(0);
-                                    self.keys.push(k);
+                                    let count = node.counts.remove(0);
+                                    let parent_key = mem::replace(&mut parent.keys[path_info.lparent.unwrap()], k);
+                                    self.keys.push(parent_key);
                                     self.entries.push(v);
-                                    parent.keys[path_info.lparent.unwrap()] = node.keys[0].clone();
-                                    btree.store_node(&BTNode::Internal(node))?;
+                                    self.counts.push(count);
+                                    parent.counts[path_info.index + 1] -= count;
+                                    parent.counts[path_info.index] += count;
+                                    btree.store_node(BTNode::Internal(node))?;
                                     done = true;
                                 }
                             }
 
                             if !done {
-                                if path_info.lsibling.is_some() {
+                                if let Some(lsibling) = path_info.lsibling {
                                     // merge this node into the left sibling
-                                    let mut node = btree.load_node(path_info.lsibling.unwrap())?.internal_node();
+                                    let mut node = btree.load_node(lsibling)?.internal_node();
                                     node.keys.push(parent.keys[path_info.rparent.unwrap()].clone());
-                                    node.keys.extend(self.keys.iter().map(|k| k.clone()));
+                                    node.keys.extend(self.keys.iter().cloned());
                                     node.entries.extend(&self.entries);
-                                    btree.on_page_deleted(self.page_nr);
+                                    node.counts.extend(&self.counts);
+                                    parent.counts[path_info.index - 1] += parent.counts[path_info.index];
+                                    btree.on_page_deleted(self.page_nr)?;
                                     deleted_page = Some(self.page_nr);
                                     *self = node;
-                                } else if path_info.rsibling.is_some() {
+                                } else if let Some(rsibling) = path_info.rsibling {
                                     // merge the right sibling into this node
                                     // we only get here if "self" if the first leaf of the BTree
-                                    let node = btree.load_node(path_info.rsibling.unwrap())?.internal_node();
+                                    let node = btree.load_node(rsibling)?.internal_node();
                                     self.keys.push(parent.keys[path_info.lparent.unwrap()].clone());
                                     self.keys.extend(node.keys);
                                     self.entries.extend(node.entries);
-                                    btree.on_page_deleted(node.page_nr);
+                                    self.counts.extend(node.counts);
+                                    parent.counts[path_info.index] += parent.counts[path_info.index + 1];
+                                    btree.on_page_deleted(node.page_nr)?;
                                     deleted_page = Some(node.page_nr);
                                 }
                             }
@@ -427,42 +1110,60 @@ where
         }
     }
 
-    fn new(page_nr: u64, keys: &[K], entries: &[PagePtr]) -> Self {
+    fn new(page_nr: u64, keys: &[K], entries: &[PagePtr], counts: &[u64]) -> Self {
         // let padding = (size - 2 * order * (mem::size_of::<K>() + mem::size_of::<PagePtr>()) - mem::size_of::<PagePtr>()) as u64;
-        Internal { page_nr, keys: keys.to_vec(), entries: entries.to_vec() }
+        Internal { page_nr, keys: keys.to_vec(), entries: entries.to_vec(), counts: counts.to_vec() }
     }
 
-    fn is_full(&self, max_key_count: u64) -> bool {
-        self.keys.len() >= max_key_count as usize
+    // A node may temporarily hold one key too many, until it's split.
+    fn is_overfull(&self, max_key_count: u64) -> bool {
+        self.keys.len() > max_key_count as usize
     }
 
     // entries has 1 more value then keys
     // take the middle key out, but leave its entry!
-    // [k0, k1, k2, k3] -> [k0, k1] | [k3]  split_key == k2
-    // [r0, r1, r2, r3, r4] -> [r0, r1, r2] | [r3, r4]
+    // [k0, k1, k2, k3, k4] -> [k0, k1] | [k3, k4]  split_key == k2
+    // [r0, r1, r2, r3, r4, r5] -> [r0, r1, r2] | [r3, r4, r5]
     fn split(&mut self, page_nr: u64, split_at: usize) -> (K, Self) {
         let split_key = self.keys[split_at].clone();
-        let node: Internal<K>;
-        node = Internal::new(page_nr, &self.keys[split_at + 1..], &self.entries[split_at + 1..]);
+        let node = Internal::new(
+            page_nr,
+            &self.keys[split_at + 1..],
+            &self.entries[split_at + 1..],
+            &self.counts[split_at + 1..],
+        );
         self.keys.drain(split_at..);
         self.entries.drain(split_at + 1..);
+        self.counts.drain(split_at + 1..);
         (split_key, node)
     }
 
-    fn insert(&mut self, i: usize, key: K, value: PagePtr) {
+    fn insert(&mut self, i: usize, key: K, value: PagePtr, count: u64) {
         self.keys.insert(i, key);
         self.entries.insert(i + 1, value);
+        self.counts.insert(i + 1, count);
     }
 
-    fn serialize_into(&self, fh: &File) -> Result<()> {
-        bincode::serialize_into(fh, &self.keys)?;
-        bincode::serialize_into(fh, &self.entries)?;
+    fn serialize_into<W: Write>(&self, mut fh: W, page_ptr_size: u64) -> Result<()> {
+        bincode::serialize_into(&mut fh, &self.keys)?;
+        write_ptrs(&mut fh, &self.entries, page_ptr_size)?;
+        bincode::serialize_into(&mut fh, &self.counts)?;
         Ok(())
     }
 
-    fn deserialize_from(fh: &File, page_nr: u64) -> Result<Self> {
-        let node = Self { page_nr, keys: bincode::deserialize_from(fh)?, entries: bincode::deserialize_from(fh)? };
-        Ok(node)
+    fn deserialize_from<R: Read>(fh: &mut R, page_nr: u64, page_ptr_size: u64) -> Result<Self> {
+        let keys: Vec<K> = bincode::deserialize_from(&mut *fh)?;
+        let entries = read_ptrs(fh, page_ptr_size)?;
+        let counts: Vec<u64> = bincode::deserialize_from(fh)?;
+        if counts.len() != entries.len() {
+            return Err(Error::InvalidFileFormat { page_nr: Some(page_nr) });
+        }
+        // The lookups index `entries` with the position of a key, so a node that doesn't have a child
+        // on either side of every key would make them panic.
+        if entries.len() != keys.len() + 1 {
+            return Err(corrupt(page_nr, format!("{} keys, but {} children", keys.len(), entries.len())));
+        }
+        Ok(Self { page_nr, keys, entries, counts })
     }
 
     pub fn keys(self) -> std::vec::IntoIter<K> {
@@ -471,6 +1172,85 @@ where
 }
 
 
+// A page in a chain of overflow pages, holding the next part of a serialized value.
+#[derive(Debug)]
+pub(crate) struct OverflowPage {
+    pub(crate) next: Option<PagePtr>,
+    pub(crate) data: Vec<u8>,
+}
+
+
+impl OverflowPage {
+    // Bytes of a page that are not available for data: tag (u8), next (an optional page number) and
+    // length of data (u64).
+    pub(crate) fn overhead(page_ptr_size: u64) -> u64 {
+        1 + (1 + page_ptr_size) + 8
+    }
+
+    pub(crate) fn serialize_into<W: Write>(&self, mut fh: W, page_ptr_size: u64) -> Result<()> {
+        bincode::serialize_into(&mut fh, &2_u8)?;
+        write_opt_ptr(&mut fh, self.next, page_ptr_size)?;
+        bincode::serialize_into(&mut fh, &self.data)?;
+        Ok(())
+    }
+
+    pub(crate) fn deserialize_from<R: Read>(fh: &mut R, page_ptr_size: u64) -> Result<Self> {
+        let mut buffer = [0_u8; 1];
+        fh.read_exact(&mut buffer)?;
+        if buffer[0] != 2 {
+            return Err(Error::InvalidFileFormat { page_nr: None });
+        }
+        let next = read_opt_ptr(fh, page_ptr_size)?;
+        let page = Self { next, data: bincode::deserialize_from(fh)? };
+        Ok(page)
+    }
+}
+
+
+// What `BTNode::verify` has seen so far, checked against the BTree metadata in `finish`.
+#[derive(Debug, Default)]
+pub(crate) struct Verification {
+    leaf_depth: Option<usize>,
+    leaves: Vec<(PagePtr, Option<PagePtr>)>,
+    // nodes and overflow pages
+    node_count: u64,
+    entry_count: u64,
+}
+
+
+impl Verification {
+    pub(crate) fn finish<K, V, C: PageCodec<K, V>>(self, btree: &BTree<K, V, C>) -> Result<()>
+    where
+        K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+        V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    {
+        for (i, (page_nr, next)) in self.leaves.iter().enumerate() {
+            let expected = self.leaves.get(i + 1).map(|(page_nr, _)| *page_nr);
+            if *next != expected {
+                return Err(corrupt(*page_nr, format!("next is {:?}, expected {:?}", next, expected)));
+            }
+        }
+        if self.leaves[0].0 != btree.first_leaf_page_nr {
+            let message = format!("the first leaf should be page {}", btree.first_leaf_page_nr);
+            return Err(corrupt(self.leaves[0].0, message));
+        }
+        if self.node_count != btree.tree_node_count() {
+            let message = format!("found {} pages, expected {}", self.node_count, btree.tree_node_count());
+            return Err(Error::Corrupt(message));
+        }
+        if self.entry_count != btree.entry_count {
+            return Err(Error::Corrupt(format!("found {} entries, expected {}", self.entry_count, btree.entry_count)));
+        }
+        Ok(())
+    }
+}
+
+
+fn corrupt(page_nr: PagePtr, msg: String) -> Error {
+    Error::Corrupt(format!("page {}: {}", page_nr, msg))
+}
+
+
 #[derive(Debug)]
 pub enum BTNode<K, V> {
     Internal(Internal<K>),
@@ -487,41 +1267,165 @@ where
         BTNode::Leaf(Leaf::new(page_nr, keys, entries, next))
     }
 
-    pub fn new_internal(page_nr: u64, keys: &[K], entries: &[u64]) -> Self {
-        BTNode::Internal(Internal::new(page_nr, keys, entries))
+    pub fn new_internal(page_nr: u64, keys: &[K], entries: &[u64], counts: &[u64]) -> Self {
+        BTNode::Internal(Internal::new(page_nr, keys, entries, counts))
     }
 
-    pub fn get(self, btree: &mut BTree<K, V>, key: K) -> Result<Option<V>> {
+    // Returns the value for `key`. Kept for existing callers: it descends like `BTree::get` does.
+    #[deprecated(note = "use `BTree::get` instead")]
+    pub fn get<C>(self, btree: &BTree<K, V, C>, key: K) -> Result<Option<V>>
+    where
+        C: PageCodec<K, V>,
+    {
         // "self" is the root page!
         match self {
-            BTNode::Leaf(node) => return Ok(node.get(&key)),
-            BTNode::Internal(node) => {
-                let mut page_nr = node.get(&key);
-                loop {
-                    match btree.load_node(page_nr)? {
-                        BTNode::Leaf(node) => return Ok(node.get(&key)),
-                        BTNode::Internal(node) => page_nr = node.get(&key),
-                    }
-                }
-            },
+            BTNode::Internal(_) => btree.get_ref(&key),
+            BTNode::Leaf(node) => node.position(&key).map(|i| node.value(btree, i)).transpose(),
         }
     }
 
-    pub fn set(self, btree: &mut BTree<K, V>, key: K, value: V) -> Result<(Option<(K, PagePtr)>, Option<V>)> {
+    pub fn set<C>(self, btree: &mut BTree<K, V, C>, key: K, value: V) -> Result<(Split<K>, Option<V>)>
+    where
+        C: PageCodec<K, V>,
+    {
         // "self" is the root page!
         match self {
-            BTNode::Internal(node) => node.set(btree, key, value),
-            BTNode::Leaf(node) => node.set(btree, key, value),
+            BTNode::Internal(node) => node.set(btree, key, || value, true),
+            BTNode::Leaf(node) => node.set(btree, key, || value, true),
+        }
+    }
+
+    // Like `set`, but a key that is already present keeps its value: that value is returned and
+    // nothing is stored. `value` is only called for a missing key. "self" is the root page.
+    pub(crate) fn set_if_absent<C, F>(self, btree: &mut BTree<K, V, C>, key: K, value: F) -> Result<(Split<K>, Option<V>)>
+    where
+        C: PageCodec<K, V>,
+        F: FnOnce() -> V,
+    {
+        match self {
+            BTNode::Internal(node) => node.set(btree, key, value, false),
+            BTNode::Leaf(node) => node.set(btree, key, value, false),
+        }
+    }
+
+    // Inserts `entries`, which are sorted by key, with one descent per subtree. "self" is the root
+    // page, the new nodes it was split into are returned with the number of entries added.
+    pub(crate) fn set_many<C>(self, btree: &mut BTree<K, V, C>, entries: Vec<(K, V)>) -> Result<(Splits<K>, u64)>
+    where
+        C: PageCodec<K, V>,
+    {
+        match self {
+            BTNode::Internal(node) => node.set_many(btree, entries),
+            BTNode::Leaf(node) => node.set_many(btree, entries),
+        }
+    }
+
+    // Stores the node, which may hold any number of keys: while it's overfull, it's split at
+    // `split_at` and the rest is split again. Returns the new nodes, this node keeps its page.
+    pub(crate) fn store_split<C: PageCodec<K, V>>(mut self, btree: &mut BTree<K, V, C>) -> Result<Splits<K>> {
+        let mut splits: Splits<K> = vec![];
+        loop {
+            let (split_key, new_node) = match &mut self {
+                BTNode::Internal(node) if node.is_overfull(btree.max_key_count) => {
+                    let (split_key, new_node) = node.split(btree.next_page_nr(), btree.split_at);
+                    (split_key, BTNode::Internal(new_node))
+                }
+                BTNode::Leaf(node) if node.is_overfull(btree)? => {
+                    let split_at = node.split_point(btree)?;
+                    let (split_key, new_node) = node.split(btree.next_page_nr(), split_at);
+                    (split_key, BTNode::Leaf(new_node))
+                }
+                _ => break,
+            };
+            // The previous new node, if any, is complete now.
+            let node = mem::replace(&mut self, new_node);
+            if let Some(split) = splits.last_mut() {
+                split.2 = node.entry_count();
+            }
+            btree.store_node(node)?;
+            splits.push((split_key, self.page_nr(), 0));
+        }
+        if let Some(split) = splits.last_mut() {
+            split.2 = self.entry_count();
+        }
+        btree.store_node(self)?;
+        Ok(splits)
+    }
+
+    // Replaces the leaf whose first entry is the n-th entry of this subtree with `leaf`, which may be
+    // overfull, see `BTree::store_overfull_leaf`. Returns the new nodes, like `store_split`.
+    pub(crate) fn store_leaf_at<C>(self, btree: &mut BTree<K, V, C>, n: u64, leaf: Leaf<K, V>) -> Result<Splits<K>>
+    where
+        C: PageCodec<K, V>,
+    {
+        let mut node = match self {
+            BTNode::Leaf(_) => return BTNode::Leaf(leaf).store_split(btree),
+            BTNode::Internal(node) => node,
+        };
+        let (i, rest) = node.select_child(n);
+        let splits = btree.load_node(node.entries[i])?.store_leaf_at(btree, rest, leaf)?;
+        if splits.is_empty() {
+            return Ok(splits);
+        }
+        for (key, page_nr, count) in splits.into_iter().rev() {
+            node.counts[i] -= count;
+            node.insert(i, key, page_nr, count);
         }
+        BTNode::Internal(node).store_split(btree)
     }
 
-    pub fn remove(self, btree: &mut BTree<K, V>, key: K) -> Result<Option<V>> {
+    pub fn remove<C: PageCodec<K, V>>(self, btree: &mut BTree<K, V, C>, key: K) -> Result<Option<V>> {
+        Ok(self.remove_entry(btree, key)?.map(|(_, value)| value))
+    }
+
+    // Like `remove`, but also returns the key as it was stored.
+    pub(crate) fn remove_entry<C: PageCodec<K, V>>(self, btree: &mut BTree<K, V, C>, key: K) -> Result<Option<(K, V)>> {
         // "self" is the root page!
-        let (original_value, _) = match self {
+        let (original_entry, _) = match self {
             BTNode::Internal(node) => node.remove(btree, key, None, None)?,
             BTNode::Leaf(node) => node.remove(btree, key, None, None)?,
         };
-        Ok(original_value)
+        Ok(original_entry)
+    }
+
+    // Removes the entries with a key smaller than `key` and returns how many. "self" is the root
+    // page, which may be left with a single child.
+    pub(crate) fn truncate_below<C: PageCodec<K, V>>(mut self, btree: &mut BTree<K, V, C>, key: &K) -> Result<u64> {
+        let removed = match &mut self {
+            BTNode::Internal(node) => node.truncate_below(btree, key)?,
+            BTNode::Leaf(node) => node.truncate_below(btree, key)?,
+        };
+        if removed > 0 {
+            btree.store_node(self)?;
+        }
+        Ok(removed)
+    }
+
+    // Deletes all pages of the subtree rooted at this node, including the overflow pages.
+    fn delete<C: PageCodec<K, V>>(self, btree: &mut BTree<K, V, C>) -> Result<()> {
+        let page_nr = self.page_nr();
+        match self {
+            BTNode::Internal(node) => {
+                for child in node.entries {
+                    btree.load_node(child)?.delete(btree)?;
+                }
+            }
+            BTNode::Leaf(node) => {
+                for overflow_page_nr in node.overflow_pages().collect::<Vec<_>>() {
+                    btree.free_overflow(overflow_page_nr)?;
+                }
+            }
+        }
+        btree.on_page_deleted(page_nr)?;
+        Ok(())
+    }
+
+    // Whether this node has fewer keys than a node other than the root needs.
+    fn is_underfull<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> bool {
+        match self {
+            BTNode::Internal(node) => node.keys.len() < btree.min_internal_key_count(),
+            BTNode::Leaf(node) => node.keys.len() < btree.min_leaf_key_count(),
+        }
     }
 
     pub fn page_nr(&self) -> u64 {
@@ -531,33 +1435,43 @@ where
         }
     }
 
-    pub fn serialize_into(&self, fh: &File) -> Result<()> {
+    pub fn serialize_into<W: Write>(&self, fh: W) -> Result<()> {
+        self.serialize_with_page_ptr_size(fh, DEFAULT_PAGE_PTR_SIZE)
+    }
+
+    // Like `serialize_into`, with the page numbers stored in `page_ptr_size` bytes (4 or 8).
+    pub fn serialize_with_page_ptr_size<W: Write>(&self, mut fh: W, page_ptr_size: u64) -> Result<()> {
         match self {
             Self::Internal(node) => {
-                bincode::serialize_into(fh, &0_u8)?;
-                node.serialize_into(fh)?;
+                bincode::serialize_into(&mut fh, &0_u8)?;
+                node.serialize_into(fh, page_ptr_size)?;
             }
             Self::Leaf(node) => {
-                bincode::serialize_into(fh, &1_u8)?;
-                node.serialize_into(fh)?;
+                bincode::serialize_into(&mut fh, &1_u8)?;
+                node.serialize_into(fh, page_ptr_size)?;
             }
         }
         Ok(())
     }
 
-    pub fn deserialize_from(fh: &mut File, page_nr: u64) -> Result<Self> {
+    pub fn deserialize_from<R: Read>(fh: &mut R, page_nr: u64) -> Result<Self> {
+        Self::deserialize_with_page_ptr_size(fh, page_nr, DEFAULT_PAGE_PTR_SIZE)
+    }
+
+    // Like `deserialize_from`, with the page numbers stored in `page_ptr_size` bytes (4 or 8).
+    pub fn deserialize_with_page_ptr_size<R: Read>(fh: &mut R, page_nr: u64, page_ptr_size: u64) -> Result<Self> {
         let mut buffer = [0_u8; 1];
         fh.read_exact(&mut buffer)?;
         match buffer[0] {
             0 => {
-                let node = Internal::<K>::deserialize_from(fh, page_nr)?;
+                let node = Internal::<K>::deserialize_from(fh, page_nr, page_ptr_size)?;
                 Ok(BTNode::Internal(node))
             }
             1 => {
-                let node = Leaf::<K, V>::deserialize_from(fh, page_nr)?;
+                let node = Leaf::<K, V>::deserialize_from(fh, page_nr, page_ptr_size)?;
                 Ok(BTNode::Leaf(node))
             }
-            _ => Err(Error::InvalidFileFormat),
+            _ => Err(Error::InvalidFileFormat { page_nr: Some(page_nr) }),
         }
     }
 
@@ -568,6 +1482,18 @@ where
         }
     }
 
+    // The number of entries in the subtree of this node.
+    fn entry_count(&self) -> u64 {
+        match self {
+            Self::Internal(node) => node.counts.iter().sum(),
+            Self::Leaf(node) => node.keys.len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn keys(self) -> std::vec::IntoIter<K> {
         match self {
             Self::Internal(node) => node.keys(),
@@ -575,8 +1501,119 @@ where
         }
     }
 
+    // Checks the keys of this node on their own: they're in order and there aren't too many of them.
+    // Besides `verify`, debug builds check every node that is stored, see `BTree::store_node`.
+    pub(crate) fn check_keys<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<()> {
+        let (keys, max_key_count) = match self {
+            Self::Internal(node) => (&node.keys, btree.max_key_count),
+            Self::Leaf(node) => (&node.keys, btree.leaf_capacity()),
+        };
+        // In a multimap, keys can be repeated, also across nodes.
+        let strict = !btree.multimap;
+        if keys.windows(2).any(|w| w[0] > w[1] || (strict && w[0] == w[1])) {
+            let msg = if strict { "keys are not strictly ascending" } else { "keys are not ascending" };
+            return Err(corrupt(self.page_nr(), String::from(msg)));
+        }
+        if keys.len() > max_key_count as usize {
+            return Err(corrupt(self.page_nr(), format!("{} keys, at most {} allowed", keys.len(), max_key_count)));
+        }
+        Ok(())
+    }
+
+    // Checks the subtree rooted at this node, whose keys should all be in `lower..upper`.
+    //
+    pub(crate) fn verify<C: PageCodec<K, V>>(
+        &self,
+        btree: &BTree<K, V, C>,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        depth: usize,
+        verification: &mut Verification,
+    ) -> Result<()> {
+        let page_nr = self.page_nr();
+        let keys = match self {
+            Self::Internal(node) => &node.keys,
+            Self::Leaf(node) => &node.keys,
+        };
+        verification.node_count += 1;
+        self.check_keys(btree)?;
+        let strict = !btree.multimap;
+        if let (Some(lower), Some(first)) = (lower, keys.first()) {
+            if first < lower {
+                return Err(corrupt(page_nr, format!("key {:?} is smaller than {:?}", first, lower)));
+            }
+        }
+        if let (Some(upper), Some(last)) = (upper, keys.last()) {
+            if last > upper || (strict && last == upper) {
+                return Err(corrupt(page_nr, format!("key {:?} is not smaller than {:?}", last, upper)));
+            }
+        }
+        match self {
+            Self::Internal(node) => {
+                if node.keys.is_empty() || node.entries.len() != node.keys.len() + 1 {
+                    let msg = format!("{} keys and {} children", node.keys.len(), node.entries.len());
+                    return Err(corrupt(page_nr, msg));
+                }
+                if node.counts.len() != node.entries.len() {
+                    let msg = format!("{} children and {} entry counts", node.entries.len(), node.counts.len());
+                    return Err(corrupt(page_nr, msg));
+                }
+                for (i, child) in node.entries.iter().enumerate() {
+                    if btree.emtpy_pages.contains(child) {
+                        return Err(corrupt(page_nr, format!("child {} has been deleted", child)));
+                    }
+                    let lower = if i == 0 { lower } else { Some(&node.keys[i - 1]) };
+                    let upper = node.keys.get(i).or(upper);
+                    let entry_count = verification.entry_count;
+                    btree.load_node(*child)?.verify(btree, lower, upper, depth + 1, verification)?;
+                    let count = verification.entry_count - entry_count;
+                    if count != node.counts[i] {
+                        let msg = format!("child {} has {} entries, not {}", child, count, node.counts[i]);
+                        return Err(corrupt(page_nr, msg));
+                    }
+                }
+            }
+            Self::Leaf(node) => {
+                if node.entries.len() != node.keys.len() {
+                    let msg = format!("{} keys and {} values", node.keys.len(), node.entries.len());
+                    return Err(corrupt(page_nr, msg));
+                }
+                let min_key_count = btree.min_leaf_key_count();
+                if depth > 0 && node.keys.len() < min_key_count {
+                    return Err(corrupt(page_nr, format!("{} keys, at least {} needed", node.keys.len(), min_key_count)));
+                }
+                match verification.leaf_depth {
+                    Some(leaf_depth) if leaf_depth != depth => {
+                        return Err(corrupt(page_nr, format!("leaf at depth {}, expected {}", depth, leaf_depth)));
+                    }
+                    _ => verification.leaf_depth = Some(depth),
+                }
+                for first_page_nr in node.overflow_pages() {
+                    for overflow_page_nr in btree.overflow_chain(first_page_nr)? {
+                        if btree.emtpy_pages.contains(&overflow_page_nr) {
+                            return Err(corrupt(page_nr, format!("overflow page {} has been deleted", overflow_page_nr)));
+                        }
+                        verification.node_count += 1;
+                    }
+                }
+                verification.leaves.push((page_nr, node.next));
+                verification.entry_count += node.keys.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
     // Only for debugging
-    pub fn dump(&self, btree: &mut BTree<K, V>) -> Result<()> {
+    pub fn dump<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<()> {
+        self.dump_to(btree, &mut std::io::stdout())
+    }
+
+    // Writes the internal nodes level by level and then all leaves to `w`, one node per line.
+    pub fn dump_to<C, W>(&self, btree: &BTree<K, V, C>, w: &mut W) -> Result<()>
+    where
+        C: PageCodec<K, V>,
+        W: Write,
+    {
         // This is the root node
         match self {
             Self::Internal(node) => {
@@ -611,27 +1648,35 @@ where
                     }
                 }
                 for page_nr in all_nodes {
-                    println!("{:?}", btree.load_node(page_nr)?);
+                    writeln!(w, "{:?}", btree.load_node(page_nr)?)?;
                 }
-                self.dump_leafs(btree)?;
+                self.dump_leafs_to(btree, w)?;
             }
-            Self::Leaf(node) => println!("{:?}", node),
+            Self::Leaf(node) => writeln!(w, "{:?}", node)?,
         }
         Ok(())
     }
 
     // Only for debugging
-    pub fn dump_leafs(&self, btree: &mut BTree<K, V>) -> Result<()> {
-        let mut page_nr = Some(0);
+    pub fn dump_leafs<C: PageCodec<K, V>>(&self, btree: &BTree<K, V, C>) -> Result<()> {
+        self.dump_leafs_to(btree, &mut std::io::stdout())
+    }
+
+    fn dump_leafs_to<C, W>(&self, btree: &BTree<K, V, C>, w: &mut W) -> Result<()>
+    where
+        C: PageCodec<K, V>,
+        W: Write,
+    {
+        let mut page_nr = Some(btree.first_leaf_page_nr);
         while page_nr.is_some() {
             let node = btree.load_node(page_nr.unwrap())?.leaf_node();
-            println!("{:?}", node);
+            writeln!(w, "{:?}", node)?;
             page_nr = node.next;
         }
         Ok(())
     }
 
-    fn leaf_node(self) -> Leaf<K, V> {
+    pub(crate) fn leaf_node(self) -> Leaf<K, V> {
         match self {
             BTNode::Internal(_) => panic!("Expected a Leaf, got an Internal"),
             BTNode::Leaf(node) => node,
@@ -651,16 +1696,78 @@ where
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::error::ErrorKind;
     use tempfile::TempDir;
 
     fn dump_btree(bt: &mut BTree<u128, u128>) -> Result<()> {
         println!("==== BTree");
         println!("{:?}", bt);
-        bt.root()?.dump(bt)?;
+        bt.dump()?;
         println!("====");
         Ok(())
     }
 
+    #[test]
+    fn test_split_at() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(8))?;
+        bt.set_max_key_count(4);
+        bt.set_split_at(1);
+        for i in 1..=5 {
+            bt.set(i * 10, i * 100)?;
+        }
+
+        // [20]
+        // [10] [20, 30, 40, 50]
+        let root = bt.root()?.internal_node();
+        assert_eq!(root.keys, vec![20]);
+        assert_eq!(bt.load_node(root.child(0))?.keys().collect::<Vec<_>>(), vec![10]);
+        assert_eq!(bt.load_node(root.child(1))?.keys().collect::<Vec<_>>(), vec![20, 30, 40, 50]);
+        bt.verify()?;
+
+        // A full leaf keeps 3 keys from now on.
+        //
+        // [20, 50]
+        // [10] [20, 30, 40] [50, 60]
+        bt.set_split_at(3);
+        bt.set(60, 600)?;
+        let root = bt.root()?.internal_node();
+        assert_eq!(root.keys, vec![20, 50]);
+        assert_eq!(bt.load_node(root.child(1))?.keys().collect::<Vec<_>>(), vec![20, 30, 40]);
+        assert_eq!(bt.load_node(root.child(2))?.keys().collect::<Vec<_>>(), vec![50, 60]);
+        // No `verify`: the leaf [10] is too small for the new minimum.
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "keys are not strictly ascending")]
+    fn test_store_invalid_node() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut bt: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4)).unwrap();
+        let page_nr = bt.next_page_nr();
+        let _ = bt.store_node(BTNode::new_leaf(page_nr, &[20, 10], &[200, 100], None));
+    }
+
+    #[test]
+    fn test_malformed_internal_node() -> Result<()> {
+        let mut page = vec![];
+        bincode::serialize_into(&mut page, &0_u8)?;
+        bincode::serialize_into(&mut page, &vec![10_u64, 20])?;
+        bincode::serialize_into(&mut page, &vec![1_u64, 2])?;
+        bincode::serialize_into(&mut page, &vec![5_u64, 5])?;
+        let err = BTNode::<u64, u64>::deserialize_from(&mut &page[..], 7).unwrap_err();
+        assert!(matches!(err, Error::Corrupt(ref msg) if msg == "page 7: 2 keys, but 2 children"));
+
+        // A page of zeroes: an internal node without keys or children.
+        let page = [0_u8; 64];
+        let err = BTNode::<u64, u64>::deserialize_from(&mut &page[..], 7).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Corrupt);
+
+        Ok(())
+    }
+
     #[test]
     fn test_set() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -724,6 +1831,7 @@ mod tests {
         }
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 10);
+        bt.verify()?;
         assert_eq!(bt.len(), 15);
 
         // Remove 120 (from leaf(6) [110, 120])
@@ -741,6 +1849,7 @@ mod tests {
         assert_eq!(bt.remove(120)?, Some(1200));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 10);
+        bt.verify()?;
         assert_eq!(bt.len(), 14);
 
         // Remove 100 (from leaf(5) [90, 100])
@@ -759,6 +1868,7 @@ mod tests {
         assert_eq!(bt.remove(100)?, Some(1000));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 9);
+        bt.verify()?;
         assert_eq!(bt.len(), 13);
 
         // Remove 110 (from leaf(6) [110, 130])
@@ -776,6 +1886,7 @@ mod tests {
         assert_eq!(bt.remove(110)?, Some(1100));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 9);
+        bt.verify()?;
         assert_eq!(bt.len(), 12);
 
         // Remove 30 (from leaf(1) [30, 40])
@@ -796,6 +1907,7 @@ mod tests {
         assert_eq!(bt.remove(30)?, Some(300));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 6);
+        bt.verify()?;
         assert_eq!(bt.len(), 11);
 
         // Remove 20, 40, 50, 60, 80, 90, 130 and 140 so that the root collapses into 1 leaf(0)
@@ -804,6 +1916,7 @@ mod tests {
         }
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 1);
+        bt.verify()?;
         assert_eq!(bt.len(), 3);
 
         Ok(())
@@ -823,29 +1936,34 @@ mod tests {
         }
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 10);
+        bt.verify()?;
         assert_eq!(bt.len(), 22);
 
         // transfer from right sibling
         assert_eq!(bt.remove(180)?, Some(1800));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 10);
+        bt.verify()?;
         assert_eq!(bt.len(), 21);
 
         // left merge
         assert_eq!(bt.remove(100)?, Some(1000));
         dump_btree(&mut bt)?;
-        assert_eq!(bt.node_count, 7);
+        assert_eq!(bt.node_count, 9);
+        bt.verify()?;
         assert_eq!(bt.len(), 20);
 
         // transfer from left sibling
         assert_eq!(bt.remove(110)?, Some(1100));
         dump_btree(&mut bt)?;
-        assert_eq!(bt.node_count, 7);
+        assert_eq!(bt.node_count, 9);
+        bt.verify()?;
         assert_eq!(bt.len(), 19);
 
         assert_eq!(bt.remove(30)?, Some(300));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 6);
+        bt.verify()?;
         assert_eq!(bt.len(), 18);
 
         for i in [10, 20_u128, 40, 50, 60, 80, 90, 120, 130, 140, 170, 200, 220].iter() {
@@ -853,6 +1971,7 @@ mod tests {
         }
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 1);
+        bt.verify()?;
         assert_eq!(bt.len(), 5);
 
         Ok(())
@@ -873,25 +1992,29 @@ mod tests {
         }
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 22);
+        bt.verify()?;
         assert_eq!(bt.len(), 29);
 
         // transfer from right sibling
         assert_eq!(bt.remove(28)?, Some(280));
         dump_btree(&mut bt)?;
         assert_eq!(bt.node_count, 22);
+        bt.verify()?;
         assert_eq!(bt.len(), 28);
 
         // left merge
         assert_eq!(bt.remove(6)?, Some(60));
         dump_btree(&mut bt)?;
         assert_eq!(bt.get(7)?, Some(70));
-        assert_eq!(bt.node_count, 18);
+        assert_eq!(bt.node_count, 21);
+        bt.verify()?;
         assert_eq!(bt.len(), 27);
 
         // transfer from left sibling
         assert_eq!(bt.remove(7)?, Some(70));
         dump_btree(&mut bt)?;
-        assert_eq!(bt.node_count, 18);
+        assert_eq!(bt.node_count, 20);
+        bt.verify()?;
         assert_eq!(bt.len(), 26);
 
         assert_eq!(bt.remove(5)?, Some(50));
@@ -899,7 +2022,8 @@ mod tests {
         assert_eq!(bt.remove(27)?, Some(270));
         assert_eq!(bt.remove(29)?, Some(290));
         dump_btree(&mut bt)?;
-        assert_eq!(bt.node_count, 15);
+        assert_eq!(bt.node_count, 19);
+        bt.verify()?;
         assert_eq!(bt.len(), 22);
 
         for i in [1, 3, 9, 11, 13, 15, 17, 19, 21, 23, 25].iter() {
@@ -907,7 +2031,8 @@ mod tests {
             assert_eq!(bt.get(*i + 1)?, Some((i + 1) * 10));
         }
         dump_btree(&mut bt)?;
-        assert_eq!(bt.node_count, 6);
+        assert_eq!(bt.node_count, 8);
+        bt.verify()?;
         assert_eq!(bt.len(), 11);
 
         Ok(())
@@ -0,0 +1,53 @@
+use std::ops::Bound;
+
+
+// Keys that are made up of bytes, so that all keys starting with a sequence of bytes can be found
+// with `BTree::scan_prefix`. The keys starting with a prefix must form a range: for keys that are
+// ordered by their bytes, that's from the prefix followed by the smallest possible bytes up to the
+// prefix with its last byte incremented.
+pub trait KeyPrefix: Sized {
+    // Returns the range of the keys that start with `prefix`, or `None` if no key can start with it.
+    fn prefix_range(prefix: &[u8]) -> Option<(Bound<Self>, Bound<Self>)>;
+}
+
+
+// Returns the first sequence of bytes that is larger than all sequences starting with `prefix`:
+// the prefix with its last byte incremented, after dropping the trailing 0xff bytes. There is no
+// such sequence if the prefix is empty or only consists of 0xff bytes.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let i = prefix.iter().rposition(|b| *b != 0xff)?;
+    let mut successor = prefix[..=i].to_vec();
+    successor[i] += 1;
+    Some(successor)
+}
+
+
+impl<const N: usize> KeyPrefix for [u8; N] {
+    fn prefix_range(prefix: &[u8]) -> Option<(Bound<Self>, Bound<Self>)> {
+        if prefix.len() > N {
+            return None;
+        }
+        let mut start = [0u8; N];
+        start[..prefix.len()].copy_from_slice(prefix);
+        let end = match prefix_successor(prefix) {
+            Some(successor) => {
+                let mut end = [0u8; N];
+                end[..successor.len()].copy_from_slice(&successor);
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        Some((Bound::Included(start), end))
+    }
+}
+
+
+impl KeyPrefix for Vec<u8> {
+    fn prefix_range(prefix: &[u8]) -> Option<(Bound<Self>, Bound<Self>)> {
+        let end = match prefix_successor(prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+        Some((Bound::Included(prefix.to_vec()), end))
+    }
+}
@@ -0,0 +1,156 @@
+use crate::codec::PageCodec;
+use crate::error::{Error, Result};
+use crate::free_pages::FreePages;
+use crate::node::PagePtr;
+use crate::BTree;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    sync::Arc,
+};
+
+
+// Identifies a snapshot of a tree, see `BTree::snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SnapshotId(u64);
+
+
+// The tree as it was when the snapshot was taken. Pages are still updated in place: before a page
+// the snapshot can reach is overwritten for the first time, its contents are copied to a new page,
+// which the snapshot reads instead.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    root_page_nr: PagePtr,
+    first_leaf_page_nr: PagePtr,
+    entry_count: u64,
+    // The number of pages in the file and the pages that weren't in use: the snapshot can only
+    // reach the other pages.
+    page_count: u64,
+    unused: BTreeSet<PagePtr>,
+    // original page number -> page number of the copy
+    copies: Arc<BTreeMap<PagePtr, PagePtr>>,
+}
+
+
+impl Snapshot {
+    // Whether page `page_nr` has to be copied before it's overwritten.
+    fn needs_copy(&self, page_nr: PagePtr) -> bool {
+        page_nr < self.page_count && !self.unused.contains(&page_nr) && !self.copies.contains_key(&page_nr)
+    }
+}
+
+
+// The snapshots of a tree, which only live in memory.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Snapshots {
+    // See `BTreeBuilder::snapshots`.
+    pub(crate) enabled: bool,
+    next_id: u64,
+    snapshots: BTreeMap<SnapshotId, Snapshot>,
+    // The pages holding copies and the number of snapshots reading each of them.
+    copies: BTreeMap<PagePtr, usize>,
+}
+
+
+impl Snapshots {
+    // The number of pages holding copies, which are counted as pages in use.
+    pub(crate) fn page_count(&self) -> u64 {
+        self.copies.len() as u64
+    }
+
+    pub(crate) fn copy_pages(&self) -> impl Iterator<Item = PagePtr> + '_ {
+        self.copies.keys().cloned()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Takes a snapshot of the tree, which can be read with `get_at_version` while the tree is
+    // modified, until it's released with `release_snapshot`. Returns `Error::InvalidConfiguration`
+    // unless the tree was opened with `BTreeBuilder::snapshots`.
+    //
+    // Taking a snapshot doesn't write anything. While it's held, the first write to each page it can
+    // reach copies the page first, so the file grows by at most the size of the tree per snapshot.
+    // Snapshots only live in memory: they're gone when the tree is dropped, and the pages holding
+    // the copies are stored as deleted pages.
+    pub fn snapshot(&mut self) -> Result<SnapshotId> {
+        if !self.snapshots.enabled {
+            let msg = "snapshots aren't enabled, see `BTreeBuilder::snapshots`";
+            return Err(Error::InvalidConfiguration(String::from(msg)));
+        }
+        let id = SnapshotId(self.snapshots.next_id);
+        self.snapshots.next_id += 1;
+        let mut unused: BTreeSet<PagePtr> = self.emtpy_pages.iter().cloned().collect();
+        unused.extend(self.snapshots.copy_pages());
+        let snapshot = Snapshot {
+            root_page_nr: self.root_page_nr,
+            first_leaf_page_nr: self.first_leaf_page_nr,
+            entry_count: self.entry_count,
+            page_count: self.node_count + self.emtpy_pages.len() as u64,
+            unused,
+            copies: Arc::default(),
+        };
+        self.snapshots.snapshots.insert(id, snapshot);
+        Ok(id)
+    }
+
+    // Releases a snapshot: the pages holding copies that no other snapshot reads are deleted.
+    pub fn release_snapshot(&mut self, id: SnapshotId) -> Result<()> {
+        let snapshot = self.snapshots.snapshots.remove(&id).ok_or(Error::SnapshotNotFound)?;
+        for copy in snapshot.copies.values() {
+            let readers = self.snapshots.copies.get_mut(copy).unwrap();
+            *readers -= 1;
+            if *readers == 0 {
+                self.snapshots.copies.remove(copy);
+                self.on_page_deleted(*copy)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the value of `key` as it was when snapshot `id` was taken. The pages are read through
+    // the file handle of the tree: snapshots keep `compact` from replacing the db file.
+    pub fn get_at_version(&self, id: SnapshotId, key: K) -> Result<Option<V>> {
+        let snapshot = self.snapshots.snapshots.get(&id).ok_or(Error::SnapshotNotFound)?;
+        let mut version = self.read_only_view(self.store.share());
+        version.root_page_nr = snapshot.root_page_nr;
+        version.first_leaf_page_nr = snapshot.first_leaf_page_nr;
+        version.entry_count = snapshot.entry_count;
+        version.emtpy_pages = FreePages::default();
+        version.page_copies = Arc::clone(&snapshot.copies);
+        version.get(key)
+    }
+
+    // Copies page `page_nr` to a new page for the snapshots that can reach it, before it's
+    // overwritten. The copy goes to the end of the file: none of the snapshots can reach that page.
+    pub(crate) fn preserve_page(&mut self, page_nr: PagePtr) -> Result<()> {
+        if !self.snapshots.snapshots.values().any(|snapshot| snapshot.needs_copy(page_nr)) {
+            return Ok(());
+        }
+        let page = self.store.read_page(page_nr, |page| Ok(page.to_vec()))?;
+        let copy = self.node_count + self.emtpy_pages.len() as u64;
+        self.node_count += 1;
+        self.dirty = true;
+        self.store.write_page(copy, |buffer| {
+            buffer.extend_from_slice(&page);
+            Ok(())
+        })?;
+        let mut readers = 0;
+        for snapshot in self.snapshots.snapshots.values_mut().filter(|snapshot| snapshot.needs_copy(page_nr)) {
+            Arc::make_mut(&mut snapshot.copies).insert(page_nr, copy);
+            readers += 1;
+        }
+        self.snapshots.copies.insert(copy, readers);
+        Ok(())
+    }
+}
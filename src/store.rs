@@ -0,0 +1,299 @@
+use crate::error::{Error, Result};
+use crate::node::PagePtr;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+
+// Pages that have been written but not yet stored in the file, by page number.
+pub(crate) type Pages = BTreeMap<PagePtr, Vec<u8>>;
+
+
+// The number of pages read and written through a tree since it was opened (or since the numbers
+// were reset), see `BTree::page_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageStats {
+    // pages read from the file
+    pub reads: u64,
+    // pages stored, to the file or to the write-ahead log and then the file
+    pub writes: u64,
+    // pages read from memory instead of the file: the pages written earlier in the same change
+    // when the write-ahead log is enabled
+    pub cache_hits: u64,
+}
+
+
+// The db file: a sequence of pages of `page_size` bytes, page `n` starts at offset `n * page_size`.
+//
+// The file is opened lazily, behind a `Mutex` so that pages can be read through `&self`. Readers of
+// the same `Store` take turns using the file handle.
+//
+// Between `begin` and `take_pending`, written pages are kept in memory instead of being written to
+// the file, so that they can be written to the write-ahead log first.
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    path: PathBuf,
+    page_size: u64,
+    read_only: bool,
+    // Shared with the stores returned by `share`.
+    fh: Arc<Mutex<Option<File>>>,
+    // The file the lock is taken on and the lock, see `lock`.
+    lock_path: Option<PathBuf>,
+    lock: Mutex<Option<File>>,
+    pending: Mutex<Option<Pages>>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    cache_hits: AtomicU64,
+}
+
+
+// Exclusive access to the handle of the db file, which is open as long as the guard exists.
+struct FileGuard<'a>(MutexGuard<'a, Option<File>>);
+
+
+impl Deref for FileGuard<'_> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.0.as_ref().unwrap()
+    }
+}
+
+
+impl DerefMut for FileGuard<'_> {
+    fn deref_mut(&mut self) -> &mut File {
+        self.0.as_mut().unwrap()
+    }
+}
+
+
+impl Store {
+    pub(crate) fn new(path: PathBuf, page_size: u64) -> Self {
+        Store { path, page_size, ..Store::default() }
+    }
+
+    // Returns a store that can only be read. The file is opened right away, to stick to the current
+    // file even if it's replaced later on.
+    pub(crate) fn read_only(path: PathBuf, page_size: u64) -> Self {
+        let fh = File::open(&path).ok();
+        Store { path, page_size, read_only: true, fh: Arc::new(Mutex::new(fh)), ..Store::default() }
+    }
+
+    // Returns a store that reads through the file handle of this one, which is opened as this store
+    // would. It doesn't hold the lock, the pages that are pending or the page statistics.
+    #[cfg(feature = "snapshots")]
+    pub(crate) fn share(&self) -> Self {
+        let fh = Arc::clone(&self.fh);
+        Store { path: self.path.clone(), page_size: self.page_size, read_only: self.read_only, fh, ..Store::default() }
+    }
+
+    // Reads page `page_nr` with `deserialize_from`. The page is read with a single read and then
+    // deserialized from memory.
+    pub(crate) fn read_page<T, F>(&self, page_nr: PagePtr, deserialize_from: F) -> Result<T>
+    where
+        F: FnOnce(&mut &[u8]) -> Result<T>,
+    {
+        if let Some(page) = self.pending()?.as_ref().and_then(|pending| pending.get(&page_nr)) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return deserialize_from(&mut &page[..]);
+        }
+        let page = self.read_pages(page_nr, 1)?;
+        deserialize_from(&mut &page[..])
+    }
+
+    // Reads `count` consecutive pages starting at `page_nr` with a single read. Fewer pages are
+    // returned if the file ends before.
+    pub(crate) fn read_pages(&self, page_nr: PagePtr, count: u64) -> Result<Vec<u8>> {
+        let offset = self.offset(page_nr)?;
+        let mut fh = self.file()?;
+        fh.seek(SeekFrom::Start(offset))?;
+        let mut buffer = Vec::with_capacity((self.page_size * count) as usize);
+        (&*fh).take(self.page_size * count).read_to_end(&mut buffer)?;
+        self.reads.fetch_add((buffer.len() as u64).div_ceil(self.page_size), Ordering::Relaxed);
+        Ok(buffer)
+    }
+
+    // Writes page `page_nr` with `serialize_into` and pads it with zeroes up to the page size. Returns
+    // `Error::NodeTooLarge` if more than a page is serialized, without writing anything.
+    pub(crate) fn write_page<F>(&self, page_nr: PagePtr, serialize_into: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<()>,
+    {
+        let page_size = self.page_size as usize;
+        let mut page = Vec::with_capacity(page_size);
+        serialize_into(&mut page)?;
+        if page.len() > page_size {
+            return Err(Error::NodeTooLarge { page_nr, needed: page.len() as u64, available: page_size as u64 });
+        }
+        page.resize(page_size, 0);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        match self.pending()?.as_mut() {
+            Some(pending) => {
+                pending.insert(page_nr, page);
+                Ok(())
+            }
+            None => self.write_raw(page_nr, &page),
+        }
+    }
+
+    // Writes `page`, which is exactly one page, to page `page_nr` of the file.
+    pub(crate) fn write_raw(&self, page_nr: PagePtr, page: &[u8]) -> Result<()> {
+        let offset = self.offset(page_nr)?;
+        let mut fh = self.file()?;
+        fh.seek(SeekFrom::Start(offset))?;
+        fh.write_all(page)?;
+        Ok(())
+    }
+
+    // Starts keeping written pages in memory.
+    pub(crate) fn begin(&self) -> Result<()> {
+        *self.pending()? = Some(Pages::new());
+        Ok(())
+    }
+
+    pub(crate) fn is_pending(&self) -> Result<bool> {
+        Ok(self.pending()?.is_some())
+    }
+
+    // Returns the pages that have been written since `begin`, new writes go to the file again.
+    pub(crate) fn take_pending(&self) -> Result<Pages> {
+        Ok(self.pending()?.take().unwrap_or_default())
+    }
+
+    pub(crate) fn stats(&self) -> PageStats {
+        PageStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset_stats(&self) {
+        self.reads.store(0, Ordering::Relaxed);
+        self.writes.store(0, Ordering::Relaxed);
+        self.cache_hits.store(0, Ordering::Relaxed);
+    }
+
+    // Returns the number of pages in the file, including the ones that were never written.
+    pub(crate) fn page_count(&self) -> Result<u64> {
+        Ok(self.file()?.metadata()?.len() / self.page_size)
+    }
+
+    // The length of the file in bytes, 0 if it hasn't been created yet.
+    pub(crate) fn len(&self) -> Result<u64> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Truncates or extends the file to `page_count` pages.
+    pub(crate) fn set_page_count(&self, page_count: u64) -> Result<()> {
+        self.file()?.set_len(page_count * self.page_size)?;
+        Ok(())
+    }
+
+    // Writes the pages to disk, if the file has been opened.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if let Some(fh) = self.fh.lock().map_err(|_| Error::InvalidFileHandle)?.as_ref() {
+            fh.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Closes the file by dropping its handle, it's opened again on next use.
+    pub(crate) fn close(&mut self) -> Result<()> {
+        *self.fh.lock().map_err(|_| Error::InvalidFileHandle)? = None;
+        Ok(())
+    }
+
+    // Takes the lock of a tree on the file `path`: an exclusive lock to write the tree, a shared lock
+    // to only read it. Returns `Error::Locked` if someone else holds a lock that conflicts, and
+    // `None` instead of an error if a tree that is only read can't be locked at all.
+    pub(crate) fn lock(path: PathBuf, read_only: bool) -> Result<Option<(PathBuf, File)>> {
+        match lock_file(&path, read_only)? {
+            Some(lock) => Ok(Some((path, lock))),
+            None => Ok(None),
+        }
+    }
+
+    // Holds `lock`, taken with `lock`, until the store is dropped or released.
+    pub(crate) fn set_lock(&mut self, (path, lock): (PathBuf, File)) {
+        self.lock_path = Some(path);
+        self.lock = Mutex::new(Some(lock));
+    }
+
+    pub(crate) fn is_locked(&self) -> bool {
+        self.lock_path.is_some()
+    }
+
+    // Moves the store to the db file `path`, once it has been released. See `BTree::relocate`.
+    pub(crate) fn set_path(&mut self, path: PathBuf) {
+        self.path = path;
+    }
+
+    // Closes the file and releases the lock, both are taken again on next use.
+    pub(crate) fn release(&mut self) -> Result<()> {
+        self.close()?;
+        *self.lock.get_mut().map_err(|_| Error::InvalidFileHandle)? = None;
+        Ok(())
+    }
+
+    // The position of page `page_nr` in the file. Page numbers and offsets are u64 on every target, but
+    // a page number read from a damaged page can still be too large for an offset.
+    fn offset(&self, page_nr: PagePtr) -> Result<u64> {
+        self.page_size
+            .checked_mul(page_nr)
+            .ok_or_else(|| Error::Corrupt(format!("page {} is beyond the largest possible file", page_nr)))
+    }
+
+    fn pending(&self) -> Result<MutexGuard<'_, Option<Pages>>> {
+        self.pending.lock().map_err(|_| Error::InvalidFileHandle)
+    }
+
+    // Returns the handle of the db file, opening it on first use.
+    fn file(&self) -> Result<FileGuard<'_>> {
+        let mut fh = self.fh.lock().map_err(|_| Error::InvalidFileHandle)?;
+        if fh.is_none() {
+            if let Some(lock_path) = &self.lock_path {
+                let mut lock = self.lock.lock().map_err(|_| Error::InvalidFileHandle)?;
+                if lock.is_none() {
+                    *lock = lock_file(lock_path, self.read_only)?;
+                }
+            }
+            *fh = Some(match self.read_only {
+                true => File::open(&self.path)?,
+                false => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path)?,
+            });
+        }
+        Ok(FileGuard(fh))
+    }
+}
+
+
+// Locks the file `path`, see `Store::lock`.
+fn lock_file(path: &Path, read_only: bool) -> Result<Option<File>> {
+    let fh = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path) {
+        Ok(fh) => fh,
+        Err(_) if read_only => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let locked = match read_only {
+        true => fh.try_lock_shared(),
+        false => fh.try_lock(),
+    };
+    match locked {
+        Ok(()) => Ok(Some(fh)),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
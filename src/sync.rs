@@ -0,0 +1,72 @@
+use crate::codec::{BincodeCodec, PageCodec};
+use crate::error::{Error, Result};
+use crate::BTree;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+
+// A BTree that can be shared between threads: a clone refers to the same tree. Every method locks
+// the tree for the duration of the call, so there's one writer at a time and readers wait for it.
+// Use `lock` to do several operations at once, or to use the rest of the API.
+#[derive(Debug)]
+pub struct SyncBTree<K, V, C = BincodeCodec>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    btree: Arc<Mutex<BTree<K, V, C>>>,
+}
+
+
+impl<K, V, C> SyncBTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    pub fn new(btree: BTree<K, V, C>) -> Self {
+        SyncBTree { btree: Arc::new(Mutex::new(btree)) }
+    }
+
+    // Locks the tree. If a thread panicked while it held the lock, the tree may be half modified and
+    // `Error::Corrupt` is returned.
+    pub fn lock(&self) -> Result<MutexGuard<'_, BTree<K, V, C>>> {
+        self.btree.lock().map_err(|_| Error::Corrupt(String::from("a thread panicked while it was using the tree")))
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>> {
+        self.lock()?.get(key)
+    }
+
+    pub fn set(&self, key: K, value: V) -> Result<Option<V>> {
+        self.lock()?.set(key, value)
+    }
+
+    pub fn remove(&self, key: K) -> Result<Option<V>> {
+        self.lock()?.remove(key)
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.lock()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.lock()?.is_empty())
+    }
+}
+
+
+impl<K, V, C> Clone for SyncBTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    fn clone(&self) -> Self {
+        SyncBTree { btree: Arc::clone(&self.btree) }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::error::Result;
+use crate::store::Pages;
+use crate::{db_path, tree_file_path, write_meta};
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+
+// The write-ahead log: the pages written by a single `set`, `remove` or `update` and the metadata
+// after it, written and synced before any of the pages is written to the db file. A crash while
+// the pages are being written leaves a complete log behind, which is replayed when the tree is
+// opened again.
+//
+// Record format, all integers are little endian u64:
+//
+//      "%bpwal%%"                      magic
+//      page_size
+//      page_count
+//      page_count × (page_nr, page)    every page is page_size bytes
+//      meta_size
+//      meta                            the serialized BTree, meta_size bytes
+//      "%commit%"                      commit marker
+//
+// A log without the commit marker was interrupted while it was being written, before the db file
+// was touched: it's ignored.
+const WAL_MAGIC: &[u8; 8] = b"%bpwal%%";
+const WAL_COMMIT: &[u8; 8] = b"%commit%";
+
+
+pub(crate) fn wal_path(directory: &Path, name: &str) -> PathBuf {
+    tree_file_path(directory, name, "wal")
+}
+
+
+// A complete record read from the log.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Transaction {
+    pub(crate) page_size: u64,
+    pub(crate) pages: Pages,
+    pub(crate) meta: Vec<u8>,
+}
+
+
+// Writes the record for `pages` and `meta` to the log and syncs it.
+pub(crate) fn write(directory: &Path, name: &str, page_size: u64, pages: &Pages, meta: &[u8]) -> Result<()> {
+    let mut record = Vec::with_capacity(32 + pages.len() * (8 + page_size as usize) + meta.len() + 8);
+    record.extend_from_slice(WAL_MAGIC);
+    record.extend_from_slice(&page_size.to_le_bytes());
+    record.extend_from_slice(&(pages.len() as u64).to_le_bytes());
+    for (page_nr, page) in pages {
+        assert_eq!(page.len() as u64, page_size, "page {} is not a whole page", page_nr);
+        record.extend_from_slice(&page_nr.to_le_bytes());
+        record.extend_from_slice(page);
+    }
+    record.extend_from_slice(&(meta.len() as u64).to_le_bytes());
+    record.extend_from_slice(meta);
+    record.extend_from_slice(WAL_COMMIT);
+
+    let mut fh = File::create(wal_path(directory, name))?;
+    fh.write_all(&record)?;
+    fh.sync_all()?;
+    Ok(())
+}
+
+
+// Returns the record in the log, or `None` if there's no log or it's empty or incomplete.
+pub(crate) fn read(path: &Path) -> Result<Option<Transaction>> {
+    let mut data = vec![];
+    match File::open(path) {
+        Ok(mut fh) => fh.read_to_end(&mut data)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(parse(&data))
+}
+
+
+fn parse(mut data: &[u8]) -> Option<Transaction> {
+    if take(&mut data, 8)? != WAL_MAGIC {
+        return None;
+    }
+    let page_size = take_u64(&mut data)?;
+    let page_count = take_u64(&mut data)?;
+    let mut pages = Pages::new();
+    for _ in 0..page_count {
+        let page_nr = take_u64(&mut data)?;
+        pages.insert(page_nr, take(&mut data, page_size)?.to_vec());
+    }
+    let meta_size = take_u64(&mut data)?;
+    let meta = take(&mut data, meta_size)?.to_vec();
+    if take(&mut data, 8)? != WAL_COMMIT {
+        return None;
+    }
+    Some(Transaction { page_size, pages, meta })
+}
+
+
+fn take<'a>(data: &mut &'a [u8], len: u64) -> Option<&'a [u8]> {
+    if (data.len() as u64) < len {
+        return None;
+    }
+    let (head, tail) = data.split_at(len as usize);
+    *data = tail;
+    Some(head)
+}
+
+
+fn take_u64(data: &mut &[u8]) -> Option<u64> {
+    take(data, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+
+// Empties the log, after its pages and metadata have been written.
+pub(crate) fn clear(directory: &Path, name: &str) -> Result<()> {
+    let path = wal_path(directory, name);
+    if path.exists() {
+        let fh = OpenOptions::new().write(true).open(&path)?;
+        fh.set_len(0)?;
+        fh.sync_all()?;
+    }
+    Ok(())
+}
+
+
+// Whether the log of tree `name` in `directory` holds anything, even an interrupted record: a change
+// that has to be finished, or discarded, before the tree can be read.
+pub(crate) fn is_pending(directory: &Path, name: &str) -> Result<bool> {
+    match fs::metadata(wal_path(directory, name)) {
+        Ok(metadata) => Ok(metadata.len() > 0),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+
+// Finishes the transaction in the log of tree `name` in `directory`, if there's one: writes its pages
+// to the db file and its metadata, then removes the log. Writing the same pages again is harmless,
+// so a crash during replay is handled by the next replay.
+pub(crate) fn replay(directory: &Path, name: &str) -> Result<()> {
+    let path = wal_path(directory, name);
+    if let Some(transaction) = read(&path)? {
+        let mut fh = OpenOptions::new().write(true).create(true).truncate(false).open(db_path(directory, name))?;
+        for (page_nr, page) in &transaction.pages {
+            fh.seek(SeekFrom::Start(page_nr * transaction.page_size))?;
+            fh.write_all(page)?;
+        }
+        fh.sync_all()?;
+        write_meta(directory, name, &transaction.meta)?;
+    }
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use crate::codec::PageCodec;
+use crate::error::{Error, Result};
+use crate::node::{BTNode, Leaf, PagePtr};
+use crate::{chunk_sizes, largest_fitting, BTree, BTreeBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fmt::Debug, fs, path::Path};
+
+
+// Writes entries that come in sorted by key to an empty tree, each page exactly once, while keeping
+// only a few nodes worth of entries in memory: the entries of the last leaves, and for every level
+// above the leaves the children of its last nodes. It builds every tree that is written bottom-up:
+// `bulk_load`, `build_streaming`, `compact` and `compact_step`.
+//
+// A node is only written once there are more than 2 nodes worth of entries (or children) after it,
+// so that the last nodes of a level can be filled evenly when the last entry comes in: every node is
+// at least half full. With a codec that compresses, a leaf is filled until it fills a page, see
+// `PageCodec::MAX_COMPRESSION_RATIO`.
+#[derive(Debug)]
+pub(crate) struct TreeWriter<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    pub(crate) tree: BTree<K, V, C>,
+    pub(crate) count: u64,
+    // The entries that haven't been written yet.
+    buffer: Vec<(K, V)>,
+    // The page of the next leaf, which is taken when the leaf before it is written.
+    next_leaf_page_nr: Option<PagePtr>,
+    // (first key, page number, number of entries) of the children that don't have a parent yet, for
+    // each level from the leaves up.
+    levels: Vec<Vec<(K, PagePtr, u64)>>,
+}
+
+
+// What a `TreeWriter` keeps in memory, to continue writing its tree later on, see `compact_step`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct WriterState<K, V> {
+    count: u64,
+    buffer: Vec<(K, V)>,
+    next_leaf_page_nr: Option<PagePtr>,
+    levels: Vec<Vec<(K, PagePtr, u64)>>,
+}
+
+
+impl<K, V, C> TreeWriter<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    pub(crate) fn new(tree: BTree<K, V, C>) -> Self {
+        TreeWriter { tree, count: 0, buffer: vec![], next_leaf_page_nr: None, levels: vec![] }
+    }
+
+    // Continues writing `tree` where the writer that returned `state` left off.
+    pub(crate) fn resume(tree: BTree<K, V, C>, state: WriterState<K, V>) -> Self {
+        let WriterState { count, buffer, next_leaf_page_nr, levels } = state;
+        TreeWriter { tree, count, buffer, next_leaf_page_nr, levels }
+    }
+
+    pub(crate) fn state(&self) -> WriterState<K, V> {
+        WriterState {
+            count: self.count,
+            buffer: self.buffer.clone(),
+            next_leaf_page_nr: self.next_leaf_page_nr,
+            levels: self.levels.clone(),
+        }
+    }
+
+    // Adds an entry after the ones added so far. The caller checks the order of the keys.
+    pub(crate) fn push(&mut self, key: K, value: V) -> Result<()> {
+        self.buffer.push((key, value));
+        self.count += 1;
+        if self.buffer.len() > 2 * self.tree.leaf_capacity() as usize {
+            let size = self.full_leaf_size()?;
+            self.write_leaf(size, false)?;
+        }
+        Ok(())
+    }
+
+    // Adds `entries`, which must be sorted by key in strictly ascending order: returns
+    // `Error::UnsortedInput` at the first key that isn't larger than the previous one.
+    pub(crate) fn push_sorted<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+    {
+        let mut last_key: Option<K> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            if last_key.as_ref().is_some_and(|last_key| *last_key >= key) {
+                return Err(Error::UnsortedInput);
+            }
+            last_key = Some(key.clone());
+            self.push(key, value)?;
+        }
+        Ok(())
+    }
+
+    // Writes the entries that are left and the nodes above them, and returns the complete tree. Its
+    // metadata isn't stored, also not when this fails and the tree is dropped.
+    pub(crate) fn finish(mut self) -> Result<BTree<K, V, C>> {
+        let result = self.write_last_nodes();
+        self.tree.dirty = false;
+        result?;
+        self.tree.entry_count = self.count;
+        self.tree.store.close()?;
+        Ok(self.tree)
+    }
+
+    fn write_last_nodes(&mut self) -> Result<()> {
+        if !self.buffer.is_empty() {
+            let sizes = self.last_leaf_sizes()?;
+            for (i, size) in sizes.iter().enumerate() {
+                self.write_leaf(*size, i == sizes.len() - 1)?;
+            }
+        }
+        let mut level = 0;
+        while level < self.levels.len() {
+            let children = self.levels[level].len();
+            if children == 1 && level == self.levels.len() - 1 {
+                self.tree.root_page_nr = self.levels[level][0].1;
+                break;
+            }
+            for size in chunk_sizes(children, self.tree.max_key_count as usize + 1).collect::<Vec<usize>>() {
+                self.write_internal(level, size)?;
+            }
+            level += 1;
+        }
+        Ok(())
+    }
+
+    // Returns how many entries of the buffer go in the next full leaf: `leaf_capacity`, or as many as
+    // fit in a page with a codec that compresses.
+    fn full_leaf_size(&self) -> Result<usize> {
+        let capacity = self.tree.leaf_capacity() as usize;
+        if C::MAX_COMPRESSION_RATIO == 1 {
+            return Ok(capacity);
+        }
+        let leaf = self.leaf_of(&self.buffer[..capacity]);
+        let (tree, page_size) = (&self.tree, self.tree.page_size());
+        largest_fitting(tree.max_leaf_key_count as usize, capacity, |n| leaf.prefix_fits(tree, n, page_size))
+    }
+
+    // Returns the sizes of the last leaves: the entries that are left are spread evenly over as few
+    // leaves as fit them.
+    fn last_leaf_sizes(&self) -> Result<Vec<usize>> {
+        let total = self.buffer.len();
+        let mut leaf_count = total.div_ceil(self.tree.leaf_capacity() as usize);
+        loop {
+            let sizes: Vec<usize> = chunk_sizes(total, total.div_ceil(leaf_count)).collect();
+            if C::MAX_COMPRESSION_RATIO == 1 || self.leaves_fit(&sizes)? {
+                return Ok(sizes);
+            }
+            leaf_count += 1;
+        }
+    }
+
+    // Returns whether the entries of the buffer fit in leaves of `sizes` entries.
+    fn leaves_fit(&self, sizes: &[usize]) -> Result<bool> {
+        let mut start = 0;
+        for &size in sizes {
+            let leaf = self.leaf_of(&self.buffer[start..start + size]);
+            if !leaf.prefix_fits(&self.tree, size, self.tree.page_size())? {
+                return Ok(false);
+            }
+            start += size;
+        }
+        Ok(true)
+    }
+
+    fn leaf_of(&self, entries: &[(K, V)]) -> Leaf<K, V> {
+        let (keys, values): (Vec<K>, Vec<V>) = entries.iter().cloned().unzip();
+        BTNode::new_leaf(0, &keys, &values, Some(0)).leaf_node()
+    }
+
+    // Writes the first `size` entries of the buffer to a leaf, which is the last one if `last` is set.
+    fn write_leaf(&mut self, size: usize, last: bool) -> Result<()> {
+        let page_nr = match self.next_leaf_page_nr.take() {
+            Some(page_nr) => page_nr,
+            None => {
+                let page_nr = self.tree.next_page_nr();
+                self.tree.first_leaf_page_nr = page_nr;
+                page_nr
+            }
+        };
+        if !last {
+            self.next_leaf_page_nr = Some(self.tree.next_page_nr());
+        }
+        let (keys, values): (Vec<K>, Vec<V>) = self.buffer.drain(..size).unzip();
+        self.tree.store_node(BTNode::new_leaf(page_nr, &keys, &values, self.next_leaf_page_nr))?;
+        self.add_child(0, (keys[0].clone(), page_nr, size as u64))
+    }
+
+    // Adds a node to the children waiting for a parent at `level`, and writes a parent when there are
+    // enough of them. The last parents are written by `finish`.
+    fn add_child(&mut self, level: usize, child: (K, PagePtr, u64)) -> Result<()> {
+        if self.levels.len() == level {
+            self.levels.push(vec![]);
+        }
+        self.levels[level].push(child);
+        let fanout = self.tree.max_key_count as usize + 1;
+        match self.levels[level].len() > 2 * fanout {
+            true => self.write_internal(level, fanout),
+            false => Ok(()),
+        }
+    }
+
+    // Writes an internal node with the first `size` children waiting at `level`. Every child but the
+    // first one gives its first key as a separator key.
+    fn write_internal(&mut self, level: usize, size: usize) -> Result<()> {
+        let mut children = self.levels[level].drain(..size);
+        let (first_key, first_page_nr, first_count) = children.next().unwrap();
+        let (mut keys, mut entries, mut counts) = (vec![], vec![first_page_nr], vec![first_count]);
+        for (key, page_nr, count) in children {
+            keys.push(key);
+            entries.push(page_nr);
+            counts.push(count);
+        }
+        let page_nr = self.tree.next_page_nr();
+        self.tree.store_node(BTNode::new_internal(page_nr, &keys, &entries, &counts))?;
+        self.add_child(level + 1, (first_key, page_nr, counts.iter().sum()))
+    }
+}
+
+
+impl<K, V, C> BTree<K, V, C>
+where
+    K: Debug + Default + Clone + Ord + Serialize + DeserializeOwned,
+    V: Debug + Default + Clone + Serialize + DeserializeOwned,
+    C: PageCodec<K, V>,
+{
+    // Builds a new BTree in `directory` from `entries` like `bulk_load`, without collecting them in
+    // memory: every leaf is written as soon as the entries after it show how to fill it, and so are
+    // the internal nodes above it. Only a few nodes per level are kept in memory, so this works for
+    // more entries than fit in memory, read from a file for example.
+    //
+    // The keys must be in strictly ascending order. Stops at the first error of `entries`, or with
+    // `Error::UnsortedInput` at the first key that isn't larger than the previous one. No metadata
+    // is written then, so `directory` doesn't contain a usable tree. `directory` must not already
+    // contain a BTree. Use `BTreeBuilder::build_streaming` for other options.
+    pub fn build_streaming<P, I>(directory: P, entries: I, override_max_key_count: Option<u64>) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = Result<(K, V)>>,
+    {
+        BTreeBuilder::new(directory.as_ref()).max_key_count(override_max_key_count).build_streaming(entries)
+    }
+
+    // See `BTreeBuilder::build_streaming`.
+    pub(crate) fn build_streaming_with<I>(
+        directory: &Path,
+        name: &str,
+        override_max_key_count: Option<u64>,
+        page_size: u64,
+        fill_factor: f64,
+        entries: I,
+    ) -> Result<Self>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+    {
+        fs::create_dir_all(directory)?;
+        let btree = Self::new(directory, name, override_max_key_count, page_size, fill_factor)?;
+        let mut writer = TreeWriter::new(btree);
+        if let Err(err) = writer.push_sorted(entries) {
+            // Don't store the metadata of the partial tree when it's dropped.
+            writer.tree.dirty = false;
+            return Err(err);
+        }
+        let mut btree = writer.finish()?;
+        btree.dirty = true;
+        btree.flush()?;
+        Ok(btree)
+    }
+}
@@ -3,10 +3,19 @@
 #![allow(unused_imports)]
 
 // use assert_cmd::prelude::*;
-use bptree::{BTNode, BTree, Result};
+use bptree::{
+    BTNode, BTree, BincodeCodec, Cursor, DiskUsage, Error, ErrorKind, FreePages, PageAllocation, PageAllocator, PageCodec,
+    PageStats, PagePtr, PersistentMap, Result, RootInfo, SyncBTree,
+};
 // use predicates::ord::eq;
 // use predicates::str::{contains, is_empty, PredicateStrExt};
 // use std::process::Command;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
 use tempfile::TempDir;
 // use walkdir::WalkDir;
 
@@ -14,7 +23,7 @@ use tempfile::TempDir;
 fn dump_btree(bt: &mut BTree<u128, u128>) -> Result<()> {
     println!("==== BTree");
     println!("{:?}", bt);
-    bt.root()?.dump(bt)?;
+    bt.dump()?;
     println!("====");
     Ok(())
 }
@@ -35,7 +44,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(btree);
-    let mut btree = BTree::open(temp_dir.path(), None)?;
+    let btree = BTree::open(temp_dir.path(), None)?;
     assert_eq!(btree.get(1)?, Some(1000));
     assert_eq!(btree.get(2)?, Some(2000));
 
@@ -64,7 +73,7 @@ fn get_stored_value_from_multiple_pages() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(btree);
-    let mut btree = BTree::open(temp_dir.path(), None)?;
+    let btree = BTree::open(temp_dir.path(), None)?;
     assert_eq!(n - 1, btree.len() as u128);
     for i in 1..n {
         assert_eq!(btree.get(i)?, Some(i * 10));
@@ -111,7 +120,7 @@ fn remove_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(btree);
-    let mut btree = BTree::open(temp_dir.path(), None)?;
+    let btree = BTree::open(temp_dir.path(), None)?;
     assert_eq!(btree.get(1)?, None);
     assert_eq!(btree.get(2)?, Some(2000));
 
@@ -156,11 +165,11 @@ fn remove_stored_value_from_multiple_pages() -> Result<()> {
     println!("{:?}", btree);
     let root = btree.root()?;
     println!("{:?}", root);
-    root.dump(&mut btree)?;
+    btree.dump()?;
 
     // Open from disk again and check persistent data.
     drop(btree);
-    let mut btree = BTree::open(temp_dir.path(), None)?;
+    let btree = BTree::open(temp_dir.path(), None)?;
     assert_eq!((n - 1) - count, btree.len() as u128, "{:?}", btree);
     for i in 1..start {
         assert_eq!(btree.get(i)?, Some(i * 10));
@@ -174,3 +183,2967 @@ fn remove_stored_value_from_multiple_pages() -> Result<()> {
 
     Ok(())
 }
+
+
+#[test]
+fn floor_and_ceil() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+
+    assert_eq!(btree.floor(10)?, None);
+    assert_eq!(btree.ceil(10)?, None);
+
+    // keys 10, 20, ..., 1000 spread over many leaves
+    let n = 100_u128;
+    for i in 1..=n {
+        btree.set(i * 10, i * 100)?;
+    }
+
+    // Every query point, including the ones that fall between two leaves.
+    for q in 0..=(n * 10 + 10) {
+        let floor = match q / 10 {
+            0 => None,
+            i if i > n => Some((n * 10, n * 100)),
+            i => Some((i * 10, i * 100)),
+        };
+        assert_eq!(btree.floor(q)?, floor, "floor({})", q);
+        let ceil = match q.div_ceil(10) {
+            i if i > n => None,
+            0 => Some((10, 100)),
+            i => Some((i * 10, i * 100)),
+        };
+        assert_eq!(btree.ceil(q)?, ceil, "ceil({})", q);
+    }
+
+    // The entries are found in a single descent, also when they're in the leaf before or after the
+    // one on the path: `floor` followed by `get` would read at least twice the depth.
+    let depth = btree.get_with_path(10)?.1.len() as u64;
+    for q in 0..=(n * 10 + 10) {
+        btree.reset_page_stats();
+        btree.floor(q)?;
+        assert!(btree.page_stats().reads < 2 * depth, "floor({})", q);
+        btree.reset_page_stats();
+        btree.ceil(q)?;
+        assert!(btree.page_stats().reads <= depth + 1, "ceil({})", q);
+    }
+
+    Ok(())
+}
+
+
+// Lookups and iteration only need a shared reference.
+fn lookup_all(btree: &BTree<u128, u128>, n: u128) -> Result<()> {
+    for i in 1..n {
+        assert!(btree.contains_key(i)?);
+        assert_eq!(btree.get(i)?, Some(i * 10));
+    }
+    assert!(!btree.contains_key(n)?);
+    assert!(btree.keys().zip(1..n).all(|(i, j)| i == j));
+    Ok(())
+}
+
+
+#[test]
+fn read_through_shared_reference() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+
+    let n = 100_u128;
+    for i in 1..n {
+        btree.set(i, i * 10)?;
+    }
+    lookup_all(&btree, n)?;
+
+    // Also when the db file has not been opened yet.
+    drop(btree);
+    let btree = BTree::open(temp_dir.path(), Some(4))?;
+    lookup_all(&btree, n)?;
+
+    Ok(())
+}
+
+
+#[test]
+fn iterate_from_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.iter_from(0).count(), 0);
+
+    // keys 10, 20, ..., 1000
+    let n = 100_u128;
+    for i in 1..=n {
+        btree.set(i * 10, i * 100)?;
+    }
+
+    assert!(btree.iter_from(0).eq((1..=n).map(|i| i * 10)));
+    assert!(btree.iter_from(500).eq((50..=n).map(|i| i * 10)));
+    assert!(btree.iter_from(501).eq((51..=n).map(|i| i * 10)));
+    assert_eq!(btree.iter_from(1001).count(), 0);
+
+    // Pagination: a page of 7 keys after each cursor.
+    for cursor in 0..=(n * 10) {
+        let page: Vec<u128> = btree.iter_from(cursor + 1).take(7).collect();
+        let expected: Vec<u128> = (1..=n).map(|i| i * 10).filter(|k| *k > cursor).take(7).collect();
+        assert_eq!(page, expected, "cursor {}", cursor);
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn bulk_load_sorted_entries() -> Result<()> {
+    for (n, max_key_count) in [(0_u128, 4), (1, 4), (4, 4), (5, 4), (1000, 4), (1000, 5), (1000, 3), (10_000, 100)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let btree = BTree::bulk_load(temp_dir.path(), (1..=n).map(|i| (i, i * 10)), Some(max_key_count))?;
+        btree.verify()?;
+        assert_eq!(btree.len() as u128, n);
+        assert!(btree.keys().eq(1..=n));
+        for i in 1..=n {
+            assert_eq!(btree.get(i)?, Some(i * 10));
+        }
+
+        // The tree is a regular tree: it can be reopened and modified.
+        drop(btree);
+        let mut btree = BTree::open(temp_dir.path(), None)?;
+        for i in (1..=n).step_by(3) {
+            assert_eq!(btree.remove(i)?, Some(i * 10));
+        }
+        btree.set(n + 1, 0)?;
+        btree.verify()?;
+        assert!(btree.keys().eq((1..=n + 1).filter(|i| i > &n || (i - 1) % 3 != 0)));
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn bulk_load_unsorted_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let result = BTree::<u128, u128>::bulk_load(temp_dir.path(), vec![(1, 10), (3, 30), (2, 20)], None);
+    assert!(matches!(result, Err(Error::UnsortedInput)));
+    let result = BTree::<u128, u128>::bulk_load(temp_dir.path(), vec![(1, 10), (1, 20)], None);
+    assert!(matches!(result, Err(Error::UnsortedInput)));
+
+    // Nothing usable has been left behind.
+    let btree = BTree::<u128, u128>::open(temp_dir.path(), None)?;
+    assert!(btree.is_empty());
+
+    Ok(())
+}
+
+
+#[test]
+fn verify_after_inserts_and_removes() -> Result<()> {
+    for max_key_count in 3..=5 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut btree = BTree::open(temp_dir.path(), Some(max_key_count))?;
+        btree.verify()?;
+
+        // Insert and remove in a scrambled order, so that keys end up in all positions of a node.
+        let n = 500_u128;
+        let key = |i: u128| (i * 7919) % n;
+        for i in 0..n {
+            btree.set(key(i), i)?;
+            btree.verify()?;
+        }
+        for i in 0..n {
+            assert_eq!(btree.remove(key(i))?, Some(i));
+            btree.verify()?;
+            if i % 50 == 0 {
+                assert!(btree.keys().eq((i + 1..n).map(key).collect::<BTreeSet<_>>().into_iter()));
+            }
+        }
+        assert!(btree.is_empty());
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn values_in_overflow_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), Some(4))?;
+
+    // From empty up to 10 KB, so values take 0 up to 3 overflow pages.
+    let value = |i: u64| vec![i as u8; (i * 997 % 10_000) as usize];
+    let n = 100;
+    for i in 0..n {
+        btree.set(i, value(i))?;
+    }
+    btree.verify()?;
+    for i in 0..n {
+        assert_eq!(btree.get(i)?, Some(value(i)));
+    }
+    assert!(btree.values().eq((0..n).map(value)));
+    assert_eq!(btree.floor(n)?, Some((n - 1, value(n - 1))));
+    // The keys are read without the overflow pages.
+    btree.reset_page_stats();
+    assert!(btree.range_keys(10..20).eq(10..20));
+    let key_reads = btree.page_stats().reads;
+    assert!(btree.range(10..20).map(|(key, _)| key).eq(10..20));
+    assert!(btree.page_stats().reads - key_reads > key_reads);
+    assert_eq!(btree.ceil(0)?, Some((0, value(0))));
+
+    // Overwriting or removing a value frees its overflow pages.
+    for i in 0..n / 2 {
+        assert_eq!(btree.set(i, value(i + 1))?, Some(value(i)));
+    }
+    for i in n / 2..n {
+        assert_eq!(btree.remove(i)?, Some(value(i)));
+    }
+    btree.verify()?;
+
+    drop(btree);
+    let btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), None)?;
+    btree.verify()?;
+    for i in 0..n / 2 {
+        assert_eq!(btree.get(i)?, Some(value(i + 1)));
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn variable_length_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let n = 2_000_u64;
+    // Lengths from empty up to a few hundred bytes, so most values are inline and leaves hold
+    // fewer of the longer ones.
+    let bytes = |i: u64| vec![(i % 251) as u8; (i * 37 % 300) as usize];
+    let string = |i: u64| "é".repeat((i * 53 % 200) as usize) + &i.to_string();
+
+    for max_key_count in [Some(4), None] {
+        let directory = temp_dir.path().join(format!("{:?}", max_key_count));
+        let mut vecs: BTree<u64, Vec<u8>> =
+            BTree::builder(&directory).name("vecs").max_key_count(max_key_count).open()?;
+        let mut strings: BTree<u64, String> =
+            BTree::builder(&directory).name("strings").max_key_count(max_key_count).open()?;
+        // In an order that splits leaves in the middle as well as at the end.
+        for i in (0..n).map(|i| i * 7 % n) {
+            vecs.set(i, bytes(i))?;
+            strings.set(i, string(i))?;
+        }
+        // The leaves were split: there's at least one internal level.
+        assert!(vecs.get_with_path(0)?.1.len() > 1 && strings.get_with_path(0)?.1.len() > 1);
+        vecs.verify()?;
+        strings.verify()?;
+        drop(vecs);
+        drop(strings);
+
+        let vecs: BTree<u64, Vec<u8>> = BTree::builder(&directory).name("vecs").create(false).open()?;
+        let strings: BTree<u64, String> = BTree::builder(&directory).name("strings").create(false).open()?;
+        vecs.verify()?;
+        strings.verify()?;
+        assert!(vecs.entries().eq((0..n).map(|i| (i, bytes(i)))));
+        assert!(strings.entries().eq((0..n).map(|i| (i, string(i)))));
+        assert_eq!(vecs.get(n / 2)?, Some(bytes(n / 2)));
+        assert_eq!(strings.get(n - 1)?, Some(string(n - 1)));
+    }
+
+    Ok(())
+}
+
+
+// 2 KiB: too large to store values in the leaves, so they always go to overflow pages.
+type Blob = ([u64; 32], [u64; 32], [u64; 32], [u64; 32], [u64; 32], [u64; 32], [u64; 32], [u64; 32]);
+
+
+#[test]
+fn large_value_type() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob = |i: u64| {
+        let mut blob = Blob::default();
+        blob.3[i as usize % 32] = i;
+        blob
+    };
+    let n = 1000;
+    let mut btree: BTree<u64, Blob> = BTree::bulk_load(temp_dir.path(), (0..n).map(|i| (i, blob(i))), None)?;
+    btree.verify()?;
+    for i in n..n + 100 {
+        btree.set(i, blob(i))?;
+    }
+    btree.verify()?;
+    for i in 0..n + 100 {
+        assert_eq!(btree.get(i)?, Some(blob(i)));
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn update_in_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+    assert!(!btree.update(1, |v: &mut u128| *v += 1)?);
+
+    let n = 100_u128;
+    for i in 0..n {
+        btree.set(i, 0)?;
+    }
+    for round in 1..=3 {
+        for i in 0..n {
+            assert!(btree.update(i, |v| *v += i)?);
+        }
+        assert!(btree.values().eq((0..n).map(|i| i * round)));
+    }
+    assert!(!btree.update(n, |_| panic!("not called for a missing key"))?);
+    assert_eq!(btree.len() as u128, n);
+    btree.verify()?;
+
+    // Values can grow into (and shrink out of) overflow pages.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), Some(4))?;
+    for i in 0..10 {
+        btree.set(i, vec![])?;
+    }
+    for _ in 0..3 {
+        assert!(btree.update(5, |v| v.extend(vec![5; 3000]))?);
+    }
+    assert_eq!(btree.get(5)?, Some(vec![5; 9000]));
+    btree.verify()?;
+    assert!(btree.update(5, |v| v.truncate(1))?);
+    assert_eq!(btree.get(5)?, Some(vec![5]));
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn replace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.replace(1, vec![1])?, None);
+    assert!(btree.is_empty());
+
+    for i in 0..100 {
+        btree.set(i * 2, vec![])?;
+    }
+    btree.reset_page_stats();
+    assert_eq!(btree.replace(11, vec![1])?, None);
+    assert_eq!(btree.page_stats().writes, 0);
+    assert_eq!(btree.replace(10, vec![10; 5000])?, Some(vec![]));
+    assert_eq!(btree.replace(10, vec![1])?, Some(vec![10; 5000]));
+    assert_eq!(btree.get(10)?, Some(vec![1]));
+    assert_eq!(btree.len(), 100);
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("map"), Some(4))?;
+    for i in (0..200).rev() {
+        assert!(btree.set_if_absent(i, i)?);
+    }
+    btree.reset_page_stats();
+    for i in 0..200 {
+        assert!(!btree.set_if_absent(i, 0)?);
+    }
+    assert_eq!(btree.page_stats().writes, 0);
+    assert_eq!(btree.len(), 200);
+    assert!(btree.entries().all(|(key, value)| key == value));
+    btree.verify()?;
+
+    let mut multimap: BTree<u64, u64> = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    for i in 0..50 {
+        multimap.set(i, 1)?;
+        multimap.set(i, 2)?;
+    }
+    assert!(!multimap.set_if_absent(10, 3)?);
+    assert!(multimap.set_if_absent(100, 3)?);
+    assert_eq!(multimap.get_all(10)?, vec![1, 2]);
+    assert_eq!(multimap.len(), 101);
+    multimap.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn range_of_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.range(..).count(), 0);
+
+    // keys 10, 20, ..., 500
+    let expected: BTreeMap<u128, u128> = (1..=50).map(|i| (i * 10, i * 100)).collect();
+    for (k, v) in expected.iter() {
+        btree.set(*k, *v)?;
+    }
+    assert!(btree.entries().eq(expected.clone().into_iter()));
+
+    let bounds = |q: u128| vec![Bound::Included(q), Bound::Excluded(q), Bound::Unbounded];
+    for start in (0..=510).step_by(5) {
+        for end in (start..=510).step_by(35) {
+            for (lower, upper) in bounds(start).into_iter().flat_map(|l| bounds(end).into_iter().map(move |u| (l, u))) {
+                if lower == Bound::Excluded(start) && upper == Bound::Excluded(end) && start == end {
+                    continue; // std panics on this one
+                }
+                let range: Vec<(u128, u128)> = btree.range((lower, upper)).collect();
+                let std_range: Vec<(u128, u128)> = expected.range((lower, upper)).map(|(k, v)| (*k, *v)).collect();
+                assert_eq!(range, std_range, "{:?}..{:?}", lower, upper);
+                let keys: Vec<u128> = btree.range_keys((lower, upper)).collect();
+                assert_eq!(keys, std_range.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+                let values: Vec<u128> = btree.range_values((lower, upper)).collect::<Result<_>>()?;
+                assert_eq!(values, std_range.into_iter().map(|(_, v)| v).collect::<Vec<_>>());
+            }
+            assert_eq!(btree.count_range(start, end)?, expected.range(start..end).count(), "{}..{}", start, end);
+        }
+    }
+    assert_eq!(btree.count_range(300, 200)?, 0);
+    assert_eq!(btree.estimate_range_count(15, 495)?, btree.count_range(15, 495)?);
+    assert_eq!(btree.estimate_range_count(300, 200)?, 0);
+
+    // Only the pages on the paths to both ends are read.
+    let (_, path) = btree.get_with_path(10)?;
+    btree.reset_page_stats();
+    assert_eq!(btree.count_range(15, 495)?, 48);
+    assert_eq!(btree.page_stats().reads, 2 * path.len() as u64);
+
+    Ok(())
+}
+
+
+#[test]
+fn range_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, String>::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.range_values(..).count(), 0);
+    for i in 1..=100 {
+        btree.set(i * 10, i.to_string())?;
+    }
+    let values = |btree: &BTree<u64, String>, range: (Bound<u64>, Bound<u64>)| -> Result<Vec<u64>> {
+        btree.range_values(range).map(|value| Ok(value?.parse().unwrap())).collect()
+    };
+    assert_eq!(values(&btree, (Bound::Included(20), Bound::Included(50)))?, vec![2, 3, 4, 5]);
+    assert_eq!(values(&btree, (Bound::Excluded(20), Bound::Excluded(50)))?, vec![3, 4]);
+    assert_eq!(values(&btree, (Bound::Included(15), Bound::Excluded(45)))?, vec![2, 3, 4]);
+    assert_eq!(values(&btree, (Bound::Unbounded, Bound::Excluded(30)))?, vec![1, 2]);
+    assert_eq!(values(&btree, (Bound::Excluded(980), Bound::Unbounded))?, vec![99, 100]);
+    assert_eq!(values(&btree, (Bound::Unbounded, Bound::Unbounded))?, (1..=100).collect::<Vec<_>>());
+
+    // Empty ranges: between two keys, before the first and after the last key.
+    assert_eq!(values(&btree, (Bound::Included(21), Bound::Included(29)))?, vec![]);
+    assert_eq!(values(&btree, (Bound::Excluded(20), Bound::Excluded(30)))?, vec![]);
+    assert_eq!(values(&btree, (Bound::Unbounded, Bound::Excluded(10)))?, vec![]);
+    assert_eq!(values(&btree, (Bound::Excluded(1000), Bound::Unbounded))?, vec![]);
+
+    // A leaf that can't be read is an error, after which the iterator ends.
+    btree.flush()?;
+    let page_size = btree.page_size();
+    std::fs::OpenOptions::new().write(true).open(temp_dir.path().join("db"))?.set_len(2 * page_size)?;
+    let mut results: Vec<Result<String>> = btree.range_values(..).collect();
+    assert!(results.pop().unwrap().is_err());
+    assert!(results.into_iter().all(|value| value.is_ok()));
+
+    Ok(())
+}
+
+
+#[test]
+fn concurrent_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(8))?;
+    let n = 1000_u128;
+    for i in 0..n {
+        btree.set(i, i * 10)?;
+    }
+
+    let reader = Arc::new(btree.reader());
+    let handles: Vec<_> = (0..4_u128)
+        .map(|t| {
+            // Threads 0 and 1 share a handle, the others have their own clone.
+            let reader = match t {
+                0 | 1 => Arc::clone(&reader),
+                _ => Arc::new(reader.as_ref().clone()),
+            };
+            thread::spawn(move || -> Result<()> {
+                for i in (t..n).step_by(4) {
+                    assert_eq!(reader.get(i)?, Some(i * 10));
+                }
+                assert!(reader.range(t * 100..).map(|(k, _)| k).eq(t * 100..n));
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // The tree itself can be shared as well.
+    thread::scope(|scope| {
+        for t in 0..4_u128 {
+            let btree = &btree;
+            scope.spawn(move || lookup_all_from(btree, t));
+        }
+    });
+
+    // The writer can continue, the reader still sees the old version.
+    btree.set(n, 0)?;
+    assert_eq!(reader.len() as u128, n);
+    assert_eq!(btree.reader().get(n)?, Some(0));
+
+    Ok(())
+}
+
+
+fn lookup_all_from(btree: &BTree<u128, u128>, start: u128) {
+    for i in start..btree.len() as u128 {
+        assert_eq!(btree.get(i).unwrap(), Some(i * 10));
+    }
+}
+
+
+#[test]
+fn error_kinds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = BTree::<u128, u128>::bulk_load(temp_dir.path(), vec![(2, 20), (1, 10)], None).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsortedInput);
+    assert_ne!(err.kind(), ErrorKind::Io);
+    assert_eq!(std::io::Error::from(err).kind(), std::io::ErrorKind::InvalidInput);
+
+    let err = BTree::<u128, u128>::open_with_page_size(temp_dir.path(), None, 1000).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+
+    // Wrapped I/O errors come out unchanged.
+    let err = Error::from(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied"));
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert_eq!(std::io::Error::from(err).kind(), std::io::ErrorKind::PermissionDenied);
+
+    Ok(())
+}
+
+
+#[test]
+fn missing_key_as_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), None)?;
+    assert!(btree.get_or_err(1).unwrap_err().is_key_not_found());
+
+    btree.set(1, 1000)?;
+    assert_eq!(btree.get_or_err(1)?, 1000);
+    assert_eq!(btree.get_or_err(2).unwrap_err().kind(), ErrorKind::KeyNotFound);
+
+    assert_eq!(btree.remove_or_err(1)?, 1000);
+    assert!(btree.remove_or_err(1).unwrap_err().is_key_not_found());
+    assert_eq!(btree.len(), 0);
+
+    Ok(())
+}
+
+
+#[test]
+fn reopen_after_removing_everything() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+    let n = 100_u128;
+    for i in 0..n {
+        btree.set(i, i * 10)?;
+    }
+    drop(btree);
+
+    let mut btree = BTree::open(temp_dir.path(), None)?;
+    for i in 0..n {
+        assert_eq!(btree.remove(i)?, Some(i * 10));
+    }
+    assert!(btree.is_empty());
+    drop(btree);
+
+    let mut btree = BTree::open(temp_dir.path(), None)?;
+    assert_eq!(btree.len(), 0);
+    assert_eq!(btree.get(1)?, None);
+    btree.verify()?;
+    for i in 0..n {
+        btree.set(i, i)?;
+    }
+    btree.verify()?;
+    drop(btree);
+
+    let btree = BTree::open(temp_dir.path(), None)?;
+    assert!(btree.entries().eq((0..n).map(|i| (i, i))));
+
+    Ok(())
+}
+
+
+#[test]
+fn compact_after_removes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_size = || std::fs::metadata(temp_dir.path().join("db")).map(|m| m.len()).unwrap_or(0);
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), Some(4))?;
+    let value = |i: u64| vec![i as u8; (i % 7) as usize * 1000];
+    let n = 1000;
+    for i in 0..n {
+        btree.set(i, value(i))?;
+    }
+    for i in (0..n).filter(|i| i % 10 != 0) {
+        btree.remove(i)?;
+    }
+    let size_before = db_size();
+    let reader = btree.reader();
+
+    btree.compact()?;
+    btree.verify()?;
+    assert!(db_size() < size_before / 5, "{} -> {}", size_before, db_size());
+    assert!(btree.entries().eq((0..n).step_by(10).map(|i| (i, value(i)))));
+
+    // The compacted tree can be modified and reopened; old readers still see the old file.
+    for i in n..n + 100 {
+        btree.set(i, value(i))?;
+    }
+    btree.verify()?;
+    assert_eq!(reader.get(10)?, Some(value(10)));
+    drop(btree);
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), None)?;
+    btree.verify()?;
+    assert_eq!(btree.len(), 200);
+
+    // Compacting an empty tree removes all pages.
+    for i in btree.keys().collect::<Vec<_>>() {
+        btree.remove(i)?;
+    }
+    btree.compact()?;
+    assert_eq!(db_size(), 0);
+    btree.set(1, value(1))?;
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn compact_in_steps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_size = || std::fs::metadata(temp_dir.path().join("db")).map(|m| m.len()).unwrap_or(0);
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), Some(4))?;
+    let value = |i: u64| vec![i as u8; (i % 7) as usize * 1000];
+    let n = 1000;
+    btree.extend((0..n).map(|i| (i, value(i))));
+    btree.retain(|key, _| key % 10 == 0)?;
+    let size_before = db_size();
+
+    let mut steps = 0;
+    while btree.compact_step(10)? {
+        // The tree is read in between, from the current db file.
+        assert_eq!(btree.get(500)?, Some(value(500)));
+        steps += 1;
+    }
+    assert!(steps > 5, "{} steps", steps);
+    btree.verify()?;
+    assert!(db_size() < size_before / 5, "{} -> {}", size_before, db_size());
+    assert!(btree.entries().eq((0..n).step_by(10).map(|i| (i, value(i)))));
+    assert!(!temp_dir.path().join("compact").exists());
+
+    // A change after the entries copied so far is copied with the rest.
+    btree.retain(|key, _| key % 20 == 0)?;
+    assert!(btree.compact_step(10)?);
+    btree.set(n, value(n))?;
+    while btree.compact_step(10)? {}
+    btree.verify()?;
+    assert!(btree.entries().eq((0..=n).step_by(20).map(|i| (i, value(i)))));
+    drop(btree);
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), None)?;
+    btree.verify()?;
+    assert_eq!(btree.len(), 51);
+
+    btree.retain(|_, _| false)?;
+    assert!(!btree.compact_step(10)?);
+    assert_eq!(db_size(), 0);
+
+    Ok(())
+}
+
+
+#[test]
+fn compact_in_steps_with_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |i: u64| vec![i as u8; (i % 5) as usize * 300];
+    let n = 3000;
+    let fill = |directory: &Path| -> Result<BTree<u64, Vec<u8>>> {
+        let mut btree = BTree::open(directory, Some(4))?;
+        btree.extend((0..n).map(|i| (i, value(i))));
+        btree.retain(|key, _| key % 3 == 0)?;
+        Ok(btree)
+    };
+    let mut btree = fill(&temp_dir.path().join("unchanged"))?;
+    let mut unchanged_steps = 1;
+    while btree.compact_step(10)? {
+        unchanged_steps += 1;
+    }
+
+    let directory = temp_dir.path().join("changed");
+    let mut btree = fill(&directory)?;
+    let mut expected: BTreeMap<u64, Vec<u8>> = btree.entries().collect();
+    let mut steps = 1;
+    while btree.compact_step(10)? {
+        // Entries that have been copied already, entries that haven't and new ones after the last.
+        let key = steps * 97 % n;
+        btree.set(key, value(key + 1))?;
+        expected.insert(key, value(key + 1));
+        let key = steps * 61 % n;
+        assert_eq!(btree.remove(key)?, expected.remove(&key));
+        btree.set(n + steps, value(steps))?;
+        expected.insert(n + steps, value(steps));
+        if steps == 20 {
+            // The progress is stored with the tree.
+            drop(btree);
+            assert!(directory.join("compact").join("progress").exists());
+            btree = BTree::open(&directory, None)?;
+        }
+        steps += 1;
+        // Every step makes progress: the compaction doesn't start over.
+        assert!(steps < 2 * unchanged_steps, "{} steps, {} without changes", steps, unchanged_steps);
+    }
+    assert!(steps > 20);
+    btree.verify()?;
+    assert!(btree.entries().eq(expected.clone()));
+    assert!(!directory.join("compact").exists());
+    drop(btree);
+
+    let btree: BTree<u64, Vec<u8>> = BTree::open(&directory, None)?;
+    btree.verify()?;
+    assert!(btree.entries().eq(expected));
+
+    Ok(())
+}
+
+
+#[test]
+fn multimap() -> Result<()> {
+    for max_key_count in 3..=5 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut btree = BTree::open_multimap(temp_dir.path(), Some(max_key_count))?;
+        let mut expected: BTreeMap<u128, Vec<u128>> = BTreeMap::new();
+
+        // Few distinct keys with many values each, so that equal keys span several leaves.
+        let n = 300_u128;
+        let key = |i: u128| (i * 7919) % 13;
+        for i in 0..n {
+            assert_eq!(btree.set(key(i), i)?, None);
+            expected.entry(key(i)).or_default().push(i);
+            btree.verify()?;
+        }
+        assert_eq!(btree.len(), n as usize);
+        for (k, values) in &expected {
+            assert_eq!(btree.get(*k)?, Some(values[0]));
+            assert_eq!(&btree.get_all(*k)?, values);
+            assert_eq!(btree.ceil(*k)?, Some((*k, values[0])));
+            assert_eq!(btree.floor(*k)?, Some((*k, *values.last().unwrap())));
+        }
+        assert!(btree.get_all(13)?.is_empty());
+        let all: Vec<(u128, u128)> = expected.iter().flat_map(|(k, vs)| vs.iter().map(move |v| (*k, *v))).collect();
+        assert!(btree.entries().eq(all.iter().cloned()));
+
+        // `remove` removes the first value of a key.
+        for i in 0..n {
+            let values = expected.get_mut(&key(i)).unwrap();
+            assert_eq!(btree.remove(key(i))?, Some(values.remove(0)));
+            btree.verify()?;
+            assert_eq!(btree.get(key(i))?, values.first().cloned());
+        }
+        assert!(btree.is_empty());
+
+        // The tree is reopened as a multimap.
+        btree.set(1, 1)?;
+        btree.set(1, 2)?;
+        drop(btree);
+        let btree: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+        assert_eq!(btree.get_all(1)?, vec![1, 2]);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), None)?;
+    btree.set(1_u64, 1_u64)?;
+    drop(btree);
+    let result = BTree::<u64, u64>::open_multimap(temp_dir.path(), None);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidConfiguration);
+
+    Ok(())
+}
+
+
+#[cfg(feature = "csv")]
+#[test]
+fn export_and_import_csv() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, String> = BTree::open(temp_dir.path().join("a"), Some(4))?;
+    for i in 0..100 {
+        btree.set(i, format!("value, {}", i))?;
+    }
+    let mut buffer = vec![];
+    btree.export_csv(&mut buffer)?;
+    assert!(buffer.starts_with(b"0,\"value, 0\"\n1,\"value, 1\"\n"));
+
+    let mut copy: BTree<u64, String> = BTree::open(temp_dir.path().join("b"), Some(4))?;
+    assert_eq!(copy.import_csv(&buffer[..])?, 100);
+    copy.verify()?;
+    assert!(copy.entries().eq(btree.entries()));
+
+    let err = copy.import_csv(&b"1,one\ntwo,2\n"[..]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Csv);
+    assert!(std::error::Error::source(&err).is_some());
+    assert_eq!(copy.get(1)?, Some(String::from("one")));
+
+    Ok(())
+}
+
+
+#[test]
+fn flush_without_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Without a lock, which would only be released when the process ends.
+    let mut btree = BTree::builder(temp_dir.path()).max_key_count(4).lock(false).open()?;
+    for i in 0..100_u64 {
+        btree.set(i, i * 10)?;
+    }
+    btree.flush()?;
+    for i in 0..50_u64 {
+        btree.remove(i)?;
+    }
+    btree.flush()?;
+
+    // Like a process that is killed: the tree is never dropped.
+    std::mem::forget(btree);
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path(), None)?;
+    btree.verify()?;
+    assert!(btree.entries().eq((50..100).map(|i| (i, i * 10))));
+
+    Ok(())
+}
+
+
+#[test]
+fn scan_with_read_ahead() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.scan_buffered(8).count(), 0);
+
+    // Scrambled inserts and removes, so that the leaves are not in consecutive pages.
+    let n = 1000_u64;
+    for i in 0..n {
+        btree.set((i * 7919) % n, i)?;
+    }
+    for i in (0..n).step_by(3) {
+        btree.remove(i)?;
+    }
+    for lookahead in [0, 1, 3, 64, 10_000] {
+        assert!(btree.scan_buffered(lookahead).eq(btree.entries()));
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn retain_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path().join("map"), Some(4))?;
+    let n = 500_u64;
+    for i in 0..n {
+        btree.set((i * 7919) % n, i)?;
+    }
+    assert_eq!(btree.retain(|k, _| k % 3 == 0)?, 333);
+    btree.verify()?;
+    assert!(btree.keys().eq((0..n).step_by(3)));
+    assert_eq!(btree.retain(|_, _| true)?, 0);
+
+    // Removing everything collapses the root, after which the tree can be filled again.
+    assert_eq!(btree.retain(|_, _| false)?, 167);
+    btree.verify()?;
+    assert!(btree.is_empty());
+    assert_eq!(btree.keys().count(), 0);
+    btree.set(1, 1)?;
+    btree.verify()?;
+
+    // In a multimap, the order of the values that are kept doesn't change.
+    let mut btree = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    for i in 0..n {
+        btree.set(i % 7, i)?;
+    }
+    assert_eq!(btree.retain(|k, v| k % 2 == 0 || v % 5 == 0)?, 171);
+    btree.verify()?;
+    for k in 0..7 {
+        let expected: Vec<u64> = (k..n).step_by(7).filter(|v| k % 2 == 0 || v % 5 == 0).collect();
+        assert_eq!(btree.get_all(k)?, expected);
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn extract_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |i: u64| "x".repeat((i as usize % 4) * 2000);
+    let mut btree: BTree<u64, String> = BTree::open(temp_dir.path().join("map"), Some(4))?;
+    btree.extend((0..300).rev().map(|i| (i, value(i))));
+    assert_eq!(btree.extract_if(|_, _| false)?, vec![]);
+
+    // A range of keys empties whole leaves.
+    let extracted = btree.extract_if(|k, _| (100..200).contains(k) || k % 10 == 0)?;
+    let expected: Vec<(u64, String)> =
+        (0..300).filter(|k| (100..200).contains(k) || k % 10 == 0).map(|k| (k, value(k))).collect();
+    assert_eq!(extracted, expected);
+    btree.verify()?;
+    assert_eq!(btree.len(), 300 - expected.len());
+    assert_eq!(btree.count()?, btree.len());
+    assert!(btree.keys().all(|k| !(100..200).contains(&k) && k % 10 != 0));
+
+    assert_eq!(btree.extract_if(|_, _| true)?.len(), 300 - expected.len());
+    btree.verify()?;
+    assert!(btree.is_empty());
+
+    // In a multimap, the values that are kept keep their order.
+    let mut btree = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    for i in 0..100_u64 {
+        btree.set(i % 3, i)?;
+    }
+    let extracted = btree.extract_if(|k, v| *k == 1 && v % 2 == 0)?;
+    assert_eq!(extracted, (1..100).step_by(3).filter(|v| v % 2 == 0).map(|v| (1, v)).collect::<Vec<_>>());
+    btree.verify()?;
+    assert_eq!(btree.get_all(1)?, (1..100).step_by(3).filter(|v| v % 2 == 1).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+
+#[test]
+fn rank_and_select() -> Result<()> {
+    for max_key_count in 3..=5 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut btree = BTree::open(temp_dir.path(), Some(max_key_count))?;
+        assert_eq!(btree.rank(1)?, 0);
+        assert_eq!(btree.select(0)?, None);
+
+        // even keys 0, 2, ..., 598, inserted in a scrambled order
+        let n = 300_u64;
+        for i in 0..n {
+            btree.set((i * 7919) % n * 2, i)?;
+            btree.verify()?;
+        }
+        let keys: Vec<u64> = btree.keys().collect();
+        for k in 0..2 * n + 1 {
+            assert_eq!(btree.rank(k)?, keys.partition_point(|key| *key < k), "rank of {}", k);
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(btree.select(i)?.map(|(k, _)| k), Some(*k));
+        }
+        assert_eq!(btree.select(keys.len())?, None);
+
+        // The counts are kept up to date when nodes are merged and entries moved between them.
+        for i in (0..n).step_by(2) {
+            btree.remove((i * 7919) % n * 2)?;
+            btree.verify()?;
+        }
+        let keys: Vec<u64> = btree.keys().collect();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(btree.rank(*k)?, i);
+            assert_eq!(btree.select(i)?.map(|(k, _)| k), Some(*k));
+        }
+    }
+
+    // Bulk loaded trees have their counts as well.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let btree = BTree::bulk_load(temp_dir.path(), (0..1000_u64).map(|i| (i * 3, i)), Some(4))?;
+    btree.verify()?;
+    assert_eq!(btree.rank(1500)?, 500);
+    assert_eq!(btree.select(700)?, Some((2100, 700)));
+
+    Ok(())
+}
+
+
+#[test]
+fn close_and_drop_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::open(temp_dir.path().join("a"), None)?;
+    btree.set(1_u64, 10_u64)?;
+    btree.close()?;
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("a"), None)?;
+    assert_eq!(btree.get(1)?, Some(10));
+
+    // The metadata can't be stored once the directory is gone: `close` returns the error, `drop`
+    // doesn't panic.
+    let mut btree = BTree::open(temp_dir.path().join("b"), None)?;
+    btree.set(1_u64, 10_u64)?;
+    std::fs::remove_dir_all(temp_dir.path().join("b"))?;
+    assert_eq!(btree.close().unwrap_err().kind(), ErrorKind::Io);
+
+    let mut btree = BTree::open(temp_dir.path().join("c"), None)?;
+    btree.set(1_u64, 10_u64)?;
+    std::fs::remove_dir_all(temp_dir.path().join("c"))?;
+    drop(btree);
+
+    Ok(())
+}
+
+
+#[test]
+fn use_after_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..100).map(|i| (i, i)));
+    btree.close()?;
+    // The tree is stored when it's closed.
+    let reopened: BTree<u64, u64> = BTree::open_existing(temp_dir.path())?;
+    assert_eq!(reopened.len(), 100);
+    drop(reopened);
+
+    // The db file is opened again when the tree is used.
+    assert_eq!(btree.get(42)?, Some(42));
+    btree.close()?;
+    btree.extend((100..200).map(|i| (i, i)));
+    btree.close()?;
+    btree.close()?;
+    drop(btree);
+    let btree: BTree<u64, u64> = BTree::open_existing(temp_dir.path())?;
+    btree.verify()?;
+    assert_eq!(btree.len(), 200);
+
+    Ok(())
+}
+
+
+#[test]
+fn extend_with_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::from_entries(temp_dir.path(), (0..100_u64).rev().map(|i| (i, i * 10)), Some(4))?;
+    btree.extend((100..200).map(|i| (i, i * 10)));
+    btree.try_extend(vec![(0, 1), (200, 2000)])?;
+    btree.verify()?;
+    assert_eq!(btree.len(), 201);
+    assert_eq!(btree.get(0)?, Some(1));
+    assert!(btree.entries().skip(1).eq((1..=200).map(|i| (i, i * 10))));
+
+    Ok(())
+}
+
+
+#[test]
+fn scan_keys_with_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<[u8; 3], u32> = BTree::open(temp_dir.path(), Some(4))?;
+    let bytes = [0x00, 0x01, 0x7f, 0xfe, 0xff];
+    let mut keys = vec![];
+    for a in bytes {
+        for b in bytes {
+            for c in bytes {
+                keys.push([a, b, c]);
+            }
+        }
+    }
+    for (i, key) in keys.iter().enumerate() {
+        btree.set(*key, i as u32)?;
+    }
+
+    let prefixes: Vec<&[u8]> = vec![&[], &[0x01], &[0x7f, 0xff], &[0xff], &[0xff, 0xff], &[0xfe, 0xff, 0x00], &[0x02]];
+    for prefix in prefixes {
+        let expected: Vec<[u8; 3]> = keys.iter().filter(|key| key.starts_with(prefix)).cloned().collect();
+        let found: Vec<[u8; 3]> = btree.scan_prefix(prefix).map(|(key, _)| key).collect();
+        assert_eq!(found, expected, "prefix {:?}", prefix);
+    }
+    assert_eq!(btree.scan_prefix(&[0, 0, 0, 0]).count(), 0);
+
+    Ok(())
+}
+
+
+#[test]
+fn paginate_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.page(None, 10)?, (vec![], None));
+
+    let expected: Vec<(u128, u128)> = (0..95).map(|i| (i * 2, i)).collect();
+    btree.extend(expected.iter().cloned());
+    for limit in [1, 7, 19, 95, 100] {
+        let mut pages: Vec<Vec<(u128, u128)>> = vec![];
+        let mut cursor: Option<Cursor<u128>> = None;
+        loop {
+            let (page, next) = btree.page(cursor, limit)?;
+            pages.push(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages.len(), 95_usize.div_ceil(limit), "limit {}", limit);
+        assert!(pages.iter().all(|page| page.len() <= limit));
+        assert_eq!(pages.concat(), expected);
+    }
+
+    // Changes between pages are seen after the cursor.
+    let (first, cursor) = btree.page(None, 10)?;
+    assert_eq!(first.last(), Some(&(18, 9)));
+    btree.remove(20)?;
+    btree.set(19, 0)?;
+    btree.set(3, 0)?;
+    let (second, _) = btree.page(cursor, 2)?;
+    assert_eq!(second, vec![(19, 0), (22, 11)]);
+
+    // An empty page would look like the end of the entries.
+    let err = btree.page(None, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+
+    // The values of a key in a multimap are split over pages as well.
+    let mut multimap: BTree<u128, u128> = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    let expected: Vec<(u128, u128)> = (0..100).map(|i| (i / 7 % 3 + i / 40 * 10, i)).collect();
+    let mut expected_order = expected.clone();
+    expected_order.sort_by_key(|(key, _)| *key);
+    multimap.extend(expected.iter().cloned());
+    for limit in [1, 2, 5, 13, 100] {
+        let mut entries = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = multimap.page(cursor, limit)?;
+            assert!(!page.is_empty() && page.len() <= limit);
+            entries.extend(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(entries, expected_order, "limit {}", limit);
+    }
+
+    Ok(())
+}
+
+
+// Encodes the nodes with bincode and flips the bits of every byte.
+struct Inverted;
+
+impl PageCodec<u128, u128> for Inverted {
+    fn encode(node: &BTNode<u128, u128>) -> Result<Vec<u8>> {
+        let bytes = <BincodeCodec as PageCodec<u128, u128>>::encode(node)?;
+        Ok(bytes.into_iter().map(|b| !b).collect())
+    }
+
+    fn decode(bytes: &[u8], page_nr: PagePtr) -> Result<BTNode<u128, u128>> {
+        let bytes: Vec<u8> = bytes.iter().map(|b| !b).collect();
+        <BincodeCodec as PageCodec<u128, u128>>::decode(&bytes, page_nr)
+    }
+}
+
+#[test]
+fn custom_page_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u128, u128, Inverted> = BTree::open_with_codec(temp_dir.path(), Some(4), 4096)?;
+    for i in 0..200 {
+        btree.set(i, i * 10)?;
+    }
+    for i in (0..200).step_by(3) {
+        btree.remove(i)?;
+    }
+    btree.verify()?;
+    let expected: Vec<(u128, u128)> = (0..200).filter(|i| i % 3 != 0).map(|i| (i, i * 10)).collect();
+    assert!(btree.entries().eq(expected.iter().cloned()));
+    assert!(btree.scan_buffered(8).eq(expected.iter().cloned()));
+    drop(btree);
+
+    let btree: BTree<u128, u128, Inverted> = BTree::open_with_codec(temp_dir.path(), None, 4096)?;
+    assert!(btree.entries().eq(expected.iter().cloned()));
+    drop(btree);
+
+    // The nodes can't be read with the default codec, starting with the root.
+    let result = BTree::<u128, u128>::open(temp_dir.path(), None);
+    assert!(matches!(result, Err(Error::Corrupt(_))));
+
+    Ok(())
+}
+
+
+#[test]
+fn page_pointer_sizes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wide = temp_dir.path().join("wide");
+    let narrow = temp_dir.path().join("narrow");
+    check_page_pointer_size::<BincodeCodec>(&wide)?;
+    check_page_pointer_size::<BincodeCodec<4>>(&narrow)?;
+
+    // The size of a page pointer is recorded in the metadata.
+    let result = BTree::<u64, Vec<u8>>::builder(&narrow).codec::<BincodeCodec<8>>().open();
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidConfiguration);
+    let result = BTree::<u64, Vec<u8>>::builder(&wide).codec::<BincodeCodec<4>>().open();
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidConfiguration);
+
+    Ok(())
+}
+
+
+// Stores values long enough to go to overflow pages in a tree with codec `C`, and reopens it.
+fn check_page_pointer_size<C: PageCodec<u64, Vec<u8>>>(directory: &Path) -> Result<()> {
+    let n = 2_000_u64;
+    let bytes = |i: u64| vec![(i % 251) as u8; (i * 37 % 300) as usize];
+    let mut btree: BTree<u64, Vec<u8>, C> = BTree::builder(directory).codec::<C>().page_size(1024).open()?;
+    for i in (0..n).map(|i| i * 7 % n) {
+        btree.set(i, bytes(i))?;
+    }
+    for i in (0..n).step_by(3) {
+        btree.remove(i)?;
+    }
+    btree.verify()?;
+    drop(btree);
+
+    let btree: BTree<u64, Vec<u8>, C> = BTree::builder(directory).codec::<C>().create(false).open()?;
+    btree.verify()?;
+    assert!(btree.entries().eq((0..n).filter(|i| i % 3 != 0).map(|i| (i, bytes(i)))));
+
+    Ok(())
+}
+
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compressed_pages() -> Result<()> {
+    use bptree::ZstdCodec;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let size = |name: &str| std::fs::metadata(temp_dir.path().join(name).join("db")).unwrap().len();
+    let mut expected: BTreeMap<u128, u128> = (0..3000).map(|i| (i, i % 7)).collect();
+    let mut btree: BTree<u128, u128> = BTree::open(path("bincode"), None)?;
+    btree.extend(expected.clone());
+    drop(btree);
+
+    // The leaves are filled until they're full when they're compressed.
+    let mut btree: BTree<u128, u128, ZstdCodec> = BTree::open_with_codec(path("zstd"), None, 4096)?;
+    btree.extend(expected.clone());
+    btree.verify()?;
+    drop(btree);
+    assert!(size("zstd") * 3 < size("bincode"));
+
+    // Entries in random order, some of them removed again.
+    let mut btree: BTree<u128, u128, ZstdCodec<3>> = BTree::open_with_codec(path("zstd"), None, 4096)?;
+    for i in 0..3000_u128 {
+        let key = i * 7_919 % 3_607;
+        if i % 4 == 3 {
+            btree.remove(key)?;
+            expected.remove(&key);
+        } else {
+            btree.set(key, key % 7)?;
+            expected.insert(key, key % 7);
+        }
+    }
+    btree.verify()?;
+    assert!(btree.entries().eq(expected.clone()));
+
+    // Values that don't compress no longer fit in the leaves as they are, they move to overflow pages.
+    let scramble = |key: u128| key.wrapping_mul(0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c835);
+    btree.map_values(|&key, _| scramble(key))?;
+    btree.verify()?;
+    assert!(btree.entries().eq(expected.keys().map(|&key| (key, scramble(key)))));
+    drop(btree);
+    // The leaves are split rather than moving every value to an overflow page.
+    assert!(size("zstd") < size("bincode"));
+
+    // A tree written bottom-up fills its leaves as well.
+    let btree: BTree<u128, u128, ZstdCodec> =
+        BTree::builder(path("bulk")).codec::<ZstdCodec>().bulk_load((0..3000).map(|i| (i, i % 7)))?;
+    btree.verify()?;
+    assert!(btree.entries().eq((0..3000).map(|i| (i, i % 7))));
+    drop(btree);
+    assert!(size("bulk") * 3 < size("bincode"));
+
+    // Overwriting values splits the leaves as well, one value at a time.
+    let value = |i: u128| if i.is_multiple_of(3) { scramble(i) } else { i % 7 };
+    let mut btree: BTree<u128, u128, ZstdCodec> = BTree::open_with_codec(path("bulk"), None, 4096)?;
+    for i in (0..1500).step_by(3) {
+        btree.set(i, scramble(i))?;
+    }
+    let mut cursor = btree.cursor_at_mut(1500)?;
+    while let Some(&key) = cursor.key() {
+        if key % 3 == 0 {
+            cursor.set_value(scramble(key))?;
+        }
+        cursor.move_next()?;
+    }
+    btree.verify()?;
+    assert!(btree.entries().eq((0..3000).map(|i| (i, value(i)))));
+
+    Ok(())
+}
+
+
+#[test]
+fn merge_trees() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut left: BTree<u128, u128> =
+        BTree::from_entries(temp_dir.path().join("left"), (0..300).map(|i| (i * 2, 1)), Some(4))?;
+    let right: BTree<u128, u128> =
+        BTree::from_entries(temp_dir.path().join("right"), (0..300).map(|i| (i * 3, 2)), Some(4))?;
+
+    let mut expected: BTreeMap<u128, u128> = left.entries().collect();
+    for (key, value) in right.entries() {
+        *expected.entry(key).or_insert(0) += value;
+    }
+    left.merge_with(&right, |_, value, other| value + other)?;
+    left.verify()?;
+    assert_eq!(left.len(), expected.len());
+    assert!(left.entries().eq(expected.clone().into_iter()));
+    assert_eq!(right.len(), 300);
+    right.verify()?;
+
+    // `other` wins.
+    left.merge(&right)?;
+    for (key, value) in right.entries() {
+        expected.insert(key, value);
+    }
+    assert!(left.entries().eq(expected.into_iter()));
+
+    // In a multimap, the values are added.
+    let mut multimap: BTree<u128, u128> = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    multimap.set(3, 1)?;
+    multimap.merge(&right)?;
+    assert_eq!(multimap.len(), 301);
+    assert_eq!(multimap.get_all(3)?, vec![1, 2]);
+    multimap.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn fill_factor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for fill_factor in [0.0, 1.0, -0.5, f64::NAN] {
+        let result = BTree::<u128, u128>::open_with_fill_factor(temp_dir.path().join("invalid"), None, fill_factor);
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))), "{}", fill_factor);
+    }
+
+    let mut sizes = vec![];
+    for (name, fill_factor) in [("half", 0.5), ("dense", 0.8), ("full", 0.99), ("sparse", 0.2)] {
+        let directory = temp_dir.path().join(name);
+        let mut btree: BTree<u128, u128> = BTree::open_with_fill_factor(&directory, Some(10), fill_factor)?;
+        btree.extend((0..1000).map(|i| (i, i)));
+        btree.verify()?;
+        drop(btree);
+        sizes.push(std::fs::metadata(directory.join("db"))?.len());
+
+        // The fill factor is kept when the tree is opened again.
+        let mut btree: BTree<u128, u128> = BTree::open(&directory, None)?;
+        btree.extend((1000..1500).map(|i| (i, i)));
+        btree.verify()?;
+        for i in (0..1500).filter(|i| i % 3 != 0) {
+            btree.remove(i)?;
+            if i % 50 == 0 {
+                btree.verify()?;
+            }
+        }
+        btree.verify()?;
+        assert!(btree.keys().eq((0..1500).step_by(3)));
+    }
+    assert!(sizes[1] < sizes[0] && sizes[2] < sizes[1] && sizes[0] < sizes[3], "{:?}", sizes);
+
+    Ok(())
+}
+
+
+#[test]
+fn shrink_to_fit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_size = || std::fs::metadata(temp_dir.path().join("db")).map(|m| m.len());
+    let mut btree: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.shrink_to_fit()?;
+    btree.extend((0..1000).map(|i| (i, i)));
+    let size = db_size()?;
+
+    // The first leaves are in the first pages, the last page is still in use.
+    for i in 0..200 {
+        btree.remove(i)?;
+    }
+    btree.shrink_to_fit()?;
+    assert_eq!(db_size()?, size);
+
+    // The last leaves are in the last pages.
+    for i in 500..1000 {
+        btree.remove(i)?;
+    }
+    btree.shrink_to_fit()?;
+    assert!(db_size()? < size / 2, "{} of {} bytes left", db_size()?, size);
+    btree.verify()?;
+    drop(btree);
+
+    let mut btree: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+    btree.verify()?;
+    assert!(btree.keys().eq(200..500));
+    btree.extend((500..1000).map(|i| (i, i)));
+    btree.verify()?;
+    assert!(btree.keys().eq(200..1000));
+
+    Ok(())
+}
+
+
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+    let mut calls = 0;
+    for i in (0..200).chain((0..300).rev()) {
+        let value = btree.get_or_insert_with(i, || {
+            calls += 1;
+            i * 10
+        })?;
+        assert_eq!(value, i * 10);
+    }
+    assert_eq!(calls, 300);
+    assert_eq!(btree.len(), 300);
+    btree.verify()?;
+
+    // An existing value is returned as it is.
+    btree.set(5, 1)?;
+    assert_eq!(btree.get_or_insert_with(5, || panic!("5 is present"))?, 1);
+
+    // Both a present and a missing key take a single descent.
+    let (_, path) = btree.get_with_path(150)?;
+    btree.reset_page_stats();
+    assert_eq!(btree.get_or_insert_with(150, || 0)?, 1500);
+    assert_eq!(btree.page_stats().reads, path.len() as u64);
+    let (_, path) = btree.get_with_path(299)?;
+    btree.reset_page_stats();
+    assert_eq!(btree.get_or_insert_with(1000, || 1)?, 1);
+    assert_eq!(btree.page_stats().reads, path.len() as u64);
+    btree.verify()?;
+
+    let mut multimap: BTree<u128, u128> = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    multimap.set(1, 10)?;
+    multimap.set(1, 20)?;
+    assert_eq!(multimap.get_or_insert_with(1, || panic!("1 is present"))?, 10);
+    assert_eq!(multimap.get_or_insert_with(2, || 30)?, 30);
+    assert_eq!(multimap.len(), 3);
+
+    Ok(())
+}
+
+
+#[test]
+fn open_existing_tree() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let directory = temp_dir.path().join("tree");
+    let result = BTree::<u128, u128>::open_existing(&directory);
+    assert!(matches!(result, Err(Error::NotFound)));
+    assert!(!directory.exists());
+
+    // A directory with other files is left alone.
+    std::fs::create_dir(&directory)?;
+    std::fs::write(directory.join("notes.txt"), "not a tree")?;
+    let result = BTree::<u128, u128>::open_existing(&directory);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(std::fs::read_dir(&directory)?.count(), 1);
+
+    std::fs::write(directory.join("meta"), "not the metadata of a tree")?;
+    let result = BTree::<u128, u128>::open_existing(&directory);
+    assert!(matches!(result, Err(Error::InvalidFileFormat { page_nr: None })));
+    std::fs::remove_file(directory.join("meta"))?;
+
+    let mut btree: BTree<u128, u128> = BTree::open(&directory, None)?;
+    btree.set(1, 10)?;
+    drop(btree);
+    let btree = BTree::<u128, u128>::open_existing(&directory)?;
+    assert_eq!(btree.get(1)?, Some(10));
+
+    Ok(())
+}
+
+
+#[test]
+fn node_too_large() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // 1000 keys don't fit in a page of 4096 bytes.
+    let mut btree: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(1000))?;
+    let err = (0..1000).try_for_each(|i| btree.set(i, i).map(|_| ())).unwrap_err();
+    match err {
+        Error::NodeTooLarge { page_nr, needed, available } => {
+            assert_eq!(page_nr, 0);
+            assert!(needed > available);
+            assert_eq!(available, 4096);
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn named_trees() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for name in ["", "a/b", "..", "by id", "x.db"] {
+        let result = BTree::<u128, u128>::open_named(temp_dir.path(), name, None);
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))), "{:?}", name);
+    }
+
+    let mut default: BTree<u128, u128> = BTree::open(temp_dir.path(), Some(4))?;
+    let mut by_id: BTree<u128, u128> = BTree::open_named(temp_dir.path(), "by_id", Some(4))?;
+    let mut by_name: BTree<u128, u128> = BTree::open_named(temp_dir.path(), "by-name", Some(4))?;
+    for i in 0..100 {
+        default.set(i, 1)?;
+        by_id.set(i, 2)?;
+        by_name.set(i * 2, 3)?;
+    }
+    for i in 0..50 {
+        by_id.remove(i)?;
+    }
+    by_id.compact()?;
+    drop((default, by_id, by_name));
+    assert!(temp_dir.path().join("by_id.meta").exists());
+    assert!(temp_dir.path().join("by-name.db").exists());
+
+    let default: BTree<u128, u128> = BTree::open(temp_dir.path(), None)?;
+    let by_id: BTree<u128, u128> = BTree::open_named(temp_dir.path(), "by_id", None)?;
+    let by_name: BTree<u128, u128> = BTree::open_named(temp_dir.path(), "by-name", None)?;
+    assert!(default.entries().eq((0..100).map(|i| (i, 1))));
+    assert!(by_id.entries().eq((50..100).map(|i| (i, 2))));
+    assert!(by_name.entries().eq((0..100).map(|i| (i * 2, 3))));
+    for btree in [default, by_id, by_name] {
+        btree.verify()?;
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn share_a_tree_between_threads() -> Result<()> {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<BTree<u128, u128>>();
+    assert_send_sync::<SyncBTree<u128, u128>>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let btree = SyncBTree::new(BTree::<u128, u128>::open(temp_dir.path(), Some(4))?);
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let btree = btree.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in (t..400).step_by(4) {
+                    btree.set(i, i * 10)?;
+                }
+                for i in (t..400).step_by(8) {
+                    assert_eq!(btree.remove(i)?, Some(i * 10));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(btree.len()?, 200);
+    assert_eq!(btree.get(5)?, Some(50));
+    assert_eq!(btree.get(8)?, None);
+    let btree = btree.lock()?;
+    btree.verify()?;
+    assert!(btree.keys().eq((0..400).filter(|i| i % 8 >= 4)));
+
+    Ok(())
+}
+
+
+#[test]
+fn map_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u128, u128> =
+        BTree::from_entries(temp_dir.path().join("a"), (0..300).map(|i| (i, i)), Some(4))?;
+    btree.map_values(|key, value| key + value * 10)?;
+    btree.verify()?;
+    assert!(btree.entries().eq((0..300).map(|i| (i, i * 11))));
+
+    // Values move in and out of overflow pages, with the write-ahead log.
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path().join("b"), Some(4))?;
+    btree.set_wal(true);
+    let value = |i: u64| vec![i as u8; (i * 997 % 10_000) as usize];
+    for i in 0..100 {
+        btree.set(i, value(i))?;
+    }
+    btree.map_values(|key, _| value(key + 1))?;
+    btree.verify()?;
+    drop(btree);
+    let btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path().join("b"), None)?;
+    btree.verify()?;
+    assert!(btree.values().eq((1..101).map(value)));
+
+    Ok(())
+}
+
+
+#[test]
+fn wal_replayed_on_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |file: &str| temp_dir.path().join(file);
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.set_wal(true);
+    for i in 0..100 {
+        btree.set(i, i * 10)?;
+    }
+    let page_size = btree.page_size() as usize;
+    drop(btree);
+    let (db_before, meta_before) = (std::fs::read(path("db"))?, std::fs::read(path("meta"))?);
+
+    // The record of a change, as it's written to the log before the db file: the pages that it
+    // changed and the metadata after it.
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), None)?;
+    btree.set_wal(true);
+    btree.set(1000, 10_000)?;
+    btree.remove(0)?;
+    drop(btree);
+    let db_after = std::fs::read(path("db"))?;
+    let meta_after = std::fs::read(path("meta"))?;
+    let pages: Vec<(u64, &[u8])> = db_after
+        .chunks(page_size)
+        .enumerate()
+        .filter(|(page_nr, page)| db_before.chunks(page_size).nth(*page_nr) != Some(*page))
+        .map(|(page_nr, page)| (page_nr as u64, page))
+        .collect();
+    assert!(!pages.is_empty());
+    let mut record = b"%bpwal%%".to_vec();
+    record.extend_from_slice(&(page_size as u64).to_le_bytes());
+    record.extend_from_slice(&(pages.len() as u64).to_le_bytes());
+    for (page_nr, page) in &pages {
+        record.extend_from_slice(&page_nr.to_le_bytes());
+        record.extend_from_slice(page);
+    }
+    record.extend_from_slice(&(meta_after.len() as u64).to_le_bytes());
+    record.extend_from_slice(&meta_after);
+    record.extend_from_slice(b"%commit%");
+
+    // A crash before anything of the change reached the db file, with the log torn or complete.
+    let crash_with_log = |log: &[u8]| -> Result<()> {
+        std::fs::write(path("db"), &db_before)?;
+        std::fs::write(path("meta"), &meta_before)?;
+        std::fs::write(path("wal"), log)?;
+        // A reader can't finish or discard the change.
+        let result = BTree::<u64, u64>::builder(temp_dir.path()).read_only(true).open();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ReadOnly);
+        Ok(())
+    };
+
+    crash_with_log(&record[..record.len() - 1])?;
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path(), None)?;
+    assert!(btree.keys().eq(0..100));
+    btree.verify()?;
+    drop(btree);
+    assert!(!path("wal").exists());
+
+    crash_with_log(&record)?;
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path(), None)?;
+    assert!(btree.keys().eq((1..100).chain([1000])));
+    assert_eq!(btree.get(1000)?, Some(10_000));
+    btree.verify()?;
+    drop(btree);
+    assert!(!path("wal").exists());
+    let btree = BTree::<u64, u64>::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(btree.len(), 100);
+
+    Ok(())
+}
+
+
+#[test]
+fn preallocated_tree() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_size = || std::fs::metadata(temp_dir.path().join("db")).map(|m| m.len());
+    let mut btree: BTree<u128, u128> = BTree::with_preallocated(temp_dir.path(), 1000, Some(4))?;
+    assert_eq!(db_size()?, 1000 * 4096);
+    btree.extend((0..1000).map(|i| (i, i)));
+    btree.verify()?;
+    assert_eq!(db_size()?, 1000 * 4096);
+    drop(btree);
+
+    // An existing tree is opened as it is and the file never shrinks.
+    let mut btree: BTree<u128, u128> = BTree::with_preallocated(temp_dir.path(), 10, None)?;
+    assert_eq!(db_size()?, 1000 * 4096);
+    assert!(btree.keys().eq(0..1000));
+    btree.shrink_to_fit()?;
+    assert!(db_size()? < 1000 * 4096);
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[cfg(feature = "snapshots")]
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.snapshot().unwrap_err().kind(), ErrorKind::InvalidConfiguration);
+    drop(btree);
+    let mut btree: BTree<u64, Vec<u8>> = BTree::builder(temp_dir.path()).snapshots(true).open()?;
+    // Some values take overflow pages.
+    let value = |i: u64, version: u8| vec![version; (i * 997 % 6_000) as usize];
+    for i in 0..200 {
+        btree.set(i, value(i, 1))?;
+    }
+    let first = btree.snapshot()?;
+
+    // Splits, merges, overwritten values and reused pages.
+    for i in (0..200).step_by(2) {
+        btree.remove(i)?;
+    }
+    for i in 200..300 {
+        btree.set(i, value(i, 2))?;
+    }
+    for i in (1..200).step_by(2) {
+        btree.set(i, value(i, 2))?;
+    }
+    let second = btree.snapshot()?;
+    for i in 0..300 {
+        btree.set(i, value(i, 3))?;
+    }
+    btree.verify()?;
+
+    for i in 0..300 {
+        let expected = match i {
+            0..=199 => Some(value(i, 1)),
+            _ => None,
+        };
+        assert_eq!(btree.get_at_version(first, i)?, expected);
+        let expected = match i {
+            0..=199 if i % 2 == 0 => None,
+            _ => Some(value(i, 2)),
+        };
+        assert_eq!(btree.get_at_version(second, i)?, expected);
+        assert_eq!(btree.get(i)?, Some(value(i, 3)));
+    }
+    assert_eq!(btree.compact().map_err(|err| err.kind()), Err(ErrorKind::SnapshotsHeld));
+
+    btree.release_snapshot(first)?;
+    assert_eq!(btree.get_at_version(first, 1).map_err(|err| err.kind()), Err(ErrorKind::SnapshotNotFound));
+    assert_eq!(btree.get_at_version(second, 1)?, Some(value(1, 2)));
+    btree.verify()?;
+
+    // The snapshots are gone when the tree is reopened, their pages are reused.
+    drop(btree);
+    let mut btree: BTree<u64, Vec<u8>> = BTree::open(temp_dir.path(), None)?;
+    btree.verify()?;
+    for i in 0..300 {
+        btree.set(i, value(i, 4))?;
+    }
+    btree.verify()?;
+    assert!(btree.values().eq((0..300).map(|i| value(i, 4))));
+    btree.compact()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn truncate_below() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("a"), Some(4))?;
+    assert_eq!(btree.truncate_below(10)?, 0);
+
+    // A sliding window of 500 keys.
+    btree.extend((0..500).map(|i| (i, i)));
+    for end in (750..5000).step_by(250) {
+        btree.extend((end - 250..end).map(|i| (i, i)));
+        assert_eq!(btree.truncate_below(end - 500)?, 250);
+        btree.verify()?;
+        assert_eq!(btree.len(), 500);
+        assert!(btree.keys().eq(end - 500..end));
+    }
+    assert_eq!(btree.truncate_below(4500)?, 250);
+    assert_eq!(btree.truncate_below(4500)?, 0);
+    assert!(btree.keys().eq(4500..4750));
+    btree.verify()?;
+    assert_eq!(btree.truncate_below(u64::MAX)?, 250);
+    assert!(btree.is_empty());
+    btree.verify()?;
+    btree.extend((0..100).map(|i| (i, i)));
+    btree.verify()?;
+    assert!(btree.keys().eq(0..100));
+
+    // Keys all over the tree, with values in overflow pages.
+    let value = |i: u64| vec![i as u8; (i * 997 % 6_000) as usize];
+    for key in (0..=150).step_by(6) {
+        let path = temp_dir.path().join(format!("b{}", key));
+        let mut btree: BTree<u64, Vec<u8>> = BTree::from_entries(&path, (0..150).map(|i| (i, value(i))), Some(4))?;
+        assert_eq!(btree.truncate_below(key)?, key.min(150) as usize);
+        btree.verify()?;
+        assert!(btree.keys().eq(key..150));
+        assert_eq!(btree.get(key)?, if key < 150 { Some(value(key)) } else { None });
+        drop(btree);
+        let btree: BTree<u64, Vec<u8>> = BTree::open(&path, None)?;
+        btree.verify()?;
+        assert!(btree.values().eq((key..150).map(value)));
+    }
+
+    // In a multimap, all values of a smaller key go.
+    let mut multimap: BTree<u64, u64> = BTree::open_multimap(temp_dir.path().join("c"), Some(4))?;
+    for i in 0..300 {
+        multimap.set(i / 10, i)?;
+    }
+    assert_eq!(multimap.truncate_below(12)?, 120);
+    multimap.verify()?;
+    assert_eq!(multimap.get_all(12)?, (120..130).collect::<Vec<_>>());
+    assert!(multimap.values().eq(120..300));
+
+    // A sparse tree, with nodes near their minimum fill after random changes.
+    let mut next = 1_u64;
+    let mut random = || {
+        next = next.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (next >> 33) % 300
+    };
+    let mut sparse: BTree<u64, u64> = BTree::open(temp_dir.path().join("d"), Some(3))?;
+    let mut expected = BTreeMap::new();
+    for i in 0..600 {
+        let key = random();
+        if i % 3 == 2 {
+            sparse.remove(key)?;
+            expected.remove(&key);
+        } else {
+            sparse.set(key, i)?;
+            expected.insert(key, i);
+        }
+    }
+    for key in (0..=300).step_by(7) {
+        let removed = expected.range(..key).count();
+        expected.retain(|&k, _| k >= key);
+        assert_eq!(sparse.truncate_below(key)?, removed);
+        sparse.verify()?;
+        assert!(sparse.entries().eq(expected.iter().map(|(&k, &v)| (k, v))));
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn find_by_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.find_by_value(1)?, None);
+    assert_eq!((btree.min_by_value()?, btree.max_by_value()?), (None, None));
+    btree.extend((0..500).map(|i| (i, i % 100)));
+    assert_eq!(btree.find_by_value(0)?, Some(0));
+    assert_eq!(btree.find_by_value(42)?, Some(42));
+    btree.truncate_below(250)?;
+    assert_eq!(btree.find_by_value(42)?, Some(342));
+    assert_eq!(btree.find_by_value(100)?, None);
+    // The first of the entries with the same value wins.
+    assert_eq!(btree.min_by_value()?, Some((300, 0)));
+    assert_eq!(btree.max_by_value()?, Some((299, 99)));
+
+    Ok(())
+}
+
+
+#[test]
+fn page_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..1000).map(|i| (i, i)));
+    assert!(btree.page_stats().writes >= 1000 / 4);
+
+    // A lookup reads one page per level, overwriting a value stores the leaf.
+    btree.reset_page_stats();
+    assert_eq!(btree.page_stats(), PageStats::default());
+    btree.get(500)?;
+    let depth = btree.page_stats().reads;
+    assert!((5..=9).contains(&depth), "{} pages read", depth);
+    btree.set(500, 0)?;
+    assert_eq!(btree.page_stats(), PageStats { reads: 2 * depth, writes: 1, cache_hits: 0 });
+
+    // A read handle counts its own reads.
+    let reader = btree.reader();
+    reader.get(1)?;
+    assert_eq!(reader.page_stats().reads, depth);
+    assert_eq!(btree.page_stats().reads, 2 * depth);
+
+    Ok(())
+}
+
+
+#[test]
+fn string_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = |i: u64| format!("key {:08}", i);
+    let mut btree: BTree<String, u64> = BTree::open(temp_dir.path().join("a"), None)?;
+    for i in (0..2000).rev() {
+        btree.set(key(i), i)?;
+    }
+    for i in (0..2000).step_by(2) {
+        btree.remove(key(i))?;
+    }
+    btree.verify()?;
+    assert_eq!(btree.get(key(1))?, Some(1));
+    assert_eq!(btree.get(key(2))?, None);
+    assert!(btree.range(key(100)..key(110)).map(|(_, v)| v).eq((101..110).step_by(2)));
+
+    // Keys that are longer than the size of a `String`, with fewer keys in a node.
+    let long_key = |i: u64| format!("{:0>200}", i);
+    let mut btree: BTree<String, u64> = BTree::open(temp_dir.path().join("b"), None)?;
+    assert_eq!(btree.set(long_key(1), 1).map_err(|err| err.kind()), Ok(None));
+    let result = btree.try_extend((2..1000).map(|i| (long_key(i), i)));
+    assert_eq!(result.map_err(|err| err.kind()), Err(ErrorKind::NodeTooLarge));
+    let mut btree: BTree<String, u64> = BTree::open(temp_dir.path().join("c"), Some(16))?;
+    btree.try_extend((0..1000).map(|i| (long_key(i), i)))?;
+    btree.verify()?;
+    assert!(btree.values().eq(0..1000));
+
+    Ok(())
+}
+
+
+#[test]
+fn builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let result = BTree::<u128, u128>::builder(temp_dir.path()).read_only(true).open();
+    assert!(matches!(result, Err(Error::NotFound)));
+
+    let mut btree: BTree<u128, u128> =
+        BTree::builder(temp_dir.path()).name("by_id").max_key_count(4).page_size(8192).wal(true).open()?;
+    for i in 0..100 {
+        btree.set(i, i * 10)?;
+    }
+    btree.verify()?;
+    drop(btree);
+    assert!(temp_dir.path().join("by_id.db").is_file());
+
+    let mut btree: BTree<u128, u128> = BTree::builder(temp_dir.path()).name("by_id").read_only(true).open()?;
+    assert_eq!(btree.len(), 100);
+    assert_eq!(btree.get(42)?, Some(420));
+    assert!(matches!(btree.set(1, 1), Err(Error::ReadOnly)));
+    assert!(matches!(btree.remove(1), Err(Error::ReadOnly)));
+    assert!(matches!(btree.compact(), Err(Error::ReadOnly)));
+    assert_eq!(btree.get(1)?, Some(10));
+    btree.verify()?;
+    drop(btree);
+
+    let result = BTree::<u128, u128>::builder(temp_dir.path()).name("by_id").multimap(true).open();
+    assert!(matches!(result, Err(Error::InvalidConfiguration(_))));
+    let mut multimap: BTree<u128, u128> = BTree::builder(temp_dir.path()).multimap(true).preallocate(16).open()?;
+    multimap.set(1, 1)?;
+    multimap.set(1, 2)?;
+    assert_eq!(multimap.get_all(1)?, vec![1, 2]);
+    assert!(std::fs::metadata(temp_dir.path().join("db"))?.len() >= 16 * 4096);
+
+    Ok(())
+}
+
+
+// A key that is compared case-insensitively, but stored as it was given.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+struct CaseInsensitive(String);
+
+
+impl PartialEq for CaseInsensitive {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+
+impl Eq for CaseInsensitive {}
+
+
+impl PartialOrd for CaseInsensitive {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl Ord for CaseInsensitive {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.to_lowercase().cmp(&other.0.to_lowercase())
+    }
+}
+
+
+#[test]
+fn remove_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<CaseInsensitive, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    for i in 0..100 {
+        btree.set(CaseInsensitive(format!("Key {}", i)), i)?;
+    }
+    let (key, value) = btree.remove_entry(CaseInsensitive(String::from("KEY 42")))?.unwrap();
+    assert_eq!(key.0, "Key 42");
+    assert_eq!(value, 42);
+    assert_eq!(btree.remove_entry(CaseInsensitive(String::from("key 42")))?, None);
+    assert_eq!(btree.len(), 99);
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("a"), Some(4))?;
+    let mut expected = BTreeMap::new();
+    btree.set_many(&[])?;
+    assert!(btree.is_empty());
+
+    // A batch into an empty tree, split into several levels at once.
+    let pairs: Vec<(u64, u64)> = (0..1000).map(|i| (i * 10, i)).collect();
+    btree.set_many(&pairs)?;
+    expected.extend(pairs);
+    btree.verify()?;
+
+    // Batches that go between the existing keys, overwrite some and repeat keys, in any order.
+    for round in 0..10u64 {
+        let pairs: Vec<(u64, u64)> =
+            (0..500).map(|i| ((i * 7919 + round * 13) % 12_000, round * 1000 + i)).collect();
+        btree.set_many(&pairs)?;
+        expected.extend(pairs);
+        btree.verify()?;
+        assert_eq!(btree.len(), expected.len());
+    }
+    assert!(btree.entries().eq(expected.into_iter()));
+
+    // Every leaf that receives entries is stored once.
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("b"), Some(16))?;
+    btree.set_many(&(0..1000).map(|i| (i * 2, i)).collect::<Vec<_>>())?;
+    btree.reset_page_stats();
+    btree.set_many(&[(101, 0), (103, 0), (105, 0), (107, 0)])?;
+    assert!(btree.page_stats().writes <= 3, "{:?}", btree.page_stats());
+
+    let mut multimap: BTree<u64, u64> = BTree::open_multimap(temp_dir.path().join("c"), Some(4))?;
+    multimap.set_many(&[(1, 1), (2, 2), (1, 3)])?;
+    multimap.set_many(&(0..100).map(|i| (1, i + 10)).collect::<Vec<_>>())?;
+    multimap.verify()?;
+    assert!(multimap.get_all(1)?.into_iter().eq([1, 3].iter().cloned().chain(10..110)));
+    assert_eq!(multimap.len(), 103);
+
+    Ok(())
+}
+
+
+#[test]
+fn invalid_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..100).map(|i| (i, i)));
+    btree.close()?;
+
+    // The first leaf is page 0, which isn't the root: the tree can still be opened.
+    let mut db = std::fs::read(temp_dir.path().join("db"))?;
+    db[0] = 7;
+    std::fs::write(temp_dir.path().join("db"), &db)?;
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    let err = btree.get(0).unwrap_err();
+    assert!(matches!(err, Error::InvalidFileFormat { page_nr: Some(0) }), "{:?}", err);
+    assert_eq!(err.to_string(), "Invalid file format at page 0");
+    assert_eq!(btree.get(99)?, Some(99));
+
+    Ok(())
+}
+
+
+#[test]
+fn last_n() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.last_n(5)?, vec![]);
+
+    for i in (0..100).rev() {
+        btree.set(i, i * 10)?;
+    }
+    assert_eq!(btree.last_n(0)?, vec![]);
+    assert_eq!(btree.last_n(1)?, vec![(99, 990)]);
+    for n in [3, 17, 99, 100] {
+        let expected: Vec<(u64, u64)> = (100 - n as u64..100).map(|i| (i, i * 10)).collect();
+        assert_eq!(btree.last_n(n)?, expected);
+    }
+    assert_eq!(btree.last_n(1000)?.len(), 100);
+
+    Ok(())
+}
+
+
+#[test]
+fn smallest_max_key_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for max_key_count in [0, 1] {
+        let result = BTree::<u64, u64>::open(temp_dir.path(), Some(max_key_count));
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))), "{}", max_key_count);
+    }
+    assert!(!temp_dir.path().join("meta").exists());
+
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(2))?;
+    let mut expected = BTreeMap::new();
+    for i in 0..500 {
+        let key = (i * 7919) % 1000;
+        btree.set(key, i)?;
+        expected.insert(key, i);
+    }
+    btree.verify()?;
+    for i in 0..250 {
+        let key = (i * 7919) % 1000;
+        assert_eq!(btree.remove(key)?, expected.remove(&key));
+    }
+    btree.verify()?;
+    assert!(btree.entries().eq(expected.into_iter()));
+
+    Ok(())
+}
+
+
+#[test]
+fn into_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let btree: BTree<u64, String> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.into_iter().count(), 0);
+
+    // Values in overflow pages are read as well.
+    let value = |i: u64| "x".repeat((i as usize % 10) * 1000);
+    let mut btree: BTree<u64, String> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..200).rev().map(|i| (i, value(i))));
+    let mut expected = 0;
+    for (key, v) in btree {
+        assert_eq!(key, expected);
+        assert_eq!(v, value(key));
+        expected += 1;
+    }
+    assert_eq!(expected, 200);
+
+    // The tree is still on disk.
+    let btree: BTree<u64, String> = BTree::open(temp_dir.path(), Some(4))?;
+    let migrated: BTreeMap<u64, String> = btree.into_iter().collect();
+    assert_eq!(migrated.len(), 200);
+
+    Ok(())
+}
+
+
+#[test]
+fn iterate_empty_tree() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    // A fresh tree hasn't written any page, an emptied tree still has its root leaf.
+    for emptied in [false, true] {
+        if emptied {
+            btree.try_extend((0..100).map(|i| (i, i)))?;
+            btree.retain(|_, _| false)?;
+        }
+        assert_eq!(btree.keys().count(), 0);
+        assert_eq!(btree.values().count(), 0);
+        assert_eq!(btree.entries().count(), 0);
+        assert_eq!(btree.range(10..20).count(), 0);
+        assert_eq!(btree.range_values(..).count(), 0);
+        assert_eq!(btree.scan_buffered(8).count(), 0);
+        assert_eq!(btree.iter_from(5).count(), 0);
+        assert_eq!(btree.page(None, 10)?, (vec![], None));
+        assert_eq!(btree.reader().range(..).count(), 0);
+        assert_eq!(btree.count()?, 0);
+    }
+    drop(btree);
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("fresh"), Some(4))?;
+    assert_eq!(btree.into_iter().count(), 0);
+
+    Ok(())
+}
+
+
+#[test]
+fn disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, String> = BTree::open_with_page_size(temp_dir.path(), Some(4), 4096)?;
+    assert_eq!(btree.disk_usage()?, DiskUsage::default());
+
+    let value = |i: u64| "x".repeat((i as usize % 10) * 1000);
+    btree.extend((0..200).map(|i| (i, value(i))));
+    drop(btree);
+    let mut btree: BTree<u64, String> = BTree::open(temp_dir.path(), Some(4))?;
+    let usage = btree.disk_usage()?;
+    let page_size = 4096;
+    assert_eq!(usage.free_pages, 0);
+    assert!(usage.live_pages > 0);
+    assert!(usage.file_bytes >= usage.live_pages * page_size);
+    assert!(usage.padding_bytes > 0 && usage.padding_bytes < usage.live_pages * page_size);
+
+    btree.retain(|key, _| key % 2 == 0)?;
+    let after_remove = btree.disk_usage()?;
+    assert!(after_remove.free_pages > 0);
+    assert!(after_remove.live_pages < usage.live_pages);
+    btree.compact()?;
+    assert_eq!(btree.disk_usage()?.free_pages, 0);
+
+    Ok(())
+}
+
+
+#[test]
+fn open_with_recovery() -> Result<()> {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..1000).map(|i| (i, i * 2)));
+    drop(btree);
+
+    // An intact tree is opened as it is.
+    let btree: BTree<u64, u64> = BTree::open_with_recovery(temp_dir.path())?;
+    assert_eq!(btree.len(), 1000);
+    assert_eq!(btree.recovery(), None);
+    drop(btree);
+
+    // Overwrite the last page with garbage, like a write that was interrupted.
+    let db_path = temp_dir.path().join("db");
+    let mut fh = OpenOptions::new().write(true).open(&db_path)?;
+    let len = fh.metadata()?.len();
+    let len_pages = len / 4096;
+    fh.seek(SeekFrom::Start(len - 4096))?;
+    fh.write_all(&[0xff; 4096])?;
+    drop(fh);
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    assert!(btree.verify().is_err());
+    drop(btree);
+
+    let mut btree: BTree<u64, u64> = BTree::open_with_recovery(temp_dir.path())?;
+    btree.verify()?;
+    let len = btree.len();
+    assert!(len > 900 && len < 1000);
+    assert_eq!(btree.count()?, len);
+    let recovery = btree.recovery().expect("discarded pages");
+    assert_eq!(recovery.discarded_pages, vec![len_pages - 1]);
+    assert_eq!(recovery.lost_entries, 1000 - len as u64);
+    for (key, value) in btree.entries() {
+        assert_eq!(value, key * 2);
+    }
+    btree.set(1000, 2000)?;
+    drop(btree);
+    let btree: BTree<u64, u64> = BTree::open_existing(temp_dir.path())?;
+    btree.verify()?;
+    assert_eq!(btree.len(), len + 1);
+
+    Ok(())
+}
+
+
+#[test]
+fn descending_keys() -> Result<()> {
+    use std::cmp::Reverse;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<Reverse<u64>, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..500).map(|i| (Reverse(i * 7 % 500), i)));
+    btree.verify()?;
+    assert!(btree.keys().eq((0..500).rev().map(Reverse)));
+    assert_eq!(btree.get(Reverse(42))?, Some(6));
+    // Reverse(600) is smaller than every key.
+    assert_eq!(btree.floor(Reverse(600))?.map(|(key, _)| key), None);
+    assert_eq!(btree.ceil(Reverse(600))?.map(|(key, _)| key), Some(Reverse(499)));
+    // Ranges go from the largest key down.
+    let range: Vec<u64> = btree.range(Reverse(10)..Reverse(5)).map(|(Reverse(key), _)| key).collect();
+    assert_eq!(range, vec![10, 9, 8, 7, 6]);
+
+    for i in (0..500).filter(|i| i % 3 != 0) {
+        assert!(btree.remove(Reverse(i))?.is_some());
+    }
+    btree.verify()?;
+    assert!(btree.keys().eq((0..500).rev().filter(|i| i % 3 == 0).map(Reverse)));
+    drop(btree);
+
+    let btree: BTree<Reverse<u64>, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.select(0)?.map(|(key, _)| key), Some(Reverse(498)));
+
+    Ok(())
+}
+
+
+#[test]
+fn contains_all_and_any() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("map"), Some(4))?;
+    assert!(btree.contains_all(&[])?);
+    assert!(!btree.contains_any(&[])?);
+    assert!(!btree.contains_any(&[1, 2])?);
+    btree.extend((0..500).map(|i| (i * 2, i)));
+
+    assert!(btree.contains_all(&[998, 0, 500, 0, 2, 4, 6])?);
+    assert!(!btree.contains_all(&[0, 2, 3, 4])?);
+    assert!(!btree.contains_all(&[1000])?);
+    assert!(btree.contains_any(&[1, 3, 5, 7, 9, 998])?);
+    assert!(btree.contains_any(&[1, 1, 2])?);
+    assert!(!btree.contains_any(&[1001, 999, 1, 3, 101, 555])?);
+    let evens: Vec<u64> = (0..500).map(|i| i * 2).collect();
+    assert!(btree.contains_all(&evens)?);
+    let odds: Vec<u64> = (0..500).map(|i| i * 2 + 1).collect();
+    assert!(!btree.contains_any(&odds)?);
+
+    // In a multimap, the entries with a key can start in the leaf after the one found by a descent.
+    let mut multimap: BTree<u64, u64> = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    for i in 0..100 {
+        for j in 0..3 {
+            multimap.set(i * 2, j)?;
+        }
+    }
+    assert!(multimap.contains_all(&evens[..100])?);
+    assert!(!multimap.contains_any(&odds[..100])?);
+    assert!(!multimap.contains_all(&[0, 200])?);
+
+    Ok(())
+}
+
+
+#[test]
+fn node_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("default"), None)?;
+    assert_eq!(btree.page_size(), 4096);
+    assert!(btree.max_key_count() > 100);
+    assert_eq!(btree.fanout(), btree.max_key_count() + 1);
+    // A leaf holds a tag and a value per key, an internal node a page pointer and a count.
+    assert!(btree.leaf_capacity() > btree.max_key_count());
+    assert_eq!(btree.split_at(), btree.max_key_count().div_ceil(2) as usize);
+
+    let mut btree: BTree<u64, u64> = BTree::open_with_fill_factor(temp_dir.path().join("small"), Some(10), 0.9)?;
+    btree.set(1, 1)?;
+    assert_eq!(btree.max_key_count(), 10);
+    assert_eq!(btree.leaf_capacity(), 10);
+    assert_eq!(btree.fanout(), 11);
+    assert_eq!(btree.split_at(), 9);
+    drop(btree);
+    // The capacity is stored with the tree.
+    let btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("small"), None)?;
+    assert_eq!((btree.max_key_count(), btree.split_at()), (10, 9));
+
+    Ok(())
+}
+
+
+#[test]
+fn persistent_map() -> Result<()> {
+    // Generic code, run against a BTree and a std BTreeMap.
+    fn fill<M: PersistentMap<u64, String>>(map: &mut M) -> Result<Vec<(u64, String)>> {
+        assert!(map.is_empty());
+        for i in (0..200).rev() {
+            assert_eq!(map.insert(i, i.to_string())?, None);
+        }
+        assert_eq!(map.insert(7, String::from("seven"))?, Some(String::from("7")));
+        assert_eq!(map.remove(&8)?, Some(String::from("8")));
+        assert_eq!(map.remove(&8)?, None);
+        assert_eq!(map.get(&7)?, Some(String::from("seven")));
+        assert!(map.contains_key(&9)? && !map.contains_key(&8)?);
+        assert_eq!(map.len(), 199);
+        Ok(map.iter().collect())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, String> = BTree::open(temp_dir.path(), Some(4))?;
+    let mut std_map = BTreeMap::new();
+    assert_eq!(fill(&mut btree)?, fill(&mut std_map)?);
+    btree.verify()?;
+
+    // The method of the tree itself doesn't return the replaced value.
+    btree.insert(7, String::from("7"))?;
+    btree.insert(8, String::from("8"))?;
+    assert_eq!(btree.len(), 200);
+    assert_eq!(btree.get(7)?, Some(String::from("7")));
+
+    Ok(())
+}
+
+
+#[test]
+fn free_page_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::builder(temp_dir.path()).max_key_count(4).free_page_limit(10).open()?;
+    btree.extend((0..1000).map(|i| (i, i)));
+    btree.retain(|key, _| key % 10 == 0)?;
+    assert!(btree.disk_usage()?.free_pages > 10);
+    btree.flush()?;
+    assert!(btree.disk_usage()?.free_pages <= 10);
+    btree.verify()?;
+    assert!(btree.keys().eq((0..1000).step_by(10)));
+
+    // Without a limit, the deleted pages are stored and reused after reopening.
+    btree.set_free_page_limit(None);
+    btree.retain(|key, _| key % 100 == 0)?;
+    let free_pages = btree.disk_usage()?.free_pages;
+    assert!(free_pages > 0);
+    btree.close()?;
+    drop(btree);
+    let mut btree: BTree<u64, u64> = BTree::open_existing(temp_dir.path())?;
+    assert_eq!(btree.disk_usage()?.free_pages, free_pages);
+    btree.verify()?;
+    btree.extend((0..1000).filter(|i| i % 100 != 0).map(|i| (i, i)));
+    assert!(btree.disk_usage()?.free_pages < free_pages);
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn root_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.root_info()?, RootInfo { page_nr: None, is_leaf: true, key_count: 0 });
+    btree.reset_page_stats();
+    btree.root_info()?;
+    assert_eq!(btree.page_stats().reads + btree.page_stats().writes, 0);
+
+    btree.extend((0..4).map(|i| (i, i)));
+    assert_eq!(btree.root_info()?, RootInfo { page_nr: Some(0), is_leaf: true, key_count: 4 });
+    btree.set(4, 4)?;
+    let root = btree.root_info()?;
+    assert!(!root.is_leaf);
+    assert_eq!(root.key_count, 1);
+    assert_ne!(root.page_nr, Some(0));
+
+    Ok(())
+}
+
+
+#[test]
+fn get_with_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree: BTree<u64, u64> = BTree::open(temp_dir.path().join("map"), Some(4))?;
+    assert_eq!(btree.get_with_path(1)?, (None, vec![]));
+
+    btree.extend((0..200).map(|i| (i * 2, i)));
+    let root_page_nr = btree.root_info()?.page_nr.unwrap();
+    let (value, path) = btree.get_with_path(100)?;
+    assert_eq!(value, Some(50));
+    assert_eq!(path[0], root_page_nr);
+    // All leaves are at the same depth.
+    for key in 0..400 {
+        let (value, key_path) = btree.get_with_path(key)?;
+        assert_eq!(value, btree.get(key)?);
+        assert_eq!(key_path.len(), path.len());
+        assert_eq!(key_path[0], root_page_nr);
+    }
+    assert_ne!(btree.get_with_path(0)?.1, btree.get_with_path(398)?.1);
+
+    let mut multimap: BTree<u64, u64> = BTree::open_multimap(temp_dir.path().join("multimap"), Some(4))?;
+    for i in 0..60 {
+        multimap.set(i / 10, i)?;
+    }
+    for key in 0..6 {
+        let (value, path) = multimap.get_with_path(key)?;
+        assert_eq!(value, Some(key * 10));
+        assert!(!path.is_empty());
+    }
+    assert_eq!(multimap.get_with_path(6)?.0, None);
+
+    Ok(())
+}
+
+
+#[test]
+fn lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || BTree::<u64, u64>::open(temp_dir.path(), Some(4));
+    let open_read_only = || BTree::<u64, u64>::builder(temp_dir.path()).read_only(true).open();
+    let mut writer = open()?;
+    writer.extend((0..100).map(|i| (i, i)));
+    writer.flush()?;
+    assert_eq!(open().unwrap_err().kind(), ErrorKind::Locked);
+    assert_eq!(open_read_only().unwrap_err().kind(), ErrorKind::Locked);
+    // A reader of the same tree doesn't lock it.
+    assert_eq!(writer.reader().len(), 100);
+    drop(writer);
+
+    // Readers share the lock.
+    let reader = open_read_only()?;
+    let other_reader = open_read_only()?;
+    assert_eq!(open().unwrap_err().kind(), ErrorKind::Locked);
+    drop((reader, other_reader));
+
+    // A closed tree is locked again when it's used.
+    let mut writer = open()?;
+    writer.close()?;
+    let reader = open_read_only()?;
+    assert_eq!(writer.get(1).unwrap_err().kind(), ErrorKind::Locked);
+    drop(reader);
+    assert_eq!(writer.get(1)?, Some(1));
+    assert_eq!(open().unwrap_err().kind(), ErrorKind::Locked);
+    drop(writer);
+
+    let unlocked = BTree::<u64, u64>::builder(temp_dir.path()).lock(false).open()?;
+    let writer = open()?;
+    assert_eq!(unlocked.len(), writer.len());
+
+    Ok(())
+}
+
+
+#[test]
+fn save_to_and_load_from() -> Result<()> {
+    use std::convert::TryInto;
+    use std::fs;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, String>::open(temp_dir.path().join("source"), Some(4))?;
+    btree.extend((0..200).map(|i| (i, format!("value {}", i))));
+    btree.retain(|key, _| key % 3 != 0)?;
+    let mut blob = vec![];
+    btree.save_to(&mut blob)?;
+    // The deleted pages are left out.
+    assert!((blob.len() as u64) < btree.disk_usage()?.file_bytes);
+    assert!(!temp_dir.path().join("source").join("compact").exists());
+
+    let loaded = BTree::<u64, String>::load_from(&blob[..], temp_dir.path().join("loaded"))?;
+    loaded.verify()?;
+    assert_eq!(loaded.len(), btree.len());
+    assert!(loaded.entries().eq(btree.entries()));
+    assert_eq!(loaded.max_key_count(), 4);
+    drop(loaded);
+    let err = BTree::<u64, String>::load_from(&blob[..], temp_dir.path().join("loaded")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+
+    let err = BTree::<u64, String>::load_from(&blob[..blob.len() - 1], temp_dir.path().join("truncated")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Corrupt);
+    let err = BTree::<u64, String>::load_from(&b"garbage"[..], temp_dir.path().join("garbage")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidFileFormat);
+
+    // A load that fails leaves no files behind.
+    assert_eq!(fs::read_dir(temp_dir.path().join("truncated"))?.count(), 0);
+    let meta_len = u64::from_le_bytes(blob[..8].try_into().unwrap()) as usize;
+    let mut broken_pages = blob.clone();
+    broken_pages[8 + meta_len..].fill(0xff);
+    assert!(BTree::<u64, String>::load_from(&broken_pages[..], temp_dir.path().join("broken_pages")).is_err());
+    assert_eq!(fs::read_dir(temp_dir.path().join("broken_pages"))?.count(), 0);
+
+    // The node count follows the magic header and the format version in the metadata. One that makes
+    // the size of the pages overflow is rejected instead of wrapping around.
+    let header_len = u64::from_le_bytes(blob[8..16].try_into().unwrap()) as usize;
+    let node_count = 16 + header_len + 4;
+    let mut huge = blob.clone();
+    huge[node_count..node_count + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    let err = BTree::<u64, String>::load_from(&huge[..], temp_dir.path().join("huge")).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidFileFormat);
+    assert!(!temp_dir.path().join("huge").exists());
+
+    let mut empty = BTree::<u64, String>::open(temp_dir.path().join("empty"), None)?;
+    let mut blob = vec![];
+    empty.save_to(&mut blob)?;
+    assert!(BTree::<u64, String>::load_from(&blob[..], temp_dir.path().join("loaded_empty"))?.is_empty());
+
+    Ok(())
+}
+
+
+#[test]
+fn remove_missing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, u64>::open(temp_dir.path(), Some(4))?;
+    assert_eq!(btree.remove(1)?, None);
+    assert_eq!(btree.len_u64(), 0);
+    assert_eq!(btree.truncate_below(10)?, 0);
+    assert_eq!(btree.len_u64(), 0);
+
+    btree.extend((0..10).map(|i| (i, i)));
+    for i in 0..20 {
+        btree.remove(i)?;
+    }
+    assert_eq!(btree.len_u64(), 0);
+    assert_eq!(btree.remove(1)?, None);
+    assert_eq!(btree.len_u64(), 0);
+    btree.verify()?;
+
+    Ok(())
+}
+
+
+#[test]
+fn relocate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (old, new) = (temp_dir.path().join("old"), temp_dir.path().join("new"));
+    let mut btree = BTree::<u64, u64>::builder(&old).name("tree").max_key_count(Some(4)).open()?;
+    btree.extend((0..100).map(|i| (i, i)));
+    btree.relocate(&new)?;
+    assert!(!old.join("tree.meta").exists() && !old.join("tree.db").exists() && !old.join("tree.lock").exists());
+    assert_eq!(btree.get(50)?, Some(50));
+    btree.set(100, 100)?;
+    btree.verify()?;
+    let err = BTree::<u64, u64>::builder(&new).name("tree").open().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Locked);
+
+    // Relocating to where the tree is does nothing.
+    btree.relocate(&new)?;
+    assert_eq!(btree.len(), 101);
+    drop(btree);
+    let mut btree = BTree::<u64, u64>::builder(&new).name("tree").open()?;
+    btree.verify()?;
+    assert!(btree.keys().eq(0..=100));
+
+    // A tree that's already there isn't overwritten.
+    BTree::<u64, u64>::builder(&old).name("tree").open()?.set(1, 2)?;
+    assert_eq!(btree.relocate(&old).unwrap_err().kind(), ErrorKind::InvalidConfiguration);
+    assert_eq!(btree.get(1)?, Some(1));
+
+    Ok(())
+}
+
+
+#[test]
+fn cursor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, u64>::open(temp_dir.path(), Some(4))?;
+    let mut cursor = btree.cursor_at(0)?;
+    assert_eq!(cursor.key(), None);
+    assert!(!cursor.move_next()? && !cursor.move_prev()?);
+    drop(cursor);
+
+    // keys 10, 20, ..., 500
+    btree.extend((1..=50).map(|i| (i * 10, i * 100)));
+    let mut cursor = btree.cursor_at(15)?;
+    assert_eq!((cursor.key(), cursor.value()?), (Some(&20), Some(200)));
+    // Back and forth over the leaves.
+    for i in 3..=50 {
+        assert!(cursor.move_next()?);
+        assert_eq!(cursor.key(), Some(&(i * 10)));
+    }
+    assert!(!cursor.move_next()? && !cursor.move_next()?);
+    assert_eq!((cursor.key(), cursor.value()?), (None, None));
+    for i in (1..=50).rev() {
+        assert!(cursor.move_prev()?);
+        assert_eq!((cursor.key(), cursor.value()?), (Some(&(i * 10)), Some(i * 100)));
+    }
+    assert!(!cursor.move_prev()? && !cursor.move_prev()?);
+    assert!(cursor.move_next()?);
+    assert_eq!(cursor.key(), Some(&10));
+
+    assert!(cursor.seek(&250)?);
+    assert_eq!(cursor.key(), Some(&250));
+    assert!(!cursor.seek(&501)?);
+    assert!(cursor.move_prev()?);
+    assert_eq!(cursor.key(), Some(&500));
+
+    // Equal keys of a multimap, spread over several leaves.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, u64>::open_multimap(temp_dir.path(), Some(4))?;
+    let entries: Vec<(u64, u64)> = (0..30).map(|i| (i / 10, i)).collect();
+    btree.try_extend(entries.clone())?;
+    let mut cursor = btree.cursor_at(2)?;
+    let mut seen = vec![];
+    while cursor.move_prev()? {
+        seen.push((*cursor.key().unwrap(), cursor.value()?.unwrap()));
+    }
+    seen.reverse();
+    assert_eq!(seen, entries[..20]);
+
+    // Values are replaced in place, also values in overflow pages.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, Vec<u8>>::open(temp_dir.path(), Some(4))?;
+    btree.extend((0..20).map(|i| (i, vec![i as u8; 10])));
+    let mut cursor = btree.cursor_at_mut(3)?;
+    while cursor.key().is_some_and(|key| *key < 15) {
+        let key = *cursor.key().unwrap();
+        cursor.set_value(vec![key as u8; key as usize * 1000])?;
+        assert_eq!(cursor.value()?, Some(vec![key as u8; key as usize * 1000]));
+        cursor.move_next()?;
+    }
+    assert!(cursor.move_prev()? && cursor.move_prev()?);
+    cursor.set_value(vec![])?;
+    assert_eq!(cursor.key(), Some(&13));
+    assert!(!cursor.seek(&20)?);
+    assert_eq!(cursor.set_value(vec![]).unwrap_err().kind(), ErrorKind::KeyNotFound);
+    drop(cursor);
+    btree.verify()?;
+    assert_eq!(btree.len(), 20);
+    for i in 0..20 {
+        let expected = match i {
+            13 => vec![],
+            3..=14 => vec![i as u8; i as usize * 1000],
+            _ => vec![i as u8; 10],
+        };
+        assert_eq!(btree.get(i)?, Some(expected));
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn user_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, u64>::open(temp_dir.path().join("tree"), Some(4))?;
+    assert!(btree.user_metadata().is_empty());
+    btree.set_user_metadata(b"schema 3".to_vec())?;
+    btree.extend((0..20).map(|i| (i, i)));
+    let err = btree.set_user_metadata(vec![0; 4097]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+    assert_eq!(btree.user_metadata(), b"schema 3");
+    drop(btree);
+
+    let mut btree = BTree::<u64, u64>::open(temp_dir.path().join("tree"), None)?;
+    assert_eq!(btree.user_metadata(), b"schema 3");
+    btree.set_user_metadata(vec![7; 4096])?;
+    let mut blob = vec![];
+    btree.save_to(&mut blob)?;
+    let loaded = BTree::<u64, u64>::load_from(&blob[..], temp_dir.path().join("loaded"))?;
+    assert_eq!(loaded.user_metadata(), &[7; 4096][..]);
+
+    Ok(())
+}
+
+
+#[test]
+fn large_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Keys of 256 bytes leave room for few keys in a node.
+    let key = |i: u64| {
+        let mut key = [u64::MAX; 32];
+        key[0] = i;
+        key
+    };
+    let mut btree = BTree::<[u64; 32], u64>::open(temp_dir.path().join("inserted"), None)?;
+    let (leaf_capacity, max_key_count) = (btree.leaf_capacity(), btree.max_key_count());
+    assert!(max_key_count <= leaf_capacity && leaf_capacity < 16);
+    let n = 3000;
+    for i in 0..n {
+        btree.set(key((i * 7919) % n), i)?;
+    }
+    btree.verify()?;
+    for i in 0..2000 {
+        assert_eq!(btree.remove(key((i * 7919) % n))?, Some(i));
+    }
+    btree.verify()?;
+    let left: BTreeSet<u64> = (2000..n).map(|i| (i * 7919) % n).collect();
+    assert!(btree.keys().map(|key| key[0]).eq(left));
+
+    // Full leaves and internal nodes, each of them fits in a page.
+    let count = leaf_capacity * (max_key_count + 1) * (max_key_count + 1);
+    let btree = BTree::bulk_load(temp_dir.path().join("loaded"), (0..count).map(|i| (key(i), i)), None)?;
+    btree.verify()?;
+    assert_eq!(btree.len(), count as usize);
+
+    Ok(())
+}
+
+
+#[test]
+fn reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = BTree::<u64, u64>::builder(temp_dir.path()).max_key_count(4).lock(false).open()?;
+    writer.extend((0..100).map(|i| (i, i)));
+    writer.flush()?;
+    let mut reader = BTree::<u64, u64>::builder(temp_dir.path()).read_only(true).lock(false).open()?;
+    assert_eq!(reader.len(), 100);
+
+    writer.extend((100..200).map(|i| (i, i)));
+    writer.retain(|key, _| key % 4 != 3)?;
+    // Nothing is seen before it's flushed.
+    reader.reopen()?;
+    assert_eq!(reader.len(), 100);
+    writer.flush()?;
+    reader.reopen()?;
+    assert_eq!(reader.len(), 150);
+    assert_eq!(reader.get(150)?, Some(150));
+    assert_eq!(reader.get(151)?, None);
+    reader.verify()?;
+
+    // The db file is replaced by a compaction.
+    writer.compact()?;
+    reader.reopen()?;
+    assert!(reader.entries().eq(writer.entries()));
+    reader.verify()?;
+
+    // The writer keeps its own changes.
+    writer.set(1000, 1000)?;
+    writer.reopen()?;
+    assert_eq!(writer.get(1000)?, Some(1000));
+    assert_eq!(writer.set(1001, 1001)?, None);
+
+    Ok(())
+}
+
+
+#[test]
+fn page_allocation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut usage = vec![];
+    for page_allocation in [PageAllocation::LastDeleted, PageAllocation::LowestDeleted, PageAllocation::Append] {
+        let directory = temp_dir.path().join(format!("{:?}", page_allocation));
+        let mut btree = BTree::<u64, u64>::builder(directory).max_key_count(4).page_allocator(page_allocation).open()?;
+        btree.extend((0..1000).map(|i| (i, i)));
+        btree.retain(|key, _| *key < 200)?;
+        let deleted = btree.disk_usage()?;
+        btree.extend((1000..1200).map(|i| (i, i)));
+        let added = btree.disk_usage()?;
+        btree.shrink_to_fit()?;
+        btree.verify()?;
+        assert!(btree.keys().eq((0..200).chain(1000..1200)));
+        usage.push((deleted, added, btree.disk_usage()?));
+    }
+    let (deleted, added, shrunk) = usage[0];
+    assert!(added.free_pages < deleted.free_pages);
+    assert!(shrunk.free_pages > 0);
+    // The deleted pages that are left are the highest ones.
+    let (deleted, added, shrunk) = usage[1];
+    assert!(added.free_pages < deleted.free_pages);
+    assert_eq!(shrunk.free_pages, 0);
+    assert!(shrunk.file_bytes < usage[0].2.file_bytes);
+    let (deleted, added, _) = usage[2];
+    assert_eq!(added.free_pages, deleted.free_pages);
+    assert!(added.file_bytes > deleted.file_bytes);
+
+    Ok(())
+}
+
+
+// Reuses the highest deleted page and counts the pages it's told about.
+#[derive(Debug, Default)]
+struct HighestDeleted {
+    freed: Arc<AtomicU64>,
+}
+
+
+impl PageAllocator for HighestDeleted {
+    fn allocate(&mut self, free: &FreePages) -> Option<PagePtr> {
+        free.highest()
+    }
+
+    fn free(&mut self, _page_nr: PagePtr) {
+        self.freed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+
+#[test]
+fn page_allocator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let allocator = HighestDeleted::default();
+    let freed = Arc::clone(&allocator.freed);
+    let mut btree = BTree::<u64, u64>::builder(temp_dir.path()).max_key_count(4).page_allocator(allocator).open()?;
+    btree.extend((0..1000).map(|i| (i, i)));
+    btree.retain(|key, _| *key < 200)?;
+    let deleted = btree.disk_usage()?;
+    assert!(deleted.free_pages > 0);
+    assert_eq!(freed.load(Ordering::Relaxed), deleted.free_pages);
+    btree.extend((1000..1200).map(|i| (i, i)));
+    let added = btree.disk_usage()?;
+    assert!(added.free_pages < deleted.free_pages);
+    assert_eq!(added.file_bytes, deleted.file_bytes);
+    btree.verify()?;
+    assert!(btree.keys().eq((0..200).chain(1000..1200)));
+    // Reusing the highest pages leaves the lowest ones deleted, none of them at the end of the file.
+    btree.shrink_to_fit()?;
+    assert_eq!(btree.disk_usage()?.free_pages, added.free_pages);
+
+    // The allocator is kept when the tree is reopened.
+    btree.reopen()?;
+    btree.retain(|key, _| *key >= 1000)?;
+    btree.extend((2000..2200).map(|i| (i, i)));
+    btree.verify()?;
+    assert!(btree.keys().eq((1000..1200).chain(2000..2200)));
+
+    Ok(())
+}
+
+
+#[test]
+fn build_streaming() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for n in [0, 1, 4, 5, 9, 100, 2_000] {
+        let directory = temp_dir.path().join(n.to_string());
+        let entries = (0..n).map(|i| Ok((i, i * 10)));
+        let btree = BTree::<u64, u64>::build_streaming(&directory, entries, Some(4))?;
+        btree.verify()?;
+        assert_eq!(btree.len(), n as usize);
+        assert!(btree.entries().eq((0..n).map(|i| (i, i * 10))));
+        drop(btree);
+        let btree = BTree::<u64, u64>::open_existing(&directory)?;
+        btree.verify()?;
+        assert!(btree.keys().eq(0..n));
+    }
+
+    let value = |i: u64| vec![i as u8; (i % 5) as usize * 3000];
+    let entries = (0..500).map(|i| Ok((i, value(i))));
+    let btree = BTree::<u64, Vec<u8>>::build_streaming(temp_dir.path().join("overflow"), entries, None)?;
+    btree.verify()?;
+    assert!(btree.entries().eq((0..500).map(|i| (i, value(i)))));
+    let err = BTree::<u64, Vec<u8>>::build_streaming(temp_dir.path().join("overflow"), vec![], None).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+
+    // No tree is left behind by an error.
+    let open_existing = |name: &str| BTree::<u64, u64>::open_existing(temp_dir.path().join(name));
+    let entries = vec![1, 2, 2].into_iter().map(|i| Ok((i, i)));
+    let err = BTree::<u64, u64>::build_streaming(temp_dir.path().join("unsorted"), entries, None).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsortedInput);
+    assert_eq!(open_existing("unsorted").unwrap_err().kind(), ErrorKind::NotFound);
+    let entries = (0..100).map(|i| if i < 50 { Ok((i, i)) } else { Err(Error::KeyNotFound) });
+    let err = BTree::<u64, u64>::build_streaming(temp_dir.path().join("failed"), entries, Some(4)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::KeyNotFound);
+    assert_eq!(open_existing("failed").unwrap_err().kind(), ErrorKind::NotFound);
+
+    Ok(())
+}
+
+
+#[test]
+fn bulk_load_with_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = || BTree::<u64, u64>::builder(temp_dir.path()).name("loaded").page_size(8192).fill_factor(0.7);
+    let btree = builder().bulk_load((0..1000).map(|i| (i, i * 10)))?;
+    btree.verify()?;
+    assert_eq!(btree.page_size(), 8192);
+    drop(btree);
+    let err = builder().bulk_load(vec![(1, 10)]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+    let entries = (1000..2000).map(|i| Ok((i, i * 10)));
+    let btree = builder().name("streamed").build_streaming(entries)?;
+    assert_eq!(btree.page_size(), 8192);
+    drop(btree);
+
+    // Both trees are stored under their own name, with the page size they were built with.
+    assert_eq!(BTree::<u64, u64>::open_existing(temp_dir.path()).unwrap_err().kind(), ErrorKind::NotFound);
+    let btree = BTree::<u64, u64>::builder(temp_dir.path()).name("loaded").create(false).open()?;
+    assert_eq!(btree.page_size(), 8192);
+    assert!(btree.keys().eq(0..1000));
+    let btree = BTree::<u64, u64>::builder(temp_dir.path()).name("streamed").create(false).open()?;
+    assert!(btree.keys().eq(1000..2000));
+
+    let err = BTree::<u64, u64>::builder(temp_dir.path()).multimap(true).bulk_load(vec![(1, 10)]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidConfiguration);
+
+    Ok(())
+}
+
+
+#[test]
+fn snapshot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<u64, u64>::open(temp_dir.path(), Some(4))?;
+    assert!(btree.snapshot_keys()?.is_empty());
+    btree.extend((0..500).map(|i| (i, i)));
+    assert!(btree.snapshot_keys()?.into_iter().eq(btree.keys()));
+
+    // The list stays as it was when the tree is changed afterwards.
+    let keys = btree.reader().snapshot_keys()?;
+    btree.retain(|key, _| key % 7 == 0)?;
+    assert!(keys.into_iter().eq(0..500));
+    assert!(btree.reader().snapshot_keys()?.into_iter().eq((0..500).step_by(7)));
+
+    Ok(())
+}
+
+
+#[test]
+fn get_and_set_by_reference() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut btree = BTree::<[u64; 32], u64>::open(temp_dir.path(), None)?;
+    let keys: Vec<[u64; 32]> = (0..200).map(|i| [i; 32]).collect();
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(btree.set_ref(key, &(i as u64))?, None);
+    }
+    assert_eq!(btree.set_ref(&keys[3], &1000)?, Some(3));
+    for (i, key) in keys.iter().enumerate().skip(4) {
+        assert_eq!(btree.get_ref(key)?, Some(i as u64));
+    }
+    assert_eq!(btree.get_ref(&keys[3])?, Some(1000));
+    assert_eq!(btree.reader().get_ref(&keys[3])?, Some(1000));
+    assert_eq!(btree.get_ref(&[1000; 32])?, None);
+    btree.verify()?;
+
+    Ok(())
+}